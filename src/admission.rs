@@ -0,0 +1,368 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{Request, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    serve::IncomingStream,
+};
+use futures::future::Either;
+use std::future::Future;
+use std::pin::Pin;
+use serde::Deserialize;
+use schemars::JsonSchema;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tower::Service;
+use tracing::warn;
+use crate::auth::client_ip;
+use crate::concurrency::hold_until_complete;
+use crate::networking::AppState;
+
+/// 負荷に応じて新規リクエストを拒否する設定
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    pub enabled: bool,
+    /// 同時に処理中のリクエスト数（ストリーミングを含む）の上限
+    pub max_in_flight: Option<usize>,
+    /// クライアントの IP ごとの同時接続の上限
+    ///
+    /// 接続を受け付けた時点で接続元の IP ごとに数え、認証前の接続にも適用します。
+    /// 接続元が `security.trusted_proxies` の場合は、`X-Forwarded-For` のクライアントごとに処理中のリクエストを数えます。
+    pub max_connections_per_ip: Option<usize>,
+}
+
+/// 処理中のリクエスト数を数え、上限を超える新規リクエストを拒否する
+///
+/// 既に受け付けたリクエストは最後まで処理し、拒否は新規リクエストのみに行います。
+pub struct AdmissionController {
+    config: AdmissionConfig,
+    in_flight: Arc<AtomicUsize>,
+    per_ip: Arc<IpCounts>,
+}
+
+const IP_COUNT_SHARDS: usize = 16;
+
+/// IP ごとの接続数。接続のたびに1つのロックを奪い合わないよう、アドレスのハッシュでシャードに分ける
+///
+/// 0 になったアドレスは削除します。
+struct IpCounts {
+    shards: [Mutex<HashMap<IpAddr, usize>>; IP_COUNT_SHARDS],
+    hasher: RandomState,
+}
+
+impl IpCounts {
+    fn new() -> Self {
+        Self { shards: Default::default(), hasher: RandomState::new() }
+    }
+
+    fn shard(&self, ip: &IpAddr) -> &Mutex<HashMap<IpAddr, usize>> {
+        &self.shards[self.hasher.hash_one(ip) as usize % IP_COUNT_SHARDS]
+    }
+
+    /// 上限未満なら1つ増やします
+    fn try_increment(&self, ip: IpAddr, max: usize) -> bool {
+        let mut shard = self.shard(&ip).lock().unwrap();
+        let count = shard.entry(ip).or_insert(0);
+        if *count >= max {
+            if *count == 0 {
+                shard.remove(&ip);
+            }
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    fn decrement(&self, ip: IpAddr) {
+        let mut shard = self.shard(&ip).lock().unwrap();
+        if let Some(count) = shard.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                shard.remove(&ip);
+            }
+        }
+    }
+
+    fn get(&self, ip: IpAddr) -> usize {
+        self.shard(&ip).lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+/// 受け付けたリクエストの枠。破棄されると処理中の数が減ります
+pub struct Admitted(Arc<AtomicUsize>);
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// IP ごとの枠。破棄されるとその IP の数が減ります（上限がない場合は何も数えない）
+pub struct IpAdmitted(Option<(Arc<IpCounts>, IpAddr)>);
+
+impl Drop for IpAdmitted {
+    fn drop(&mut self) {
+        if let Some((per_ip, ip)) = &self.0 {
+            per_ip.decrement(*ip);
+        }
+    }
+}
+
+impl AdmissionController {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self {
+            config,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            per_ip: Arc::new(IpCounts::new()),
+        }
+    }
+
+    /// IP ごとの接続数の上限（無効なら None）
+    fn ip_limit(&self) -> Option<usize> {
+        self.config.max_connections_per_ip.filter(|_| self.config.enabled)
+    }
+
+    /// IP ごとの上限内なら枠を返します（上限がなければ数えずに枠を返す）
+    pub fn try_admit_ip(&self, ip: IpAddr) -> Option<IpAdmitted> {
+        let Some(max) = self.ip_limit() else {
+            return Some(IpAdmitted(None));
+        };
+        self.per_ip.try_increment(ip, max).then(|| IpAdmitted(Some((self.per_ip.clone(), ip))))
+    }
+
+    /// その IP の接続数（上限がない場合は数えないため 0）
+    pub fn connections_from(&self, ip: IpAddr) -> usize {
+        self.per_ip.get(ip)
+    }
+
+    /// 受け付け可能なら枠を返します
+    pub fn try_admit(&self) -> Option<Admitted> {
+        let previous = self.in_flight.fetch_add(1, Ordering::AcqRel);
+        let admitted = Admitted(self.in_flight.clone());
+        if self.config.enabled && self.config.max_in_flight.is_some_and(|max| previous >= max) {
+            // admitted の破棄で加算を取り消す
+            return None;
+        }
+        Some(admitted)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
+
+pub async fn admission_middleware(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    // ヘルスチェックは負荷に関係なく応答する
+    if req.uri().path() == "/health" {
+        return next.run(req).await;
+    }
+    // 信頼するプロキシからの接続は接続元では数えられないため、転送元のクライアントごとに処理中のリクエストを数える
+    // （1つのクライアントが枠を占有しないよう、全体の上限より先に確認する）
+    let trusted_proxies = &state.security.trusted_proxies;
+    let via_proxy = state.admission.ip_limit().is_some()
+        && req.extensions().get::<ConnectInfo<SocketAddr>>().is_some_and(|peer| trusted_proxies.contains(&peer.0.ip()));
+    let ip = via_proxy.then(|| client_ip(req.extensions(), req.headers(), trusted_proxies)).flatten();
+    let ip_admitted = match ip.map(|ip| (ip, state.admission.try_admit_ip(ip))) {
+        Some((ip, None)) => {
+            warn!("Rejecting request to {} from {}: {} requests in flight", req.uri().path(), ip, state.admission.connections_from(ip));
+            return too_many_connections();
+        }
+        Some((_, admitted)) => admitted,
+        None => None,
+    };
+    match state.admission.try_admit() {
+        Some(admitted) => hold_until_complete(next.run(req).await, (admitted, ip_admitted)),
+        None => {
+            warn!("Shedding request to {}: {} requests in flight", req.uri().path(), state.admission.in_flight());
+            (StatusCode::SERVICE_UNAVAILABLE, "Server is overloaded").into_response()
+        }
+    }
+}
+
+fn too_many_connections() -> Response {
+    (StatusCode::SERVICE_UNAVAILABLE, [(header::CONNECTION, "close")], "Too many connections from this address").into_response()
+}
+
+/// 接続を受け付けた時点で、接続元の IP ごとの接続数を数える（`max_connections_per_ip`）
+///
+/// `into_make_service_with_connect_info` を包みます。上限を超えた接続には、リクエストを処理せずに
+/// 503 を返して接続を閉じます。数えた枠は接続が閉じるまで保持します。
+#[derive(Clone)]
+pub struct ConnectionLimit<M> {
+    inner: M,
+    state: Arc<AppState>,
+}
+
+impl<M> ConnectionLimit<M> {
+    pub fn new(inner: M, state: Arc<AppState>) -> Self {
+        Self { inner, state }
+    }
+
+    fn admit(&self, peer: SocketAddr) -> ConnectionAdmission {
+        let admission = &self.state.admission;
+        // 上限がない場合や信頼するプロキシからの接続は数えない
+        if admission.ip_limit().is_none() || self.state.security.trusted_proxies.contains(&peer.ip()) {
+            return ConnectionAdmission::Unlimited;
+        }
+        match admission.try_admit_ip(peer.ip()) {
+            Some(admitted) => ConnectionAdmission::Admitted { _slot: Arc::new(admitted) },
+            None => {
+                warn!("Rejecting connection from {}: {} connections open", peer.ip(), admission.connections_from(peer.ip()));
+                ConnectionAdmission::Rejected
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum ConnectionAdmission {
+    Unlimited,
+    // 接続が閉じてサービスが破棄されるまで枠を保持する
+    Admitted { _slot: Arc<IpAdmitted> },
+    Rejected,
+}
+
+/// `ConnectionLimit` が接続ごとに作るサービス
+#[derive(Clone)]
+pub struct LimitedConnection<S> {
+    inner: S,
+    admission: ConnectionAdmission,
+}
+
+impl<'a, M, S> Service<IncomingStream<'a>> for ConnectionLimit<M>
+where
+    M: Service<IncomingStream<'a>, Response = S, Error = Infallible>,
+    M::Future: Unpin,
+{
+    type Response = LimitedConnection<S>;
+    type Error = Infallible;
+    type Future = AdmitConnection<M::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: IncomingStream<'a>) -> Self::Future {
+        let admission = self.admit(target.remote_addr());
+        AdmitConnection { connection: self.inner.call(target), admission: Some(admission) }
+    }
+}
+
+/// `axum_server`（HTTPS）で使う場合
+impl<M, S> Service<SocketAddr> for ConnectionLimit<M>
+where
+    M: Service<SocketAddr, Response = S, Error = Infallible>,
+    M::Future: Unpin,
+{
+    type Response = LimitedConnection<S>;
+    type Error = Infallible;
+    type Future = AdmitConnection<M::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, peer: SocketAddr) -> Self::Future {
+        let admission = self.admit(peer);
+        AdmitConnection { connection: self.inner.call(peer), admission: Some(admission) }
+    }
+}
+
+/// 接続ごとのサービスを作り、受け付けの結果を持たせる
+pub struct AdmitConnection<F> {
+    connection: F,
+    admission: Option<ConnectionAdmission>,
+}
+
+impl<F, S> Future for AdmitConnection<F>
+where
+    F: Future<Output = Result<S, Infallible>> + Unpin,
+{
+    type Output = Result<LimitedConnection<S>, Infallible>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let inner = futures::ready!(Pin::new(&mut this.connection).poll(cx))?;
+        let admission = this.admission.take().expect("AdmitConnection polled after completion");
+        Poll::Ready(Ok(LimitedConnection { inner, admission }))
+    }
+}
+
+impl<S, B> Service<Request<B>> for LimitedConnection<S>
+where
+    S: Service<Request<B>, Response = Response, Error = Infallible>,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Either<S::Future, std::future::Ready<Result<Response, Infallible>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        match self.admission {
+            ConnectionAdmission::Rejected => Poll::Ready(Ok(())),
+            _ => self.inner.poll_ready(cx),
+        }
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        match self.admission {
+            ConnectionAdmission::Rejected => Either::Right(std::future::ready(Ok(too_many_connections()))),
+            _ => Either::Left(self.inner.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sheds_above_threshold() {
+        let controller = AdmissionController::new(AdmissionConfig { enabled: true, max_in_flight: Some(2), ..Default::default() });
+        let first = controller.try_admit().unwrap();
+        let _second = controller.try_admit().unwrap();
+        assert!(controller.try_admit().is_none());
+        assert_eq!(controller.in_flight(), 2, "rejected requests must not count as in flight");
+
+        drop(first);
+        assert!(controller.try_admit().is_some());
+    }
+
+    #[test]
+    fn test_disabled_never_sheds() {
+        let controller = AdmissionController::new(AdmissionConfig { enabled: false, max_in_flight: Some(0), max_connections_per_ip: Some(0) });
+        assert!(controller.try_admit().is_some());
+        assert!(controller.try_admit_ip([10, 0, 0, 1].into()).is_some());
+        // 上限がなければ数えない
+        let _admitted = controller.try_admit_ip([10, 0, 0, 1].into()).unwrap();
+        assert_eq!(controller.connections_from([10, 0, 0, 1].into()), 0);
+    }
+
+    #[test]
+    fn test_limits_connections_per_ip() {
+        let controller = AdmissionController::new(AdmissionConfig {
+            enabled: true,
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+        let abusive: IpAddr = [10, 0, 0, 1].into();
+        let first = controller.try_admit_ip(abusive).unwrap();
+        let _second = controller.try_admit_ip(abusive).unwrap();
+        assert!(controller.try_admit_ip(abusive).is_none());
+        assert!(controller.try_admit_ip([10, 0, 0, 2].into()).is_some(), "other addresses are unaffected");
+        assert_eq!(controller.connections_from(abusive), 2);
+
+        drop(first);
+        assert!(controller.try_admit_ip(abusive).is_some());
+    }
+}
@@ -1,42 +1,632 @@
 use axum::{
     body::Body,
-    http::{Request, StatusCode, header},
+    http::{HeaderMap, Request, StatusCode, header},
     response::Response,
     middleware::Next,
-    extract::State,
+    extract::{MatchedPath, State},
 };
+use serde::Deserialize;
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use subtle::{Choice, ConstantTimeEq};
+use std::time::Duration;
 use crate::networking::AppState;
-use tracing::warn;
+use tracing::{debug, warn};
+
+/// `Authorization` 以外で API キーを受け付けるヘッダー名
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// `api_keys` / `admin_keys` / `max_concurrent_requests` / ルートの `fair_queue.weights` に書くキーの形式
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyFormat {
+    /// キーをそのまま書く
+    #[default]
+    Plain,
+    /// キーの SHA-256（16進数）を書く。設定が漏れても有効なキーは分からない
+    Sha256,
+}
+
+impl KeyFormat {
+    /// 提示されたキーが一覧のいずれかに一致するか
+    pub fn is_listed(&self, keys: &[String], presented: &str) -> bool {
+        self.position(keys.iter().map(String::as_str), presented).is_some()
+    }
+
+    /// 提示されたキーに一致する最初の位置（一致の有無・位置で時間が変わらないよう全件を比較する）
+    pub fn position<'a>(&self, keys: impl IntoIterator<Item = &'a str>, presented: &str) -> Option<usize> {
+        let presented = self.normalize(presented);
+        let mut found = None;
+        let mut matched = Choice::from(0);
+        for (index, key) in keys.into_iter().enumerate() {
+            let equal = self.canonical(key).as_bytes().ct_eq(presented.as_bytes());
+            if bool::from(equal & !matched) {
+                found = Some(index);
+            }
+            matched |= equal;
+        }
+        found
+    }
+
+    /// 提示されたキーを設定と同じ形式にします（`max_concurrent_requests` の照合にも使う）
+    pub fn normalize<'a>(&self, presented: &'a str) -> std::borrow::Cow<'a, str> {
+        match self {
+            Self::Plain => presented.into(),
+            Self::Sha256 => hash_api_key(presented).into(),
+        }
+    }
+
+    /// 設定されたキーを照合に使う形に揃えます（SHA-256 の16進数は大文字でも書けるよう小文字にし、plain はそのまま）
+    pub fn canonical<'a>(&self, configured: &'a str) -> std::borrow::Cow<'a, str> {
+        match self {
+            Self::Plain => configured.into(),
+            Self::Sha256 => configured.to_ascii_lowercase().into(),
+        }
+    }
+
+    /// 設定されたキーを、そのキーで認証したリクエストの `KeyId` にします（`validate` 済みのキーを渡す）
+    pub fn key_id(&self, configured: &str) -> KeyId {
+        match self {
+            Self::Plain => KeyId::of(configured),
+            Self::Sha256 => KeyId(self.canonical(configured)[..16].to_string()),
+        }
+    }
+
+    /// 設定されたキーがこの形式として正しいかを確認します
+    pub fn validate(&self, keys: &[String]) -> anyhow::Result<()> {
+        if *self == Self::Sha256
+            && let Some(key) = keys.iter().find(|key| key.len() != 64 || !key.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            let shown: String = key.chars().take(4).collect();
+            anyhow::bail!("security.key_format is sha256 but a configured key ({}...) is not a SHA-256 hex digest", shown);
+        }
+        Ok(())
+    }
+}
+
+/// `security.api_keys` の1件
+///
+/// 文字列だけで書いた場合はすべてのパスを許可し、`{ key, allowed_paths }` で書いた場合は
+/// マッチしたルートの `path`（ルートに一致しない場合はリクエストのパス）が一覧にあるものだけを許可します。
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(from = "ApiKeySpec")]
+pub struct ApiKeyEntry {
+    pub key: String,
+    /// 未設定ならすべてのパスを許可する
+    pub allowed_paths: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum ApiKeySpec {
+    Plain(String),
+    Scoped { key: String, allowed_paths: Vec<String> },
+}
+
+impl From<ApiKeySpec> for ApiKeyEntry {
+    fn from(spec: ApiKeySpec) -> Self {
+        match spec {
+            ApiKeySpec::Plain(key) => Self { key, allowed_paths: None },
+            ApiKeySpec::Scoped { key, allowed_paths } => Self { key, allowed_paths: Some(allowed_paths) },
+        }
+    }
+}
+
+impl From<&str> for ApiKeyEntry {
+    fn from(key: &str) -> Self {
+        Self { key: key.to_string(), allowed_paths: None }
+    }
+}
+
+impl From<String> for ApiKeyEntry {
+    fn from(key: String) -> Self {
+        Self { key, allowed_paths: None }
+    }
+}
+
+impl ApiKeyEntry {
+    pub fn allows(&self, path: &str) -> bool {
+        self.allowed_paths.as_ref().is_none_or(|paths| paths.iter().any(|allowed| allowed == path))
+    }
+}
+
+/// `key_format = "sha256"` の設定に書く値を返します（`orchix hash-key <key>` と同じ）
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// 認証を通ったリクエストの拡張に入れる、API キーの識別子（キーそのものではなく SHA-256 の先頭 16 桁）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyId(pub String);
+
+impl KeyId {
+    pub fn of(key: &str) -> Self {
+        Self(hash_api_key(key)[..16].to_string())
+    }
+}
+
+/// 認証を通ったリクエストの拡張に入れる、キーに許可されたルート（`allowed_paths` を設定したキーのみ）
+///
+/// 転送するリクエストのルートはボディの `model` まで使って照合するため、照合したあとで `allows` を確認します。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteScope(pub Vec<String>);
+
+impl RouteScope {
+    pub fn allows(&self, route: &str) -> bool {
+        self.0.iter().any(|allowed| allowed == route)
+    }
+}
+
+/// 認証していないリクエストのレート制限・予算をまとめて数えるクライアント ID
+pub const ANONYMOUS_CLIENT: &str = "anonymous";
+
+/// レート制限・予算を数えるクライアント ID（認証したキーの `KeyId`、無ければ `ANONYMOUS_CLIENT`）
+pub fn client_id(extensions: &axum::http::Extensions) -> &str {
+    extensions.get::<KeyId>().map_or(ANONYMOUS_CLIENT, |id| id.0.as_str())
+}
+
+/// 外部のキー管理サービスで API キーを検証する設定
+///
+/// `url` にキーを `{"api_key": "..."}` として POST し、2xx（ボディが `{"valid": false}` でないもの）を有効、
+/// 401 / 403 / 404 を無効とみなします。それ以外の応答や接続エラーは利用不可として `fail_mode` に従います。
+#[derive(Deserialize, Clone, JsonSchema)]
+pub struct KeyServiceConfig {
+    pub url: String,
+    /// キー管理サービスへの認証に使うトークン（`Authorization: Bearer` で送る）
+    #[serde(default)]
+    pub token: Option<String>,
+    /// 検証結果をキャッシュする秒数。失効したキーはこの秒数以内に拒否される（0 ならキャッシュしない）
+    #[serde(default = "default_key_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    #[serde(default = "default_key_service_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub fail_mode: KeyServiceFailMode,
+}
+
+// 設定全体をログに出すため、`token` は伏せて表示する
+impl std::fmt::Debug for KeyServiceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyServiceConfig")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "[REDACTED]"))
+            .field("cache_ttl_secs", &self.cache_ttl_secs)
+            .field("timeout_ms", &self.timeout_ms)
+            .field("fail_mode", &self.fail_mode)
+            .finish()
+    }
+}
+
+fn default_key_cache_ttl_secs() -> u64 {
+    60
+}
+
+fn default_key_service_timeout_ms() -> u64 {
+    2000
+}
+
+/// キー管理サービスに問い合わせできない場合の扱い
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyServiceFailMode {
+    /// 503 で拒否する
+    #[default]
+    Closed,
+    /// 警告を出して通す
+    Open,
+}
+
+/// キー管理サービスによる検証結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyValidation {
+    Valid,
+    Invalid,
+    Unavailable,
+}
+
+/// 外部のキー管理サービスで API キーを検証し、結果を短時間キャッシュする
+pub struct KeyServiceAuthenticator {
+    config: KeyServiceConfig,
+    client: reqwest::Client,
+    // キーそのものは保持せず、ハッシュ値で結果を引く
+    cache: moka::future::Cache<[u8; 32], bool>,
+}
+
+impl KeyServiceAuthenticator {
+    pub fn new(config: KeyServiceConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to build key service HTTP client: {}", e))?;
+        let cache = moka::future::Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(Duration::from_secs(config.cache_ttl_secs.max(1)))
+            .build();
+        Ok(Self { config, client, cache })
+    }
+
+    /// キーが有効かを返します。サービスに問い合わせできない場合は `fail_mode` に従い、
+    /// fail-closed なら `503` を返します
+    pub async fn check(&self, key: &str) -> Result<bool, StatusCode> {
+        match self.validate(key).await {
+            KeyValidation::Valid => Ok(true),
+            KeyValidation::Invalid => Ok(false),
+            KeyValidation::Unavailable => match self.config.fail_mode {
+                KeyServiceFailMode::Open => {
+                    warn!("Key service is unavailable; allowing request (fail-open)");
+                    Ok(true)
+                }
+                KeyServiceFailMode::Closed => {
+                    warn!("Key service is unavailable; rejecting request (fail-closed)");
+                    Err(StatusCode::SERVICE_UNAVAILABLE)
+                }
+            },
+        }
+    }
+
+    /// キャッシュ、無ければキー管理サービスでキーを検証します（利用不可の結果はキャッシュしない）
+    ///
+    /// キャッシュが切れた直後に同じキーのリクエストが重なっても、問い合わせは1件にまとめます。
+    pub async fn validate(&self, key: &str) -> KeyValidation {
+        if self.config.cache_ttl_secs == 0 {
+            return self.query(key).await;
+        }
+        let hash: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        let valid = self.cache
+            .optionally_get_with(hash, async {
+                match self.query(key).await {
+                    KeyValidation::Unavailable => None,
+                    validation => Some(validation == KeyValidation::Valid),
+                }
+            })
+            .await;
+        match valid {
+            Some(true) => KeyValidation::Valid,
+            Some(false) => KeyValidation::Invalid,
+            None => KeyValidation::Unavailable,
+        }
+    }
+
+    async fn query(&self, key: &str) -> KeyValidation {
+        let mut request = self.client
+            .post(&self.config.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::json!({ "api_key": key }).to_string());
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to reach key service: {}", e);
+                return KeyValidation::Unavailable;
+            }
+        };
+        match response.status() {
+            status if status.is_success() => {
+                // ボディが無い、または `valid` を含まない場合はステータスのみで判定する
+                let body = response.bytes().await.unwrap_or_default();
+                let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default();
+                if body.get("valid").and_then(|v| v.as_bool()) == Some(false) {
+                    KeyValidation::Invalid
+                } else {
+                    KeyValidation::Valid
+                }
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => KeyValidation::Invalid,
+            status => {
+                debug!("Key service returned {}", status);
+                KeyValidation::Unavailable
+            }
+        }
+    }
+}
 
 pub async fn auth_middleware(
     State(state): State<Arc<AppState>>,
-    req: Request<Body>,
+    mut req: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    // セキュリティ設定が空（APIキーが1つも設定されていない）の場合は認証をスキップ（開発用）
-    if state.security.api_keys.is_empty() {
+    // キーが1つも設定されておらず、キー管理サービスも無い場合は認証をスキップ（開発用）
+    if state.security.api_keys.is_empty() && state.key_service.is_none() {
         return Ok(next.run(req).await);
     }
 
-    let auth_header = req
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|h| h.to_str().ok());
-
-    match auth_header {
-        Some(auth) if auth.starts_with("Bearer ") => {
-            let key = &auth[7..];
-            if state.security.api_keys.iter().any(|k| k == key) {
-                Ok(next.run(req).await)
+    let extracted = extract_api_key(req.headers(), state.security.strict_credentials).inspect_err(|_| {
+        crate::metrics::record_auth("conflicting");
+    })?;
+    match extracted {
+        Some(key) => {
+            let format = state.security.key_format;
+            let keys = &state.security.api_keys;
+            let listed = format.position(keys.iter().map(|entry| entry.key.as_str()), key).map(|index| &keys[index]);
+            let valid = if listed.is_some() {
+                true
+            } else if let Some(service) = &state.key_service {
+                service.check(key).await.inspect_err(|_| crate::metrics::record_auth("unavailable"))?
+            } else {
+                false
+            };
+            // キーに許可されたルートか（キー管理サービスで検証したキーは制限しない）
+            // 個別のエンドポイントはここでパスを確認し、転送するリクエストはルートを照合したあとで確認する
+            let scope = listed.and_then(|entry| entry.allowed_paths.clone()).map(RouteScope);
+            if let Some(scope) = &scope
+                && req.extensions().get::<MatchedPath>().is_some()
+                && !scope.allows(req.uri().path())
+            {
+                warn!("API key is not allowed to access {}", req.uri().path());
+                crate::metrics::record_auth("forbidden");
+                return Err(StatusCode::FORBIDDEN);
+            }
+            if valid {
+                // キーごとの同時実行数の制限
+                let Some(permit) = state.concurrency.try_acquire(&format.normalize(key)) else {
+                    warn!("Concurrent request limit reached for API key");
+                    crate::metrics::record_auth("concurrency_limited");
+                    return Err(StatusCode::TOO_MANY_REQUESTS);
+                };
+                crate::metrics::record_auth("accepted");
+                let key_id = KeyId::of(key);
+                req.extensions_mut().insert(key_id);
+                if let Some(scope) = scope {
+                    req.extensions_mut().insert(scope);
+                }
+                Ok(permit.hold_until_complete(next.run(req).await))
             } else {
                 warn!("Invalid API key attempt");
+                crate::metrics::record_auth("invalid");
                 Err(StatusCode::UNAUTHORIZED)
             }
         }
-        _ => {
+        None => {
             warn!("Missing or invalid Authorization header");
+            crate::metrics::record_auth("missing");
             Err(StatusCode::UNAUTHORIZED)
         }
     }
 }
+
+/// 管理用エンドポイントの認証
+///
+/// `security.admin_keys` が未設定の場合、管理用エンドポイントは無効（403）になります。
+pub async fn admin_auth_middleware(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if state.security.admin_keys.is_empty() {
+        warn!("Admin endpoint requested but no admin keys are configured");
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    match extract_api_key(req.headers(), state.security.strict_credentials)? {
+        Some(key) if state.security.key_format.is_listed(&state.security.admin_keys, key) => Ok(next.run(req).await),
+        _ => {
+            warn!("Invalid admin key attempt");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// リクエストを送ったクライアントの IP アドレスを求めます
+///
+/// 接続元が `trusted_proxies` に含まれる場合は、`X-Forwarded-For` を右から辿り、
+/// 信頼するプロキシ以外の最初のアドレスを使います（クライアントが付けた値で偽装されないようにするため）。
+/// 接続情報が無い場合（テストなど）は None を返します。
+pub fn client_ip(req_extensions: &axum::http::Extensions, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req_extensions.get::<axum::extract::ConnectInfo<SocketAddr>>()?.0.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    for hop in forwarded.into_iter().rev() {
+        match hop {
+            Some(ip) if trusted_proxies.contains(&ip) => continue,
+            Some(ip) => return Some(ip),
+            // 読めない値より左は信頼できないため、接続元のアドレスを使う
+            None => break,
+        }
+    }
+    Some(peer)
+}
+
+/// リクエストヘッダーから API キーを取り出します
+///
+/// `Authorization: Bearer <key>` を `x-api-key` より優先します。
+/// `strict` が有効な場合、両方に異なるキーが指定されていると `400` を返します。
+pub fn extract_api_key(headers: &HeaderMap, strict: bool) -> Result<Option<&str>, StatusCode> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "));
+
+    let api_key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|h| h.to_str().ok());
+
+    match (bearer, api_key) {
+        (Some(b), Some(k)) if b != k => {
+            if strict {
+                warn!("Conflicting credentials in Authorization and {} headers", API_KEY_HEADER);
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Ok(Some(b))
+        }
+        (Some(b), _) => Ok(Some(b)),
+        (None, k) => Ok(k),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(bearer: Option<&str>, api_key: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(b) = bearer {
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", b)).unwrap());
+        }
+        if let Some(k) = api_key {
+            headers.insert(API_KEY_HEADER, HeaderValue::from_str(k).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_single_source() {
+        assert_eq!(extract_api_key(&headers(Some("a"), None), true), Ok(Some("a")));
+        assert_eq!(extract_api_key(&headers(None, Some("b")), true), Ok(Some("b")));
+        assert_eq!(extract_api_key(&headers(None, None), true), Ok(None));
+    }
+
+    #[test]
+    fn test_matching_both() {
+        assert_eq!(extract_api_key(&headers(Some("a"), Some("a")), true), Ok(Some("a")));
+        assert_eq!(extract_api_key(&headers(Some("a"), Some("a")), false), Ok(Some("a")));
+    }
+
+    #[test]
+    fn test_conflicting_both() {
+        // strict モードでは拒否
+        assert_eq!(extract_api_key(&headers(Some("a"), Some("b")), true), Err(StatusCode::BAD_REQUEST));
+        // 非 strict モードでは Authorization が優先
+        assert_eq!(extract_api_key(&headers(Some("a"), Some("b")), false), Ok(Some("a")));
+    }
+
+    #[test]
+    fn test_hashed_keys_match_presented_keys() {
+        let keys = vec![hash_api_key("secret-1"), hash_api_key("secret-2").to_uppercase()];
+        assert!(KeyFormat::Sha256.is_listed(&keys, "secret-1"));
+        assert!(KeyFormat::Sha256.is_listed(&keys, "secret-2"));
+        assert!(!KeyFormat::Sha256.is_listed(&keys, "secret-3"));
+        // 設定に書いたハッシュ値そのものをキーとして使うことはできない
+        assert!(!KeyFormat::Sha256.is_listed(&keys, &keys[0]));
+
+        assert!(KeyFormat::Plain.is_listed(&["secret-1".to_string()], "secret-1"));
+        assert!(!KeyFormat::Plain.is_listed(&["secret-1".to_string()], "secret-10"));
+    }
+
+    #[test]
+    fn test_plain_keys_compare_case_sensitively() {
+        let keys = vec!["sk-MixedCase-Key".to_string()];
+        assert!(KeyFormat::Plain.is_listed(&keys, "sk-MixedCase-Key"));
+        assert!(!KeyFormat::Plain.is_listed(&keys, "sk-mixedcase-key"));
+        assert_eq!(KeyFormat::Plain.canonical("sk-MixedCase-Key"), "sk-MixedCase-Key");
+        assert_eq!(KeyFormat::Sha256.canonical("ABCDEF"), "abcdef");
+    }
+
+    #[test]
+    fn test_api_key_entries_accept_strings_and_scoped_tables() {
+        #[derive(Deserialize)]
+        struct Keys {
+            api_keys: Vec<ApiKeyEntry>,
+        }
+        let keys: Keys = toml::from_str(r#"api_keys = ["full", { key = "embed-only", allowed_paths = ["/v1/embeddings"] }]"#).unwrap();
+        assert_eq!(keys.api_keys[0], ApiKeyEntry::from("full"));
+        assert!(keys.api_keys[0].allows("/v1/chat"));
+        assert!(keys.api_keys[1].allows("/v1/embeddings"));
+        assert!(!keys.api_keys[1].allows("/v1/chat"));
+
+        let index = KeyFormat::Plain.position(keys.api_keys.iter().map(|entry| entry.key.as_str()), "embed-only");
+        assert_eq!(index, Some(1));
+    }
+
+    #[test]
+    fn test_sha256_format_rejects_plain_keys_in_config() {
+        assert!(KeyFormat::Sha256.validate(&[hash_api_key("secret")]).is_ok());
+        let err = KeyFormat::Sha256.validate(&["secret-orchix-key".to_string()]).unwrap_err().to_string();
+        assert!(!err.contains("secret-orchix-key"), "the key itself must not be logged: {}", err);
+        assert!(KeyFormat::Plain.validate(&["secret-orchix-key".to_string()]).is_ok());
+    }
+
+    async fn authenticator(upstream: &crate::test_support::MockServer, cache_ttl_secs: u64, fail_mode: KeyServiceFailMode) -> KeyServiceAuthenticator {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        KeyServiceAuthenticator::new(KeyServiceConfig {
+            url: upstream.url("/keys/validate"),
+            token: Some("kms-token".to_string()),
+            cache_ttl_secs,
+            timeout_ms: 2000,
+            fail_mode,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_debug_redacts_key_service_token() {
+        let config: KeyServiceConfig = toml::from_str("url = 'https://kms.example/validate'\ntoken = 'kms-token'").unwrap();
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("kms-token"), "{}", debug);
+        assert!(debug.contains("https://kms.example/validate"), "{}", debug);
+    }
+
+    #[tokio::test]
+    async fn test_valid_key_is_cached() {
+        let kms = crate::test_support::MockUpstream::new().respond_with(200, r#"{"valid":true}"#).start().await;
+        let service = authenticator(&kms, 60, KeyServiceFailMode::Closed).await;
+
+        assert_eq!(service.validate("tenant-key").await, KeyValidation::Valid);
+        assert_eq!(service.check("tenant-key").await, Ok(true));
+        assert_eq!(kms.hits(), 1);
+
+        let request = &kms.requests()[0];
+        assert_eq!(request.headers["authorization"], "Bearer kms-token");
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&request.body).unwrap()["api_key"], "tenant-key");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_validations_share_one_query() {
+        let kms = crate::test_support::MockUpstream::new()
+            .respond_with(200, r#"{"valid":true}"#)
+            .delay(Duration::from_millis(100))
+            .start()
+            .await;
+        let service = authenticator(&kms, 60, KeyServiceFailMode::Closed).await;
+
+        let results = futures::future::join_all((0..5).map(|_| service.validate("tenant-key"))).await;
+        assert!(results.iter().all(|validation| *validation == KeyValidation::Valid));
+        assert_eq!(kms.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_key_is_rejected() {
+        for (status, body) in [(401, ""), (200, r#"{"valid":false}"#)] {
+            let kms = crate::test_support::MockUpstream::new().respond_with(status, body).start().await;
+            let service = authenticator(&kms, 0, KeyServiceFailMode::Open).await;
+            assert_eq!(service.validate("revoked").await, KeyValidation::Invalid, "{}", status);
+            assert_eq!(service.check("revoked").await, Ok(false));
+            // キャッシュしない設定では毎回問い合わせる（失効がすぐに反映される）
+            assert_eq!(kms.hits(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_service_follows_fail_mode() {
+        let kms = crate::test_support::MockUpstream::new().respond_with(503, "down").start().await;
+        let closed = authenticator(&kms, 60, KeyServiceFailMode::Closed).await;
+        assert_eq!(closed.validate("key").await, KeyValidation::Unavailable);
+        assert_eq!(closed.check("key").await, Err(StatusCode::SERVICE_UNAVAILABLE));
+        let open = authenticator(&kms, 60, KeyServiceFailMode::Open).await;
+        assert_eq!(open.check("key").await, Ok(true));
+        // 利用不可の結果はキャッシュしない
+        assert_eq!(kms.hits(), 3);
+
+        let unreachable = KeyServiceAuthenticator::new(KeyServiceConfig {
+            url: "http://127.0.0.1:9/keys".to_string(),
+            token: None,
+            cache_ttl_secs: 60,
+            timeout_ms: 500,
+            fail_mode: KeyServiceFailMode::Closed,
+        })
+        .unwrap();
+        assert_eq!(unreachable.validate("key").await, KeyValidation::Unavailable);
+    }
+}
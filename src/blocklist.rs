@@ -0,0 +1,201 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use schemars::JsonSchema;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+use crate::error::OrchixError;
+use crate::networking::AppState;
+
+/// 拒否するリクエストのフィンガープリントの設定
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct BlocklistConfig {
+    /// `body:<ハッシュ>`（小文字の16進数64桁）/ `key:<ハッシュ>`（同16桁）/ `ip:<アドレス>` のいずれか。1つでも一致すれば 403
+    pub fingerprints: Vec<String>,
+}
+
+/// 1リクエスト分のフィンガープリント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestFingerprint {
+    /// 正規化したボディ（JSON はキー順・空白を揃える）の SHA-256
+    pub body: String,
+    /// API キーの SHA-256 の先頭16文字（上流へ送る `x-orchix-key-id` と同じ値）
+    pub key: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl RequestFingerprint {
+    pub fn compute(body: &[u8], api_key: Option<&str>, ip: Option<IpAddr>) -> Self {
+        // 同じ内容で空白やキーの順序だけが異なるボディは同じ値になる
+        let normalized = serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .and_then(|json| serde_json::to_vec(&json).ok());
+        let body = hex::encode(Sha256::digest(normalized.as_deref().unwrap_or(body)));
+        let key = api_key.map(|key| hex::encode(Sha256::digest(key.as_bytes()))[..16].to_string());
+        Self { body: format!("body:{}", body), key: key.map(|k| format!("key:{}", k)), ip: ip.map(|ip| format!("ip:{}", ip)) }
+    }
+
+    fn values(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.body.as_str()).chain(self.key.as_deref()).chain(self.ip.as_deref())
+    }
+}
+
+/// 拒否するフィンガープリントの一覧（設定の再読み込み・管理 API で差し替えられる）
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    entries: RwLock<BTreeSet<String>>,
+}
+
+impl Blocklist {
+    pub fn new(config: &BlocklistConfig) -> anyhow::Result<Self> {
+        let blocklist = Self::default();
+        blocklist.replace(&config.fingerprints)?;
+        Ok(blocklist)
+    }
+
+    /// 一覧を置き換えます（管理 API で追加したものも含めて入れ替わる）
+    pub fn replace(&self, fingerprints: &[String]) -> anyhow::Result<()> {
+        Self::validate_all(fingerprints)?;
+        *self.entries.write().unwrap() = fingerprints.iter().cloned().collect();
+        Ok(())
+    }
+
+    /// 設定のフィンガープリントがすべて正しい形式かを確認します
+    pub fn validate_all(fingerprints: &[String]) -> anyhow::Result<()> {
+        for fingerprint in fingerprints {
+            validate(fingerprint).map_err(|e| anyhow::anyhow!("Invalid blocklist fingerprint '{}': {}", fingerprint, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn add(&self, fingerprint: &str) -> Result<(), String> {
+        validate(fingerprint)?;
+        self.entries.write().unwrap().insert(fingerprint.to_string());
+        Ok(())
+    }
+
+    pub fn remove(&self, fingerprint: &str) -> bool {
+        self.entries.write().unwrap().remove(fingerprint)
+    }
+
+    /// 1件も登録されていなければ true（リクエストのフィンガープリントの計算を省ける）
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().unwrap().is_empty()
+    }
+
+    /// 登録済みのフィンガープリント（昇順）
+    pub fn entries(&self) -> Vec<String> {
+        self.entries.read().unwrap().iter().cloned().collect()
+    }
+
+    /// 一致した登録内容を返します（一致しなければ None）
+    pub fn matching(&self, fingerprint: &RequestFingerprint) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        if entries.is_empty() {
+            return None;
+        }
+        fingerprint.values().find(|value| entries.contains(*value)).map(str::to_string)
+    }
+}
+
+/// 計算したフィンガープリントと同じ形（小文字の16進数で body は64桁、key は16桁）でなければ一致しないため拒否します
+fn validate(fingerprint: &str) -> Result<(), String> {
+    let is_hash = |hash: &str, len: usize| hash.len() == len && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    match fingerprint.split_once(':') {
+        Some(("body", hash)) if is_hash(hash, 64) => Ok(()),
+        Some(("key", hash)) if is_hash(hash, 16) => Ok(()),
+        Some(("ip", addr)) if addr.parse::<IpAddr>().is_ok() => Ok(()),
+        _ => Err("expected body:<64 lowercase hex>, key:<16 lowercase hex> or ip:<address>".to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlocklistEntry {
+    pub fingerprint: String,
+}
+
+/// `GET /admin/blocklist`
+pub async fn list_handler(State(state): State<Arc<AppState>>) -> Response {
+    Json(json!({"fingerprints": state.blocklist.entries()})).into_response()
+}
+
+/// `POST /admin/blocklist`（`{"fingerprint": "..."}`）
+pub async fn add_handler(State(state): State<Arc<AppState>>, Json(entry): Json<BlocklistEntry>) -> Response {
+    if let Err(reason) = state.blocklist.add(&entry.fingerprint) {
+        return OrchixError::new(StatusCode::BAD_REQUEST, "invalid_fingerprint", reason).into_response();
+    }
+    info!("Blocked request fingerprint {}", entry.fingerprint);
+    Json(json!({"fingerprint": entry.fingerprint, "blocked": true})).into_response()
+}
+
+/// `DELETE /admin/blocklist/{fingerprint}`
+pub async fn remove_handler(State(state): State<Arc<AppState>>, Path(fingerprint): Path<String>) -> Response {
+    if !state.blocklist.remove(&fingerprint) {
+        return OrchixError::new(StatusCode::NOT_FOUND, "unknown_fingerprint", format!("'{}' is not blocked", fingerprint))
+            .into_response();
+    }
+    info!("Unblocked request fingerprint {}", fingerprint);
+    Json(json!({"fingerprint": fingerprint, "blocked": false})).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_fingerprint_ignores_formatting() {
+        let compact = RequestFingerprint::compute(br#"{"model":"gpt-4","messages":[]}"#, None, None);
+        let spaced = RequestFingerprint::compute(b"{ \"messages\": [], \"model\": \"gpt-4\" }", None, None);
+        assert_eq!(compact.body, spaced.body);
+        assert_ne!(compact.body, RequestFingerprint::compute(br#"{"model":"gpt-3.5"}"#, None, None).body);
+    }
+
+    #[test]
+    fn test_blocked_and_allowed_fingerprints() {
+        let abusive = RequestFingerprint::compute(b"{\"prompt\":\"spam\"}", Some("key-a"), Some("10.0.0.1".parse().unwrap()));
+        let blocklist = Blocklist::new(&BlocklistConfig { fingerprints: vec![abusive.body.clone(), "ip:192.0.2.7".to_string()] }).unwrap();
+
+        assert_eq!(blocklist.matching(&abusive), Some(abusive.body.clone()));
+        let other_ip = RequestFingerprint::compute(b"{}", Some("key-a"), Some("192.0.2.7".parse().unwrap()));
+        assert_eq!(blocklist.matching(&other_ip).as_deref(), Some("ip:192.0.2.7"));
+        let allowed = RequestFingerprint::compute(b"{}", Some("key-a"), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(blocklist.matching(&allowed), None);
+
+        blocklist.add(allowed.key.as_deref().unwrap()).unwrap();
+        assert!(blocklist.matching(&allowed).is_some());
+
+        let empty = Blocklist::default();
+        assert!(empty.is_empty());
+        empty.add("ip:192.0.2.7").unwrap();
+        assert!(!empty.is_empty());
+        empty.remove("ip:192.0.2.7");
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_fingerprints_rejected() {
+        assert!(Blocklist::new(&BlocklistConfig { fingerprints: vec!["spam".to_string()] }).is_err());
+        let blocklist = Blocklist::default();
+        assert!(blocklist.add("ip:not-an-address").is_err());
+        assert!(blocklist.add("body:zz").is_err());
+
+        // 計算した値と同じ小文字・桁数でなければ一致しないため拒否する
+        let computed = RequestFingerprint::compute(b"{}", Some("key-a"), None);
+        let (body, key) = (computed.body, computed.key.unwrap());
+        assert!(blocklist.add(&body).is_ok());
+        assert!(blocklist.add(&key).is_ok());
+        assert!(blocklist.add(&body.to_uppercase().replacen("BODY", "body", 1)).is_err());
+        assert!(blocklist.add(&key.to_uppercase().replacen("KEY", "key", 1)).is_err());
+        assert!(blocklist.add(&body[..body.len() - 1]).is_err());
+        assert!(blocklist.add(&format!("{}0", key)).is_err());
+    }
+}
@@ -1,23 +1,142 @@
 use sha2::{Sha256, Digest};
 use moka::future::Cache;
+use moka::notification::RemovalCause;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::time::Duration;
+use tokio::time::Instant;
 use bytes::Bytes;
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use crate::config::CacheConfig;
+use crate::sensitive::SensitiveHeaders;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use axum::response::sse::{Event, Sse};
+use futures::stream::BoxStream;
+use tracing::warn;
 
+/// キャッシュのキー（`<パス>#<ハッシュ>`。パスの前方一致で無効化できるようパスを残す）
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CacheKey(pub String);
 
+/// 組み立て済みストリーミングレスポンスのキーの接頭辞
+const AGGREGATED_PREFIX: &str = "aggregated:";
+
 impl CacheKey {
     pub fn new(path: &str, body: &[u8]) -> Self {
         let mut hasher = Sha256::new();
         hasher.update(path.as_bytes());
         hasher.update(body);
         let result = hasher.finalize();
-        Self(hex::encode(result))
+        Self::with_path(path, &hex::encode(result))
+    }
+
+    fn with_path(path: &str, hash: &str) -> Self {
+        Self(format!("{}#{}", path, hash))
+    }
+
+    /// キーを作成したリクエストのパス
+    pub fn path(&self) -> &str {
+        let key = self.0.strip_prefix(AGGREGATED_PREFIX).unwrap_or(&self.0);
+        key.split_once('#').map_or(key, |(path, _)| path)
+    }
+
+    /// 設定に応じてメソッドとクエリ文字列も含めたキーを作成します
+    ///
+    /// どちらも無効な場合は `CacheKey::new` と同じキーになります。
+    pub fn for_request(config: &CacheConfig, method: &str, path: &str, query: Option<&str>, body: &[u8]) -> Self {
+        if !config.key_include_method && !config.key_include_query {
+            return Self::new(path, body);
+        }
+
+        let mut hasher = Sha256::new();
+        if config.key_include_method {
+            hasher.update(method.to_ascii_uppercase().as_bytes());
+            hasher.update(b"\0");
+        }
+        hasher.update(path.as_bytes());
+        if config.key_include_query {
+            hasher.update(b"?");
+            hasher.update(normalize_query(query.unwrap_or("")).as_bytes());
+            hasher.update(b"\0");
+        }
+        hasher.update(body);
+        Self::with_path(path, &hex::encode(hasher.finalize()))
+    }
+
+    /// `vary_headers` のヘッダーと、`key_include_model` が有効なら転送先のモデルをキーに加えます
+    ///
+    /// ヘッダーは名前でソートしてから加えるため、設定やリクエストでの順序はキーに影響しません。
+    /// どちらも対象外の場合はキーをそのまま返します。
+    pub fn varying(self, config: &CacheConfig, headers: &axum::http::HeaderMap, model: Option<&str>) -> Self {
+        let model = model.filter(|_| config.key_include_model);
+        if config.vary_headers.is_empty() && model.is_none() {
+            return self;
+        }
+        let mut names: Vec<String> = config.vary_headers.iter().map(|name| name.to_ascii_lowercase()).collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.0.as_bytes());
+        for name in &names {
+            let mut values = headers.get_all(name.as_str()).iter().peekable();
+            if values.peek().is_none() {
+                continue;
+            }
+            hasher.update(b"\0");
+            hasher.update(name.as_bytes());
+            for value in values {
+                hasher.update(b":");
+                hasher.update(value.as_bytes());
+            }
+        }
+        if let Some(model) = model {
+            hasher.update(b"\0model:");
+            hasher.update(model.as_bytes());
+        }
+        let hash = hex::encode(hasher.finalize());
+        match self.0.strip_prefix(AGGREGATED_PREFIX) {
+            Some(_) => Self(format!("{}{}", AGGREGATED_PREFIX, Self::with_path(self.path(), &hash).0)),
+            None => Self::with_path(self.path(), &hash),
+        }
+    }
+
+    /// 組み立て済みストリーミングレスポンス用の名前空間のキーを作成します
+    ///
+    /// `stream` / `stream_options` を除いて正規化するため、ストリーミングの
+    /// リクエストと同じ入力の非ストリーミングリクエストが同じキーになります。
+    pub fn aggregated(config: &CacheConfig, method: &str, path: &str, query: Option<&str>, body: &serde_json::Value) -> Self {
+        let mut normalized = body.clone();
+        if let Some(object) = normalized.as_object_mut() {
+            object.remove("stream");
+            object.remove("stream_options");
+        }
+        let bytes = serde_json::to_vec(&normalized).unwrap_or_default();
+        let inner = Self::for_request(config, method, path, query, &bytes);
+        Self(format!("{}{}", AGGREGATED_PREFIX, inner.0))
     }
 }
 
+/// `path` が `prefix` そのものか、セグメントの区切りで続く配下のパスか
+fn is_under_path(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// `Cache-Control` の値に `no-store` が含まれるか
+fn has_no_store(cache_control: &str) -> bool {
+    cache_control.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+}
+
+/// クエリパラメータをソートして順序の違いを吸収します
+fn normalize_query(query: &str) -> String {
+    let mut params: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+    params.sort_unstable();
+    params.join("&")
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CachedResponse {
     pub status: u16,
@@ -25,26 +144,1024 @@ pub struct CachedResponse {
     pub body: Bytes,
 }
 
+impl CachedResponse {
+    /// 2xx の応答か（上流のエラーはキャッシュしない）
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// ストリーミング（`text/event-stream`）のレスポンスを保存したものか
+    pub fn is_event_stream(&self) -> bool {
+        self.headers
+            .iter()
+            .any(|(name, value)| name.eq_ignore_ascii_case("content-type") && value.trim_start().starts_with("text/event-stream"))
+    }
+
+    /// 保存した SSE のボディをイベントごとに分け、SSE として返し直します
+    ///
+    /// `replay_delay` を指定すると、各イベントの前にその時間だけ待ちます。
+    /// ステータスとヘッダーは呼び出し側で設定してください。
+    pub fn into_sse_stream(self, replay_delay: Option<Duration>) -> Sse<BoxStream<'static, Result<Event, std::convert::Infallible>>> {
+        let body = String::from_utf8_lossy(&self.body).replace("\r\n", "\n");
+        let events: Vec<_> = body.split("\n\n").filter_map(parse_sse_event).map(Ok).collect();
+        let stream = futures::stream::iter(events);
+        let stream = match replay_delay {
+            Some(delay) => futures::StreamExt::boxed(futures::StreamExt::then(stream, move |event| async move {
+                tokio::time::sleep(delay).await;
+                event
+            })),
+            None => futures::StreamExt::boxed(stream),
+        };
+        Sse::new(stream)
+    }
+}
+
+/// 空行で区切った SSE のブロックを `Event` に戻します（空のブロックは None）
+fn parse_sse_event(block: &str) -> Option<Event> {
+    let mut event = Event::default();
+    let (mut name, mut id, mut retry, mut data) = (None, None, None, None::<String>);
+    let mut empty = true;
+    for line in block.lines().filter(|line| !line.is_empty()) {
+        empty = false;
+        if let Some(comment) = line.strip_prefix(':') {
+            event = event.comment(comment.strip_prefix(' ').unwrap_or(comment));
+            continue;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "data" => match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            },
+            "event" => name = Some(value),
+            "id" => id = Some(value),
+            "retry" => retry = value.parse().ok(),
+            _ => {}
+        }
+    }
+    if empty {
+        return None;
+    }
+    if let Some(name) = name {
+        event = event.event(name);
+    }
+    if let Some(id) = id {
+        event = event.id(id);
+    }
+    if let Some(retry) = retry {
+        event = event.retry(Duration::from_millis(retry));
+    }
+    if let Some(data) = data {
+        event = event.data(data);
+    }
+    Some(event)
+}
+
+/// キャッシュ応答に付与するキャッシュ状態ヘッダー
+///
+/// `HIT` / `MISS` / `STALE` / `STALE-DEGRADED` / `BYPASS`（キャッシュを使わなかった）のいずれか。
+pub const CACHE_STATUS_HEADER: &str = "x-orchix-cache";
+
+/// `true` を指定したリクエストはキャッシュを参照も保存もしない（`Cache-Control: no-cache` と同じ）
+pub const NO_CACHE_HEADER: &str = "x-orchix-no-cache";
+
+/// クライアントがキャッシュを使わない新しいレスポンスを求めているか
+pub fn request_bypasses_cache(headers: &axum::http::HeaderMap) -> bool {
+    let no_cache = headers
+        .get_all(axum::http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+    no_cache
+        || headers
+            .get(NO_CACHE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// 保存時刻付きのキャッシュエントリ
+#[derive(Clone)]
+struct Entry {
+    response: CachedResponse,
+    stored_at: Instant,
+    // このエントリに適用する TTL（ストリーミングのレスポンスは別の TTL を持つ）
+    ttl: Duration,
+    // 照合でヒットした回数（追い出し時のフックに渡す）
+    hits: Arc<AtomicU32>,
+}
+
+/// エントリの TTL に猶予を加えた時間で moka から追い出す（ルートごとに TTL が異なるため）
+struct EntryExpiry {
+    grace: Duration,
+}
+
+impl moka::Expiry<CacheKey, Entry> for EntryExpiry {
+    fn expire_after_create(&self, _key: &CacheKey, entry: &Entry, _created_at: std::time::Instant) -> Option<Duration> {
+        Some(entry.ttl + self.grace)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &CacheKey,
+        entry: &Entry,
+        _updated_at: std::time::Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(entry.ttl + self.grace)
+    }
+}
+
+/// エントリが追い出された理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionCause {
+    /// ハード TTL を過ぎた
+    Expired,
+    /// `max_capacity` を超えた
+    Size,
+}
+
+impl EvictionCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Expired => "expired",
+            Self::Size => "size",
+        }
+    }
+}
+
+/// 追い出されたエントリの情報（上書き・明示的な削除は含まない）
+#[derive(Debug, Clone)]
+pub struct CacheEviction {
+    pub key: CacheKey,
+    pub cause: EvictionCause,
+    /// 保存してから追い出されるまでに照合でヒットした回数
+    pub hits: u32,
+}
+
+/// エントリが追い出されたときに呼ばれるフック
+pub type EvictionHook = Arc<dyn Fn(&CacheEviction) + Send + Sync>;
+
+/// 理由ごとの追い出し回数の累計
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct EvictionCounts {
+    pub expired: u64,
+    pub size: u64,
+}
+
+#[derive(Default)]
+struct EvictionListener {
+    expired: AtomicU64,
+    size: AtomicU64,
+    hook: RwLock<Option<EvictionHook>>,
+    // 期限切れのときに再取得させるヒット回数の下限（`refresh_ahead_min_hits`）と、キーの送り先
+    refresh_ahead_min_hits: Option<u32>,
+    refresh_ahead: Mutex<Option<tokio::sync::mpsc::UnboundedSender<CacheKey>>>,
+}
+
+impl EvictionListener {
+    fn notify(&self, key: Arc<CacheKey>, entry: Entry, cause: RemovalCause) {
+        let cause = match cause {
+            RemovalCause::Expired => EvictionCause::Expired,
+            RemovalCause::Size => EvictionCause::Size,
+            RemovalCause::Explicit | RemovalCause::Replaced => return,
+        };
+        let counter = match cause {
+            EvictionCause::Expired => &self.expired,
+            EvictionCause::Size => &self.size,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        crate::metrics::record_cache_eviction(cause.as_str());
+        let hits = entry.hits.load(Ordering::Relaxed);
+        if let Some(hook) = self.hook.read().unwrap().as_ref() {
+            hook(&CacheEviction { key: (*key).clone(), cause, hits });
+        }
+        if cause == EvictionCause::Expired
+            && self.refresh_ahead_min_hits.is_some_and(|min_hits| hits >= min_hits)
+            && let Some(sender) = self.refresh_ahead.lock().unwrap().as_ref()
+        {
+            let _ = sender.send((*key).clone());
+        }
+    }
+}
+
+/// `[caching.redis]` の設定
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+pub struct RedisCacheConfig {
+    /// 接続先（例: `redis://127.0.0.1:6379/0`）
+    pub url: String,
+    /// キャッシュキーの前に付ける文字列（他の用途のキーと分ける）
+    #[serde(default = "default_redis_key_prefix")]
+    pub key_prefix: String,
+    /// 接続・コマンドのタイムアウト（超えた場合はキャッシュミスとして扱う）
+    #[serde(default = "default_redis_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_redis_key_prefix() -> String {
+    "orchix:cache:".to_string()
+}
+
+fn default_redis_timeout_ms() -> u64 {
+    200
+}
+
+/// Redis に保存するエントリ（鮮度の判定に保存時刻と TTL も持つ）
+#[derive(Serialize, Deserialize)]
+struct RemoteEntry {
+    response: CachedResponse,
+    stored_at_ms: u64,
+    ttl_ms: u64,
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Redis のエントリの読み書き
+///
+/// 接続できない・タイムアウトした場合はログを出してキャッシュミス（保存しない）として扱い、
+/// リクエストは失敗させません。
+struct RedisStore {
+    connection: ConnectionManager,
+    key_prefix: String,
+    timeout: Duration,
+}
+
+impl RedisStore {
+    fn new(config: &RedisCacheConfig) -> anyhow::Result<Self> {
+        let client = redis::Client::open(config.url.as_str())
+            .map_err(|e| anyhow::anyhow!("Invalid caching.redis.url: {}", e))?;
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let manager = ConnectionManagerConfig::new()
+            .set_connection_timeout(Some(timeout))
+            .set_response_timeout(Some(timeout))
+            .set_number_of_retries(1);
+        // 起動時に Redis が落ちていても起動できるよう、最初のコマンドで接続する
+        let connection = ConnectionManager::new_lazy_with_config(client, manager)
+            .map_err(|e| anyhow::anyhow!("Failed to set up the Redis cache: {}", e))?;
+        Ok(Self { connection, key_prefix: config.key_prefix.clone(), timeout })
+    }
+
+    fn key(&self, key: &CacheKey) -> String {
+        format!("{}{}", self.key_prefix, key.0)
+    }
+
+    async fn get(&self, key: &CacheKey) -> Option<Entry> {
+        let mut connection = self.connection.clone();
+        let mut command = redis::cmd("GET");
+        command.arg(self.key(key));
+        let bytes = match tokio::time::timeout(self.timeout, command.query_async::<Option<Vec<u8>>>(&mut connection)).await {
+            Ok(Ok(bytes)) => bytes?,
+            Ok(Err(e)) => {
+                warn!("Redis cache lookup failed, treating as a miss: {}", e);
+                return None;
+            }
+            Err(_) => {
+                warn!("Redis cache lookup timed out, treating as a miss");
+                return None;
+            }
+        };
+        let remote: RemoteEntry = serde_json::from_slice(&bytes)
+            .inspect_err(|e| warn!("Discarding unreadable Redis cache entry: {}", e))
+            .ok()?;
+        if axum::http::StatusCode::from_u16(remote.response.status).is_err() {
+            warn!("Discarding Redis cache entry with invalid status {}", remote.response.status);
+            return None;
+        }
+        // 保存からの経過時間を、このプロセスの時刻に置き換えて鮮度を判定する
+        let age = Duration::from_millis(unix_millis().saturating_sub(remote.stored_at_ms));
+        Some(Entry {
+            response: remote.response,
+            stored_at: Instant::now().checked_sub(age)?,
+            ttl: Duration::from_millis(remote.ttl_ms),
+            hits: Arc::default(),
+        })
+    }
+
+    /// `expire` 経過後に Redis 側で消えるよう保存します
+    async fn set(&self, key: &CacheKey, entry: &Entry, expire: Duration) {
+        let remote = RemoteEntry {
+            response: entry.response.clone(),
+            stored_at_ms: unix_millis(),
+            ttl_ms: entry.ttl.as_millis() as u64,
+        };
+        let Ok(value) = serde_json::to_vec(&remote) else {
+            return;
+        };
+        let mut connection = self.connection.clone();
+        let mut command = redis::cmd("SET");
+        command.arg(self.key(key)).arg(value).arg("PX").arg(expire.as_millis().max(1) as u64);
+        match tokio::time::timeout(self.timeout, command.query_async::<()>(&mut connection)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to store Redis cache entry: {}", e),
+            Err(_) => warn!("Storing Redis cache entry timed out"),
+        }
+    }
+
+    /// パスが `path_prefix` かその配下のエントリ（None ならすべて）を削除し、削除した数を返します
+    ///
+    /// 他のインスタンスと共有するエントリも削除されます。
+    async fn invalidate(&self, path_prefix: Option<&str>) -> anyhow::Result<u64> {
+        let patterns = match path_prefix {
+            Some(path) => {
+                // キーは `<パス>#<ハッシュ>` のため、パスそのものと配下（`/` の後）だけに一致させる
+                let path = escape_glob(path.trim_end_matches('/'));
+                [self.key_prefix.clone(), format!("{}{}", self.key_prefix, AGGREGATED_PREFIX)]
+                    .iter()
+                    .flat_map(|prefix| [format!("{}{}#*", prefix, path), format!("{}{}/*", prefix, path)])
+                    .collect()
+            }
+            None => vec![format!("{}*", self.key_prefix)],
+        };
+        let mut connection = self.connection.clone();
+        let mut removed = 0;
+        for pattern in patterns {
+            let mut cursor = 0u64;
+            loop {
+                let mut scan = redis::cmd("SCAN");
+                scan.arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(100);
+                let (next, keys): (u64, Vec<String>) = tokio::time::timeout(self.timeout, scan.query_async(&mut connection))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("Redis SCAN timed out"))??;
+                if !keys.is_empty() {
+                    let mut del = redis::cmd("DEL");
+                    del.arg(&keys);
+                    removed += tokio::time::timeout(self.timeout, del.query_async::<u64>(&mut connection))
+                        .await
+                        .map_err(|_| anyhow::anyhow!("Redis DEL timed out"))??;
+                }
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Redis の `MATCH` パターンで特別な意味を持つ文字をエスケープします
+fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// キャッシュエントリの鮮度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// TTL 内
+    Fresh,
+    /// TTL は過ぎたが stale-while-revalidate の猶予内
+    Stale,
+}
+
+/// 再取得中のキーの登録（破棄すると登録を外す）
+pub struct RefreshGuard {
+    refreshing: Arc<Mutex<HashSet<CacheKey>>>,
+    key: CacheKey,
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        self.refreshing.lock().unwrap().remove(&self.key);
+    }
+}
+
 #[derive(Clone)]
 pub struct OrchixCache {
-    client: Cache<CacheKey, CachedResponse>,
+    client: Cache<CacheKey, Entry>,
+    ttl: Duration,
+    streaming_ttl: Duration,
+    stale_while_revalidate: Duration,
+    degraded_extension: Duration,
+    // バックグラウンドで再取得中のキー
+    refreshing: Arc<Mutex<HashSet<CacheKey>>>,
+    min_bytes: usize,
+    max_bytes: Option<usize>,
+    // 保存前に取り除くヘッダー（他のクライアントへの漏洩を防ぐ）
+    sensitive: SensitiveHeaders,
+    sampling_rate: f64,
+    admit_after_misses: u32,
+    cacheable_statuses: Option<Vec<u16>>,
+    // まだ保存していないキーごとのキャッシュミス回数
+    misses: Cache<CacheKey, u32>,
+    evictions: Arc<EvictionListener>,
+    // 設定されている場合はエントリを Redis に保存する（ミス回数などはインスタンスごと）
+    redis: Option<Arc<RedisStore>>,
 }
 
 impl OrchixCache {
     pub fn new(config: &CacheConfig) -> Self {
+        let ttl = Duration::from_secs(config.ttl_seconds);
+        let stale_while_revalidate = Duration::from_secs(config.stale_while_revalidate_seconds);
+        let degraded_extension = Duration::from_secs(config.degraded_ttl_seconds);
+        let streaming_ttl = config.streaming_ttl_seconds.map_or(ttl, Duration::from_secs);
+        // moka ではエントリごとの TTL + 最長の猶予で追い出し、鮮度は保存時刻から判定する
+        let evictions = Arc::new(EvictionListener {
+            refresh_ahead_min_hits: config.refresh_ahead_min_hits,
+            ..Default::default()
+        });
+        let listener = evictions.clone();
         let client = Cache::builder()
             .max_capacity(config.max_capacity)
-            .time_to_live(Duration::from_secs(config.ttl_seconds))
+            .expire_after(EntryExpiry { grace: stale_while_revalidate.max(degraded_extension) })
+            .eviction_listener(move |key, entry, cause| listener.notify(key, entry, cause))
+            .build();
+        let misses = Cache::builder()
+            .max_capacity(config.max_capacity)
+            .time_to_live(ttl)
             .build();
         
-        Self { client }
+        Self {
+            client,
+            ttl,
+            streaming_ttl,
+            stale_while_revalidate,
+            degraded_extension,
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+            min_bytes: config.min_cache_bytes,
+            max_bytes: config.max_cache_bytes,
+            sensitive: SensitiveHeaders::default(),
+            sampling_rate: config.cache_sampling_rate,
+            admit_after_misses: config.cache_admit_after_misses,
+            cacheable_statuses: config.cacheable_statuses.clone(),
+            misses,
+            evictions,
+            redis: None,
+        }
+    }
+
+    /// エントリの保存先を Redis にします（`backend = "redis"`）
+    ///
+    /// 追い出しのフック・回数（`set_eviction_hook` / `evictions`）はメモリのキャッシュのみが対象です。
+    pub fn with_redis(mut self, config: &RedisCacheConfig) -> anyhow::Result<Self> {
+        self.redis = Some(Arc::new(RedisStore::new(config)?));
+        Ok(self)
+    }
+
+    async fn entry(&self, key: &CacheKey) -> Option<Entry> {
+        match &self.redis {
+            Some(redis) => redis.get(key).await,
+            None => self.client.get(key).await,
+        }
+    }
+
+    /// エントリが期限切れ・容量超過で追い出されたときに呼ぶフックを設定します（既存のフックは置き換える）
+    ///
+    /// フックは moka の `eviction_listener` から呼ばれ、追い出しの理由と `hits` を受け取ります。
+    /// よくヒットしたキーの再取得（refresh-ahead）は `subscribe_refresh_ahead` で受け取ります。
+    /// 複製したキャッシュ（`AppState::cache` など）にも反映されます。
+    /// フックは moka の内部処理の中で呼ばれるため、重い処理は別のタスクに渡してください。
+    pub fn set_eviction_hook<F>(&self, hook: F)
+    where
+        F: Fn(&CacheEviction) + Send + Sync + 'static,
+    {
+        *self.evictions.hook.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    /// 期限切れで追い出されたキーのうち、`refresh_ahead_min_hits` 回以上ヒットしたものを受け取ります
+    ///
+    /// 未設定の場合や Redis に保存する場合は None です。受け取り先は1つだけで、呼ぶたびに新しいものに置き換えます。
+    pub fn subscribe_refresh_ahead(&self) -> Option<tokio::sync::mpsc::UnboundedReceiver<CacheKey>> {
+        if self.evictions.refresh_ahead_min_hits.is_none() || self.redis.is_some() {
+            return None;
+        }
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        *self.evictions.refresh_ahead.lock().unwrap() = Some(sender);
+        Some(receiver)
+    }
+
+    /// 期限切れのエントリの追い出しなど、moka が後回しにしている処理を実行します
+    #[cfg(test)]
+    pub(crate) async fn run_pending_tasks(&self) {
+        self.client.run_pending_tasks().await;
+    }
+
+    /// 理由ごとの追い出し回数の累計
+    pub fn evictions(&self) -> EvictionCounts {
+        EvictionCounts {
+            expired: self.evictions.expired.load(Ordering::Relaxed),
+            size: self.evictions.size.load(Ordering::Relaxed),
+        }
     }
 
+    pub fn with_sensitive_headers(mut self, sensitive: SensitiveHeaders) -> Self {
+        self.sensitive = sensitive;
+        self
+    }
+
+    /// 鮮度付きでエントリを取得します
+    pub async fn lookup(&self, key: &CacheKey) -> Option<(CachedResponse, Freshness)> {
+        let found = self.lookup_entry(key).await;
+        crate::metrics::record_cache_lookup(match &found {
+            Some((_, Freshness::Fresh)) => "hit",
+            Some((_, Freshness::Stale)) => "stale",
+            None => "miss",
+        });
+        found
+    }
+
+    async fn lookup_entry(&self, key: &CacheKey) -> Option<(CachedResponse, Freshness)> {
+        let entry = self.entry(key).await?;
+        let age = entry.stored_at.elapsed();
+        if age < entry.ttl + self.stale_while_revalidate {
+            entry.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        if age < entry.ttl {
+            Some((entry.response, Freshness::Fresh))
+        } else if age < entry.ttl + self.stale_while_revalidate {
+            Some((entry.response, Freshness::Stale))
+        } else {
+            None
+        }
+    }
+
+    /// 上流が不調な場合に返せるエントリを取得します（TTL を `degraded_ttl_seconds` だけ延長）
+    pub async fn lookup_degraded(&self, key: &CacheKey) -> Option<CachedResponse> {
+        let entry = self.entry(key).await?;
+        let extension = self.stale_while_revalidate.max(self.degraded_extension);
+        (entry.stored_at.elapsed() < entry.ttl + extension).then_some(entry.response)
+    }
+
+    /// バックグラウンド再取得を開始します。同じキーを再取得中なら None（重複再取得を防ぐ）
+    ///
+    /// 返したガードを破棄すると再取得の終了として扱います（途中で失敗・パニックしても解放される）。
+    pub fn begin_refresh(&self, key: &CacheKey) -> Option<RefreshGuard> {
+        self.refreshing
+            .lock()
+            .unwrap()
+            .insert(key.clone())
+            .then(|| RefreshGuard { refreshing: self.refreshing.clone(), key: key.clone() })
+    }
+
+    /// 上流のレスポンスを保存してよいかを、ステータスとヘッダーから判定します
+    ///
+    /// `cacheable_statuses`（未設定なら 2xx）以外のステータス、`Set-Cookie` を含むもの、
+    /// `Cache-Control: no-store` が指定されたものは保存しません。
+    pub fn is_cacheable(&self, response: &CachedResponse) -> bool {
+        self.is_cacheable_status(response.status)
+            && !response.headers.contains_key("set-cookie")
+            && !response.headers.get("cache-control").is_some_and(|v| has_no_store(v))
+    }
+
+    /// ストリーミングのレスポンスを保存してよいか（判定は `is_cacheable` と同じ）
+    pub fn is_cacheable_head(&self, status: u16, headers: &axum::http::HeaderMap) -> bool {
+        self.is_cacheable_status(status)
+            && !headers.contains_key(axum::http::header::SET_COOKIE)
+            && !headers
+                .get_all(axum::http::header::CACHE_CONTROL)
+                .iter()
+                .any(|v| v.to_str().is_ok_and(has_no_store))
+    }
+
+    fn is_cacheable_status(&self, status: u16) -> bool {
+        match &self.cacheable_statuses {
+            Some(statuses) => statuses.contains(&status),
+            None => (200..300).contains(&status),
+        }
+    }
+
+    /// ボディサイズがキャッシュ対象の範囲内かを判定します
+    pub fn admits(&self, body_len: usize) -> bool {
+        body_len >= self.min_bytes && self.max_bytes.is_none_or(|max| body_len <= max)
+    }
+
+    /// キャッシュミスしたレスポンスを保存するかを決めます
+    ///
+    /// 同じキーのミスが `cache_admit_after_misses` 回に達したものを、`cache_sampling_rate` の割合だけ保存します。
+    pub async fn should_store(&self, key: &CacheKey) -> bool {
+        self.should_store_with_roll(key, rand::random::<f64>()).await
+    }
+
+    /// 0〜1の乱数 `roll` を使って `should_store` の判定を行います
+    async fn should_store_with_roll(&self, key: &CacheKey, roll: f64) -> bool {
+        if self.admit_after_misses > 1 {
+            let misses = self.misses
+                .entry(key.clone())
+                .and_upsert_with(|entry| async move { entry.map_or(1, |e| e.into_value().saturating_add(1)) })
+                .await
+                .into_value();
+            if misses < self.admit_after_misses {
+                return false;
+            }
+        }
+        if roll >= self.sampling_rate {
+            return false;
+        }
+        self.misses.invalidate(key).await;
+        true
+    }
+
+    /// TTL 内のエントリのみを取得します
     pub async fn get(&self, key: &CacheKey) -> Option<CachedResponse> {
-        self.client.get(key).await
+        match self.lookup(key).await {
+            Some((response, Freshness::Fresh)) => Some(response),
+            _ => None,
+        }
     }
 
     pub async fn set(&self, key: CacheKey, response: CachedResponse) {
-        self.client.insert(key, response).await;
+        self.set_with_ttl(key, response, None).await;
+    }
+
+    /// ルートの `cache_ttl_seconds` などで TTL を指定して保存します（None なら `ttl_seconds`）
+    pub async fn set_with_ttl(&self, key: CacheKey, response: CachedResponse, ttl: Option<Duration>) {
+        self.insert(key, response, ttl.unwrap_or(self.ttl)).await;
+    }
+
+    /// ストリーミングのレスポンス（SSE のボディ）を保存します（TTL が None なら `streaming_ttl_seconds`）
+    ///
+    /// 終端（`[DONE]`）まで届いたストリームだけを渡してください（途中で切れた生成結果は保存しない）。
+    pub async fn set_streaming(&self, key: CacheKey, response: CachedResponse, ttl: Option<Duration>) {
+        self.insert(key, response, ttl.unwrap_or(self.streaming_ttl)).await;
+    }
+
+    /// パスが `path_prefix` かその配下（`/v1/chat` なら `/v1/chat/completions` も含み、`/v1/chatbot` は含まない）の
+    /// エントリ（None ならすべて）を無効化し、無効化した数を返します
+    ///
+    /// Redis に接続できない場合はエラーを返します（無効化できたと誤解させないため）。
+    pub async fn invalidate(&self, path_prefix: Option<&str>) -> anyhow::Result<u64> {
+        if let Some(redis) = &self.redis {
+            return redis.invalidate(path_prefix).await;
+        }
+        let keys: Vec<Arc<CacheKey>> = self
+            .client
+            .iter()
+            .filter(|(key, _)| path_prefix.is_none_or(|prefix| is_under_path(key.path(), prefix)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            self.client.invalidate(key.as_ref()).await;
+        }
+        Ok(keys.len() as u64)
+    }
+
+    async fn insert(&self, key: CacheKey, mut response: CachedResponse, ttl: Duration) {
+        self.sensitive.strip(&mut response.headers);
+        let entry = Entry { response, stored_at: Instant::now(), ttl, hits: Arc::default() };
+        match &self.redis {
+            Some(redis) => redis.set(&key, &entry, ttl + self.stale_while_revalidate.max(self.degraded_extension)).await,
+            None => self.client.insert(key, entry).await,
+        }
+        crate::metrics::record_cache_store();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> CacheConfig {
+        CacheConfig {
+            enabled: true,
+            ttl_seconds: 60,
+            max_capacity: 100,
+            min_cache_bytes: 10,
+            max_cache_bytes: Some(100),
+            key_include_method: false,
+            key_include_query: false,
+            vary_headers: Vec::new(),
+            key_include_model: false,
+            coalesce_requests: false,
+            stale_while_revalidate_seconds: 0,
+            stream_cache_mode: Default::default(),
+            degraded_ttl_seconds: 0,
+            streaming_ttl_seconds: None,
+            stream_replay_delay_ms: None,
+            cache_sampling_rate: 1.0,
+            cache_admit_after_misses: 1,
+            cache_max_request_bytes: None,
+            cacheable_statuses: None,
+            refresh_ahead_min_hits: None,
+            backend: Default::default(),
+            redis: None,
+        }
+    }
+
+    #[test]
+    fn test_cacheable_statuses_and_headers() {
+        let response = |status: u16, header: Option<(&str, &str)>| CachedResponse {
+            status,
+            headers: header.map(|(n, v)| (n.to_string(), v.to_string())).into_iter().collect(),
+            body: Bytes::from_static(b"{}"),
+        };
+        let cache = OrchixCache::new(&test_config());
+        assert!(cache.is_cacheable(&response(200, None)));
+        assert!(cache.is_cacheable(&response(204, Some(("cache-control", "max-age=60")))));
+        assert!(!cache.is_cacheable(&response(500, None)));
+        assert!(!cache.is_cacheable(&response(200, Some(("set-cookie", "session=abc")))));
+        assert!(!cache.is_cacheable(&response(200, Some(("cache-control", "private, No-Store")))));
+
+        let mut headers = axum::http::HeaderMap::new();
+        assert!(cache.is_cacheable_head(200, &headers));
+        headers.insert(axum::http::header::CACHE_CONTROL, axum::http::HeaderValue::from_static("no-store"));
+        assert!(!cache.is_cacheable_head(200, &headers));
+
+        let configured = OrchixCache::new(&CacheConfig { cacheable_statuses: Some(vec![200, 404]), ..test_config() });
+        assert!(configured.is_cacheable(&response(404, None)));
+        assert!(!configured.is_cacheable(&response(201, None)));
+    }
+
+    #[tokio::test]
+    async fn test_sse_body_replayed_as_separate_events() {
+        let mut headers = std::collections::HashMap::new();
+        headers.insert("content-type".to_string(), "text/event-stream".to_string());
+        let body = ": ping\r\n\r\nevent: orchix\r\ndata: {\"type\":\"start\"}\r\n\r\ndata: line1\ndata: line2\n\ndata: [DONE]\n\n";
+        let cached = CachedResponse { status: 200, headers, body: Bytes::from(body) };
+        assert!(cached.is_event_stream());
+
+        let res = axum::response::IntoResponse::into_response(cached.into_sse_stream(None));
+        let replayed = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            String::from_utf8(replayed.to_vec()).unwrap(),
+            ": ping\n\nevent: orchix\ndata: {\"type\":\"start\"}\n\ndata: line1\ndata: line2\n\ndata: [DONE]\n\n",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_by_path_prefix() {
+        let cache = OrchixCache::new(&test_config());
+        let config = test_config();
+        let chat = CacheKey::for_request(&config, "POST", "/v1/chat", None, b"{}");
+        let aggregated = CacheKey::aggregated(&config, "POST", "/v1/chat/completions", None, &serde_json::json!({}));
+        let embeddings = CacheKey::new("/v1/embeddings", b"{}");
+        let chatbot = CacheKey::new("/v1/chatbot", b"{}");
+        assert_eq!(aggregated.path(), "/v1/chat/completions");
+        for key in [&chat, &aggregated, &embeddings, &chatbot] {
+            let response = CachedResponse { status: 200, headers: Default::default(), body: Bytes::from_static(b"cached body") };
+            cache.set(key.clone(), response).await;
+        }
+
+        assert_eq!(cache.invalidate(Some("/v1/chat")).await.unwrap(), 2);
+        assert!(cache.get(&chat).await.is_none());
+        assert!(cache.get(&aggregated).await.is_none());
+        assert!(cache.get(&embeddings).await.is_some());
+        // パスの途中で切れる前方一致（/v1/chatbot）は含めない
+        assert!(cache.get(&chatbot).await.is_some());
+        assert_eq!(cache.invalidate(Some("/v1/chatbot/")).await.unwrap(), 1);
+        assert_eq!(cache.invalidate(None).await.unwrap(), 1);
+        assert!(cache.get(&embeddings).await.is_none());
+    }
+
+    #[test]
+    fn test_admission_band() {
+        let cache = OrchixCache::new(&test_config());
+        assert!(!cache.admits(9));
+        assert!(cache.admits(10));
+        assert!(cache.admits(100));
+        assert!(!cache.admits(101));
+
+        let unbounded = OrchixCache::new(&CacheConfig { min_cache_bytes: 0, max_cache_bytes: None, ..test_config() });
+        assert!(unbounded.admits(0));
+        assert!(unbounded.admits(usize::MAX));
+    }
+
+    #[test]
+    fn test_query_in_key() {
+        let disabled = test_config();
+        let a = CacheKey::for_request(&disabled, "GET", "/v1/models", Some("a=1"), b"");
+        let b = CacheKey::for_request(&disabled, "GET", "/v1/models", Some("a=2"), b"");
+        assert_eq!(a, b);
+        assert_eq!(a, CacheKey::new("/v1/models", b""));
+
+        let enabled = CacheConfig { key_include_query: true, ..test_config() };
+        let a = CacheKey::for_request(&enabled, "GET", "/v1/models", Some("a=1"), b"");
+        let b = CacheKey::for_request(&enabled, "GET", "/v1/models", Some("a=2"), b"");
+        assert_ne!(a, b);
+
+        // パラメータの順序は区別しない
+        let x = CacheKey::for_request(&enabled, "GET", "/v1/models", Some("b=2&a=1"), b"");
+        let y = CacheKey::for_request(&enabled, "GET", "/v1/models", Some("a=1&b=2"), b"");
+        assert_eq!(x, y);
+    }
+
+    #[test]
+    fn test_method_in_key() {
+        let disabled = test_config();
+        assert_eq!(
+            CacheKey::for_request(&disabled, "GET", "/v1/chat", None, b"{}"),
+            CacheKey::for_request(&disabled, "POST", "/v1/chat", None, b"{}"),
+        );
+
+        let enabled = CacheConfig { key_include_method: true, ..test_config() };
+        assert_ne!(
+            CacheKey::for_request(&enabled, "GET", "/v1/chat", None, b"{}"),
+            CacheKey::for_request(&enabled, "POST", "/v1/chat", None, b"{}"),
+        );
+    }
+
+    #[test]
+    fn test_vary_headers_and_model_in_key() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut map = axum::http::HeaderMap::new();
+            for (name, value) in pairs {
+                map.insert(*name, axum::http::HeaderValue::from_static(value));
+            }
+            map
+        };
+        let key = |config: &CacheConfig, pairs: &[(&'static str, &'static str)], model: Option<&str>| {
+            CacheKey::new("/v1/chat", b"{}").varying(config, &headers(pairs), model)
+        };
+
+        // 対象が無ければキーは変わらない
+        let plain = test_config();
+        assert_eq!(key(&plain, &[("x-tenant", "a")], Some("gpt-4")), CacheKey::new("/v1/chat", b"{}"));
+
+        let vary = CacheConfig { vary_headers: vec!["X-Tenant".to_string(), "authorization".to_string()], ..test_config() };
+        let tenant_a = key(&vary, &[("x-tenant", "a"), ("authorization", "Bearer k")], None);
+        assert_ne!(tenant_a, key(&vary, &[("x-tenant", "b"), ("authorization", "Bearer k")], None));
+        // 設定・ヘッダーの順序に依存しない
+        let reordered = CacheConfig { vary_headers: vec!["authorization".to_string(), "x-tenant".to_string()], ..test_config() };
+        assert_eq!(tenant_a, key(&reordered, &[("authorization", "Bearer k"), ("x-tenant", "a")], None));
+        assert_ne!(tenant_a, key(&vary, &[("x-tenant", "a")], None));
+        assert_eq!(tenant_a.path(), "/v1/chat");
+
+        let with_model = CacheConfig { key_include_model: true, ..test_config() };
+        assert_ne!(key(&with_model, &[], Some("gpt-4")), key(&with_model, &[], Some("gpt-4o")));
+        assert_eq!(key(&with_model, &[], None), CacheKey::new("/v1/chat", b"{}"));
+
+        let aggregated = CacheKey::aggregated(&vary, "POST", "/v1/chat", None, &serde_json::json!({}))
+            .varying(&vary, &headers(&[("x-tenant", "a")]), None);
+        assert!(aggregated.0.starts_with(AGGREGATED_PREFIX), "{}", aggregated.0);
+        assert_eq!(aggregated.path(), "/v1/chat");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_soft_and_hard_ttl() {
+        let cache = OrchixCache::new(&CacheConfig {
+            ttl_seconds: 10,
+            stale_while_revalidate_seconds: 20,
+            ..test_config()
+        });
+        let key = CacheKey::new("/v1/chat", b"{}");
+        cache.set(key.clone(), CachedResponse {
+            status: 200,
+            headers: Default::default(),
+            body: Bytes::from_static(b"cached"),
+        }).await;
+
+        assert_eq!(cache.lookup(&key).await.map(|(_, f)| f), Some(Freshness::Fresh));
+        tokio::time::advance(Duration::from_secs(15)).await;
+        assert_eq!(cache.lookup(&key).await.map(|(_, f)| f), Some(Freshness::Stale));
+        assert!(cache.get(&key).await.is_none(), "get only returns fresh entries");
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(cache.lookup(&key).await.is_none());
+    }
+
+    #[test]
+    fn test_refresh_guard_releases_key_on_drop() {
+        let cache = OrchixCache::new(&test_config());
+        let key = CacheKey::new("/v1/chat", b"{}");
+        let refreshing = cache.begin_refresh(&key).unwrap();
+        assert!(cache.begin_refresh(&key).is_none(), "the same key is refreshed only once at a time");
+        assert!(cache.begin_refresh(&CacheKey::new("/v1/chat", b"{\"a\":1}")).is_some());
+        drop(refreshing);
+        assert!(cache.begin_refresh(&key).is_some());
+    }
+
+    #[test]
+    fn test_aggregated_key_ignores_stream_flag() {
+        let config = test_config();
+        let streamed = serde_json::json!({"model": "gpt-4", "stream": true, "stream_options": {"include_usage": true}});
+        let buffered = serde_json::json!({"model": "gpt-4"});
+        let aggregated = CacheKey::aggregated(&config, "POST", "/v1/chat", None, &streamed);
+        assert_eq!(aggregated, CacheKey::aggregated(&config, "POST", "/v1/chat", None, &buffered));
+        // 通常のキーとは別の名前空間になる
+        assert_ne!(aggregated, CacheKey::new("/v1/chat", &serde_json::to_vec(&buffered).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_sensitive_headers_are_not_cached() {
+        let cache = OrchixCache::new(&test_config())
+            .with_sensitive_headers(SensitiveHeaders::new(&["set-cookie".to_string(), "x-provider-secret".to_string()]));
+        let key = CacheKey::new("/v1/chat", b"{}");
+        let headers = std::collections::HashMap::from([
+            ("set-cookie".to_string(), "session=abc".to_string()),
+            ("X-Provider-Secret".to_string(), "s3cr3t".to_string()),
+            ("content-type".to_string(), "application/json".to_string()),
+        ]);
+        cache.set(key.clone(), CachedResponse { status: 200, headers, body: Bytes::from_static(b"cached body") }).await;
+
+        let cached = cache.get(&key).await.unwrap();
+        assert_eq!(cached.headers.len(), 1);
+        assert_eq!(cached.headers["content-type"], "application/json");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_degraded_lookup_extends_ttl() {
+        let cache = OrchixCache::new(&CacheConfig { ttl_seconds: 10, degraded_ttl_seconds: 50, ..test_config() });
+        let key = CacheKey::new("/v1/chat", b"{}");
+        cache.set(key.clone(), CachedResponse {
+            status: 200,
+            headers: Default::default(),
+            body: Bytes::from_static(b"cached body"),
+        }).await;
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(cache.lookup(&key).await.is_none(), "regular lookup honours the TTL");
+        assert!(cache.lookup_degraded(&key).await.is_some());
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(cache.lookup_degraded(&key).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_streaming_entries_use_streaming_ttl() {
+        let cache = OrchixCache::new(&CacheConfig { ttl_seconds: 60, streaming_ttl_seconds: Some(5), ..test_config() });
+        let response = || CachedResponse {
+            status: 200,
+            headers: Default::default(),
+            body: Bytes::from_static(b"data: cached\n\n"),
+        };
+        let (streamed, buffered) = (CacheKey::new("/v1/chat", b"stream"), CacheKey::new("/v1/chat", b"json"));
+        cache.set_streaming(streamed.clone(), response(), None).await;
+        cache.set(buffered.clone(), response()).await;
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(cache.get(&streamed).await.is_none());
+        assert!(cache.get(&buffered).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_stored_after_configured_misses() {
+        let cache = OrchixCache::new(&CacheConfig { cache_admit_after_misses: 3, ..test_config() });
+        let (popular, other) = (CacheKey::new("/v1/chat", b"popular"), CacheKey::new("/v1/chat", b"other"));
+        assert!(!cache.should_store(&popular).await);
+        assert!(!cache.should_store(&other).await);
+        assert!(!cache.should_store(&popular).await);
+        assert!(cache.should_store(&popular).await);
+        // 保存したキーは回数をリセットする（期限切れ後は再び数え直す）
+        assert!(!cache.should_store(&popular).await);
+        assert!(!cache.should_store(&other).await);
+
+        let default = OrchixCache::new(&test_config());
+        assert!(default.should_store(&popular).await);
+    }
+
+    #[tokio::test]
+    async fn test_sampling_rate_limits_stored_fraction() {
+        let cache = OrchixCache::new(&CacheConfig { cache_sampling_rate: 0.25, ..test_config() });
+        let key = CacheKey::new("/v1/chat", b"{}");
+        assert!(cache.should_store_with_roll(&key, 0.2).await);
+        assert!(!cache.should_store_with_roll(&key, 0.3).await);
+
+        let n = 10_000;
+        let mut stored = 0;
+        for i in 0..n {
+            if cache.should_store(&CacheKey::new("/v1/chat", format!("{}", i).as_bytes())).await {
+                stored += 1;
+            }
+        }
+        let rate = stored as f64 / n as f64;
+        assert!((rate - 0.25).abs() < 0.03, "stored rate {}", rate);
+    }
+
+    #[tokio::test]
+    async fn test_sampling_applies_after_miss_threshold() {
+        let cache = OrchixCache::new(&CacheConfig { cache_sampling_rate: 0.5, cache_admit_after_misses: 2, ..test_config() });
+        let key = CacheKey::new("/v1/chat", b"{}");
+        assert!(!cache.should_store_with_roll(&key, 0.0).await);
+        // 回数に達しても抽選に外れたものは保存せず、次のミスで再び抽選する
+        assert!(!cache.should_store_with_roll(&key, 0.9).await);
+        assert!(cache.should_store_with_roll(&key, 0.1).await);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_listener_counts_expired_and_size_evictions() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let seen = evicted.clone();
+        let cache = OrchixCache::new(&CacheConfig { ttl_seconds: 1, max_capacity: 1, ..test_config() });
+        cache.set_eviction_hook(move |eviction| seen.lock().unwrap().push((eviction.key.clone(), eviction.cause, eviction.hits)));
+        let response = || CachedResponse { status: 200, headers: Default::default(), body: Bytes::from_static(b"cached") };
+        let (hot, cold) = (CacheKey::new("/v1/chat", b"hot"), CacheKey::new("/v1/chat", b"cold"));
+
+        cache.set(hot.clone(), response()).await;
+        cache.get(&hot).await.unwrap();
+        cache.get(&hot).await.unwrap();
+        // 実時間の TTL で期限切れにする（moka の時計は tokio の一時停止の影響を受けない）
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        cache.client.run_pending_tasks().await;
+        assert_eq!(cache.evictions(), EvictionCounts { expired: 1, size: 0 });
+        assert_eq!(evicted.lock().unwrap()[0], (hot, EvictionCause::Expired, 2));
+
+        // 容量 1 に2件目を入れると、どちらかが容量超過で追い出される
+        cache.set(cold.clone(), response()).await;
+        cache.client.run_pending_tasks().await;
+        cache.set(CacheKey::new("/v1/chat", b"other"), response()).await;
+        cache.client.run_pending_tasks().await;
+        assert_eq!(cache.evictions().size, 1);
+        assert_eq!(evicted.lock().unwrap().len(), 2);
     }
 }
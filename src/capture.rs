@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use crate::tap::redact;
+
+/// 評価用データセットの収集のため、リクエスト・レスポンスの組をファイルに書き出す設定（デフォルトは無効）
+///
+/// キャッシュ（応答に使う）とは異なり、書き出した内容がプロキシの動作に使われることはありません。
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct CaptureConfig {
+    pub enabled: bool,
+    /// 書き出し先のディレクトリ（`captures.jsonl` に追記する）
+    pub directory: PathBuf,
+    /// 書き出すリクエストの割合 (0.0 - 1.0)
+    pub sample_rate: f64,
+    /// ファイルサイズの上限。超える分は書き出さない
+    pub max_file_bytes: u64,
+    /// 書き込み待ちの組を保持する数。満杯の間に発生したものは捨てる
+    pub buffer: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("captures"),
+            sample_rate: 0.01,
+            max_file_bytes: 100 * 1024 * 1024,
+            buffer: 256,
+        }
+    }
+}
+
+/// 書き出す1リクエスト分の組（機密キーはマスク済み）
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureRecord {
+    pub method: String,
+    pub path: String,
+    /// マッチしたルートの `path`
+    pub route: Option<String>,
+    pub status: u16,
+    pub request: Value,
+    pub response: Value,
+}
+
+impl CaptureRecord {
+    pub fn new(method: &str, path: &str, route: Option<String>, status: u16, request: &[u8], response: &[u8]) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            route,
+            status,
+            request: redacted_body(request),
+            response: redacted_body(response),
+        }
+    }
+}
+
+/// JSON のボディはマスクしたうえでそのまま、それ以外は文字列として保持します
+fn redacted_body(body: &[u8]) -> Value {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut json) => {
+            redact(&mut json);
+            json
+        }
+        Err(_) => Value::String(String::from_utf8_lossy(body).into_owned()),
+    }
+}
+
+/// サンプリングしたリクエストを、リクエスト処理とは別のタスクでファイルに書き出す
+pub struct Capture {
+    sample_rate: f64,
+    sender: Option<mpsc::Sender<CaptureRecord>>,
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    /// 有効な場合は書き出し用のタスクを起動します
+    pub fn new(config: &CaptureConfig) -> Self {
+        if !config.enabled {
+            return Self { sample_rate: 0.0, sender: None, dropped: AtomicU64::new(0) };
+        }
+        let (capture, receiver) = Self::channel(config);
+        tokio::spawn(write_captures(config.directory.join("captures.jsonl"), config.max_file_bytes, receiver));
+        capture
+    }
+
+    fn channel(config: &CaptureConfig) -> (Self, mpsc::Receiver<CaptureRecord>) {
+        let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+        let capture = Self { sample_rate: config.sample_rate, sender: Some(sender), dropped: AtomicU64::new(0) };
+        (capture, receiver)
+    }
+
+    /// このリクエストを書き出すかを決めます
+    ///
+    /// ボディの読み込みを避けるため、組を作る前に呼び出します。
+    pub fn should_sample(&self) -> bool {
+        self.should_sample_with_roll(rand::random::<f64>())
+    }
+
+    /// 0〜1の乱数 `roll` を使って `should_sample` の判定を行います
+    fn should_sample_with_roll(&self, roll: f64) -> bool {
+        self.sender.is_some() && roll < self.sample_rate
+    }
+
+    /// 書き込み待ちに積みます（待機せずに戻る）
+    pub fn submit(&self, record: CaptureRecord) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if sender.try_send(record).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!("Capture queue is full, dropped record (total dropped: {})", dropped);
+        }
+    }
+
+    /// 書き込みが追いつかずに捨てた組の累計数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// キューの組を JSONL として追記し続けます。ファイルサイズが上限に達した後は捨てる
+async fn write_captures(path: PathBuf, max_file_bytes: u64, mut receiver: mpsc::Receiver<CaptureRecord>) {
+    let mut file = match open_capture_file(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open capture file {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let mut written = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    let mut full = false;
+    while let Some(record) = receiver.recv().await {
+        let Ok(mut line) = serde_json::to_vec(&record) else {
+            continue;
+        };
+        line.push(b'\n');
+        if written + line.len() as u64 > max_file_bytes {
+            if !full {
+                warn!("Capture file {} reached {} bytes, skipping further captures", path.display(), max_file_bytes);
+                full = true;
+            }
+            continue;
+        }
+        if let Err(e) = file.write_all(&line).await {
+            warn!("Failed to write capture to {}: {}", path.display(), e);
+            continue;
+        }
+        written += line.len() as u64;
+    }
+    let _ = file.flush().await;
+}
+
+async fn open_capture_file(path: &Path) -> std::io::Result<tokio::fs::File> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::OpenOptions::new().create(true).append(true).open(path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("orchix-capture-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("captures.jsonl")
+    }
+
+    fn record(status: u16) -> CaptureRecord {
+        CaptureRecord::new("POST", "/v1/chat", Some("/v1/chat".to_string()), status, br#"{"api_key":"sk-1","prompt":"hi"}"#, b"plain")
+    }
+
+    #[tokio::test]
+    async fn test_captures_written_at_sample_rate() {
+        let path = capture_path("rate");
+        let (capture, receiver) = Capture::channel(&CaptureConfig { enabled: true, sample_rate: 0.5, ..Default::default() });
+        for (i, roll) in [0.1, 0.7, 0.49, 0.5, 0.9, 0.2].into_iter().enumerate() {
+            if capture.should_sample_with_roll(roll) {
+                capture.submit(record(200 + i as u16));
+            }
+        }
+        drop(capture);
+        write_captures(path.clone(), u64::MAX, receiver).await;
+
+        let lines: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let statuses: Vec<_> = lines.iter().map(|line| line["status"].as_u64().unwrap()).collect();
+        assert_eq!(statuses, vec![200, 202, 205]);
+        assert_eq!(lines[0]["request"]["api_key"], "[REDACTED]");
+        assert_eq!(lines[0]["request"]["prompt"], "hi");
+        assert_eq!(lines[0]["response"], "plain");
+    }
+
+    #[tokio::test]
+    async fn test_captures_stop_at_file_size_cap() {
+        let path = capture_path("cap");
+        let line_len = serde_json::to_vec(&record(200)).unwrap().len() as u64 + 1;
+        let (capture, receiver) = Capture::channel(&CaptureConfig { enabled: true, sample_rate: 1.0, ..Default::default() });
+        for _ in 0..3 {
+            capture.submit(record(200));
+        }
+        drop(capture);
+        write_captures(path.clone(), line_len * 2, receiver).await;
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_disabled_capture_never_samples() {
+        let capture = Capture::new(&CaptureConfig { sample_rate: 1.0, ..Default::default() });
+        assert!(!capture.should_sample_with_roll(0.0));
+    }
+}
@@ -0,0 +1,174 @@
+use serde::Deserialize;
+use schemars::JsonSchema;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// 上流ごとのサーキットブレーカーの設定
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    /// 連続してこの回数失敗したら回路を開く
+    pub failure_threshold: u32,
+    /// 回路を開いてから再試行（half-open）を許すまでの秒数
+    pub open_seconds: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            failure_threshold: 5,
+            open_seconds: 30,
+        }
+    }
+}
+
+#[derive(Default)]
+struct UpstreamHealth {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// half-open の試行を送り、結果を待っている間は true
+    trial: AtomicBool,
+}
+
+/// 上流（転送先 URL）ごとの健全性を追跡する
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    upstreams: Mutex<HashMap<String, UpstreamHealth>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 回路が開いている（上流への呼び出しを控えるべき）場合は true
+    ///
+    /// 転送先を選ぶための判定で、half-open の試行の枠は取りません（呼び出す直前に `try_acquire` を使う）。
+    pub fn is_open(&self, upstream: &str) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+        let upstreams = self.upstreams.lock().unwrap();
+        upstreams
+            .get(upstream)
+            .and_then(|h| h.opened_at)
+            .is_some_and(|opened| opened.elapsed() < Duration::from_secs(self.config.open_seconds))
+    }
+
+    /// 上流を呼び出してよければ true を返します
+    ///
+    /// `open_seconds` を過ぎた回路は half-open として、最初の1件だけを試行として通します。
+    /// 試行の結果が記録されるまで回路は開いたままとし、結果が返らないまま再び `open_seconds` を過ぎたら次の1件を通します。
+    pub fn try_acquire(&self, upstream: &str) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let Some(health) = upstreams.get_mut(upstream) else {
+            return true;
+        };
+        let Some(opened) = health.opened_at else {
+            return true;
+        };
+        if opened.elapsed() < Duration::from_secs(self.config.open_seconds) {
+            return false;
+        }
+        if !health.trial.swap(true, Ordering::AcqRel) {
+            info!("Circuit half-open for upstream {}; sending a trial request", upstream);
+        }
+        health.opened_at = Some(Instant::now());
+        true
+    }
+
+    pub fn record_success(&self, upstream: &str) {
+        if let Some(health) = self.upstreams.lock().unwrap().get_mut(upstream) {
+            if health.opened_at.is_some() {
+                info!("Circuit closed for upstream {}", upstream);
+            }
+            *health = UpstreamHealth::default();
+        }
+    }
+
+    pub fn record_failure(&self, upstream: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let health = upstreams.entry(upstream.to_string()).or_default();
+        health.consecutive_failures += 1;
+        // half-open の試行が失敗した場合も開き直す
+        if health.consecutive_failures >= self.config.failure_threshold || health.trial.swap(false, Ordering::AcqRel) {
+            warn!("Circuit opened for upstream {} after {} failures", upstream, health.consecutive_failures);
+            health.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig { enabled: true, failure_threshold: 2, open_seconds: 10 })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_opens_after_threshold_and_half_opens() {
+        let breaker = breaker();
+        breaker.record_failure("http://a");
+        assert!(!breaker.is_open("http://a"));
+        breaker.record_failure("http://a");
+        assert!(breaker.is_open("http://a"));
+        assert!(!breaker.is_open("http://b"));
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(!breaker.is_open("http://a"), "half-open after cooldown");
+        assert!(breaker.try_acquire("http://a"));
+        breaker.record_failure("http://a");
+        assert!(breaker.is_open("http://a"), "a failed trial re-opens the circuit");
+        assert!(!breaker.try_acquire("http://a"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_half_open_allows_a_single_trial() {
+        let breaker = breaker();
+        breaker.record_failure("http://a");
+        breaker.record_failure("http://a");
+        tokio::time::advance(Duration::from_secs(11)).await;
+
+        assert!(breaker.try_acquire("http://a"), "the first request is the trial");
+        assert!(!breaker.try_acquire("http://a"), "concurrent requests wait for the trial");
+        assert!(breaker.is_open("http://a"));
+        breaker.record_success("http://a");
+        assert!(breaker.try_acquire("http://a"));
+        assert!(breaker.try_acquire("http://a"));
+
+        // 試行の結果が返らないまま再び open_seconds を過ぎたら、次の1件を通す
+        breaker.record_failure("http://a");
+        breaker.record_failure("http://a");
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(breaker.try_acquire("http://a"));
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(breaker.try_acquire("http://a"));
+        assert!(!breaker.try_acquire("http://a"));
+        assert!(breaker.try_acquire("http://b"));
+    }
+
+    #[test]
+    fn test_success_resets() {
+        let breaker = breaker();
+        breaker.record_failure("http://a");
+        breaker.record_success("http://a");
+        breaker.record_failure("http://a");
+        assert!(!breaker.is_open("http://a"));
+    }
+}
@@ -0,0 +1,276 @@
+use axum::{body::Body, response::Response};
+use futures::StreamExt;
+use serde::Deserialize;
+use schemars::JsonSchema;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+/// API キー（プリンシパル）ごとの同時実行数の制限
+pub struct ConcurrencyLimiter {
+    semaphores: HashMap<String, Arc<Semaphore>>,
+}
+
+/// 同時実行枠。破棄されると枠が解放されます（上限のないキーでは何も保持しない）
+pub struct ConcurrencyPermit(Option<OwnedSemaphorePermit>);
+
+impl ConcurrencyLimiter {
+    pub fn new(limits: &HashMap<String, usize>) -> Self {
+        Self {
+            semaphores: limits
+                .iter()
+                .map(|(key, &limit)| (key.clone(), Arc::new(Semaphore::new(limit))))
+                .collect(),
+        }
+    }
+
+    /// 実行枠を取得します。上限に達している場合は None
+    pub fn try_acquire(&self, key: &str) -> Option<ConcurrencyPermit> {
+        match self.semaphores.get(key) {
+            Some(semaphore) => semaphore.clone().try_acquire_owned().ok().map(|p| ConcurrencyPermit(Some(p))),
+            None => Some(ConcurrencyPermit(None)),
+        }
+    }
+
+    /// 現在利用可能な枠の数（上限のないキーは None）
+    pub fn available(&self, key: &str) -> Option<usize> {
+        self.semaphores.get(key).map(|s| s.available_permits())
+    }
+}
+
+impl ConcurrencyPermit {
+    /// レスポンスボディの送信が終わるまで枠を保持します（ストリーミングを含む）
+    pub fn hold_until_complete(self, response: Response) -> Response {
+        if self.0.is_none() {
+            return response;
+        }
+        hold_until_complete(response, self)
+    }
+}
+
+/// レスポンスボディが破棄されるまで `guard` を保持します
+pub fn hold_until_complete<T: Send + Sync + 'static>(response: Response, guard: T) -> Response {
+    let (parts, body) = response.into_parts();
+    let stream = body.into_data_stream().map(move |chunk| {
+        let _held = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+/// ルートの上流への同時実行数を、キー間で公平に割り当てる設定
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+pub struct FairQueueConfig {
+    /// ルート全体での上流への同時実行数（1 以上。0 は読み込み時に拒否する）
+    pub max_concurrent: usize,
+    /// キーごとの重み（`security.key_format` の形式で書く。未指定のキーは 1）。重みに比例して枠を割り当てる
+    #[serde(default)]
+    pub weights: HashMap<String, u32>,
+}
+
+/// 重み付き公平キュー
+///
+/// 枠が空いたときは先着順ではなく、待機中のキーのうちこれまでに受けた割り当て
+/// （重みで割った仮想時間）が最も少ないキーに割り当てます。
+pub struct FairQueue {
+    weights: HashMap<String, u32>,
+    state: Mutex<FairState>,
+}
+
+// 仮想時間の単位（重み 1 のキーが1回割り当てを受けると進む量）
+const VIRTUAL_UNIT: u64 = 1_000_000;
+
+struct FairState {
+    available: usize,
+    // 直近に割り当てた時点の仮想時間。休止していたキーが溜めた分で割り込まないようにする
+    clock: u64,
+    keys: HashMap<String, KeyDemand>,
+}
+
+#[derive(Default)]
+struct KeyDemand {
+    in_flight: usize,
+    finish: u64,
+    waiters: VecDeque<oneshot::Sender<FairPermit>>,
+}
+
+impl FairState {
+    fn grant(&mut self, key: &str, weight: u64) {
+        let clock = self.clock;
+        let demand = self.keys.entry(key.to_string()).or_default();
+        let start = demand.finish.max(clock);
+        demand.finish = start + VIRTUAL_UNIT / weight;
+        demand.in_flight += 1;
+        self.clock = start;
+        self.available -= 1;
+    }
+}
+
+/// `FairQueue` の実行枠。破棄されると次の待機者に割り当てます
+pub struct FairPermit {
+    queue: Arc<FairQueue>,
+    key: String,
+}
+
+impl FairQueue {
+    pub fn new(config: &FairQueueConfig) -> Arc<Self> {
+        Arc::new(Self {
+            weights: config.weights.clone(),
+            state: Mutex::new(FairState { available: config.max_concurrent, clock: 0, keys: HashMap::new() }),
+        })
+    }
+
+    /// 枠が割り当てられるまで待ちます
+    pub async fn acquire(self: &Arc<Self>, key: &str) -> FairPermit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.grant(key, self.weight(key));
+                return FairPermit { queue: self.clone(), key: key.to_string() };
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.keys.entry(key.to_string()).or_default().waiters.push_back(sender);
+            receiver
+        };
+        // 割り当て側は送信前に枠を確保済み（送信側が先に破棄されることはない）
+        receiver.await.expect("fair queue dropped a waiter")
+    }
+
+    /// キーごとの実行中・待機中の数
+    pub fn demand(&self, key: &str) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        state.keys.get(key).map_or((0, 0), |d| (d.in_flight, d.waiters.len()))
+    }
+
+    /// 枠を待っているリクエストの数（すべてのキーの合計。待機をやめたものは除く）
+    pub fn queued(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.keys.values().flat_map(|d| &d.waiters).filter(|waiter| !waiter.is_closed()).count()
+    }
+
+    fn release(self: &Arc<Self>, key: &str) {
+        let mut grants = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.available += 1;
+            if let Some(demand) = state.keys.get_mut(key) {
+                demand.in_flight -= 1;
+            }
+
+            while state.available > 0 {
+                // 仮想時間が最も進んでいない、待機中のキーを選ぶ
+                let clock = state.clock;
+                let next = state
+                    .keys
+                    .iter()
+                    .filter(|(_, d)| !d.waiters.is_empty())
+                    .min_by_key(|(_, d)| (d.finish.max(clock), d.in_flight))
+                    .map(|(k, _)| k.clone());
+                let Some(next) = next else { break };
+                let sender = state.keys.get_mut(&next).unwrap().waiters.pop_front().unwrap();
+                state.grant(&next, self.weight(&next));
+                grants.push((sender, next));
+            }
+            state.keys.retain(|_, d| d.in_flight > 0 || !d.waiters.is_empty());
+        }
+
+        // 待機をやめたリクエストに送った枠は、破棄時に解放されて次へ回る
+        for (sender, key) in grants {
+            let _ = sender.send(FairPermit { queue: self.clone(), key });
+        }
+    }
+
+    /// キーの重み（未指定のキーは 1）
+    pub fn weight(&self, key: &str) -> u64 {
+        self.weights.get(key).copied().unwrap_or(1).max(1) as u64
+    }
+}
+
+impl Drop for FairPermit {
+    fn drop(&mut self) {
+        self.queue.release(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_are_limited_and_released() {
+        let limiter = ConcurrencyLimiter::new(&HashMap::from([("tenant-a".to_string(), 2)]));
+
+        let first = limiter.try_acquire("tenant-a").unwrap();
+        let _second = limiter.try_acquire("tenant-a").unwrap();
+        assert!(limiter.try_acquire("tenant-a").is_none());
+
+        drop(first);
+        assert_eq!(limiter.available("tenant-a"), Some(1));
+        assert!(limiter.try_acquire("tenant-a").is_some());
+    }
+
+    #[test]
+    fn test_uncapped_keys_are_unlimited() {
+        let limiter = ConcurrencyLimiter::new(&HashMap::new());
+        let permits: Vec<_> = (0..100).map(|_| limiter.try_acquire("tenant-b")).collect();
+        assert!(permits.iter().all(Option::is_some));
+        assert_eq!(limiter.available("tenant-b"), None);
+    }
+
+    #[tokio::test]
+    async fn test_heavy_key_does_not_starve_light_key() {
+        let queue = FairQueue::new(&FairQueueConfig { max_concurrent: 1, weights: HashMap::new() });
+        let first = queue.acquire("heavy").await;
+
+        // heavy のバーストが先に並んでから light が並ぶ
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for (queued, key) in ["heavy", "heavy", "heavy", "heavy", "light"].into_iter().enumerate() {
+            let (waiter, tx) = (queue.clone(), tx.clone());
+            tokio::spawn(async move {
+                let permit = waiter.acquire(key).await;
+                tx.send(key).unwrap();
+                tokio::task::yield_now().await;
+                drop(permit);
+            });
+            while queue.demand("heavy").1 + queue.demand("light").1 <= queued {
+                tokio::task::yield_now().await;
+            }
+        }
+        assert_eq!(queue.demand("heavy"), (1, 4));
+
+        drop(first);
+        assert_eq!(rx.recv().await.unwrap(), "light", "the light key is served before the rest of the heavy burst");
+        for _ in 0..4 {
+            assert_eq!(rx.recv().await.unwrap(), "heavy");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_weights_bias_allocation() {
+        let queue = FairQueue::new(&FairQueueConfig {
+            max_concurrent: 3,
+            weights: HashMap::from([("gold".to_string(), 2)]),
+        });
+        let _gold = queue.acquire("gold").await;
+        let _bronze = queue.acquire("bronze").await;
+        let last = queue.acquire("bronze").await;
+
+        let gold = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire("gold").await }
+        });
+        let bronze = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire("bronze").await }
+        });
+        while queue.demand("gold").1 + queue.demand("bronze").1 < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // 重み 2 の gold は1回分の仮想時間の進みが半分なので先に割り当てられる
+        drop(last);
+        let _granted = gold.await.unwrap();
+        assert_eq!(queue.demand("bronze"), (1, 1));
+        bronze.abort();
+    }
+}
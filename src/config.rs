@@ -1,23 +1,178 @@
 use serde::Deserialize;
+use schemars::JsonSchema;
 use config::{Config, ConfigError, File, Environment};
 use std::env;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 pub struct CacheConfig {
     pub enabled: bool,
     pub ttl_seconds: u64,
     pub max_capacity: u64,
+    /// これより小さいレスポンスはキャッシュしない（バイト）
+    #[serde(default)]
+    pub min_cache_bytes: usize,
+    /// これより大きいレスポンスはキャッシュしない（バイト、未設定なら無制限）
+    #[serde(default)]
+    pub max_cache_bytes: Option<usize>,
+    /// キャッシュキーに HTTP メソッドを含める
+    #[serde(default)]
+    pub key_include_method: bool,
+    /// キャッシュキーに正規化（ソート）したクエリ文字列を含める
+    #[serde(default)]
+    pub key_include_query: bool,
+    /// キャッシュキーに含めるリクエストヘッダー（テナントや認証情報ごとにエントリを分ける。順序は問わない）
+    #[serde(default)]
+    pub vary_headers: Vec<String>,
+    /// キャッシュキーにルートが解決した転送先のモデル（`target_model` を展開したもの）を含める
+    #[serde(default)]
+    pub key_include_model: bool,
+    /// キャッシュミスした同一リクエストが同時に来た場合、1回の処理にまとめる
+    #[serde(default)]
+    pub coalesce_requests: bool,
+    /// TTL 経過後も、この秒数の間は古いエントリを返しつつバックグラウンドで再取得する
+    #[serde(default)]
+    pub stale_while_revalidate_seconds: u64,
+    /// ストリーミングレスポンスをどの形式でキャッシュするか
+    #[serde(default)]
+    pub stream_cache_mode: StreamCacheMode,
+    /// 上流が不調（サーキットブレーカーが開いている）の間、TTL を過ぎたエントリを
+    /// さらにこの秒数まで `STALE-DEGRADED` として返す
+    #[serde(default)]
+    pub degraded_ttl_seconds: u64,
+    /// ストリーミング（`text/event-stream`）のレスポンスに使う TTL（未設定なら `ttl_seconds`）
+    #[serde(default)]
+    pub streaming_ttl_seconds: Option<u64>,
+    /// キャッシュした SSE を返す際、イベントごとに空ける間隔（ミリ秒、未設定なら間隔なし）
+    #[serde(default)]
+    pub stream_replay_delay_ms: Option<u64>,
+    /// 保存条件を満たしたレスポンスのうち、実際に保存する割合 (0.0 - 1.0)
+    #[serde(default = "default_cache_sampling_rate")]
+    pub cache_sampling_rate: f64,
+    /// 同じキーでこの回数キャッシュミスするまで保存しない（1 なら初回から保存）
+    #[serde(default = "default_cache_admit_after_misses")]
+    pub cache_admit_after_misses: u32,
+    /// リクエストボディがこれより大きい場合はキャッシュキーを計算せず、キャッシュを使わない（バイト、未設定なら無制限）
+    #[serde(default)]
+    pub cache_max_request_bytes: Option<usize>,
+    /// 保存する上流のステータスコード（未設定なら 2xx）
+    #[serde(default)]
+    pub cacheable_statuses: Option<Vec<u16>>,
+    /// 期限切れで追い出されたエントリのうち、この回数以上ヒットしたものを裏で再取得する（未設定なら再取得しない）
+    ///
+    /// 再取得にはエントリを保存したリクエストを使います。メモリのキャッシュ（`backend = "memory"`）のみが対象です。
+    #[serde(default)]
+    pub refresh_ahead_min_hits: Option<u32>,
+    /// エントリの保存先
+    #[serde(default)]
+    pub backend: CacheBackend,
+    /// `backend = "redis"` の接続設定
+    #[serde(default)]
+    pub redis: Option<crate::cache::RedisCacheConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheBackend {
+    /// プロセス内のメモリ（再起動で消え、インスタンス間で共有しない）
+    #[default]
+    Memory,
+    /// Redis（複数のインスタンスで共有し、再起動後も残る）
+    Redis,
+}
+
+impl CacheConfig {
+    /// このサイズのリクエストボディでキャッシュを使わないか
+    pub fn bypasses_request(&self, body_len: usize) -> bool {
+        self.cache_max_request_bytes.is_some_and(|max| body_len > max)
+    }
+
+    pub fn stream_replay_delay(&self) -> Option<std::time::Duration> {
+        self.stream_replay_delay_ms.map(std::time::Duration::from_millis)
+    }
+}
+
+fn default_cache_sampling_rate() -> f64 {
+    1.0
+}
+
+fn default_cache_admit_after_misses() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamCacheMode {
+    /// SSE のボディをそのまま保存する
+    #[default]
+    Raw,
+    /// 組み立て済みの JSON（非ストリーミング形式）のみを保存する
+    Aggregated,
+    /// 両方を保存する
+    Both,
+}
+
+impl StreamCacheMode {
+    pub fn stores_raw(self) -> bool {
+        matches!(self, Self::Raw | Self::Both)
+    }
+
+    pub fn stores_aggregated(self) -> bool {
+        matches!(self, Self::Aggregated | Self::Both)
+    }
+}
+
+/// 上流へのリクエストの再試行設定
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// 最初の試行を含む最大試行回数（1 なら再試行しない）
+    pub max_attempts: u32,
+    /// 再試行する上流のステータスコード
+    pub retry_on_status: Vec<u16>,
+    /// チャンク転送で送られたリクエストボディも、読み取り済みのバッファから再送する
+    ///
+    /// 無効にすると、`Content-Length` のないリクエストは再送できないものとして再試行しません。
+    pub buffer_streamed_bodies: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            retry_on_status: vec![502, 503, 504],
+            buffer_streamed_bodies: true,
+        }
+    }
+}
+
+/// 上流へ付与するメタデータヘッダーの設定（内部構造の漏えいを避けるため、すべてデフォルトで無効）
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct UpstreamMetadataConfig {
+    /// マッチしたルートの `path` を `x-orchix-route` で送る
+    pub route: bool,
+    /// API キーのハッシュを `x-orchix-key-id` で送る
+    pub key_id: bool,
+    /// リクエスト ID を `x-orchix-request-id` で送る（クライアントの `x-request-id` があればそれを使う）
+    pub request_id: bool,
+    /// 経由した回数を `x-orchix-hops` で送る（転送先が別の Orchix の場合に有効にし、ループを `server.max_hops` で止める）
+    pub hops: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(transform = load_defaults(&["enabled", "hourly_rate_limit", "daily_budget_tokens", "max_request_tokens"]))]
 pub struct CostConfig {
     pub enabled: bool,
     pub hourly_rate_limit: u32,
     pub daily_budget_tokens: u32,
     pub max_request_tokens: u32,
+    /// モデル名ごとの料金表（推定コストの算出に使用）
+    #[serde(default)]
+    pub prices: std::collections::HashMap<String, crate::cost_control::ModelPrice>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(transform = load_defaults(&["server", "log", "security", "cost"]))]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub log: LogConfig,
@@ -26,22 +181,165 @@ pub struct AppConfig {
     pub security: SecurityConfig,
     pub caching: CacheConfig,
     pub cost: CostConfig,
+    #[serde(default)]
+    pub tap: crate::tap::TapConfig,
+    #[serde(default)]
+    pub fault_injection: crate::fault::FaultInjectionConfig,
+    #[serde(default)]
+    pub features: crate::features::FeaturesConfig,
+    #[serde(default)]
+    pub health: crate::health::HealthConfig,
+    #[serde(default)]
+    pub admission: crate::admission::AdmissionConfig,
+    #[serde(default)]
+    pub circuit_breaker: crate::circuit_breaker::CircuitBreakerConfig,
+    /// クライアント向けのモデル名 → 上流のモデル名
+    #[serde(default)]
+    pub model_aliases: std::collections::HashMap<String, String>,
+    #[serde(default)]
+    pub upstream_metadata: UpstreamMetadataConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub events: crate::events::EventSinkConfig,
+    #[serde(default)]
+    pub metrics: crate::metrics::MetricsConfig,
+    #[serde(default)]
+    pub capture: crate::capture::CaptureConfig,
+    #[serde(default)]
+    pub blocklist: crate::blocklist::BlocklistConfig,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(transform = load_defaults(&["api_keys"]))]
 pub struct SecurityConfig {
-    pub api_keys: Vec<String>,
+    /// クライアントの API キー（`{ key, allowed_paths }` で使えるルートを制限できる）
+    pub api_keys: Vec<crate::auth::ApiKeyEntry>,
+    /// Authorization と x-api-key に異なるキーが指定された場合に 400 で拒否する
+    #[serde(default)]
+    pub strict_credentials: bool,
+    /// 管理用エンドポイント (/admin/*) 用のキー
+    #[serde(default)]
+    pub admin_keys: Vec<String>,
+    /// `api_keys` / `admin_keys` / `max_concurrent_requests` のキーの書き方（デフォルトは plain）
+    #[serde(default)]
+    pub key_format: crate::auth::KeyFormat,
+    /// API キーごとの同時実行リクエスト数の上限（未指定のキーは無制限）
+    #[serde(default)]
+    pub max_concurrent_requests: std::collections::HashMap<String, usize>,
+    /// キャッシュに保存せず、ログにも出さないヘッダー
+    #[serde(default = "crate::sensitive::default_sensitive_headers")]
+    pub sensitive_headers: Vec<String>,
+    /// `api_keys` に無いキーを外部のキー管理サービスで検証する
+    #[serde(default)]
+    pub key_service: Option<crate::auth::KeyServiceConfig>,
+    /// 接続元がこのアドレスの場合は `X-Forwarded-For` からクライアントの IP を求める（ロードバランサーなど）
+    #[serde(default)]
+    pub trusted_proxies: Vec<std::net::IpAddr>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(transform = load_defaults(&["host", "port"]))]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// ポートが使用中の場合の再試行設定（未設定なら即座にエラー）
+    #[serde(default)]
+    pub port_retry: Option<PortRetryConfig>,
+    /// シャットダウン時に通常リクエストの完了を待つ秒数
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// シャットダウン時に SSE / WebSocket ストリームの完了を待つ秒数
+    #[serde(default = "default_streaming_shutdown_timeout_secs")]
+    pub streaming_shutdown_timeout_secs: u64,
+    /// 転送を許可する上流のホスト（`*.example.com` でサブドメインを許可。空なら制限しない）
+    #[serde(default)]
+    pub allowed_upstream_hosts: Vec<String>,
+    /// このインスタンスのリージョン（同じ `region` の転送先を優先する）
+    #[serde(default)]
+    pub region: Option<String>,
+    /// SIGHUP が続けて届いた場合に、最後の受信からこの時間待って1回の読み直しにまとめる
+    #[serde(default = "default_reload_debounce_ms")]
+    pub reload_debounce_ms: u64,
+    /// 設定した場合は HTTPS で待ち受ける（未設定なら HTTP）
+    #[serde(default)]
+    pub tls: Option<crate::tls::TlsConfig>,
+    /// `x-orchix-hops` がこの回数を超えたリクエストは転送ループとみなして 508 で拒否する
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u32,
+    /// 上流への接続を待つミリ秒数
+    #[serde(default = "default_upstream_connect_timeout_ms")]
+    pub upstream_connect_timeout_ms: u64,
+    /// 上流からの受信が途切れた場合に待つ秒数（ストリーミングではイベントの間隔に適用される）
+    #[serde(default = "default_upstream_read_timeout_secs")]
+    pub upstream_read_timeout_secs: u64,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_streaming_shutdown_timeout_secs() -> u64 {
+    300
+}
+
+fn default_reload_debounce_ms() -> u64 {
+    500
+}
+
+fn default_max_hops() -> u32 {
+    10
+}
+
+fn default_upstream_connect_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_upstream_read_timeout_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PortRetryMode {
+    /// 次のポート番号を順に試す
+    #[default]
+    NextPort,
+    /// 同じポートが解放されるまで待機して再試行する（TIME_WAIT 対策）
+    Wait,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+pub struct PortRetryConfig {
+    #[serde(default)]
+    pub mode: PortRetryMode,
+    /// 最初の試行に加えて再試行する回数
+    pub attempts: u16,
+    /// wait モードでの再試行間隔（ミリ秒）
+    #[serde(default = "default_retry_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_retry_interval_ms() -> u64 {
+    500
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[schemars(transform = load_defaults(&["level"]))]
 pub struct LogConfig {
     pub level: String,
+    /// 各リクエストのルート照合の経緯（評価順・キャプチャ・外れたルール）を info で出力する
+    #[serde(default)]
+    pub route_provenance: bool,
+}
+
+/// `load` が `set_default` で補う項目を、スキーマの必須項目から外します
+fn load_defaults(fields: &'static [&'static str]) -> impl FnMut(&mut schemars::Schema) {
+    move |schema| {
+        if let Some(serde_json::Value::Array(required)) = schema.get_mut("required") {
+            required.retain(|name| !name.as_str().is_some_and(|name| fields.contains(&name)));
+        }
+    }
 }
 
 impl AppConfig {
@@ -52,7 +350,7 @@ impl AppConfig {
         let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
 
         let s = Config::builder()
-            // デフォルト値の設定
+            // デフォルト値の設定（スキーマでは `load_defaults` で省略可能にしている）
             .set_default("server.host", "127.0.0.1")?
             .set_default("server.port", 3000)?
             .set_default("log.level", "info")?
@@ -71,4 +369,43 @@ impl AppConfig {
 
         s.try_deserialize()
     }
+
+    /// 設定ファイルの JSON Schema（エディタでの検証・補完用、`orchix schema` で出力する）
+    ///
+    /// `load` が既定値を補う項目（`server.host` / `log.level` など）は省略できる項目として扱います。
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(AppConfig)).expect("schema serializes to JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_schema_validates_bundled_config() {
+        let validator = jsonschema::validator_for(&AppConfig::json_schema()).unwrap();
+        let bundled: serde_json::Value = toml::from_str(include_str!("../config.toml")).unwrap();
+        let errors: Vec<String> = validator.iter_errors(&bundled).map(|e| e.to_string()).collect();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(serde_json::from_value::<AppConfig>(bundled.clone()).is_ok());
+
+        let mut bad = bundled;
+        bad["routing"][0]["target_url"] = serde_json::json!(42);
+        bad["caching"]["backend"] = serde_json::json!("memcached");
+        let errors = validator.iter_errors(&bad).count();
+        assert!(errors >= 2, "expected both invalid fields to be reported, got {}", errors);
+        assert!(!validator.is_valid(&serde_json::json!({"server": {"host": "127.0.0.1"}})));
+
+        // `load` が既定値を補う項目は省略できる
+        let minimal = serde_json::json!({
+            "routing": [],
+            "interception": {"forbidden_tools": []},
+            "caching": {"enabled": false, "ttl_seconds": 60, "max_capacity": 100},
+            "server": {"port_retry": {"attempts": 3}},
+            "security": {"admin_keys": ["admin"]},
+        });
+        let errors: Vec<String> = validator.iter_errors(&minimal).map(|e| e.to_string()).collect();
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
 }
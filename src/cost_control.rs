@@ -3,8 +3,73 @@ use tokio::sync::Mutex;
 use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 use crate::config::CostConfig;
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde_json::Value;
 use tracing::{info, warn};
 
+/// モデルごとの料金（1,000 トークンあたり）
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt_per_1k + usage.completion_tokens as f64 * self.completion_per_1k) / 1000.0
+    }
+}
+
+/// 1リクエストあたりのトークン使用量
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl Usage {
+    /// OpenAI 形式の `usage` フィールドを読み取ります
+    pub fn from_json(json: &Value) -> Option<Self> {
+        let usage = json.get("usage")?;
+        Some(Self {
+            prompt_tokens: usage.get("prompt_tokens")?.as_u64()? as u32,
+            completion_tokens: usage.get("completion_tokens")?.as_u64()? as u32,
+        })
+    }
+}
+
+/// トークン数を簡易推定する（文字数 / 4）
+pub fn estimate_tokens(text: &str) -> u32 {
+    // 日本語などのマルチバイト文字も考慮し、文字数ベースで計算
+    let char_count = text.chars().count() as u32;
+    // 1トークンを平均4文字と仮定（簡易版）
+    (char_count / 4).max(1)
+}
+
+/// プリフライト（転送せずに制限状況を確認）の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preflight {
+    /// 直近1時間で残っているリクエスト数（コスト制御が無効なら None）
+    pub requests_remaining: Option<u32>,
+    /// 残りのトークン予算（コスト制御が無効なら None）
+    pub budget_remaining: Option<u32>,
+    /// 拒否される場合の理由
+    pub rejection: Option<&'static str>,
+}
+
+/// レート制限の判定結果（`x-ratelimit-*` ヘッダーとしてクライアントに返す）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub allowed: bool,
+    /// 直近1時間で許可されるリクエスト数
+    pub limit: u32,
+    /// 今回のリクエストを記録した後の残り回数
+    pub remaining: u32,
+    /// 次に1回分の枠が空くまでの秒数
+    pub reset_secs: u64,
+}
+
 pub struct CostManager {
     config: CostConfig,
     // クライアントIDごとのリクエスト履歴（秒単位のタイムスタンプ）
@@ -24,16 +89,36 @@ impl CostManager {
 
     /// トークン数を簡易推定する（文字数 / 4）
     pub fn estimate_tokens(&self, text: &str) -> u32 {
-        // 日本語などのマルチバイト文字も考慮し、文字数ベースで計算
-        let char_count = text.chars().count() as u32;
-        // 1トークンを平均4文字と仮定（簡易版）
-        (char_count / 4).max(1)
+        estimate_tokens(text)
+    }
+
+    /// 料金表からモデルの料金を取得します
+    pub fn price_for(&self, model: &str) -> Option<ModelPrice> {
+        self.config.prices.get(model).copied()
+    }
+
+    /// 上流レスポンスの使用量を求めます
+    ///
+    /// レスポンスに `usage` があればその値を使い、なければ本文から推定します。
+    pub fn usage_for_response(&self, prompt_tokens: u32, body: &[u8]) -> Usage {
+        serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|json| Usage::from_json(&json))
+            .unwrap_or_else(|| Usage {
+                prompt_tokens,
+                completion_tokens: estimate_tokens(&String::from_utf8_lossy(body)),
+            })
     }
 
     /// レート制限のチェック（直近1時間の回数）
     pub async fn check_rate_limit(&self, client_id: &str) -> bool {
+        self.acquire_rate_limit(client_id).await.is_none_or(|status| status.allowed)
+    }
+
+    /// レート制限を確認し、許可した場合は今回のリクエストを記録します（コスト制御が無効なら None）
+    pub async fn acquire_rate_limit(&self, client_id: &str) -> Option<RateLimitStatus> {
         if !self.config.enabled {
-            return true;
+            return None;
         }
 
         let mut status = self.request_history.lock().await;
@@ -47,14 +132,20 @@ impl CostManager {
         // 1時間以上前の履歴を削除
         history.retain(|&t| t > now - 3600);
 
-        if history.len() >= self.config.hourly_rate_limit as usize {
+        let limit = self.config.hourly_rate_limit;
+        let allowed = history.len() < limit as usize;
+        if allowed {
+            // 今回のリクエストを記録
+            history.push(now);
+        } else {
             warn!("Rate limit exceeded for client: {}", client_id);
-            return false;
         }
-
-        // 今回のリクエストを記録
-        history.push(now);
-        true
+        Some(RateLimitStatus {
+            allowed,
+            limit,
+            remaining: limit.saturating_sub(history.len() as u32),
+            reset_secs: history.first().map_or(0, |&oldest| (oldest + 3600).saturating_sub(now)),
+        })
     }
 
     /// 予算（累積トークン）のチェック
@@ -87,6 +178,43 @@ impl CostManager {
         info!("Usage tracked for {}: +{} tokens (Total: {})", client_id, tokens, *usage);
     }
 
+    /// レート制限・予算・トークン上限の状況を、記録せずに確認します
+    pub async fn preflight(&self, client_id: &str, estimated_tokens: u32) -> Preflight {
+        if !self.config.enabled {
+            return Preflight { requests_remaining: None, budget_remaining: None, rejection: None };
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let recent = self.request_history.lock().await
+            .get(client_id)
+            .map(|h| h.iter().filter(|&&t| t > now - 3600).count() as u32)
+            .unwrap_or(0);
+        let usage = self.usage_stats.lock().await.get(client_id).cloned().unwrap_or(0);
+
+        let requests_remaining = self.config.hourly_rate_limit.saturating_sub(recent);
+        let budget_remaining = self.config.daily_budget_tokens.saturating_sub(usage);
+
+        // 実際のチェックと同じ順序で判定する
+        let rejection = if requests_remaining == 0 {
+            Some("rate_limit")
+        } else if budget_remaining == 0 {
+            Some("budget")
+        } else if !self.is_within_max_tokens(estimated_tokens) {
+            Some("max_request_tokens")
+        } else {
+            None
+        };
+
+        Preflight {
+            requests_remaining: Some(requests_remaining),
+            budget_remaining: Some(budget_remaining),
+            rejection,
+        }
+    }
+
     /// 最大リクエストトークン数のチェック
     pub fn is_within_max_tokens(&self, tokens: u32) -> bool {
         if !self.config.enabled {
@@ -107,6 +235,10 @@ mod tests {
             hourly_rate_limit: 2,
             daily_budget_tokens: 100,
             max_request_tokens: 50,
+            prices: HashMap::from([(
+                "gpt-4".to_string(),
+                ModelPrice { prompt_per_1k: 0.03, completion_per_1k: 0.06 },
+            )]),
         }
     }
 
@@ -120,6 +252,22 @@ mod tests {
         assert!(!manager.check_rate_limit(client).await); // 3回目は制限
     }
 
+    #[tokio::test]
+    async fn test_rate_limit_status_counts_down() {
+        let manager = CostManager::new(test_config());
+        let client = "test_user";
+
+        let first = manager.acquire_rate_limit(client).await.unwrap();
+        assert_eq!((first.allowed, first.limit, first.remaining), (true, 2, 1));
+        assert!((3599..=3600).contains(&first.reset_secs));
+        assert_eq!(manager.acquire_rate_limit(client).await.unwrap().remaining, 0);
+        let rejected = manager.acquire_rate_limit(client).await.unwrap();
+        assert_eq!((rejected.allowed, rejected.remaining), (false, 0));
+
+        let disabled = CostManager::new(CostConfig { enabled: false, ..test_config() });
+        assert_eq!(disabled.acquire_rate_limit(client).await, None);
+    }
+
     #[tokio::test]
     async fn test_budgeting() {
         let manager = CostManager::new(test_config());
@@ -136,4 +284,45 @@ mod tests {
         assert_eq!(manager.estimate_tokens("12345678"), 2);
         assert_eq!(manager.estimate_tokens("あいうえ"), 1);
     }
+
+    #[tokio::test]
+    async fn test_preflight_reflects_remaining_budget() {
+        let manager = CostManager::new(test_config());
+        let client = "test_user";
+
+        let before = manager.preflight(client, 10).await;
+        assert_eq!(before, Preflight { requests_remaining: Some(2), budget_remaining: Some(100), rejection: None });
+
+        assert!(manager.check_rate_limit(client).await);
+        manager.track_usage(client, 30).await;
+        let after = manager.preflight(client, 10).await;
+        assert_eq!(after.requests_remaining, Some(1));
+        assert_eq!(after.budget_remaining, Some(70));
+
+        // プリフライト自体は使用量を消費しない
+        assert_eq!(manager.preflight(client, 10).await, after);
+
+        assert_eq!(manager.preflight(client, 51).await.rejection, Some("max_request_tokens"));
+        manager.track_usage(client, 70).await;
+        assert_eq!(manager.preflight(client, 10).await.rejection, Some("budget"));
+    }
+
+    #[test]
+    fn test_usage_from_response_and_cost() {
+        let manager = CostManager::new(test_config());
+        let body = br#"{"choices":[],"usage":{"prompt_tokens":1000,"completion_tokens":500,"total_tokens":1500}}"#;
+        let usage = manager.usage_for_response(1, body);
+        assert_eq!(usage, Usage { prompt_tokens: 1000, completion_tokens: 500 });
+
+        let cost = manager.price_for("gpt-4").unwrap().cost(&usage);
+        assert!((cost - 0.06).abs() < 1e-9, "cost {}", cost);
+        assert!(manager.price_for("unknown").is_none());
+    }
+
+    #[test]
+    fn test_usage_estimated_without_usage_field() {
+        let manager = CostManager::new(test_config());
+        let usage = manager.usage_for_response(7, b"12345678");
+        assert_eq!(usage, Usage { prompt_tokens: 7, completion_tokens: 2 });
+    }
 }
@@ -0,0 +1,71 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// クライアントに返すエラー（OpenAI 互換の `{"error": {...}}` 形式）
+#[derive(Debug, Clone)]
+pub struct OrchixError {
+    pub status: StatusCode,
+    /// 機械的に判別するためのコード（例: `body_too_large`）
+    pub code: &'static str,
+    pub message: String,
+    /// `error.type` に入れる種別（デフォルトは `orchix_error`）
+    pub error_type: &'static str,
+}
+
+impl OrchixError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into(), error_type: "orchix_error" }
+    }
+
+    /// インターセプターがポリシー違反としてブロックしたことを示すエラー（403）
+    pub fn policy_violation(code: &'static str, message: impl Into<String>) -> Self {
+        Self { error_type: "orchix_policy_violation", ..Self::new(StatusCode::FORBIDDEN, code, message) }
+    }
+}
+
+/// `OrchixError` から作ったレスポンスの拡張に入る、エラーの種別とコード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorKind {
+    pub error_type: &'static str,
+    pub code: &'static str,
+}
+
+impl ErrorKind {
+    /// インターセプターのポリシー違反によるエラーか
+    pub fn is_policy_violation(&self) -> bool {
+        self.error_type == "orchix_policy_violation"
+    }
+}
+
+impl IntoResponse for OrchixError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": {
+                "message": self.message,
+                "type": self.error_type,
+                "code": self.code,
+            }
+        });
+        let mut res = (self.status, Json(body)).into_response();
+        // ログやイベントで参照できるよう、種別とコードをレスポンスに残す
+        res.extensions_mut().insert(ErrorKind { error_type: self.error_type, code: self.code });
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_envelope_shape() {
+        let res = OrchixError::new(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large", "too big").into_response();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body, json!({"error": {"message": "too big", "type": "orchix_error", "code": "body_too_large"}}));
+    }
+}
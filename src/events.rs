@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// リクエストごとのイベントを外部のメッセージキューへ送る設定（デフォルトは無効）
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct EventSinkConfig {
+    pub kind: EventSinkKind,
+    /// 送信先（NATS の場合は `nats://host:port`）
+    pub url: String,
+    /// 発行するサブジェクト（トピック）
+    pub subject: String,
+    /// 送信待ちのイベントを保持する数。満杯の間に発生したイベントは捨てる
+    pub buffer: usize,
+}
+
+impl Default for EventSinkConfig {
+    fn default() -> Self {
+        Self {
+            kind: EventSinkKind::None,
+            url: "nats://127.0.0.1:4222".to_string(),
+            subject: "orchix.requests".to_string(),
+            buffer: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSinkKind {
+    /// 送信しない
+    #[default]
+    None,
+    /// NATS のコアプロトコルで発行する
+    Nats,
+}
+
+/// 1リクエスト分のイベント
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RequestEvent {
+    pub method: String,
+    pub path: String,
+    /// マッチしたルートの `path`
+    pub route: Option<String>,
+    pub status: u16,
+    pub latency_ms: u64,
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    pub estimated_cost: Option<f64>,
+    /// インターセプターがポリシー違反としてブロックしたか
+    pub blocked: bool,
+}
+
+/// リクエストのイベントを受け取る送信先
+///
+/// `publish` はリクエストの処理中に呼ばれるため、待機せずにすぐ戻る必要があります。
+pub trait EventSink: Send + Sync {
+    fn publish(&self, event: RequestEvent);
+
+    /// 送信が追いつかずに捨てたイベントの累計数
+    fn dropped(&self) -> u64 {
+        0
+    }
+}
+
+/// 何もしない送信先（デフォルト）
+pub struct NoopSink;
+
+impl EventSink for NoopSink {
+    fn publish(&self, _event: RequestEvent) {}
+}
+
+/// 有界のチャネルに積み、バックグラウンドのタスクが送信する送信先
+pub struct QueueSink {
+    sender: mpsc::Sender<RequestEvent>,
+    dropped: AtomicU64,
+}
+
+impl QueueSink {
+    /// チャネルを作成します。受信側をバックグラウンドのタスクで処理してください
+    pub fn new(buffer: usize) -> (Self, mpsc::Receiver<RequestEvent>) {
+        let (sender, receiver) = mpsc::channel(buffer.max(1));
+        (Self { sender, dropped: AtomicU64::new(0) }, receiver)
+    }
+}
+
+impl EventSink for QueueSink {
+    fn publish(&self, event: RequestEvent) {
+        if self.sender.try_send(event).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!("Event queue is full, dropped event (total dropped: {})", dropped);
+        }
+    }
+
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// 設定に従って送信先を作成します（NATS の場合は送信タスクを起動する）
+pub fn sink_from_config(config: &EventSinkConfig) -> Arc<dyn EventSink> {
+    match config.kind {
+        EventSinkKind::None => Arc::new(NoopSink),
+        EventSinkKind::Nats => {
+            let (sink, receiver) = QueueSink::new(config.buffer);
+            tokio::spawn(publish_to_nats(nats_address(&config.url), config.subject.clone(), receiver));
+            Arc::new(sink)
+        }
+    }
+}
+
+/// `nats://host:port` から接続先のアドレスを取り出します（ポートの省略時は 4222）
+fn nats_address(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(parsed) if parsed.host_str().is_some() => {
+            format!("{}:{}", parsed.host_str().unwrap(), parsed.port().unwrap_or(4222))
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// キューのイベントを NATS に発行し続けます。切断された場合は次のイベントで再接続する
+async fn publish_to_nats(address: String, subject: String, mut receiver: mpsc::Receiver<RequestEvent>) {
+    let mut connection: Option<NatsConnection> = None;
+    while let Some(event) = receiver.recv().await {
+        if connection.is_none() {
+            match NatsConnection::connect(&address).await {
+                Ok(conn) => connection = Some(conn),
+                Err(e) => {
+                    warn!("Failed to connect to NATS at {}: {}", address, e);
+                    continue;
+                }
+            }
+        }
+        let Ok(payload) = serde_json::to_vec(&event) else {
+            continue;
+        };
+        if let Some(conn) = &mut connection
+            && let Err(e) = conn.publish(&subject, &payload).await
+        {
+            warn!("Failed to publish event to NATS: {}", e);
+            connection = None;
+        }
+    }
+}
+
+/// 発行のみを行う最小限の NATS クライアント
+struct NatsConnection {
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+    // 読み取り途中の行（中断しても続きから読めるよう保持する）
+    line: String,
+}
+
+impl NatsConnection {
+    async fn connect(address: &str) -> std::io::Result<Self> {
+        let (read, writer) = TcpStream::connect(address).await?.into_split();
+        let mut conn = Self { reader: BufReader::new(read), writer, line: String::new() };
+        // サーバーの INFO を受け取ってから CONNECT を送る
+        conn.reader.read_line(&mut conn.line).await?;
+        if !std::mem::take(&mut conn.line).starts_with("INFO") {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected INFO from NATS server"));
+        }
+        conn.writer.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"orchix\"}\r\n").await?;
+        Ok(conn)
+    }
+
+    async fn publish(&mut self, subject: &str, payload: &[u8]) -> std::io::Result<()> {
+        self.answer_pings().await?;
+        let mut frame = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(b"\r\n");
+        self.writer.write_all(&frame).await
+    }
+
+    /// 受信済みの PING に応答します（応答しないとサーバーから切断される）
+    async fn answer_pings(&mut self) -> std::io::Result<()> {
+        loop {
+            // 読み取れるデータが無ければすぐに戻る
+            let read = tokio::time::timeout(std::time::Duration::ZERO, self.reader.read_line(&mut self.line)).await;
+            match read {
+                Err(_) => return Ok(()),
+                Ok(Ok(0)) => return Err(std::io::ErrorKind::ConnectionAborted.into()),
+                Ok(Ok(_)) => {
+                    if std::mem::take(&mut self.line).starts_with("PING") {
+                        self.writer.write_all(b"PONG\r\n").await?;
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn event(status: u16) -> RequestEvent {
+        RequestEvent {
+            method: "POST".to_string(),
+            path: "/v1/chat".to_string(),
+            route: Some("/v1/chat".to_string()),
+            status,
+            latency_ms: 5,
+            prompt_tokens: Some(10),
+            completion_tokens: Some(2),
+            estimated_cost: None,
+            blocked: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_drops_when_full() {
+        let (sink, mut receiver) = QueueSink::new(2);
+        for status in [200, 201, 202] {
+            sink.publish(event(status));
+        }
+        assert_eq!(sink.dropped(), 1);
+        assert_eq!(receiver.recv().await.unwrap().status, 200);
+        assert_eq!(receiver.recv().await.unwrap().status, 201);
+    }
+
+    #[tokio::test]
+    async fn test_events_published_to_nats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            write.write_all(b"INFO {\"server_id\":\"test\"}\r\nPING\r\n").await.unwrap();
+            let mut lines = BufReader::new(read).lines();
+            let mut received = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let done = line.starts_with('{');
+                received.push(line);
+                if done {
+                    break;
+                }
+            }
+            received
+        });
+
+        let sink = sink_from_config(&EventSinkConfig {
+            kind: EventSinkKind::Nats,
+            url: format!("nats://{}", address),
+            subject: "orchix.test".to_string(),
+            buffer: 8,
+        });
+        sink.publish(event(200));
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert!(received[0].starts_with("CONNECT "), "{:?}", received);
+        assert!(received.iter().any(|line| line == "PONG"), "{:?}", received);
+        let payload = received.last().unwrap();
+        let publish = &received[received.len() - 2];
+        assert_eq!(publish, &format!("PUB orchix.test {}", payload.len()));
+        let published: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(published["status"], 200);
+        assert_eq!(published["route"], "/v1/chat");
+    }
+}
@@ -0,0 +1,162 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    middleware::Next,
+    extract::State,
+};
+use serde::Deserialize;
+use schemars::JsonSchema;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+use crate::networking::AppState;
+
+/// 負荷試験・カオステスト用の障害注入設定（デフォルトは無効）
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct FaultInjectionConfig {
+    pub enabled: bool,
+    /// 対象とするパスのプレフィックス（空なら全ルート）
+    pub routes: Vec<String>,
+    /// 遅延を注入する確率 (0.0 - 1.0)
+    pub latency_probability: f64,
+    pub latency_ms: u64,
+    /// 合成の 503 を返す確率
+    pub error_probability: f64,
+    /// 接続を切断する確率
+    pub drop_probability: f64,
+}
+
+impl Default for FaultInjectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            routes: Vec::new(),
+            latency_probability: 0.0,
+            latency_ms: 1000,
+            error_probability: 0.0,
+            drop_probability: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Latency(Duration),
+    Error,
+    Drop,
+}
+
+pub struct FaultInjector {
+    config: FaultInjectionConfig,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultInjectionConfig) -> Self {
+        Self { config }
+    }
+
+    /// このリクエストに注入する障害を決めます（0〜1の乱数 `roll` を使用）
+    pub fn decide(&self, path: &str, roll: f64) -> Option<Fault> {
+        if !self.config.enabled {
+            return None;
+        }
+        if !self.config.routes.is_empty() && !self.config.routes.iter().any(|r| path.starts_with(r.as_str())) {
+            return None;
+        }
+
+        // 確率の区間を順に並べて1回の乱数で判定する
+        let drop = self.config.drop_probability;
+        let error = drop + self.config.error_probability;
+        let latency = error + self.config.latency_probability;
+        if roll < drop {
+            Some(Fault::Drop)
+        } else if roll < error {
+            Some(Fault::Error)
+        } else if roll < latency {
+            Some(Fault::Latency(Duration::from_millis(self.config.latency_ms)))
+        } else {
+            None
+        }
+    }
+}
+
+pub async fn fault_injection_middleware(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if !state.features.fault_injection() {
+        return next.run(req).await;
+    }
+    match state.fault_injector.decide(req.uri().path(), rand::random::<f64>()) {
+        None => next.run(req).await,
+        Some(Fault::Latency(delay)) => {
+            warn!("Injecting {:?} latency into {}", delay, req.uri().path());
+            tokio::time::sleep(delay).await;
+            next.run(req).await
+        }
+        Some(Fault::Error) => {
+            warn!("Injecting synthetic 503 into {}", req.uri().path());
+            (StatusCode::SERVICE_UNAVAILABLE, "Injected fault").into_response()
+        }
+        Some(Fault::Drop) => {
+            warn!("Injecting connection drop into {}", req.uri().path());
+            // ボディのストリームをエラーで終わらせ、接続を中断させる
+            let aborted = futures::stream::once(async {
+                Err::<bytes::Bytes, _>(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "injected drop"))
+            });
+            Response::new(Body::from_stream(aborted))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn injector(routes: Vec<String>) -> FaultInjector {
+        FaultInjector::new(FaultInjectionConfig {
+            enabled: true,
+            routes,
+            latency_probability: 0.2,
+            latency_ms: 10,
+            error_probability: 0.1,
+            drop_probability: 0.05,
+        })
+    }
+
+    #[test]
+    fn test_faults_occur_at_configured_rate() {
+        let injector = injector(Vec::new());
+        let n = 20_000;
+        let (mut latency, mut error, mut drop) = (0, 0, 0);
+        for _ in 0..n {
+            match injector.decide("/v1/chat", rand::random::<f64>()) {
+                Some(Fault::Latency(_)) => latency += 1,
+                Some(Fault::Error) => error += 1,
+                Some(Fault::Drop) => drop += 1,
+                None => {}
+            }
+        }
+
+        let rate = |count: i32| count as f64 / n as f64;
+        assert!((rate(latency) - 0.2).abs() < 0.02, "latency rate {}", rate(latency));
+        assert!((rate(error) - 0.1).abs() < 0.02, "error rate {}", rate(error));
+        assert!((rate(drop) - 0.05).abs() < 0.02, "drop rate {}", rate(drop));
+    }
+
+    #[test]
+    fn test_only_matched_routes_are_affected() {
+        let injector = injector(vec!["/v1/chat".to_string()]);
+        assert_eq!(injector.decide("/v1/chat/completions", 0.0), Some(Fault::Drop));
+        assert_eq!(injector.decide("/v1/images", 0.0), None);
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let injector = FaultInjector::new(FaultInjectionConfig::default());
+        assert_eq!(injector.decide("/v1/chat", 0.0), None);
+    }
+}
@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::info;
+
+/// 機能ごとの有効・無効の切り替え（`[features]` セクション）
+///
+/// 各機能の詳細設定（`caching.enabled` など）とあわせて両方が有効な場合に動作します。
+/// `/admin/reload` で再起動せずに切り替えられます。
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(default)]
+pub struct FeaturesConfig {
+    pub caching: bool,
+    pub interception: bool,
+    pub fault_injection: bool,
+    /// ルートの `shadow_url` へリクエストの写しを送るか
+    pub shadow_traffic: bool,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        Self {
+            caching: true,
+            interception: true,
+            fault_injection: true,
+            shadow_traffic: true,
+        }
+    }
+}
+
+/// 実行時に切り替え可能なフラグ
+pub struct FeatureFlags {
+    caching: AtomicBool,
+    interception: AtomicBool,
+    fault_injection: AtomicBool,
+    shadow_traffic: AtomicBool,
+}
+
+impl FeatureFlags {
+    pub fn new(config: FeaturesConfig) -> Self {
+        Self {
+            caching: AtomicBool::new(config.caching),
+            interception: AtomicBool::new(config.interception),
+            fault_injection: AtomicBool::new(config.fault_injection),
+            shadow_traffic: AtomicBool::new(config.shadow_traffic),
+        }
+    }
+
+    pub fn caching(&self) -> bool {
+        self.caching.load(Ordering::Relaxed)
+    }
+
+    pub fn interception(&self) -> bool {
+        self.interception.load(Ordering::Relaxed)
+    }
+
+    pub fn fault_injection(&self) -> bool {
+        self.fault_injection.load(Ordering::Relaxed)
+    }
+
+    pub fn shadow_traffic(&self) -> bool {
+        self.shadow_traffic.load(Ordering::Relaxed)
+    }
+
+    /// 新しい設定を反映します
+    pub fn apply(&self, config: FeaturesConfig) {
+        self.caching.store(config.caching, Ordering::Relaxed);
+        self.interception.store(config.interception, Ordering::Relaxed);
+        self.fault_injection.store(config.fault_injection, Ordering::Relaxed);
+        self.shadow_traffic.store(config.shadow_traffic, Ordering::Relaxed);
+        info!("Feature flags updated: {:?}", config);
+    }
+
+    /// 現在の値
+    pub fn snapshot(&self) -> FeaturesConfig {
+        FeaturesConfig {
+            caching: self.caching(),
+            interception: self.interception(),
+            fault_injection: self.fault_injection(),
+            shadow_traffic: self.shadow_traffic(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_default_on_and_can_be_reloaded() {
+        let flags = FeatureFlags::new(FeaturesConfig::default());
+        assert_eq!(flags.snapshot(), FeaturesConfig::default());
+
+        let off = FeaturesConfig { caching: false, interception: false, fault_injection: false, shadow_traffic: false };
+        flags.apply(off);
+        assert!(!flags.caching());
+        assert!(!flags.interception());
+        assert!(!flags.fault_injection());
+        assert!(!flags.shadow_traffic());
+        assert_eq!(flags.snapshot(), off);
+    }
+
+    #[test]
+    fn test_partial_section_keeps_other_defaults() {
+        let config: FeaturesConfig = toml::from_str("caching = false").unwrap();
+        assert_eq!(config, FeaturesConfig { caching: false, ..FeaturesConfig::default() });
+    }
+}
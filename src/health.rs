@@ -0,0 +1,99 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::sync::Arc;
+use crate::networking::AppState;
+
+/// `/health` の応答設定
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub format: HealthFormat,
+    /// API キーによる認証を必須にする（デフォルトは認証なし）
+    pub require_auth: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthFormat {
+    /// バージョン・稼働時間・ビルド情報を含む JSON
+    #[default]
+    Json,
+    /// 最小限のプローブ向けに `OK` のみを返す
+    Plain,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub uptime_seconds: u64,
+    pub build: BuildInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub profile: &'static str,
+}
+
+impl HealthReport {
+    pub fn new(uptime_seconds: u64) -> Self {
+        Self {
+            status: "ok",
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_seconds,
+            build: BuildInfo {
+                name: env!("CARGO_PKG_NAME"),
+                profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+            },
+        }
+    }
+}
+
+/// `/health/ready` のレスポンス
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub status: &'static str,
+    /// 管理 API でドレイン中にした転送先
+    pub draining_upstreams: Vec<String>,
+    /// インスタンスのリージョンが設定されている場合の転送先の選択結果
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_selection: Option<RegionReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegionReport {
+    pub region: String,
+    #[serde(flatten)]
+    pub selection: crate::upstreams::RegionSelectionSnapshot,
+}
+
+/// シャットダウン中はロードバランサーが振り分けを止めるよう、readiness だけ 503 を返す
+///
+/// liveness (`/health`) は処理中のリクエストを流し切るまで 200 のままにし、
+/// ドレイン中のプロセスがオーケストレーターに再起動されないようにする。
+pub async fn readiness_handler(State(state): State<Arc<AppState>>) -> Response {
+    let status = if state.shutdown.is_draining() { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+    let report = Json(ReadinessReport {
+        status: if status.is_success() { "ready" } else { "draining" },
+        draining_upstreams: state.drains.draining(),
+        region_selection: state.region.clone().map(|region| RegionReport {
+            region,
+            selection: state.region_selection.snapshot(),
+        }),
+    });
+    (status, report).into_response()
+}
+
+pub async fn health_handler(State(state): State<Arc<AppState>>) -> Response {
+    match state.health.format {
+        HealthFormat::Plain => "OK".into_response(),
+        HealthFormat::Json => Json(HealthReport::new(state.started_at.elapsed().as_secs())).into_response(),
+    }
+}
@@ -1,46 +1,622 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
 use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Component, Path};
+use std::sync::Arc;
 use tracing::{info, warn};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 pub struct InterceptionConfig {
     pub forbidden_tools: Vec<String>,
+    /// 設定した場合、ここに無いツールの呼び出しをすべて拒否する（`forbidden_tools` が優先）
+    #[serde(default)]
+    pub allowed_tools: Option<Vec<String>>,
+    /// リクエストで宣言できるツール定義（`tools` / `functions`）の最大数
+    #[serde(default)]
+    pub max_tool_definitions: Option<usize>,
+    /// ファイルパス引数をサンドボックス内に制限する設定
+    #[serde(default)]
+    pub path_sandbox: Option<PathSandboxConfig>,
+    /// 何もブロックしないポリシーで起動した場合の扱い
+    #[serde(default)]
+    pub fail_mode: PolicyFailMode,
+    /// 解析前に検査する JSON ボディの入れ子の深さ・要素数の上限
+    #[serde(default)]
+    pub json_limits: JsonLimits,
+    /// ツール名 → 引数（`arguments`）が満たすべき JSON Schema
+    #[serde(default)]
+    pub tool_schemas: HashMap<String, Value>,
+}
+
+/// インターセプションで解析する JSON ボディの上限（JSON 爆弾による負荷を防ぐ）
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(default)]
+pub struct JsonLimits {
+    /// オブジェクト・配列の入れ子の最大の深さ
+    pub max_depth: usize,
+    /// 構造トークン（`{` `[` `,` `:`）の最大数。おおよその要素数に相当する
+    pub max_tokens: usize,
+}
+
+impl Default for JsonLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_tokens: 100_000,
+        }
+    }
+}
+
+/// 空のポリシーを検出したときの起動時の挙動
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyFailMode {
+    /// 警告ログのみ出して起動を続ける
+    #[default]
+    Warn,
+    /// 設定エラーとして起動を中止する
+    Error,
+}
+
+/// 指定したツールのパス引数を検査する設定
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+pub struct PathSandboxConfig {
+    /// 対象のツール名（例: `write_file`, `read_file`）
+    pub tools: Vec<String>,
+    /// パスを表す引数名
+    #[serde(default = "default_path_argument")]
+    pub argument: String,
+    /// 絶対パスを許可するディレクトリ（未設定なら絶対パスはすべて拒否）
+    #[serde(default)]
+    pub root: Option<String>,
+}
+
+fn default_path_argument() -> String {
+    "path".to_string()
 }
 
 #[derive(Clone)]
 pub struct Interceptor {
     pub config: InterceptionConfig,
+    // `tool_schemas` をコンパイルしたもの
+    schemas: Arc<HashMap<String, jsonschema::Validator>>,
 }
 
 impl Interceptor {
-    pub fn new(config: InterceptionConfig) -> Self {
-        Self { config }
+    /// 設定から作成します。`tool_schemas` に不正なスキーマがあればエラー
+    pub fn try_new(config: InterceptionConfig) -> anyhow::Result<Self> {
+        let schemas = config
+            .tool_schemas
+            .iter()
+            .map(|(tool, schema)| {
+                jsonschema::validator_for(schema)
+                    .map(|validator| (tool.clone(), validator))
+                    .map_err(|e| anyhow::anyhow!("Invalid JSON Schema for tool '{}': {}", tool, e))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        Ok(Self { config, schemas: Arc::new(schemas) })
+    }
+
+    /// ポリシーが実質的に何もブロックしない設定になっていないか確認します
+    ///
+    /// `forbidden_tools` が空で `allowed_tools` もパスのサンドボックスもない場合、インターセプターは
+    /// すべてのツール呼び出しを通してしまうため、`fail_mode` に従って警告または失敗とします。
+    pub fn check_policy(&self) -> anyhow::Result<()> {
+        if !self.config.forbidden_tools.is_empty()
+            || self.config.allowed_tools.is_some()
+            || self.config.path_sandbox.is_some()
+            || !self.schemas.is_empty()
+        {
+            return Ok(());
+        }
+        let message = "Interception is enabled but the policy is empty (no forbidden_tools, allowed_tools, path_sandbox or tool_schemas); no tool calls will be blocked";
+        match self.config.fail_mode {
+            PolicyFailMode::Warn => {
+                warn!("{}", message);
+                Ok(())
+            }
+            PolicyFailMode::Error => anyhow::bail!(message),
+        }
     }
 
     /// リクエストボディ内のツール呼び出しを検証します
     pub fn validate_tools(&self, body: &Value) -> Result<(), String> {
+        self.check_tool_calls(body, true)
+    }
+
+    /// ストリーミングの差分（`delta`）内のツール呼び出しを検証します
+    ///
+    /// 差分の `arguments` は断片のため、スキーマは組み立て後に `validate_argument_schema` で検証してください。
+    pub fn validate_tool_deltas(&self, delta: &Value) -> Result<(), String> {
+        self.check_tool_calls(delta, false)
+    }
+
+    fn check_tool_calls(&self, body: &Value, check_schema: bool) -> Result<(), String> {
         info!("Intercepting tool calls in request body...");
 
         // OpenAI 互換の tool_calls 構造を想定
         if let Some(tool_calls) = body.get("tool_calls").and_then(|v| v.as_array()) {
             for call in tool_calls {
                 if let Some(name) = call.get("function").and_then(|f| f.get("name")).and_then(|n| n.as_str()) {
-                    if self.config.forbidden_tools.contains(&name.to_string()) {
+                    if !self.is_tool_permitted(name) {
                         warn!("Forbidden tool call detected: {}", name);
                         return Err(format!("Tool '{}' is blocked by Orchix security policy", name));
                     }
+                    if let Some(arguments) = call.get("function").and_then(|f| f.get("arguments")) {
+                        if check_schema {
+                            self.validate_argument_schema(name, arguments)?;
+                        }
+                        self.validate_path_arguments(name, arguments)?;
+                    }
                 }
             }
         }
 
         // 古い functions API の場合
-        if let Some(name) = body.get("function_call").and_then(|f| f.get("name")).and_then(|n| n.as_str()) {
-            if self.config.forbidden_tools.contains(&name.to_string()) {
+        if let Some(call) = body.get("function_call")
+            && let Some(name) = call.get("name").and_then(|n| n.as_str())
+        {
+            if !self.is_tool_permitted(name) {
                 warn!("Forbidden function call detected: {}", name);
                 return Err(format!("Function '{}' is blocked by Orchix security policy", name));
             }
+            if let Some(arguments) = call.get("arguments") {
+                if check_schema {
+                    self.validate_argument_schema(name, arguments)?;
+                }
+                self.validate_path_arguments(name, arguments)?;
+            }
         }
 
         Ok(())
     }
+
+    /// ツール名がポリシー上許可されているかを返します
+    ///
+    /// `forbidden_tools` に含まれるものは `allowed_tools` に関わらず拒否します。
+    fn is_tool_permitted(&self, name: &str) -> bool {
+        if self.config.forbidden_tools.iter().any(|t| t == name) {
+            return false;
+        }
+        self.config.allowed_tools.as_ref().is_none_or(|allowed| allowed.iter().any(|t| t == name))
+    }
+
+    /// `tool_schemas` にスキーマがあるツールについて、引数がスキーマを満たすか検証します
+    ///
+    /// `arguments` は OpenAI 形式の JSON 文字列、またはオブジェクトを受け付けます。
+    /// スキーマがあるツールの引数が JSON として解析できない場合も拒否します。
+    pub fn validate_argument_schema(&self, tool: &str, arguments: &Value) -> Result<(), String> {
+        let Some(validator) = self.schemas.get(tool) else {
+            return Ok(());
+        };
+
+        let parsed;
+        let arguments = match arguments {
+            Value::String(raw) => match serde_json::from_str::<Value>(raw) {
+                Ok(value) => {
+                    parsed = value;
+                    &parsed
+                }
+                Err(e) => {
+                    warn!("Malformed arguments for {}: {}", tool, e);
+                    return Err(format!("Arguments for tool '{}' are not valid JSON: {}", tool, e));
+                }
+            },
+            other => other,
+        };
+        if let Err(error) = validator.validate(arguments) {
+            // 必須プロパティの欠落などはルート（空のパス）で報告される
+            let field = match error.instance_path().as_str() {
+                "" => "/".to_string(),
+                path => path.to_string(),
+            };
+            warn!("Schema violation in arguments for {} at {}: {}", tool, field, error);
+            return Err(format!("Argument '{}' for tool '{}' is invalid: {}", field, tool, error));
+        }
+        Ok(())
+    }
+
+    /// サンドボックス対象のツールについて、パス引数を検証します
+    ///
+    /// `arguments` は OpenAI 形式の JSON 文字列、またはオブジェクトを受け付けます。
+    /// 絶対パス（`root` 配下を除く）と `..` を含むパスを拒否します。
+    pub fn validate_path_arguments(&self, tool: &str, arguments: &Value) -> Result<(), String> {
+        let Some(sandbox) = &self.config.path_sandbox else {
+            return Ok(());
+        };
+        if !sandbox.tools.iter().any(|t| t == tool) {
+            return Ok(());
+        }
+
+        let parsed;
+        let arguments = match arguments {
+            Value::String(raw) => {
+                // 解析できない引数はパス以外の検証に委ねる
+                let Ok(value) = serde_json::from_str::<Value>(raw) else {
+                    return Ok(());
+                };
+                parsed = value;
+                &parsed
+            }
+            other => other,
+        };
+        let Some(path) = arguments.get(&sandbox.argument).and_then(|v| v.as_str()) else {
+            return Ok(());
+        };
+
+        let reject = |reason: &str| {
+            warn!("Blocked {} path argument for {}: {}", reason, tool, path);
+            Err(format!("Path '{}' for tool '{}' is outside the allowed sandbox ({})", path, tool, reason))
+        };
+        let candidate = Path::new(path);
+        if candidate.components().any(|c| c == Component::ParentDir) {
+            return reject("traversal");
+        }
+        // Windows 形式の絶対パスも拒否する
+        let looks_absolute = candidate.has_root()
+            || path.starts_with('\\')
+            || path.as_bytes().get(1) == Some(&b':');
+        if looks_absolute {
+            match &sandbox.root {
+                Some(root) if candidate.starts_with(root) => {}
+                _ => return reject("absolute path"),
+            }
+        }
+        Ok(())
+    }
+
+    /// ボディをパースする前に、入れ子の深さと構造トークン数が上限内か走査して確認します
+    ///
+    /// 文字列リテラル内の記号は数えません。上限を超えた時点で走査を打ち切ります。
+    pub fn check_json_limits(&self, body: &[u8]) -> Result<(), String> {
+        let limits = self.config.json_limits;
+        let (mut depth, mut tokens) = (0usize, 0usize);
+        let (mut in_string, mut escaped) = (false, false);
+        for &b in body {
+            if in_string {
+                match b {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match b {
+                b'"' => in_string = true,
+                b'{' | b'[' => {
+                    depth += 1;
+                    tokens += 1;
+                    if depth > limits.max_depth {
+                        warn!("JSON body exceeds nesting depth limit of {}", limits.max_depth);
+                        return Err(format!("JSON body exceeds the nesting depth limit of {}", limits.max_depth));
+                    }
+                }
+                b'}' | b']' => depth = depth.saturating_sub(1),
+                b',' | b':' => tokens += 1,
+                _ => continue,
+            }
+            if tokens > limits.max_tokens {
+                warn!("JSON body exceeds token limit of {}", limits.max_tokens);
+                return Err(format!("JSON body exceeds the limit of {} structural tokens", limits.max_tokens));
+            }
+        }
+        Ok(())
+    }
+
+    /// リクエストで宣言されたツール定義の数を検証します
+    pub fn validate_tool_definitions(&self, body: &Value) -> Result<(), String> {
+        let Some(max) = self.config.max_tool_definitions else {
+            return Ok(());
+        };
+
+        let count: usize = ["tools", "functions"]
+            .iter()
+            .filter_map(|k| body.get(*k).and_then(|v| v.as_array()))
+            .map(|defs| defs.len())
+            .sum();
+
+        if count > max {
+            warn!("Too many tool definitions: {} (limit: {})", count, max);
+            return Err(format!("Request declares {} tool definitions, exceeding the limit of {}", count, max));
+        }
+        Ok(())
+    }
+}
+
+/// インターセプションの各段階の判定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceVerdict {
+    Pass,
+    Block,
+    /// リクエストを書き換えて通した
+    Modified,
+    /// 機能が無効、または対象がないため実行しなかった
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TraceStage {
+    pub stage: &'static str,
+    pub verdict: TraceVerdict,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// 1リクエストで実行したインターセプションの段階と判定の記録（管理者のデバッグ用）
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InterceptionTrace {
+    pub stages: Vec<TraceStage>,
+}
+
+impl InterceptionTrace {
+    pub fn record(&mut self, stage: &'static str, verdict: TraceVerdict, detail: Option<String>) {
+        self.stages.push(TraceStage { stage, verdict, detail });
+    }
+
+    /// 最初にブロックした段階
+    pub fn blocked_by(&self) -> Option<&'static str> {
+        self.stages.iter().find(|s| s.verdict == TraceVerdict::Block).map(|s| s.stage)
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({"stages": self.stages, "blocked_by": self.blocked_by()})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn interceptor(max_tool_definitions: Option<usize>) -> Interceptor {
+        Interceptor::try_new(InterceptionConfig {
+            forbidden_tools: vec!["rm_rf".to_string()],
+            allowed_tools: None,
+            max_tool_definitions,
+            path_sandbox: None,
+            fail_mode: PolicyFailMode::Warn,
+            json_limits: JsonLimits::default(),
+            tool_schemas: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    fn sandboxed(root: Option<&str>) -> Interceptor {
+        Interceptor::try_new(InterceptionConfig {
+            forbidden_tools: Vec::new(),
+            allowed_tools: None,
+            max_tool_definitions: None,
+            path_sandbox: Some(PathSandboxConfig {
+                tools: vec!["write_file".to_string(), "read_file".to_string()],
+                argument: default_path_argument(),
+                root: root.map(str::to_string),
+            }),
+            fail_mode: PolicyFailMode::Warn,
+            json_limits: JsonLimits::default(),
+            tool_schemas: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    fn write_call(path: &str) -> Value {
+        let arguments = json!({"path": path, "content": "x"}).to_string();
+        json!({"tool_calls": [{"function": {"name": "write_file", "arguments": arguments}}]})
+    }
+
+    fn tools(n: usize) -> Value {
+        let defs: Vec<Value> = (0..n)
+            .map(|i| json!({"type": "function", "function": {"name": format!("tool_{}", i)}}))
+            .collect();
+        json!({"tools": defs})
+    }
+
+    #[test]
+    fn test_tool_definitions_at_and_above_limit() {
+        let interceptor = interceptor(Some(2));
+        assert!(interceptor.validate_tool_definitions(&tools(2)).is_ok());
+        assert!(interceptor.validate_tool_definitions(&tools(3)).is_err());
+    }
+
+    #[test]
+    fn test_tool_definitions_unlimited_by_default() {
+        assert!(interceptor(None).validate_tool_definitions(&tools(100)).is_ok());
+    }
+
+    #[test]
+    fn test_sandboxed_paths_allowed() {
+        let interceptor = sandboxed(Some("/srv/sandbox"));
+        assert!(interceptor.validate_tools(&write_call("notes/today.md")).is_ok());
+        assert!(interceptor.validate_tools(&write_call("./out.txt")).is_ok());
+        assert!(interceptor.validate_tools(&write_call("/srv/sandbox/out.txt")).is_ok());
+    }
+
+    #[test]
+    fn test_traversal_rejected() {
+        let interceptor = sandboxed(Some("/srv/sandbox"));
+        assert!(interceptor.validate_tools(&write_call("../etc/passwd")).is_err());
+        assert!(interceptor.validate_tools(&write_call("notes/../../secret")).is_err());
+        assert!(interceptor.validate_tools(&write_call("/srv/sandbox/../other")).is_err());
+    }
+
+    #[test]
+    fn test_absolute_paths_rejected() {
+        assert!(sandboxed(None).validate_tools(&write_call("/etc/passwd")).is_err());
+        assert!(sandboxed(None).validate_tools(&write_call("C:\\Windows\\system32")).is_err());
+        assert!(sandboxed(Some("/srv/sandbox")).validate_tools(&write_call("/srv/sandboxed/x")).is_err());
+    }
+
+    #[test]
+    fn test_legacy_function_call_paths_checked() {
+        let call = |path: &str| {
+            let arguments = json!({"path": path}).to_string();
+            json!({"function_call": {"name": "write_file", "arguments": arguments}})
+        };
+        assert!(sandboxed(None).validate_tools(&call("notes/today.md")).is_ok());
+        assert!(sandboxed(None).validate_tools(&call("/etc/passwd")).is_err());
+        assert!(sandboxed(None).validate_tools(&call("../secret")).is_err());
+    }
+
+    #[test]
+    fn test_unlisted_tools_are_not_checked() {
+        let body = json!({"tool_calls": [{"function": {"name": "search", "arguments": "{\"path\":\"/etc\"}"}}]});
+        assert!(sandboxed(None).validate_tools(&body).is_ok());
+    }
+
+    fn empty_policy(fail_mode: PolicyFailMode) -> Interceptor {
+        Interceptor::try_new(InterceptionConfig {
+            forbidden_tools: Vec::new(),
+            allowed_tools: None,
+            max_tool_definitions: None,
+            path_sandbox: None,
+            fail_mode,
+            json_limits: JsonLimits::default(),
+            tool_schemas: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_empty_policy_warns_by_default() {
+        assert!(empty_policy(PolicyFailMode::Warn).check_policy().is_ok());
+        assert!(interceptor(None).check_policy().is_ok());
+        assert!(sandboxed(None).check_policy().is_ok());
+    }
+
+    #[test]
+    fn test_empty_policy_errors_in_error_mode() {
+        let err = empty_policy(PolicyFailMode::Error).check_policy().unwrap_err();
+        assert!(err.to_string().contains("policy is empty"));
+    }
+
+    fn allowlisted(allowed: &[&str]) -> Interceptor {
+        let mut interceptor = interceptor(None);
+        interceptor.config.allowed_tools = Some(allowed.iter().map(|t| t.to_string()).collect());
+        interceptor
+    }
+
+    fn tool_call(name: &str) -> Value {
+        json!({"tool_calls": [{"function": {"name": name, "arguments": "{}"}}]})
+    }
+
+    #[test]
+    fn test_allowlist_rejects_unlisted_tools() {
+        let interceptor = allowlisted(&["search", "rm_rf"]);
+        assert!(interceptor.validate_tools(&tool_call("search")).is_ok());
+        assert!(interceptor.validate_tools(&tool_call("send_email")).is_err());
+        assert!(interceptor.validate_tools(&json!({"function_call": {"name": "send_email"}})).is_err());
+        // 両方に含まれる場合は denylist が優先
+        assert!(interceptor.validate_tools(&tool_call("rm_rf")).is_err());
+    }
+
+    #[test]
+    fn test_empty_allowlist_blocks_everything() {
+        let interceptor = allowlisted(&[]);
+        assert!(interceptor.validate_tools(&tool_call("search")).is_err());
+        assert!(interceptor.check_policy().is_ok());
+    }
+
+    #[test]
+    fn test_unset_allowlist_allows_unlisted_tools() {
+        let interceptor = interceptor(None);
+        assert!(interceptor.validate_tools(&tool_call("search")).is_ok());
+        assert!(interceptor.validate_tools(&tool_call("rm_rf")).is_err());
+    }
+
+    fn with_schema(tool: &str, schema: Value) -> Interceptor {
+        let mut config = interceptor(None).config;
+        config.tool_schemas.insert(tool.to_string(), schema);
+        Interceptor::try_new(config).unwrap()
+    }
+
+    fn write_file_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": {"type": "string", "pattern": "^sandbox/"},
+                "content": {"type": "string"}
+            },
+            "required": ["path", "content"]
+        })
+    }
+
+    #[test]
+    fn test_arguments_validated_against_schema() {
+        let interceptor = with_schema("write_file", write_file_schema());
+        let call = |arguments: &str| json!({"tool_calls": [{"function": {"name": "write_file", "arguments": arguments}}]});
+
+        assert!(interceptor.validate_tools(&call(r#"{"path":"sandbox/a.txt","content":"x"}"#)).is_ok());
+        let err = interceptor.validate_tools(&call(r#"{"path":"/etc/passwd","content":"x"}"#)).unwrap_err();
+        assert!(err.contains("'/path'"), "{}", err);
+        let err = interceptor.validate_tools(&call(r#"{"path":"sandbox/a.txt"}"#)).unwrap_err();
+        assert!(err.contains("content"), "{}", err);
+        // 解析済みのオブジェクトで渡された引数も検証する
+        let object = json!({"function_call": {"name": "write_file", "arguments": {"path": "tmp/a", "content": "x"}}});
+        assert!(interceptor.validate_tools(&object).is_err());
+        // スキーマの無いツールは対象外
+        assert!(interceptor.validate_tools(&tool_call("search")).is_ok());
+    }
+
+    #[test]
+    fn test_schema_only_policy_is_not_empty() {
+        let mut config = empty_policy(PolicyFailMode::Error).config;
+        config.tool_schemas.insert("write_file".to_string(), write_file_schema());
+        let interceptor = Interceptor::try_new(config).unwrap();
+        assert!(interceptor.check_policy().is_ok());
+
+        // 複数の呼び出しのうち、スキーマに反するものが一つでもあれば拒否する
+        let calls = json!({"tool_calls": [
+            {"function": {"name": "write_file", "arguments": r#"{"path":"sandbox/a.txt","content":"x"}"#}},
+            {"function": {"name": "write_file", "arguments": r#"{"path":"/etc/passwd","content":"x"}"#}}
+        ]});
+        let err = interceptor.validate_tools(&calls).unwrap_err();
+        assert!(err.contains("'/path'"), "{}", err);
+    }
+
+    #[test]
+    fn test_malformed_arguments_rejected_for_schema_tools() {
+        let interceptor = with_schema("write_file", write_file_schema());
+        let call = json!({"tool_calls": [{"function": {"name": "write_file", "arguments": "{\"path\": "}}]});
+        let err = interceptor.validate_tools(&call).unwrap_err();
+        assert!(err.contains("not valid JSON"), "{}", err);
+        // ストリーミングの断片はスキーマを検証しない
+        assert!(interceptor.validate_tool_deltas(&call).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_schema_rejected_at_startup() {
+        let mut config = interceptor(None).config;
+        config.tool_schemas.insert("write_file".to_string(), json!({"type": 12}));
+        let err = Interceptor::try_new(config).err().unwrap();
+        assert!(err.to_string().contains("write_file"));
+    }
+
+    fn limited(max_depth: usize, max_tokens: usize) -> Interceptor {
+        let mut interceptor = interceptor(None);
+        interceptor.config.json_limits = JsonLimits { max_depth, max_tokens };
+        interceptor
+    }
+
+    #[test]
+    fn test_over_deep_json_rejected() {
+        let interceptor = limited(8, 1000);
+        let deep = format!("{}{}", "[".repeat(9), "]".repeat(9));
+        assert!(interceptor.check_json_limits(deep.as_bytes()).unwrap_err().contains("depth"));
+        let ok = format!("{}{}", "[".repeat(8), "]".repeat(8));
+        assert!(interceptor.check_json_limits(ok.as_bytes()).is_ok());
+        // 文字列内の括弧は数えない
+        let quoted = json!({"content": "[[[[[[[[[[[[\\\"{{{{{{{{{{"}).to_string();
+        assert!(interceptor.check_json_limits(quoted.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_over_large_json_rejected() {
+        let interceptor = limited(64, 100);
+        let large = json!({"messages": vec![0; 200]}).to_string();
+        assert!(interceptor.check_json_limits(large.as_bytes()).unwrap_err().contains("tokens"));
+        let small = json!({"messages": vec![0; 50]}).to_string();
+        assert!(interceptor.check_json_limits(small.as_bytes()).is_ok());
+    }
 }
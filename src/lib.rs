@@ -0,0 +1,49 @@
+//! Orchix: Rust 製のエージェント向けプロキシ
+//!
+//! Orchix を組み込んでビルドする場合、名前付きのボディ変換を登録して
+//! ルート設定から参照できます。
+//!
+//! ```
+//! use serde_json::json;
+//!
+//! // config.toml のルートで `transform = "tag_user"` と指定すると適用される
+//! orchix::register_transform("tag_user", |body| {
+//!     body["user"] = json!("orchix");
+//! });
+//! ```
+
+pub mod networking;
+pub mod config;
+pub mod routing;
+pub mod interception;
+pub mod streaming;
+pub mod auth;
+pub mod cache;
+pub mod cost_control;
+pub mod tap;
+pub mod tls;
+pub mod shutdown;
+pub mod transform;
+pub mod singleflight;
+pub mod fault;
+pub mod concurrency;
+pub mod features;
+pub mod health;
+pub mod response_format;
+pub mod request_format;
+pub mod admission;
+pub mod sensitive;
+pub mod error;
+pub mod postprocess;
+pub mod circuit_breaker;
+pub mod upstreams;
+pub mod events;
+pub mod metrics;
+pub mod capture;
+pub mod upstream_auth;
+pub mod blocklist;
+
+#[cfg(test)]
+pub(crate) mod test_support;
+
+pub use transform::register_transform;
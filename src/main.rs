@@ -1,19 +1,25 @@
 use tracing::info;
 use tracing_subscriber::{FmtSubscriber, EnvFilter};
 use std::str::FromStr;
-
-
-mod networking;
-mod config;
-mod routing;
-mod interception;
-mod streaming;
-mod auth;
-mod cache;
-mod cost_control;
+use orchix::{config, networking};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // `orchix hash-key <key>`: key_format = "sha256" の設定に書く値を出力する
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("hash-key") {
+        let Some(key) = args.get(1) else {
+            anyhow::bail!("usage: orchix hash-key <api-key>");
+        };
+        println!("{}", orchix::auth::hash_api_key(key));
+        return Ok(());
+    }
+    // `orchix schema`: 設定ファイルの JSON Schema を出力する（エディタの検証・補完用）
+    if args.first().map(String::as_str) == Some("schema") {
+        println!("{}", serde_json::to_string_pretty(&config::AppConfig::json_schema())?);
+        return Ok(());
+    }
+
     // 設定の読み込み
     let app_config = config::AppConfig::load()?;
 
@@ -30,14 +36,7 @@ async fn main() -> anyhow::Result<()> {
     info!("Configuration loaded: {:?}", app_config);
 
     // Networkingサーバーの起動
-    networking::run_server(
-        app_config.server, 
-        app_config.routing, 
-        app_config.interception,
-        app_config.security,
-        app_config.caching,
-        app_config.cost,
-    ).await?;
+    networking::run_server(app_config).await?;
 
     Ok(())
 }
@@ -0,0 +1,142 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::Deserialize;
+use schemars::JsonSchema;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tracing::warn;
+use crate::auth::extract_api_key;
+use crate::networking::AppState;
+
+/// `/metrics`（Prometheus 形式）の設定（デフォルトは無効）
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// 設定した場合、`/metrics` の取得にこのキー（`Authorization: Bearer` または `x-api-key`）を要求する
+    pub api_key: Option<String>,
+}
+
+/// ルート・ステータスコードごとのリクエスト数
+pub const REQUESTS_TOTAL: &str = "orchix_requests_total";
+/// ルートごとのリクエスト全体の処理時間
+pub const REQUEST_DURATION_SECONDS: &str = "orchix_request_duration_seconds";
+/// キャッシュの照合結果（`hit` / `stale` / `miss`）ごとの回数
+pub const CACHE_LOOKUPS_TOTAL: &str = "orchix_cache_lookups_total";
+pub const CACHE_STORES_TOTAL: &str = "orchix_cache_stores_total";
+/// キャッシュから追い出されたエントリ数（`expired` / `size`）
+pub const CACHE_EVICTIONS_TOTAL: &str = "orchix_cache_evictions_total";
+/// API キーの認証結果ごとの回数
+pub const AUTH_TOTAL: &str = "orchix_auth_total";
+/// リージョンを考慮した転送先の選択結果（`local` / `cross_region`）ごとの回数
+pub const REGION_SELECTIONS_TOTAL: &str = "orchix_upstream_region_selections_total";
+pub const DRAINING_UPSTREAMS: &str = "orchix_draining_upstreams";
+/// 処理中のリクエスト数（`admission.max_in_flight` と比べる値。スクレイプ自身を含む）
+pub const IN_FLIGHT_REQUESTS: &str = "orchix_in_flight_requests";
+/// ルートの公平キュー（`fair_queue`）で上流の枠を待っているリクエスト数の合計
+pub const QUEUED_REQUESTS: &str = "orchix_queued_requests";
+/// イベントの送信先が追いつかずに捨てたイベント数
+pub const EVENTS_DROPPED_TOTAL: &str = "orchix_events_dropped_total";
+
+const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Prometheus のレコーダーをインストールし、出力用のハンドルを返します
+///
+/// レコーダーはプロセス全体で1つのため、2回目以降は同じハンドルを返します。
+pub fn install() -> PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE
+        .get_or_init(|| {
+            let builder = || {
+                PrometheusBuilder::new()
+                    .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION_SECONDS.to_string()), DURATION_BUCKETS)
+                    .expect("duration buckets are not empty")
+            };
+            builder().install_recorder().unwrap_or_else(|e| {
+                // 組み込み先が別のレコーダーを使っている場合は、何も集計しないハンドルを返す
+                warn!("Failed to install the Prometheus recorder: {}", e);
+                builder().build_recorder().handle()
+            })
+        })
+        .clone()
+}
+
+/// プロキシしたリクエストを記録します（ルートに一致しなかったものは `route="unmatched"`）
+pub fn record_request(route: Option<&str>, status: StatusCode, duration: Duration) {
+    let route = route.unwrap_or("unmatched").to_string();
+    metrics::counter!(REQUESTS_TOTAL, "route" => route.clone(), "status" => status.as_u16().to_string()).increment(1);
+    metrics::histogram!(REQUEST_DURATION_SECONDS, "route" => route).record(duration.as_secs_f64());
+}
+
+pub fn record_cache_lookup(result: &'static str) {
+    metrics::counter!(CACHE_LOOKUPS_TOTAL, "result" => result).increment(1);
+}
+
+pub fn record_cache_store() {
+    metrics::counter!(CACHE_STORES_TOTAL).increment(1);
+}
+
+pub fn record_cache_eviction(cause: &'static str) {
+    metrics::counter!(CACHE_EVICTIONS_TOTAL, "cause" => cause).increment(1);
+}
+
+pub fn record_auth(result: &'static str) {
+    metrics::counter!(AUTH_TOTAL, "result" => result).increment(1);
+}
+
+pub fn record_region_selection(locality: &'static str) {
+    metrics::counter!(REGION_SELECTIONS_TOTAL, "locality" => locality).increment(1);
+}
+
+/// `GET /metrics`
+///
+/// `metrics.api_key` が設定されている場合のみキーを確認します（プロキシ用の API キーとは別）。
+pub async fn metrics_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    // 無効な場合はエンドポイント自体が無いものとして扱う
+    let Some(handle) = &state.metrics else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Some(expected) = &state.metrics_config.api_key {
+        match extract_api_key(&headers, true) {
+            // 応答時間からキーを推測されないよう、一致の判定は定数時間で行う
+            Ok(Some(key)) if bool::from(key.as_bytes().ct_eq(expected.as_bytes())) => {}
+            _ => return StatusCode::UNAUTHORIZED.into_response(),
+        }
+    }
+
+    // スクレイプ時点の値を反映するゲージ
+    metrics::gauge!(DRAINING_UPSTREAMS).set(state.drains.draining().len() as f64);
+    metrics::gauge!(IN_FLIGHT_REQUESTS).set(state.admission.in_flight() as f64);
+    let queued: usize = state.routing().queues.iter().flatten().map(|queue| queue.queued()).sum();
+    metrics::gauge!(QUEUED_REQUESTS).set(queued as f64);
+    metrics::counter!(EVENTS_DROPPED_TOTAL).absolute(state.event_sink.dropped());
+    handle.run_upkeep();
+
+    let mut res = handle.render().into_response();
+    res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_includes_recorded_metrics() {
+        let handle = install();
+        record_request(Some("/metrics-unit"), StatusCode::BAD_GATEWAY, Duration::from_millis(30));
+        record_request(None, StatusCode::NOT_FOUND, Duration::from_millis(1));
+
+        let text = handle.render();
+        assert!(text.contains(r#"orchix_requests_total{route="/metrics-unit",status="502"} 1"#), "{}", text);
+        assert!(text.contains(r#"orchix_requests_total{route="unmatched",status="404"}"#), "{}", text);
+        // 30ms は 0.05 秒以下のバケットに入る
+        assert!(text.contains(r#"orchix_request_duration_seconds_bucket{route="/metrics-unit",le="0.05"} 1"#), "{}", text);
+        assert!(text.contains(r#"orchix_request_duration_seconds_bucket{route="/metrics-unit",le="0.025"} 0"#), "{}", text);
+    }
+}
@@ -1,83 +1,503 @@
 use axum::{
-    routing::{get, any},
-    response::IntoResponse,
+    http::request::Parts,
+    routing::{get, post, delete, any},
+    response::{IntoResponse, Response},
+    Json,
+    body::Body,
     Router,
     extract::{ws::{WebSocketUpgrade, WebSocket}, State, Request},
 };
 use std::net::SocketAddr;
+use tokio::net::TcpListener;
 use std::sync::Arc;
-use tracing::{info, warn};
-use crate::routing::{RouteRule, Router as OrchixRouter};
-use crate::interception::{InterceptionConfig, Interceptor};
+use tracing::{debug, info, warn};
+use crate::routing::{FailureResponse, HeaderCase, ModelAliases, RouteMatch, RouteRule, Router as OrchixRouter, UpstreamHostAllowlist, UpstreamTarget, requests_streaming};
+use crate::transform;
+use crate::postprocess;
+use crate::interception::{InterceptionTrace, Interceptor, TraceVerdict};
 use crate::streaming::StreamingAnalyzer;
-use crate::config::{ServerConfig, SecurityConfig, CacheConfig, CostConfig};
-use crate::auth::auth_middleware;
-use crate::cache::{OrchixCache, CacheKey, CachedResponse};
+use crate::config::{AppConfig, SecurityConfig, CacheConfig, PortRetryConfig, PortRetryMode, RetryConfig, UpstreamMetadataConfig};
+use crate::auth::{auth_middleware, admin_auth_middleware, extract_api_key, KeyFormat};
+use crate::cache::{OrchixCache, CacheKey, CachedResponse, Freshness, RefreshGuard, CACHE_STATUS_HEADER, NO_CACHE_HEADER, request_bypasses_cache};
 use futures::stream;
 use axum::response::sse::Sse;
 use std::convert::Infallible;
 use tokio_stream::StreamExt as _;
 use std::time::Duration;
 use bytes::Bytes;
-use crate::cost_control::CostManager;
+use crate::cost_control::{CostManager, RateLimitStatus, Usage};
+use crate::tap::{Tap, TapEvent};
+use crate::shutdown::{Shutdown, shutdown_middleware, shutdown_signal};
+use crate::singleflight::{Flight, SingleFlight};
+use crate::fault::{FaultInjector, fault_injection_middleware};
+use crate::concurrency::ConcurrencyLimiter;
+use crate::features::FeatureFlags;
+use crate::health::{HealthConfig, health_handler, readiness_handler};
+use crate::upstreams::{RegionSelection, UpstreamDrains, UpstreamLoad, drain_handler, undrain_handler};
+use crate::admission::{AdmissionController, ConnectionLimit, admission_middleware};
+use crate::sensitive::SensitiveHeaders;
+use crate::error::OrchixError;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::concurrency::{FairQueue, FairQueueConfig};
+use crate::events::{EventSink, RequestEvent};
+use tokio_stream::wrappers::BroadcastStream;
+use std::time::Instant;
+use arc_swap::ArcSwap;
 
-pub struct AppState {
+/// ルーティングのルールと、ルールごとの公平キュー・インターセプター（再読み込み時にまとめて差し替える）
+pub struct RoutingTable {
     pub router: OrchixRouter,
+    /// ルートごとの公平キュー（`Router::rules` と同じ順序）
+    pub queues: Vec<Option<Arc<FairQueue>>>,
+    /// ルートの `interception` から作ったインターセプター（`Router::rules` と同じ順序）
+    pub interceptors: Vec<Option<Interceptor>>,
+}
+
+impl RoutingTable {
+    /// ルートごとのインターセプターは全体の設定と同じく、スキーマとポリシーを検証してから作ります
+    ///
+    /// 公平キューの重みのキーは `key_format` で検証し、認証したリクエストの `KeyId` で引けるようにします。
+    fn new(rules: Vec<RouteRule>, key_format: KeyFormat) -> anyhow::Result<Self> {
+        let queues = rules
+            .iter()
+            .map(|rule| {
+                rule.fair_queue
+                    .as_ref()
+                    .map(|config| {
+                        key_format.validate(&config.weights.keys().cloned().collect::<Vec<_>>())?;
+                        let weights = config.weights.iter().map(|(key, &weight)| (key_format.key_id(key).0, weight)).collect();
+                        Ok(FairQueue::new(&FairQueueConfig { weights, ..config.clone() }))
+                    })
+                    .transpose()
+                    .map_err(|e: anyhow::Error| anyhow::anyhow!("Invalid fair_queue for route '{}': {}", rule.path, e))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let interceptors = rules
+            .iter()
+            .map(|rule| {
+                rule.interception
+                    .clone()
+                    .map(|config| {
+                        let interceptor = Interceptor::try_new(config)?;
+                        interceptor.check_policy()?;
+                        Ok(interceptor)
+                    })
+                    .transpose()
+                    .map_err(|e: anyhow::Error| anyhow::anyhow!("Invalid interception for route '{}': {}", rule.path, e))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { router: OrchixRouter::try_new(rules)?, queues, interceptors })
+    }
+
+    /// マッチしたルートに固有のインターセプター（無ければ None で、全体の設定を使う）
+    pub fn interceptor_for(&self, route: &RouteMatch) -> Option<&Interceptor> {
+        self.interceptors.get(route.provenance.priority)?.as_ref()
+    }
+}
+
+pub struct AppState {
+    /// 現在のルーティング（SIGHUP・`/admin/reload` で差し替わる。処理中のリクエストは読み込んだ時点のものを使う）
+    pub routing: ArcSwap<RoutingTable>,
     pub interceptor: Interceptor,
     pub security: SecurityConfig,
     pub cache: OrchixCache,
     pub caching_config: CacheConfig,
     pub cost_manager: CostManager,
+    pub tap: Tap,
+    pub shutdown: Shutdown,
+    pub single_flight: SingleFlight,
+    pub fault_injector: FaultInjector,
+    pub concurrency: ConcurrencyLimiter,
+    pub features: FeatureFlags,
+    pub health: HealthConfig,
+    pub started_at: Instant,
+    pub admission: AdmissionController,
+    pub sensitive_headers: SensitiveHeaders,
+    pub circuit_breaker: CircuitBreaker,
+    pub upstream_hosts: UpstreamHostAllowlist,
+    pub model_aliases: ModelAliases,
+    pub log_route_provenance: bool,
+    pub drains: UpstreamDrains,
+    pub upstream_load: Arc<UpstreamLoad>,
+    /// このインスタンスのリージョン（`server.region`）
+    pub region: Option<String>,
+    /// 転送ループとみなす `x-orchix-hops` の上限（`server.max_hops`）
+    pub max_hops: u32,
+    pub region_selection: RegionSelection,
+    pub upstream_metadata: UpstreamMetadataConfig,
+    pub retry: RetryConfig,
+    /// 上流への転送に使う HTTP クライアント（接続プールを共有する）
+    pub http_client: reqwest::Client,
+    /// `header_case = "title_case"` のルートで使う HTTP クライアント
+    pub title_case_http_client: reqwest::Client,
+    // `host_override` のルートで使う HTTP クライアント
+    host_override_clients: HostOverrideClients,
+    // `caching.refresh_ahead_min_hits` を設定した場合、保存したエントリのキーごとの再取得に使うリクエスト
+    refresh_ahead: Option<moka::future::Cache<CacheKey, Arc<RefreshCall>>>,
+    /// リクエストごとのイベントの送信先
+    pub event_sink: Arc<dyn EventSink>,
+    /// 外部のキー管理サービスによる API キーの検証（未設定なら `api_keys` のみ）
+    pub key_service: Option<crate::auth::KeyServiceAuthenticator>,
+    /// Prometheus 形式の出力（`metrics.enabled` の場合のみ）
+    pub metrics: Option<metrics_exporter_prometheus::PrometheusHandle>,
+    pub metrics_config: crate::metrics::MetricsConfig,
+    /// データセット収集用のリクエスト・レスポンスの書き出し
+    pub capture: crate::capture::Capture,
+    /// `upstream_oauth` を設定したルートで上流へ送るアクセストークン
+    pub upstream_tokens: crate::upstream_auth::UpstreamTokens,
+    /// 拒否するリクエストのフィンガープリント
+    pub blocklist: crate::blocklist::Blocklist,
+    // 設定の再読み込みを1つずつ行うためのロック（SIGHUP と管理 API の競合を防ぐ）
+    reload_lock: tokio::sync::Mutex<()>,
+    reload_debounce: Duration,
+    // `/admin/reload` で設定を読み直す方法（既定は設定ファイルと環境変数）
+    config_loader: Box<dyn Fn() -> anyhow::Result<AppConfig> + Send + Sync>,
+}
+
+/// 処理中の同一リクエストの結果を共有したレスポンスに付与するヘッダー
+pub const COALESCED_HEADER: &str = "x-orchix-coalesced";
+
+/// 上流の処理が完了したレスポンスに付与する使用量・推定コストのヘッダー
+pub const PROMPT_TOKENS_HEADER: &str = "x-orchix-prompt-tokens";
+pub const COMPLETION_TOKENS_HEADER: &str = "x-orchix-completion-tokens";
+pub const ESTIMATED_COST_HEADER: &str = "x-orchix-estimated-cost";
+
+/// コスト制御のレート制限の状況（上限・残り回数・枠が空くまでの秒数）を返すヘッダー
+pub const RATE_LIMIT_LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub const RATE_LIMIT_REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub const RATE_LIMIT_RESET_HEADER: &str = "x-ratelimit-reset";
+
+/// 管理者キーのリクエストに付けると、インターセプションの各段階の判定を返す（上流には転送しない）
+pub const TRACE_INTERCEPTION_HEADER: &str = "x-orchix-trace-interception";
+/// バッファしたレスポンスでトレースを返すヘッダー（ストリーミングでは末尾のイベントで返す）
+pub const INTERCEPTION_TRACE_HEADER: &str = "x-orchix-interception-trace";
+
+/// Orchix を経由した回数（転送のたびに1つ増やし、上限を超えたら転送ループとして拒否する）
+pub const HOPS_HEADER: &str = "x-orchix-hops";
+
+/// プロキシが受け付けるリクエストボディの上限
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// プリフライトで見積もりトークン数を指定するヘッダー
+pub const ESTIMATED_TOKENS_HEADER: &str = "x-orchix-estimated-tokens";
+
+impl AppState {
+    pub fn new(config: &AppConfig) -> anyhow::Result<Self> {
+        let interceptor = Interceptor::try_new(config.interception.clone())?;
+        if config.features.interception {
+            interceptor.check_policy()?;
+        }
+        let security = &config.security;
+        let api_keys: Vec<String> = security.api_keys.iter().map(|entry| entry.key.clone()).collect();
+        let concurrency_keys: Vec<String> = security.max_concurrent_requests.keys().cloned().collect();
+        for keys in [&api_keys, &security.admin_keys, &concurrency_keys] {
+            security.key_format.validate(keys)?;
+        }
+        let upstream_hosts = UpstreamHostAllowlist::new(&config.server.allowed_upstream_hosts);
+        upstream_hosts.validate_routes(&config.routing)?;
+        // 上流への TLS もサーバー側と同じ ring の実装を使う（設定済みならそのまま）
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let timeouts = UpstreamTimeouts {
+            connect: Duration::from_millis(config.server.upstream_connect_timeout_ms),
+            read: Duration::from_secs(config.server.upstream_read_timeout_secs),
+        };
+        let http_client = timeouts.client_builder()
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to build upstream HTTP client: {}", e))?;
+        let key_service = config.security.key_service.clone().map(crate::auth::KeyServiceAuthenticator::new).transpose()?;
+        let title_case_http_client = timeouts.client_builder()
+            .http1_title_case_headers()
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to build upstream HTTP client: {}", e))?;
+        let cache = OrchixCache::new(&config.caching)
+            .with_sensitive_headers(SensitiveHeaders::new(&config.security.sensitive_headers));
+        let cache = match config.caching.backend {
+            crate::config::CacheBackend::Memory => cache,
+            crate::config::CacheBackend::Redis => {
+                let redis = config.caching.redis.as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("caching.backend = \"redis\" requires [caching.redis]"))?;
+                cache.with_redis(redis)?
+            }
+        };
+        Ok(Self {
+            routing: ArcSwap::from_pointee(RoutingTable::new(config.routing.clone(), security.key_format)?),
+            interceptor,
+            security: config.security.clone(),
+            cache,
+            caching_config: config.caching.clone(),
+            cost_manager: CostManager::new(config.cost.clone()),
+            tap: Tap::new(config.tap.clone()),
+            shutdown: Shutdown::new(
+                Duration::from_secs(config.server.shutdown_timeout_secs),
+                Duration::from_secs(config.server.streaming_shutdown_timeout_secs),
+            ),
+            single_flight: SingleFlight::new(),
+            fault_injector: FaultInjector::new(config.fault_injection.clone()),
+            concurrency: ConcurrencyLimiter::new(
+                &security.max_concurrent_requests.iter()
+                    .map(|(key, &limit)| (security.key_format.canonical(key).into_owned(), limit))
+                    .collect(),
+            ),
+            features: FeatureFlags::new(config.features),
+            health: config.health.clone(),
+            started_at: Instant::now(),
+            admission: AdmissionController::new(config.admission.clone()),
+            sensitive_headers: SensitiveHeaders::new(&config.security.sensitive_headers),
+            circuit_breaker: CircuitBreaker::new(config.circuit_breaker.clone()),
+            upstream_hosts,
+            model_aliases: ModelAliases::new(config.model_aliases.clone()),
+            log_route_provenance: config.log.route_provenance,
+            drains: UpstreamDrains::default(),
+            upstream_load: Arc::default(),
+            region: config.server.region.clone(),
+            max_hops: config.server.max_hops,
+            region_selection: RegionSelection::default(),
+            upstream_metadata: config.upstream_metadata.clone(),
+            retry: config.retry.clone(),
+            title_case_http_client,
+            host_override_clients: HostOverrideClients::new(timeouts),
+            // エントリと同じ件数まで保持する（古いものから捨て、捨てたキーは再取得しない）
+            refresh_ahead: config.caching.refresh_ahead_min_hits.map(|_| moka::future::Cache::new(config.caching.max_capacity)),
+            event_sink: crate::events::sink_from_config(&config.events),
+            key_service,
+            metrics: config.metrics.enabled.then(crate::metrics::install),
+            metrics_config: config.metrics.clone(),
+            capture: crate::capture::Capture::new(&config.capture),
+            upstream_tokens: crate::upstream_auth::UpstreamTokens::new(http_client.clone()),
+            blocklist: crate::blocklist::Blocklist::new(&config.blocklist)?,
+            reload_lock: tokio::sync::Mutex::new(()),
+            reload_debounce: Duration::from_millis(config.server.reload_debounce_ms),
+            config_loader: Box::new(|| Ok(AppConfig::load()?)),
+            http_client,
+        })
+    }
+
+    /// `/admin/reload` で設定を読み直す方法を差し替えます
+    pub fn with_config_loader(mut self, loader: impl Fn() -> anyhow::Result<AppConfig> + Send + Sync + 'static) -> Self {
+        self.config_loader = Box::new(loader);
+        self
+    }
+
+    /// 現在のルーティング
+    pub fn routing(&self) -> Arc<RoutingTable> {
+        self.routing.load_full()
+    }
+
+    /// ルーティングのルールを差し替えます
+    ///
+    /// 検証に失敗した場合はエラーを返し、現在のルールをそのまま使い続けます。
+    /// 公平キューは新しいルールの分が作り直されます（処理中のリクエストは古いキューの枠を保持したまま完了する）。
+    pub fn reload_routing(&self, rules: Vec<RouteRule>) -> anyhow::Result<()> {
+        self.upstream_hosts.validate_routes(&rules)?;
+        let table = RoutingTable::new(rules, self.security.key_format)?;
+        info!("Routing reloaded with {} rules", table.router.rules.len());
+        self.routing.store(Arc::new(table));
+        Ok(())
+    }
+
+    /// ルートのヘッダー名の書き方に合わせた HTTP クライアント
+    fn http_client_for(&self, header_case: HeaderCase) -> &reqwest::Client {
+        match header_case {
+            HeaderCase::Lowercase => &self.http_client,
+            HeaderCase::TitleCase => &self.title_case_http_client,
+        }
+    }
+
+    /// ルートの設定に合わせて、上流へ送る HTTP クライアントと URL を選びます
+    ///
+    /// `host_override` があれば URL のホストをその名前に置き換え、名前を `url` の接続先に解決するクライアントを使います。
+    async fn upstream_client_for(&self, rule: &RouteRule, url: &str) -> anyhow::Result<(reqwest::Client, String)> {
+        match &rule.host_override {
+            Some(host) => self.host_override_clients.client_for(host, url, rule.header_case).await,
+            None => Ok((self.http_client_for(rule.header_case).clone(), url.to_string())),
+        }
+    }
+
+    /// キャッシュの設定と機能フラグの両方が有効か
+    pub fn caching_enabled(&self) -> bool {
+        self.caching_config.enabled && self.features.caching()
+    }
+
+    /// ルートの `cache_enabled` を反映して、キャッシュを使うか（機能フラグが無効なら常に false）
+    pub fn caching_enabled_for(&self, rule: Option<&RouteRule>) -> bool {
+        self.features.caching() && rule.map_or(self.caching_config.enabled, |rule| rule.caches(self.caching_config.enabled))
+    }
 }
 
-pub async fn run_server(
-    config: ServerConfig, 
-    rules: Vec<RouteRule>,
-    interception_config: InterceptionConfig,
-    security_config: SecurityConfig,
-    cache_config: CacheConfig,
-    cost_config: CostConfig,
-) -> anyhow::Result<()> {
+pub async fn run_server(config: AppConfig) -> anyhow::Result<()> {
     // 状態の初期化
-    let state = Arc::new(AppState {
-        router: OrchixRouter::new(rules),
-        interceptor: Interceptor::new(interception_config),
-        security: security_config,
-        cache: OrchixCache::new(&cache_config),
-        caching_config: cache_config,
-        cost_manager: CostManager::new(cost_config),
+    let state = Arc::new(AppState::new(&config)?);
+    let app = build_app(state.clone());
+    spawn_refresh_ahead(&state);
+    for (route, url) in crate::routing::self_referential_targets(&config.routing, &config.server.host, config.server.port) {
+        warn!(
+            "Route '{}' forwards to {}, which is this server's own listen address; requests will loop unless upstream_metadata.hops is enabled (then stopped after max_hops = {})",
+            route, url, config.server.max_hops,
+        );
+    }
+
+    // 証明書・秘密鍵はバインドより前に読み込み、不備があれば起動しない
+    let server = &config.server;
+    let tls = server.tls.as_ref().map(|tls| tls.load_server_config()).transpose()?;
+
+    // サーバーの起動
+    let listener = bind_listener(&server.host, server.port, server.port_retry.as_ref()).await?;
+    info!("listening on {} ({})", listener.local_addr()?, if tls.is_some() { "https" } else { "http" });
+
+    // SIGHUP で設定ファイルのルーティングを読み直す（処理中のリクエストは元のルールのまま完了する）
+    #[cfg(unix)]
+    if let Err(e) = spawn_reload_on_sighup(state.clone(), || Ok(AppConfig::load()?)) {
+        warn!("Failed to listen for SIGHUP; configuration reload by signal is disabled: {}", e);
+    }
+
+    // シグナル受信後は新規接続を拒否し、既存のリクエスト・ストリームの完了を待つ
+    let signal_state = state.clone();
+    let app = into_make_service(app, state.clone());
+    let Some(tls) = tls else {
+        let serve = axum::serve(listener, app).with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            signal_state.shutdown.begin();
+        });
+        tokio::select! {
+            res = async { serve.await } => res?,
+            _ = state.shutdown.final_deadline() => {
+                warn!(
+                    "Shutdown timeout reached with {} requests still in flight, forcing exit",
+                    state.admission.in_flight()
+                );
+            }
+        }
+        return Ok(());
+    };
+
+    let handle = axum_server::Handle::new();
+    let signal_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        signal_state.shutdown.begin();
+        signal_handle.graceful_shutdown(None);
     });
+    let rustls_config = axum_server::tls_rustls::RustlsConfig::from_config(Arc::new(tls));
+    let serve = axum_server::from_tcp_rustls(listener.into_std()?, rustls_config).handle(handle.clone()).serve(app);
+    tokio::select! {
+        res = serve => res?,
+        _ = state.shutdown.final_deadline() => {
+            warn!(
+                "Shutdown timeout reached with {} connections still open, forcing exit",
+                handle.connection_count()
+            );
+        }
+    }
+
+    Ok(())
+}
 
+/// 待ち受けに渡すサービス（接続元の IP ごとの接続数を受け付け時に数える）
+///
+/// フィンガープリントに接続元の IP を含めるため、接続情報をリクエストに付与します。
+pub fn into_make_service(
+    app: Router,
+    state: Arc<AppState>,
+) -> ConnectionLimit<axum::extract::connect_info::IntoMakeServiceWithConnectInfo<Router, SocketAddr>> {
+    ConnectionLimit::new(app.into_make_service_with_connect_info::<SocketAddr>(), state)
+}
+
+/// HTTPルーター（Axum側）の設定
+pub fn build_app(state: Arc<AppState>) -> Router {
     let auth_layer = axum::middleware::from_fn_with_state(state.clone(), auth_middleware);
+    let admin_layer = axum::middleware::from_fn_with_state(state.clone(), admin_auth_middleware);
+    let shutdown_layer = axum::middleware::from_fn_with_state(state.clone(), shutdown_middleware);
+    let fault_layer = axum::middleware::from_fn_with_state(state.clone(), fault_injection_middleware);
+    let admission_layer = axum::middleware::from_fn_with_state(state.clone(), admission_middleware);
+
+    let (health, ready) = if state.health.require_auth {
+        (get(health_handler).layer(auth_layer.clone()), get(readiness_handler).layer(auth_layer.clone()))
+    } else {
+        (get(health_handler), get(readiness_handler))
+    };
 
-    // HTTPルーター（Axum側）の設定
-    let app = Router::new()
-        .route("/health", get(health_check))
+    Router::new()
+        .route("/health", health)
+        .route("/health/ready", ready)
+        .route("/metrics", get(crate::metrics::metrics_handler))
         .route("/ws", get(ws_handler).layer(auth_layer.clone()))
         .route("/v1/stream_test", get(stream_test_handler).layer(auth_layer.clone()))
-        .fallback(any(proxy_handler).layer(auth_layer))
-        .with_state(state);
+        .route("/admin/tap", get(tap_handler).layer(admin_layer.clone()))
+        .route("/admin/reload", post(reload_handler).layer(admin_layer.clone()))
+        .route("/v1/cache", delete(cache_invalidate_handler).layer(admin_layer.clone()))
+        .route(
+            "/admin/blocklist",
+            get(crate::blocklist::list_handler).post(crate::blocklist::add_handler).layer(admin_layer.clone()),
+        )
+        .route("/admin/blocklist/:fingerprint", delete(crate::blocklist::remove_handler).layer(admin_layer.clone()))
+        .route("/admin/upstreams/:id/drain", post(drain_handler).layer(admin_layer.clone()))
+        .route("/admin/upstreams/:id/undrain", post(undrain_handler).layer(admin_layer))
+        .fallback(any(proxy_handler).layer(fault_layer).layer(auth_layer))
+        .layer(shutdown_layer)
+        // 負荷による拒否は認証などより先に、最小限のコストで行う
+        .layer(admission_layer)
+        .with_state(state)
+}
 
-    // 設定値に基づいてアドレスを作成
-    let addr_str = format!("{}:{}", config.host, config.port);
-    let addr: SocketAddr = addr_str.parse()?;
-    info!("listening on {}", addr);
+/// 設定に従ってリスナーをバインドします
+///
+/// ポートが使用中の場合は `port_retry` に従って再試行し、
+/// すべて失敗した場合はアドレスを含むエラーを返します。
+pub async fn bind_listener(
+    host: &str,
+    port: u16,
+    retry: Option<&PortRetryConfig>,
+) -> anyhow::Result<TcpListener> {
+    let attempts = retry.map(|r| r.attempts).unwrap_or(0);
+    let mut current_port = port;
 
-    // サーバーの起動
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    for attempt in 0..=attempts {
+        // 設定値に基づいてアドレスを作成
+        let addr: SocketAddr = format!("{}:{}", host, current_port)
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid listen address {}:{}: {}", host, current_port, e))?;
 
-    Ok(())
-}
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                let Some(retry) = retry.filter(|_| attempt < attempts) else {
+                    return Err(anyhow::anyhow!(
+                        "Failed to bind {}: address already in use (is another Orchix instance running?)",
+                        addr
+                    ));
+                };
+                warn!("Address {} is already in use, retrying ({}/{})", addr, attempt + 1, attempts);
+                match retry.mode {
+                    PortRetryMode::NextPort => {
+                        current_port = current_port.checked_add(1).ok_or_else(|| {
+                            anyhow::anyhow!("Failed to bind {}: no more ports to try", addr)
+                        })?;
+                    }
+                    PortRetryMode::Wait => {
+                        tokio::time::sleep(Duration::from_millis(retry.interval_ms)).await;
+                    }
+                }
+            }
+            Err(e) => return Err(anyhow::anyhow!("Failed to bind {}: {}", addr, e)),
+        }
+    }
 
-// ヘルスチェック用ハンドラ
-async fn health_check() -> impl IntoResponse {
-    "OK"
+    unreachable!("bind loop always returns")
 }
 
 // WebSocketハンドラ
-async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(handle_socket)
+async fn ws_handler(
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let deadline = state.shutdown.streaming_deadline();
+    ws.on_upgrade(move |socket| async move {
+        // シャットダウンのストリーミング猶予を過ぎたら接続を閉じる
+        tokio::select! {
+            _ = handle_socket(socket) => {},
+            _ = deadline => info!("WebSocket connection closed by shutdown timeout"),
+        }
+    })
 }
 
 async fn handle_socket(mut socket: WebSocket) {
@@ -101,30 +521,302 @@ async fn handle_socket(mut socket: WebSocket) {
 async fn proxy_handler(
     State(state): State<Arc<AppState>>,
     req: Request,
-) -> impl IntoResponse {
+) -> Response {
+    let started = Instant::now();
+    let method = req.method().to_string();
     let path = req.uri().path().to_string();
-    let (_parts, body) = req.into_parts();
+    let (parts, body) = req.into_parts();
+
+    // 自分自身（または別の Orchix を経由して自分）に転送し続けるループを止める
+    let hops = request_hops(&parts.headers);
+    if hops > state.max_hops {
+        warn!("Rejected request to {} after {} hops (max_hops = {})", path, hops, state.max_hops);
+        return OrchixError::new(
+            axum::http::StatusCode::LOOP_DETECTED,
+            "loop_detected",
+            format!("Request passed through Orchix {} times; the routing configuration likely loops back", hops),
+        )
+        .into_response();
+    }
+
+    // HEAD はプリフライトとして扱い、上流には転送しない
+    if parts.method == axum::http::Method::HEAD {
+        return preflight_response(&state, &parts).await;
+    }
+
+    // `Expect: 100-continue` の場合、100 Continue はボディを読み始めた時点で Orchix が送る
+    // （上流の 100 Continue は中継しない。ボディ全体を読み取ってから、`forward_expect_continue` の
+    // ルート以外では Expect を外して転送する）。
+    // 受け付けられないリクエストはボディを送らせる前に拒否する
+    if let Some(expect) = parts.headers.get(axum::http::header::EXPECT)
+        && !expect.as_bytes().eq_ignore_ascii_case(b"100-continue")
+    {
+        return (axum::http::StatusCode::EXPECTATION_FAILED, "Unsupported expectation").into_response();
+    }
+    let declared_length = parts.headers
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared_length.is_some_and(|len| len > MAX_BODY_BYTES) {
+        return body_too_large().into_response();
+    }
 
     // ボディの読み取り（1MB制限）
-    let bytes = match axum::body::to_bytes(body, 1024 * 1024).await {
+    let bytes = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
         Ok(b) => b,
+        Err(e) if is_length_limit_error(&e) => {
+            warn!("Request body exceeded {} bytes", MAX_BODY_BYTES);
+            return body_too_large().into_response();
+        }
         Err(e) => {
             warn!("Failed to read request body: {}", e);
-            return (axum::http::StatusCode::BAD_REQUEST, "Failed to read body").into_response();
+            return OrchixError::new(axum::http::StatusCode::BAD_REQUEST, "body_read_failed", format!("Failed to read request body: {}", e))
+                .into_response();
+        }
+    };
+
+    // 管理者が登録したパターンに一致するリクエストは何もせずに拒否する
+    // 一覧が空ならボディのハッシュは計算しない（デバッグログで登録用の値を確認する場合を除く）
+    if !state.blocklist.is_empty() || tracing::enabled!(tracing::Level::DEBUG) {
+        let ip = crate::auth::client_ip(&parts.extensions, &parts.headers, &state.security.trusted_proxies);
+        let fingerprint = crate::blocklist::RequestFingerprint::compute(&bytes, extract_api_key(&parts.headers, false).ok().flatten(), ip);
+        if let Some(entry) = state.blocklist.matching(&fingerprint) {
+            warn!("Rejected request to {} matching blocklisted fingerprint {}", path, entry);
+            return OrchixError::new(axum::http::StatusCode::FORBIDDEN, "request_blocked", "This request has been blocked")
+                .into_response();
+        }
+        debug!("Request fingerprint for {}: {}", path, fingerprint.body);
+    }
+
+    // 処理中に設定が読み直されても、このリクエストは同じルーティングを使う
+    let routing = state.routing();
+    let mut matched = None;
+    let response = forward_request(&state, &routing, &parts, &bytes, &mut matched).await;
+    let route = matched.as_ref().map(|route| route.rule.path.clone());
+    crate::metrics::record_request(route.as_deref(), response.status(), started.elapsed());
+    publish_request_event(&state, &method, &path, route.clone(), &response, started);
+
+    // データセット収集用の書き出し（ストリーミングは送信を遅らせないよう対象外）
+    let response = if state.capture.should_sample() && !is_event_stream(&response) {
+        capture_response(&state.capture, &method, &path, route, &bytes, response).await
+    } else {
+        response
+    };
+
+    // タップへの配信（購読者がいる場合のみ）
+    if state.tap.should_sample() {
+        return tap_response(&state.tap, method, path, &bytes, response, started).await;
+    }
+    response
+}
+
+/// レスポンスのステータス・使用量ヘッダーからイベントを作り、送信先に渡します
+///
+/// ストリーミングの使用量は送信後に確定するため、トークン数とコストは含まれません。
+fn publish_request_event(state: &AppState, method: &str, path: &str, route: Option<String>, response: &Response, started: Instant) {
+    let header = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok());
+    state.event_sink.publish(RequestEvent {
+        method: method.to_string(),
+        path: path.to_string(),
+        route,
+        status: response.status().as_u16(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        prompt_tokens: header(PROMPT_TOKENS_HEADER).and_then(|v| v.parse().ok()),
+        completion_tokens: header(COMPLETION_TOKENS_HEADER).and_then(|v| v.parse().ok()),
+        estimated_cost: header(ESTIMATED_COST_HEADER).and_then(|v| v.parse().ok()),
+        blocked: response
+            .extensions()
+            .get::<crate::error::ErrorKind>()
+            .is_some_and(|kind| kind.is_policy_violation()),
+    });
+}
+
+/// 現在のレート制限・予算で、リクエストが許可されるかを返します
+///
+/// 見積もりトークン数は `x-orchix-estimated-tokens`、無ければ `Content-Length` から推定します。
+async fn preflight_response(state: &AppState, parts: &Parts) -> Response {
+    let client_id = crate::auth::client_id(&parts.extensions);
+    let estimated_tokens = parts.headers
+        .get(ESTIMATED_TOKENS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u32>().ok())
+        .or_else(|| {
+            parts.headers
+                .get(axum::http::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u32>().ok())
+                .map(|bytes| (bytes / 4).max(1))
+        })
+        .unwrap_or(1);
+
+    let routing = state.routing();
+    let Some(rule) = routing.router.resolve(parts.uri.path()) else {
+        return axum::http::StatusCode::NOT_FOUND.into_response();
+    };
+    let preflight = state.cost_manager.preflight(client_id, estimated_tokens).await;
+
+    let mut res = axum::http::StatusCode::OK.into_response();
+    let headers = res.headers_mut();
+    if let Ok(route) = axum::http::HeaderValue::from_str(&rule.path) {
+        headers.insert("x-orchix-route", route);
+    }
+    if let Some(remaining) = preflight.budget_remaining {
+        headers.insert("x-orchix-budget-remaining", remaining.into());
+    }
+    if let Some(remaining) = preflight.requests_remaining {
+        headers.insert("x-orchix-requests-remaining", remaining.into());
+    }
+    headers.insert(
+        "x-orchix-would-throttle",
+        axum::http::HeaderValue::from_static(if preflight.rejection.is_some() { "true" } else { "false" }),
+    );
+    if let Some(reason) = preflight.rejection {
+        headers.insert("x-orchix-throttle-reason", axum::http::HeaderValue::from_static(reason));
+    }
+    res
+}
+
+/// レスポンスのボディを読み込んで書き出し待ちに積み、同じ内容のレスポンスを返します
+async fn capture_response(
+    capture: &crate::capture::Capture,
+    method: &str,
+    path: &str,
+    route: Option<String>,
+    request_body: &[u8],
+    response: Response,
+) -> Response {
+    let (parts, body) = response.into_parts();
+    let response_body = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    capture.submit(crate::capture::CaptureRecord::new(method, path, route, parts.status.as_u16(), request_body, &response_body));
+    Response::from_parts(parts, Body::from(response_body))
+}
+
+fn is_event_stream(response: &Response) -> bool {
+    response
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"))
+}
+
+/// レスポンスをタップに配信し、同じ内容のレスポンスを返します
+async fn tap_response(
+    tap: &Tap,
+    method: String,
+    path: String,
+    request_body: &[u8],
+    response: Response,
+    started: Instant,
+) -> Response {
+    // ストリーミングは送信を遅らせないよう、ボディの抜粋なしで配信する
+    let streaming = is_event_stream(&response);
+    let (parts, body) = response.into_parts();
+    let (body, response_body) = if streaming {
+        (body, Bytes::new())
+    } else {
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+        (Body::from(bytes.clone()), bytes)
+    };
+
+    tap.publish(TapEvent {
+        method,
+        path,
+        status: parts.status.as_u16(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        request_excerpt: tap.excerpt(request_body),
+        response_excerpt: tap.excerpt(&response_body),
+    });
+
+    Response::from_parts(parts, body)
+}
+
+/// リクエストを転送します。照合したルートは `matched` に入れて返します（照合より前に拒否した場合は None）
+async fn forward_request<'a>(
+    state: &Arc<AppState>,
+    routing: &'a RoutingTable,
+    parts: &Parts,
+    bytes: &Bytes,
+    matched: &mut Option<RouteMatch<'a>>,
+) -> Response {
+    // コスト制御：レート制限のチェック（結果は拒否した場合も含めてヘッダーで返す）
+    // 認証したキーごとに数える
+    let client_id = crate::auth::client_id(&parts.extensions);
+    let rate_limit = state.cost_manager.acquire_rate_limit(client_id).await;
+    let mut trace = wants_interception_trace(state, &parts.headers).then(InterceptionTrace::default);
+    let mut response = match rate_limit {
+        Some(status) if !status.allowed => {
+            (axum::http::StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded").into_response()
         }
+        _ => forward_admitted(state, routing, parts, bytes, client_id, &mut trace, matched).await,
     };
+    if let Some(status) = rate_limit {
+        insert_rate_limit_headers(response.headers_mut(), &status);
+    }
+    // ストリーミングに渡さなかったトレースはヘッダーで返す
+    if let Some(trace) = trace
+        && let Ok(value) = axum::http::HeaderValue::from_str(&ascii_json(&trace.to_json()))
+    {
+        response.headers_mut().insert(INTERCEPTION_TRACE_HEADER, value);
+    }
+    response
+}
+
+/// トレースを要求したリクエストが管理者キーを使っているかを判定します
+fn wants_interception_trace(state: &AppState, headers: &axum::http::HeaderMap) -> bool {
+    headers.contains_key(TRACE_INTERCEPTION_HEADER)
+        && !state.security.admin_keys.is_empty()
+        && extract_api_key(headers, false)
+            .ok()
+            .flatten()
+            .is_some_and(|key| state.security.key_format.is_listed(&state.security.admin_keys, key))
+}
+
+fn trace_stage(trace: &mut Option<InterceptionTrace>, stage: &'static str, verdict: TraceVerdict, detail: Option<String>) {
+    if let Some(trace) = trace {
+        trace.record(stage, verdict, detail);
+    }
+}
+
+fn trace_result(trace: &mut Option<InterceptionTrace>, stage: &'static str, result: &Result<(), String>) {
+    match result {
+        Ok(()) => trace_stage(trace, stage, TraceVerdict::Pass, None),
+        Err(msg) => trace_stage(trace, stage, TraceVerdict::Block, Some(msg.clone())),
+    }
+}
 
-    // コスト制御：レート制限と予算のチェック
-    let client_id = "default_user"; // 本来は認証情報から取得
-    if !state.cost_manager.check_rate_limit(client_id).await {
-        return (axum::http::StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded").into_response();
+/// ヘッダーに入れられるよう、非 ASCII 文字を `\uXXXX` にエスケープした JSON にします
+fn ascii_json(value: &serde_json::Value) -> String {
+    let mut out = String::new();
+    for c in value.to_string().chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                out.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
     }
+    out
+}
+
+async fn forward_admitted<'a>(
+    state: &Arc<AppState>,
+    routing: &'a RoutingTable,
+    parts: &Parts,
+    bytes: &Bytes,
+    client_id: &str,
+    trace: &mut Option<InterceptionTrace>,
+    matched: &mut Option<RouteMatch<'a>>,
+) -> Response {
+    let path = parts.uri.path();
+
+    // 予算のチェック
     if !state.cost_manager.check_budget(client_id).await {
         return (axum::http::StatusCode::FORBIDDEN, "Daily budget exceeded").into_response();
     }
 
     // トークン数のチェック
-    let input_text = String::from_utf8_lossy(&bytes);
+    let input_text = String::from_utf8_lossy(bytes);
     let estimated_tokens = state.cost_manager.estimate_tokens(&input_text);
     if !state.cost_manager.is_within_max_tokens(estimated_tokens) {
         return (axum::http::StatusCode::PAYLOAD_TOO_LARGE, "Request tokens exceed limit").into_response();
@@ -133,28 +825,156 @@ async fn proxy_handler(
     // 使用量の記録（リクエスト分）
     state.cost_manager.track_usage(client_id, estimated_tokens).await;
 
+    // パースの前に JSON の深さ・大きさを確認する（深すぎるボディはパースに失敗し検査を素通りするため）
+    if state.features.interception() {
+        let limits = state.interceptor.check_json_limits(bytes);
+        trace_result(trace, "json_limits", &limits);
+        if let Err(msg) = limits {
+            return OrchixError::new(axum::http::StatusCode::BAD_REQUEST, "json_too_complex", msg).into_response();
+        }
+    } else {
+        trace_stage(trace, "json_limits", TraceVerdict::Skipped, None);
+    }
+
     // JSONとしてパースを試みる
-    if let Ok(json_body) = serde_json::from_slice::<serde_json::Value>(&bytes) {
+    let mut json_body = serde_json::from_slice::<serde_json::Value>(bytes).ok();
+
+    // モデルの別名を上流のモデル名に揃える（別名どうしでキャッシュを共有するため、キーの計算より前に行う）
+    let normalized;
+    let bytes = if let Some(json) = json_body.as_mut()
+        && state.model_aliases.normalize(json)
+    {
+        let model = json.get("model").and_then(|m| m.as_str()).unwrap_or_default();
+        trace_stage(trace, "model_aliases", TraceVerdict::Modified, Some(format!("model rewritten to {}", model)));
+        normalized = serde_json::to_vec(json).map(Bytes::from).unwrap_or_else(|_| bytes.clone());
+        &normalized
+    } else {
+        bytes
+    };
+    // ルートは別名を揃えたボディの `model` も使って一度だけ照合し、以降の判定はすべてこの結果を使う
+    *matched = resolve_route(&routing.router, path, json_body.as_ref());
+    let matched = matched.as_ref();
+    // 使えるルートを制限したキーは、照合したルートの `path`（一致しなければリクエストのパス）で判定する
+    if let Some(scope) = parts.extensions.get::<crate::auth::RouteScope>()
+        && !scope.allows(matched.map_or(path, |route| route.rule.path.as_str()))
+    {
+        warn!("API key is not allowed to access {}", path);
+        crate::metrics::record_auth("forbidden");
+        return axum::http::StatusCode::FORBIDDEN.into_response();
+    }
+    // JSON のボディが必須のルートでは、空や JSON でないボディを検査をすり抜けたまま転送しない
+    if json_body.is_none()
+        && let Some(route) = matched
+        && route.rule.require_body
+    {
+        let (code, message) = if bytes.iter().all(u8::is_ascii_whitespace) {
+            ("missing_body", format!("A JSON request body is required for {}", route.rule.path))
+        } else {
+            ("invalid_json", format!("The request body for {} must be valid JSON", route.rule.path))
+        };
+        return OrchixError::new(axum::http::StatusCode::BAD_REQUEST, code, message).into_response();
+    }
+    // ストリーミングを受け付けないルートでは拒否するか、`stream: false` に書き換える
+    let rewritten;
+    let bytes = if let Some(json) = json_body.as_mut()
+        && let Some(route) = matched
+    {
+        match enforce_streaming_policy(route.rule, json) {
+            Err(e) => {
+                trace_stage(trace, "streaming_policy", TraceVerdict::Block, Some(e.message.clone()));
+                return e.into_response();
+            }
+            Ok(true) => {
+                trace_stage(trace, "streaming_policy", TraceVerdict::Modified, Some("stream rewritten to false".to_string()));
+                rewritten = serde_json::to_vec(json).map(Bytes::from).unwrap_or_else(|_| bytes.clone());
+                &rewritten
+            }
+            Ok(false) => bytes,
+        }
+    } else {
+        bytes
+    };
+    // ツールのポリシーはマッチしたルートの設定を優先する
+    let interceptor = matched.and_then(|route| routing.interceptor_for(route)).unwrap_or(&state.interceptor);
+    // `cache_probability` のルートでは、ヒットしても一定の割合で上流から取得し直す
+    let serves_hit = matched.is_none_or(|route| route.rule.serves_cache_hit(rand::random::<f64>()));
+    let caching = state.caching_enabled_for(matched.map(|route| route.rule));
+    // キャッシュキーに含める転送先のモデル（`key_include_model`）
+    let resolved_model = matched.map(RouteMatch::model);
+    let vary = |key: CacheKey| key.varying(&state.caching_config, &parts.headers, resolved_model.as_deref());
+    // 出典はリクエストごとに異なるため、キャッシュには含めず返す直前に付与する
+    let citation = matched
+        .and_then(|route| route.rule.citations.as_ref())
+        .and_then(|citations| citations.sources(&parts.headers).map(|sources| (citations.field.as_str(), sources)));
+    let replay_delay = state.caching_config.stream_replay_delay();
+    let respond = |cached: CachedResponse| replay_cached_response(with_sources(cached, citation.as_ref()), replay_delay);
+    if let Some(json) = &json_body
+        && state.features.interception()
+    {
+        // ツール定義数の検証
+        let definitions = interceptor.validate_tool_definitions(json);
+        trace_result(trace, "tool_definitions", &definitions);
+        if let Err(msg) = definitions {
+            return (axum::http::StatusCode::BAD_REQUEST, msg).into_response();
+        }
         // ツール呼び出しの検証（インターセプション）
-        if let Err(msg) = state.interceptor.validate_tools(&json_body) {
-            return (axum::http::StatusCode::FORBIDDEN, msg).into_response();
-        }
-    }
-
-    // キャッシュの確認
-    let cache_key = if state.caching_config.enabled {
-        let key = CacheKey::new(&path, &bytes);
-        if let Some(cached) = state.cache.get(&key).await {
-            info!("Cache hit for path: {}", path);
-            let mut res = cached.body.into_response();
-            *res.status_mut() = axum::http::StatusCode::from_u16(cached.status).unwrap();
-            for (k, v) in cached.headers {
-                if let Ok(name) = axum::http::HeaderName::from_bytes(k.as_bytes()) {
-                    if let Ok(value) = axum::http::HeaderValue::from_str(&v) {
-                        res.headers_mut().insert(name, value);
-                    }
+        let tools = interceptor.validate_tools(json);
+        trace_result(trace, "tool_calls", &tools);
+        if let Err(msg) = tools {
+            return OrchixError::policy_violation("tool_blocked", msg).into_response();
+        }
+    } else {
+        trace_stage(trace, "tool_definitions", TraceVerdict::Skipped, None);
+        trace_stage(trace, "tool_calls", TraceVerdict::Skipped, None);
+    }
+
+    // キャッシュの確認（大きすぎるリクエストはほぼ繰り返されないため、キーの計算も省く）
+    // クライアントが `no-cache` を求めた場合は参照も保存もしない
+    let bypassed = caching
+        && (state.caching_config.bypasses_request(bytes.len()) || request_bypasses_cache(&parts.headers));
+    let cache_key = if caching && !bypassed {
+        let key = vary(CacheKey::for_request(&state.caching_config, parts.method.as_str(), path, parts.uri.query(), bytes));
+        let found = if serves_hit { state.cache.lookup(&key).await } else { None };
+        match found {
+            Some((cached, Freshness::Fresh)) => {
+                info!("Cache hit for path: {}", path);
+                let mut res = respond(cached);
+                res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static("HIT"));
+                return res;
+            }
+            Some((cached, Freshness::Stale)) => {
+                // 古いエントリを即座に返し、裏で再取得する（上流が不調なら再取得しない）
+                // 再取得はキーごとに1件だけとし、再取得中のキーでは half-open の試行の枠も取らない
+                info!("Serving stale cache entry for path: {}", path);
+                if let Some(route) = matched
+                    && let Some(refreshing) = state.cache.begin_refresh(&key)
+                    && let Some(target) = preferred_target(state, route)
+                    && let url = route.upstream_url(&target, parts.uri.query())
+                    && state.upstream_hosts.check(&url).is_ok()
+                    && state.circuit_breaker.try_acquire(&target.url)
+                {
+                    let body = prepare_upstream_body(route.rule, json_body.clone(), bytes);
+                    let request = UpstreamRequest::for_route(state, route.rule, &parts.headers, body);
+                    let method = parts.method.clone();
+                    spawn_refresh(state.clone(), refreshing, key, route.rule.clone(), target, UpstreamCall { method, url, request });
                 }
+                let mut res = respond(cached);
+                res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static("STALE"));
+                return res;
             }
+            None => {}
+        }
+        // 同じ入力のストリーミングで組み立て済みのレスポンスがあれば返す
+        if serves_hit
+            && state.caching_config.stream_cache_mode.stores_aggregated()
+            && let Some(json) = json_body.as_ref().filter(|json| !requests_streaming(json))
+            && let Some(cached) = state.cache.get(&vary(CacheKey::aggregated(
+                &state.caching_config, parts.method.as_str(), path, parts.uri.query(), json,
+            ))).await
+        {
+            info!("Aggregated stream cache hit for path: {}", path);
+            let mut res = respond(cached);
+            res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static("HIT"));
             return res;
         }
         Some(key)
@@ -162,94 +982,4045 @@ async fn proxy_handler(
         None
     };
 
-    if let Some(rule) = state.router.resolve(&path) {
-        info!("Matched rule: {} -> {} ({})", rule.path, rule.target_model, rule.target_url);
-        
-        let response_text = format!("Routing request to {} (Model: {})", rule.target_url, rule.target_model);
-        
-        // キャッシュの保存（非ストリーミングの場合の暫定的な実装）
-        if let Some(key) = cache_key {
-            let mut headers = std::collections::HashMap::new();
-            headers.insert("content-type".to_string(), "text/plain; charset=utf-8".to_string());
-            
-            state.cache.set(key, CachedResponse {
-                status: 200,
-                headers,
-                body: response_text.clone().into(),
-            }).await;
-        }
-
-        response_text.into_response()
+    // 同一リクエストが処理中であれば、その結果を共有する
+    let mut leader = None;
+    if state.caching_config.coalesce_requests
+        && let Some(key) = &cache_key
+    {
+        match state.single_flight.join(key) {
+            Flight::Follower(rx) => {
+                if let Some(shared) = state.single_flight.wait(rx).await {
+                    info!("Coalesced in-flight request for path: {}", path);
+                    let mut res = respond(shared);
+                    res.headers_mut().insert(COALESCED_HEADER, axum::http::HeaderValue::from_static("true"));
+                    return res;
+                }
+            }
+            Flight::Leader(flight) => leader = Some(flight),
+        }
+    }
+
+    if let Some(route) = matched {
+        // ドレイン中の転送先には新しいリクエストを送らない
+        let Some(target) = preferred_target(state, route) else {
+            warn!("All upstreams for {} are draining or have zero weight", route.rule.path);
+            return upstream_failure(route.rule, OrchixError::new(
+                axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                "upstream_unavailable",
+                format!("No upstream for {} is accepting new requests", route.rule.path),
+            ));
+        };
+        info!("Matched rule: {} -> {} ({})", route.rule.path, target.model, target.url);
+        state.region_selection.record(state.region.as_deref(), target.region.as_deref());
+        if state.log_route_provenance {
+            info!("Route provenance for {}: {}", path, route.describe());
+        } else {
+            debug!("Route provenance for {}: {}", path, route.describe());
+        }
+
+        // キャプチャから組み立てた URL も含め、許可されたホストにのみ転送する
+        let url = route.upstream_url(&target, parts.uri.query());
+        if let Err(reason) = state.upstream_hosts.check(&url) {
+            warn!("Rejected upstream for {}: {}", route.rule.path, reason);
+            return OrchixError::new(
+                axum::http::StatusCode::BAD_GATEWAY,
+                "upstream_host_not_allowed",
+                "The upstream host for this route is not allowed",
+            )
+            .into_response();
+        }
+
+        // 上流が不調・飽和している間は呼び出さず、期限切れのキャッシュがあればそれを返す（half-open では試行の1件だけを通す）
+        let slot = state.upstream_load.try_acquire(&target.id, target.max_in_flight);
+        if slot.is_none() || !state.circuit_breaker.try_acquire(&target.url) {
+            if let Some(key) = &cache_key
+                && let Some(cached) = state.cache.lookup_degraded(key).await
+            {
+                warn!("Upstream {} is unhealthy or saturated; serving degraded cache entry", target.url);
+                let mut res = respond(cached);
+                res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static("STALE-DEGRADED"));
+                return res;
+            }
+            return upstream_failure(route.rule, OrchixError::new(
+                axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                "upstream_unavailable",
+                format!("Upstream for {} is temporarily unavailable", route.rule.path),
+            ));
+        }
+
+        let request_stream = json_body.as_ref().is_some_and(requests_streaming);
+        let aggregated_key = cache_key.as_ref().filter(|_| state.caching_config.stream_cache_mode.stores_aggregated()).map(|_| {
+            let body = json_body.clone().unwrap_or_default();
+            vary(CacheKey::aggregated(&state.caching_config, parts.method.as_str(), path, parts.uri.query(), &body))
+        });
+        let upstream_body = prepare_upstream_body(route.rule, json_body, bytes);
+        let mut upstream = UpstreamRequest::for_route(state, route.rule, &parts.headers, upstream_body);
+        // 写しは上流の認証情報を付ける前に作る（shadow_url 側に本番のトークンを渡さない）
+        if state.features.shadow_traffic()
+            && let Some(url) = route.shadow_url(parts.uri.query())
+        {
+            let call = UpstreamCall { method: parts.method.clone(), url, request: upstream.for_attempt() };
+            spawn_shadow(state.http_client_for(route.rule.header_case).clone(), call);
+        }
+        // 追い出し後の再取得に使う写しも、上流の認証情報を付ける前に作る（トークンは再取得のたびに付け直す）
+        let refresh_request = state.refresh_ahead.is_some().then(|| upstream.for_attempt());
+        if let Some(oauth) = &route.rule.upstream_oauth {
+            match state.upstream_tokens.bearer(oauth).await {
+                Ok(token) => upstream = upstream.with_bearer_token(&token),
+                Err(e) => {
+                    warn!("Failed to obtain upstream access token for {}: {}", route.rule.path, e);
+                    return upstream_failure(route.rule, OrchixError::new(
+                        axum::http::StatusCode::BAD_GATEWAY,
+                        "upstream_auth_failed",
+                        format!("Failed to authenticate to the upstream for {}", route.rule.path),
+                    ));
+                }
+            }
+        }
+        debug!("Upstream request headers: {:?}", state.sensitive_headers.redacted(&upstream.headers));
+
+        // ルートの上流枠を認証したキー間で公平に割り当てる（未認証のキーの値ごとに枠を分けない）
+        let fair_permit = match &routing.queues[route.provenance.priority] {
+            Some(queue) => Some(queue.acquire(crate::auth::client_id(&parts.extensions)).await),
+            None => None,
+        };
+        let (client, upstream_url) = match state.upstream_client_for(route.rule, &url).await {
+            Ok(selected) => selected,
+            Err(e) => {
+                warn!("Failed to prepare upstream connection to {}: {}", url, e);
+                record_upstream_result(state, &target, axum::http::StatusCode::BAD_GATEWAY.as_u16());
+                return upstream_failure(route.rule, upstream_unreachable(&route.rule.path));
+            }
+        };
+        let call = UpstreamCall { method: parts.method.clone(), url: upstream_url, request: upstream };
+        let response = match send_with_retries(&state.retry, &call.request, |attempt| call.send(&client, attempt)).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to reach upstream {}: {}", call.url, e);
+                record_upstream_result(state, &target, axum::http::StatusCode::BAD_GATEWAY.as_u16());
+                return upstream_failure(route.rule, upstream_unreachable(&route.rule.path));
+            }
+        };
+        record_upstream_result(state, &target, response.status().as_u16());
+        // 期限前に失効したトークンは次のリクエストで取得し直す
+        if response.status() == axum::http::StatusCode::UNAUTHORIZED
+            && let Some(oauth) = &route.rule.upstream_oauth
+        {
+            state.upstream_tokens.invalidate(oauth).await;
+        }
+
+        // ストリーミングのレスポンスは解析しながらそのまま返す（枠は送信が終わるまで保持する）
+        let content_type = response.headers().get(axum::http::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        if route.rule.stream_detection.is_streaming(request_stream, content_type) {
+            let status = response.status();
+            let headers = forwarded_headers(response.headers());
+            let cacheable = state.cache.is_cacheable_head(status.as_u16(), response.headers());
+            let (cache_key, aggregated_key) = (cache_key.filter(|_| cacheable), aggregated_key.filter(|_| cacheable));
+            let cache_status = cache_status(bypassed, cache_key.is_some());
+            let chunks = futures::StreamExt::map(response.bytes_stream(), |chunk| chunk.map_err(axum::Error::new));
+            let stream = StreamSource { cache_key, aggregated_key, prompt_tokens: estimated_tokens, trace: trace.take() };
+            let mut res =
+                stream_response(state, Some(route.rule), Some(&target), interceptor, &parts.headers, Box::pin(chunks), stream);
+            *res.status_mut() = status;
+            for (name, value) in &headers {
+                if name != axum::http::header::CONTENT_TYPE {
+                    res.headers_mut().append(name, value.clone());
+                }
+            }
+            if let Some(cache_status) = cache_status {
+                res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static(cache_status));
+            }
+            return match (fair_permit, slot) {
+                (None, Some(slot)) if slot.is_unlimited() => res,
+                guards => crate::concurrency::hold_until_complete(res, guards),
+            };
+        }
+
+        let upstream_headers = forwarded_headers(response.headers());
+        let shared = match buffer_response(response).await {
+            Ok(shared) => postprocess_response(route.rule, shared),
+            Err(e) => {
+                warn!("Failed to read upstream response from {}: {}", call.url, e);
+                return upstream_failure(route.rule, upstream_unreachable(&route.rule.path));
+            }
+        };
+        drop((fair_permit, slot));
+        // 再試行しても上流がエラーを返した場合は、設定された合成レスポンスに置き換える
+        if shared.status >= 500
+            && let Some(failure) = &route.rule.failure_response
+        {
+            warn!("Upstream for {} returned {}; serving failure_response", route.rule.path, shared.status);
+            return synthetic_response(failure);
+        }
+
+        // キャッシュの保存（上流のエラー・Set-Cookie・no-store は保存しない）
+        let cache_key = cache_key.filter(|_| state.cache.is_cacheable(&shared));
+        let cache_status = cache_status(bypassed, cache_key.is_some());
+        if let Some(key) = cache_key
+            && state.cache.admits(shared.body.len())
+            && state.cache.should_store(&key).await
+        {
+            if let Some(refresh_ahead) = &state.refresh_ahead
+                && let Some(request) = refresh_request
+            {
+                let method = parts.method.clone();
+                let call = RefreshCall { rule: route.rule.clone(), target: target.clone(), method, url, request };
+                refresh_ahead.insert(key.clone(), Arc::new(call)).await;
+            }
+            state.cache.set_with_ttl(key, shared.clone(), route.rule.cache_ttl()).await;
+        }
+        if let Some(flight) = leader {
+            flight.complete(shared.clone());
+        }
+
+        let usage = state.cost_manager.usage_for_response(estimated_tokens, &shared.body);
+        let cost = state.cost_manager.price_for(&target.model).map(|p| p.cost(&usage));
+        let mut res = respond(shared);
+        restore_repeated_headers(res.headers_mut(), &upstream_headers);
+        insert_usage_headers(res.headers_mut(), &usage, cost);
+        if let Some(cache_status) = cache_status {
+            res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static(cache_status));
+        }
+        res
     } else {
         warn!("No route matched for path: {}", path);
         "No matching route found".into_response()
     }
 }
 
-// ストリーミングテスト用ハンドラ
-async fn stream_test_handler(
-    State(state): State<Arc<AppState>>,
-    req: Request,
-) -> impl IntoResponse {
-    let path = req.uri().path().to_string();
-    
-    // キャッシュの確認
-    if state.caching_config.enabled {
-        // テスト用なので固定の空ボディでハッシュ
-        let key = CacheKey::new(&path, &[]);
-        if let Some(cached) = state.cache.get(&key).await {
-            info!("Cache hit (streaming) for path: {}", path);
-            let mut res = cached.body.into_response();
-            // SSEとして返すためのヘッダー設定
-            res.headers_mut().insert(axum::http::header::CONTENT_TYPE, axum::http::HeaderValue::from_static("text/event-stream"));
-            return res;
-        }
+/// インスタンスと同じリージョンの、回路が開いておらず飽和していない転送先を優先して選びます
+fn preferred_target(state: &AppState, route: &RouteMatch<'_>) -> Option<UpstreamTarget> {
+    route.preferred_target(&state.drains, state.region.as_deref(), |endpoint, url| {
+        !state.circuit_breaker.is_open(url) && !state.upstream_load.is_saturated(endpoint.id(), endpoint.max_in_flight)
+    })
+}
+
+/// JSON のボディがあれば `model` も使ってルートを照合します
+fn resolve_route<'a>(router: &'a OrchixRouter, path: &str, json_body: Option<&serde_json::Value>) -> Option<RouteMatch<'a>> {
+    match json_body {
+        Some(json) => router.resolve_match_with_body(path, json),
+        None => router.resolve_match(path),
     }
+}
 
-    info!("Stream test requested");
+/// 上流がすべて失敗した場合の応答（ルートに `failure_response` があればエラーの代わりに返す）
+fn upstream_failure(rule: &RouteRule, error: OrchixError) -> Response {
+    match &rule.failure_response {
+        Some(failure) => synthetic_response(failure),
+        None => error.into_response(),
+    }
+}
 
-    let stream = stream::iter(vec![
-        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"Hello, "}}]}"#),
-        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"this "}}]}"#),
-        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"is "}}]}"#),
-        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"a "}}]}"#),
-        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"stream. "}}]}"#),
-        // 途中からツール呼び出しをシミュレート
-        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"name":"get_weather"}}]}}]}"#),
-        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"function":{"name":"rm_rf"}}]}}]}"#),
-        Ok::<&str, Infallible>("[DONE]"),
-    ])
-    .throttle(Duration::from_millis(500));
+fn synthetic_response(failure: &FailureResponse) -> Response {
+    cached_response(CachedResponse {
+        status: failure.status,
+        headers: std::collections::HashMap::from([("content-type".to_string(), failure.content_type.clone())]),
+        body: Bytes::from(failure.body.clone()),
+    })
+}
 
-    // StreamingAnalyzer でラップして検証を行う
-    let bytes_stream = futures::StreamExt::map(stream, |res| {
-        match res {
-            Ok(data) => {
-                let formatted = if data == "[DONE]" {
-                    "data: [DONE]\n\n".to_string()
-                } else {
-                    format!("data: {}\n\n", data)
-                };
-                Ok::<Bytes, axum::Error>(Bytes::from(formatted))
-            },
-            Err(_) => unreachable!(),
+/// 上流に接続できなかった、またはレスポンスを読み取れなかった場合のエラー
+fn upstream_unreachable(route: &str) -> OrchixError {
+    OrchixError::new(
+        axum::http::StatusCode::BAD_GATEWAY,
+        "upstream_unreachable",
+        format!("Failed to reach the upstream for {}", route),
+    )
+}
+
+fn body_too_large() -> OrchixError {
+    OrchixError::new(
+        axum::http::StatusCode::PAYLOAD_TOO_LARGE,
+        "body_too_large",
+        format!("Request body exceeds the limit of {} bytes", MAX_BODY_BYTES),
+    )
+}
+
+/// ボディ読み取りのエラーがサイズ上限によるものかを判定します
+fn is_length_limit_error(error: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(e) = source {
+        if e.is::<http_body_util::LengthLimitError>() {
+            return true;
         }
-    });
+        source = e.source();
+    }
+    false
+}
 
-    let cache_info = if state.caching_config.enabled {
-        let key = CacheKey::new(&path, &[]);
-        Some((state.cache.clone(), key))
-    } else {
-        None
-    };
+/// 上流に付与するメタデータヘッダー（`[upstream_metadata]` で有効化）
+pub const UPSTREAM_ROUTE_HEADER: &str = "x-orchix-route";
+pub const UPSTREAM_KEY_ID_HEADER: &str = "x-orchix-key-id";
+pub const UPSTREAM_REQUEST_ID_HEADER: &str = "x-orchix-request-id";
 
-    let analyzer = StreamingAnalyzer::new(
-        Box::pin(bytes_stream), 
-        Arc::new(state.interceptor.clone()),
-        cache_info,
-    );
-    
-    Sse::new(analyzer)
-        .keep_alive(axum::response::sse::KeepAlive::default())
-        .into_response()
+/// クライアントの `x-request-id` があればそれを、無ければ新しいリクエスト ID を返します
+fn request_id(headers: &axum::http::HeaderMap) -> String {
+    headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| hex::encode(rand::random::<[u8; 16]>()))
+}
+
+/// 上流に転送するリクエストのヘッダーとボディ
+///
+/// ボディは `to_bytes` で読み取り済みのバッファを保持し、再試行のたびに複製して送ります。
+struct UpstreamRequest {
+    headers: axum::http::HeaderMap,
+    body: Bytes,
+    // クライアントがチャンク転送（`Content-Length` なし）で送ったボディか
+    streamed_body: bool,
+    // このリクエストが転送前に Orchix を経由した回数
+    hops: u32,
+}
+
+impl UpstreamRequest {
+    /// クライアントのヘッダーを引き継ぎ、`Content-Length` を実際に送るボディに合わせます
+    ///
+    /// クライアントが送った `Host` と、Orchix への認証情報（`Authorization` / `x-api-key`）は
+    /// Orchix 宛てのため取り除きます。上流の認証はルートの設定から付与します。
+    fn new(client_headers: &axum::http::HeaderMap, body: Bytes) -> Self {
+        let streamed_body = !client_headers.contains_key(axum::http::header::CONTENT_LENGTH);
+        let mut headers = client_headers.clone();
+        headers.insert(axum::http::header::CONTENT_LENGTH, body.len().into());
+        headers.remove(axum::http::header::HOST);
+        headers.remove(axum::http::header::AUTHORIZATION);
+        headers.remove(crate::auth::API_KEY_HEADER);
+        // ボディは読み取り済みのため、上流には期待を伝えず 100 Continue も待たない（`for_route` で戻す場合を除く）
+        headers.remove(axum::http::header::EXPECT);
+        strip_hop_by_hop(&mut headers);
+        headers.remove(HOPS_HEADER);
+        Self { headers, body, streamed_body, hops: request_hops(client_headers) }
+    }
+
+    /// ルートの設定に従って上流へのリクエストを作ります（通常の転送とキャッシュの再取得で共通）
+    ///
+    /// Orchix への指示として読むヘッダー（引用の挿入・トレース・キャッシュの迂回）は上流に送りません。
+    fn for_route(state: &AppState, rule: &RouteRule, client_headers: &axum::http::HeaderMap, body: Bytes) -> Self {
+        let api_key = extract_api_key(client_headers, false).ok().flatten();
+        let mut request = Self::new(client_headers, body)
+            .with_host_override(rule.host_override.as_deref())
+            .with_default_headers(&rule.default_request_headers)
+            .with_metadata(&state.upstream_metadata, &rule.path, api_key);
+        if rule.forward_expect_continue
+            && let Some(expect) = client_headers.get(axum::http::header::EXPECT)
+        {
+            request.headers.insert(axum::http::header::EXPECT, expect.clone());
+        }
+        if let Some(citations) = &rule.citations {
+            request.headers.remove(citations.header.as_str());
+        }
+        request.headers.remove(TRACE_INTERCEPTION_HEADER);
+        request.headers.remove(NO_CACHE_HEADER);
+        request
+    }
+
+    /// 1回分の試行に使うリクエスト（ボディはバッファを共有する複製）
+    fn for_attempt(&self) -> Self {
+        Self { headers: self.headers.clone(), body: self.body.clone(), streamed_body: self.streamed_body, hops: self.hops }
+    }
+
+    /// 同じボディを再送できるか
+    fn replayable(&self, retry: &RetryConfig) -> bool {
+        !self.streamed_body || retry.buffer_streamed_bodies
+    }
+
+    /// ルートの `host_override` を `Host` ヘッダーに反映します（値はルールの読み込み時に検証済み）
+    fn with_host_override(mut self, host: Option<&str>) -> Self {
+        if let Some(value) = host.and_then(|h| axum::http::HeaderValue::from_str(h).ok()) {
+            self.headers.insert(axum::http::header::HOST, value);
+        }
+        self
+    }
+
+    /// 上流向けのアクセストークンを `Authorization` に設定します
+    fn with_bearer_token(mut self, token: &str) -> Self {
+        match axum::http::HeaderValue::from_str(&format!("Bearer {}", token)) {
+            Ok(value) => {
+                self.headers.insert(axum::http::header::AUTHORIZATION, value);
+            }
+            Err(_) => warn!("Ignoring upstream access token that is not a valid header value"),
+        }
+        self
+    }
+
+    /// ルートの `default_request_headers` のうち、クライアントが送っていないものを付与します
+    fn with_default_headers(mut self, defaults: &std::collections::HashMap<String, String>) -> Self {
+        for (name, value) in defaults {
+            let (Ok(name), Ok(value)) =
+                (axum::http::HeaderName::try_from(name.as_str()), axum::http::HeaderValue::from_str(value))
+            else {
+                warn!("Ignoring invalid default request header: {}", name);
+                continue;
+            };
+            if !self.headers.contains_key(&name) {
+                self.headers.insert(name, value);
+            }
+        }
+        self
+    }
+
+    /// 設定で有効にしたメタデータヘッダーを付与します
+    ///
+    /// クライアントが送った同名のヘッダーは、無効な項目も含めて常に取り除きます。
+    fn with_metadata(mut self, config: &UpstreamMetadataConfig, route: &str, api_key: Option<&str>) -> Self {
+        for name in [UPSTREAM_ROUTE_HEADER, UPSTREAM_KEY_ID_HEADER, UPSTREAM_REQUEST_ID_HEADER] {
+            self.headers.remove(name);
+        }
+        if config.route
+            && let Ok(value) = axum::http::HeaderValue::from_str(route)
+        {
+            self.headers.insert(UPSTREAM_ROUTE_HEADER, value);
+        }
+        if config.key_id
+            && let Some(key) = api_key
+        {
+            // キーそのものは送らず、照合用に短いハッシュのみを送る
+            self.headers.insert(UPSTREAM_KEY_ID_HEADER, crate::auth::KeyId::of(key).0.parse().unwrap());
+        }
+        if config.request_id {
+            let request_id = request_id(&self.headers).parse().unwrap();
+            self.headers.insert(UPSTREAM_REQUEST_ID_HEADER, request_id);
+        }
+        if config.hops {
+            self.headers.insert(HOPS_HEADER, (self.hops + 1).into());
+        }
+        self
+    }
+}
+
+/// 接続ごとのヘッダー（RFC 9110 7.6.1）。プロキシは転送しない
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// リクエストが Orchix を経由した回数（ヘッダーがない・読めない場合は 0）
+fn request_hops(headers: &axum::http::HeaderMap) -> u32 {
+    headers
+        .get(HOPS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// hop-by-hop ヘッダーと、`Connection` で指定されたヘッダーを取り除きます
+fn strip_hop_by_hop(headers: &mut axum::http::HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(axum::http::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    for name in HOP_BY_HOP_HEADERS.iter().copied().chain(listed.iter().map(String::as_str)) {
+        headers.remove(name);
+    }
+}
+
+/// 上流のレスポンスヘッダーからクライアントに返すものを選びます
+///
+/// ボディの長さは Orchix 側で決まるため `Content-Length` も転送しません。
+fn forwarded_headers(upstream: &axum::http::HeaderMap) -> axum::http::HeaderMap {
+    let mut headers = upstream.clone();
+    strip_hop_by_hop(&mut headers);
+    headers.remove(axum::http::header::CONTENT_LENGTH);
+    headers
+}
+
+/// 上流への1回の呼び出しに必要なもの（メソッド・連結済みの URL・リクエスト）
+struct UpstreamCall {
+    method: axum::http::Method,
+    url: String,
+    request: UpstreamRequest,
+}
+
+impl UpstreamCall {
+    /// リクエストを送り、レスポンスヘッダーを受け取った時点で返します（ボディは読み取らない）
+    async fn send(&self, client: &reqwest::Client, request: UpstreamRequest) -> reqwest::Result<reqwest::Response> {
+        debug!("Forwarding {} {} ({} byte body)", self.method, self.url, request.body.len());
+        client
+            .request(self.method.clone(), &self.url)
+            .headers(request.headers)
+            .body(request.body)
+            .send()
+            .await
+    }
+}
+
+/// リクエストの写しをバックグラウンドで送ります（結果はログに残すだけで、レスポンスは破棄する）
+fn spawn_shadow(client: reqwest::Client, call: UpstreamCall) {
+    tokio::spawn(async move {
+        match call.send(&client, call.request.for_attempt()).await {
+            Ok(response) => debug!("Shadow request to {} returned {}", call.url, response.status()),
+            Err(e) => warn!("Shadow request to {} failed: {}", call.url, e),
+        }
+    });
+}
+
+/// 上流への接続・読み取りの待ち時間の上限（`server.upstream_*_timeout_*`）
+#[derive(Clone, Copy)]
+struct UpstreamTimeouts {
+    connect: Duration,
+    read: Duration,
+}
+
+impl UpstreamTimeouts {
+    /// 上流向けの HTTP クライアントの共通設定
+    fn client_builder(&self) -> reqwest::ClientBuilder {
+        // 上流のリダイレクトには従わない（転送先の検証を迂回させない）
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .connect_timeout(self.connect)
+            .read_timeout(self.read)
+    }
+}
+
+/// `host_override` のルートで使う HTTP クライアント
+///
+/// URL のホストを `host_override` の名前に置き換え、その名前を `target_url` の接続先のアドレスに解決させます。
+/// これで接続先は変えずに、`Host` と TLS の SNI・証明書の検証をその名前で行います。
+/// クライアントは名前・接続先・ヘッダー名の書き方の組ごとに作り、接続プールを使い回します。
+struct HostOverrideClients {
+    timeouts: UpstreamTimeouts,
+    clients: std::sync::Mutex<std::collections::HashMap<(String, SocketAddr, HeaderCase), reqwest::Client>>,
+}
+
+impl HostOverrideClients {
+    fn new(timeouts: UpstreamTimeouts) -> Self {
+        Self { timeouts, clients: std::sync::Mutex::default() }
+    }
+
+    /// `url` のホストを `host` の名前に置き換えた URL と、その名前を `url` の接続先に解決するクライアントを返します
+    async fn client_for(&self, host: &str, url: &str, header_case: HeaderCase) -> anyhow::Result<(reqwest::Client, String)> {
+        let mut url = url::Url::parse(url)?;
+        let port = url.port_or_known_default().ok_or_else(|| anyhow::anyhow!("No port for upstream URL {}", url))?;
+        let addr = match url.host() {
+            Some(url::Host::Ipv4(ip)) => SocketAddr::from((ip, port)),
+            Some(url::Host::Ipv6(ip)) => SocketAddr::from((ip, port)),
+            Some(url::Host::Domain(domain)) => tokio::net::lookup_host((domain, port))
+                .await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("No address found for upstream host {}", domain))?,
+            None => anyhow::bail!("No host in upstream URL {}", url),
+        };
+        // `host[:port]` の名前の部分だけを使う（接続するポートは `target_url` のまま）
+        let name = host.parse::<axum::http::uri::Authority>()?.host().to_string();
+        url.set_host(Some(&name))?;
+
+        let key = (name, addr, header_case);
+        let mut clients = self.clients.lock().unwrap();
+        let client = match clients.get(&key) {
+            Some(client) => client.clone(),
+            None => {
+                let builder = self.timeouts.client_builder().resolve(&key.0, addr);
+                let builder = match header_case {
+                    HeaderCase::Lowercase => builder,
+                    HeaderCase::TitleCase => builder.http1_title_case_headers(),
+                };
+                let client = builder.build()?;
+                clients.insert(key, client.clone());
+                client
+            }
+        };
+        Ok((client, url.to_string()))
+    }
+}
+
+/// 上流のレスポンスを最後まで読み取り、キャッシュ可能な形にします
+///
+/// ヘッダーは名前ごとに1つの値にまとめるので、クライアントへ返す際は
+/// `restore_repeated_headers` で同じ名前の複数のヘッダーを戻してください。
+async fn buffer_response(response: reqwest::Response) -> reqwest::Result<CachedResponse> {
+    let status = response.status().as_u16();
+    let headers = forwarded_headers(response.headers())
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let body = response.bytes().await?;
+    Ok(CachedResponse { status, headers, body })
+}
+
+/// キャッシュ用にまとめる前の上流のヘッダーのうち、同じ名前で複数あるもの（`Set-Cookie` など）を戻します
+fn restore_repeated_headers(headers: &mut axum::http::HeaderMap, upstream: &axum::http::HeaderMap) {
+    for name in upstream.keys() {
+        let values = upstream.get_all(name);
+        if values.iter().nth(1).is_some() {
+            headers.remove(name);
+            for value in &values {
+                headers.append(name, value.clone());
+            }
+        }
+    }
+}
+
+/// 再試行の判定に使う上流の応答のステータス
+trait UpstreamStatus {
+    fn upstream_status(&self) -> u16;
+}
+
+impl UpstreamStatus for CachedResponse {
+    fn upstream_status(&self) -> u16 {
+        self.status
+    }
+}
+
+impl UpstreamStatus for reqwest::Result<reqwest::Response> {
+    // 接続できなかった場合は 502 として扱う
+    fn upstream_status(&self) -> u16 {
+        match self {
+            Ok(response) => response.status().as_u16(),
+            Err(_) => axum::http::StatusCode::BAD_GATEWAY.as_u16(),
+        }
+    }
+}
+
+/// 設定に従って、再試行対象のステータスの間は上流へのリクエストを繰り返します
+///
+/// 再送できないボディ（`replayable` でないもの）は1回だけ送ります。
+async fn send_with_retries<F, Fut, R>(retry: &RetryConfig, request: &UpstreamRequest, mut send: F) -> R
+where
+    F: FnMut(UpstreamRequest) -> Fut,
+    Fut: Future<Output = R>,
+    R: UpstreamStatus,
+{
+    let max_attempts = if request.replayable(retry) { retry.max_attempts.max(1) } else { 1 };
+    let mut attempt = 1;
+    loop {
+        let response = send(request.for_attempt()).await;
+        let status = response.upstream_status();
+        if attempt >= max_attempts || !retry.retry_on_status.contains(&status) {
+            return response;
+        }
+        warn!("Upstream returned {} (attempt {}/{}), retrying", status, attempt, max_attempts);
+        attempt += 1;
+    }
+}
+
+/// キャッシュのエントリを保存したリクエスト（追い出された後の再取得に使う）
+struct RefreshCall {
+    rule: RouteRule,
+    target: UpstreamTarget,
+    method: axum::http::Method,
+    url: String,
+    request: UpstreamRequest,
+}
+
+/// `caching.refresh_ahead_min_hits` 回以上ヒットして期限切れで追い出されたエントリを、保存したときのリクエストで再取得します
+///
+/// 再取得は古いエントリの再取得（`spawn_refresh`）と同じく、キーごとに1件だけ行い、上流が不調な間は行いません。
+/// 設定していない場合は何もせず None を返します。
+pub fn spawn_refresh_ahead(state: &Arc<AppState>) -> Option<tokio::task::JoinHandle<()>> {
+    state.refresh_ahead.as_ref()?;
+    let mut expired = state.cache.subscribe_refresh_ahead()?;
+    // 状態を破棄すると送り手も破棄され、タスクは終了する
+    let state = Arc::downgrade(state);
+    Some(tokio::spawn(async move {
+        while let Some(key) = expired.recv().await {
+            let Some(state) = state.upgrade() else { break };
+            let Some(refresh_ahead) = &state.refresh_ahead else { break };
+            let Some(saved) = refresh_ahead.get(&key).await else { continue };
+            if state.upstream_hosts.check(&saved.url).is_ok()
+                && let Some(refreshing) = state.cache.begin_refresh(&key)
+                && state.circuit_breaker.try_acquire(&saved.target.url)
+            {
+                debug!("Refreshing evicted hot cache entry from {}", saved.url);
+                let call = UpstreamCall { method: saved.method.clone(), url: saved.url.clone(), request: saved.request.for_attempt() };
+                spawn_refresh(state.clone(), refreshing, key, saved.rule.clone(), saved.target.clone(), call);
+            }
+        }
+    }))
+}
+
+/// 古くなったキャッシュエントリをバックグラウンドで再取得します（`refreshing` は完了まで保持する）
+fn spawn_refresh(
+    state: Arc<AppState>,
+    refreshing: RefreshGuard,
+    key: CacheKey,
+    rule: RouteRule,
+    target: UpstreamTarget,
+    call: UpstreamCall,
+) {
+    tokio::spawn(async move {
+        let _refreshing = refreshing;
+        let mut call = call;
+        if let Some(oauth) = &rule.upstream_oauth {
+            match state.upstream_tokens.bearer(oauth).await {
+                Ok(token) => call.request = call.request.with_bearer_token(&token),
+                Err(e) => {
+                    warn!("Failed to refresh stale cache entry from {}: {}", call.url, e);
+                    return;
+                }
+            }
+        }
+        let client = match state.upstream_client_for(&rule, &call.url).await {
+            Ok((client, url)) => {
+                call.url = url;
+                client
+            }
+            Err(e) => {
+                warn!("Failed to refresh stale cache entry from {}: {}", call.url, e);
+                record_upstream_result(&state, &target, axum::http::StatusCode::BAD_GATEWAY.as_u16());
+                return;
+            }
+        };
+        let response = send_with_retries(&state.retry, &call.request, |attempt| call.send(&client, attempt)).await;
+        let fresh = match response {
+            Ok(response) => buffer_response(response).await,
+            Err(e) => Err(e),
+        };
+        match fresh {
+            Ok(fresh) => {
+                let fresh = postprocess_response(&rule, fresh);
+                record_upstream_result(&state, &target, fresh.status);
+                if state.cache.is_cacheable(&fresh) && state.cache.admits(fresh.body.len()) {
+                    state.cache.set_with_ttl(key.clone(), fresh, rule.cache_ttl()).await;
+                }
+                debug!("Refreshed stale cache entry for {}", call.url);
+            }
+            Err(e) => {
+                warn!("Failed to refresh stale cache entry from {}: {}", call.url, e);
+                record_upstream_result(&state, &target, axum::http::StatusCode::BAD_GATEWAY.as_u16());
+            }
+        }
+    });
+}
+
+/// 上流の応答結果をサーキットブレーカーに記録します（5xx を失敗とみなす）
+fn record_upstream_result(state: &AppState, target: &UpstreamTarget, status: u16) {
+    if status >= 500 {
+        state.circuit_breaker.record_failure(&target.url);
+    } else {
+        state.circuit_breaker.record_success(&target.url);
+    }
+}
+
+/// ルートの設定に従って上流のレスポンスを加工します（キャッシュにも加工後の内容を保存する）
+fn postprocess_response(rule: &RouteRule, mut response: CachedResponse) -> CachedResponse {
+    if rule.hide_reasoning
+        && let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&response.body)
+    {
+        let removed = postprocess::strip_reasoning(&mut json);
+        if !removed.is_empty() {
+            if rule.log_reasoning {
+                for reasoning in &removed {
+                    info!("Withheld reasoning for route {}: {}", rule.path, reasoning);
+                }
+            }
+            response.body = serde_json::to_vec(&json).map(Bytes::from).unwrap_or(response.body);
+        }
+    }
+    response
+}
+
+/// ルートの `citations` で受け取った出典を成功したレスポンスに追加します
+fn with_sources(mut response: CachedResponse, citation: Option<&(&str, serde_json::Value)>) -> CachedResponse {
+    if let Some((field, sources)) = citation
+        && response.is_success()
+        && let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&response.body)
+        && postprocess::attach_sources(&mut json, field, sources)
+    {
+        response.body = serde_json::to_vec(&json).map(Bytes::from).unwrap_or(response.body);
+    }
+    response
+}
+
+/// 使用量と推定コストをレスポンスヘッダーに付与します
+fn insert_usage_headers(headers: &mut axum::http::HeaderMap, usage: &Usage, cost: Option<f64>) {
+    headers.insert(PROMPT_TOKENS_HEADER, usage.prompt_tokens.into());
+    headers.insert(COMPLETION_TOKENS_HEADER, usage.completion_tokens.into());
+    if let Some(cost) = cost
+        && let Ok(value) = axum::http::HeaderValue::from_str(&format!("{:.6}", cost))
+    {
+        headers.insert(ESTIMATED_COST_HEADER, value);
+    }
+}
+
+/// 上流から返したレスポンスの `x-orchix-cache`
+///
+/// キャッシュを使わなかったリクエストは `BYPASS`、キャッシュに無く保存の対象になるレスポンスは `MISS` とし、
+/// 保存の対象にならないレスポンス（上流のエラーなど）には付けません。
+fn cache_status(bypassed: bool, stored: bool) -> Option<&'static str> {
+    if bypassed {
+        Some("BYPASS")
+    } else {
+        stored.then_some("MISS")
+    }
+}
+
+/// レート制限の状況をレスポンスヘッダーに付与します
+fn insert_rate_limit_headers(headers: &mut axum::http::HeaderMap, status: &RateLimitStatus) {
+    headers.insert(RATE_LIMIT_LIMIT_HEADER, status.limit.into());
+    headers.insert(RATE_LIMIT_REMAINING_HEADER, status.remaining.into());
+    headers.insert(RATE_LIMIT_RESET_HEADER, status.reset_secs.into());
+}
+
+/// キャッシュ済み（または共有された）レスポンスを HTTP レスポンスに変換します
+fn cached_response(cached: CachedResponse) -> Response {
+    let mut res = cached.body.into_response();
+    *res.status_mut() = axum::http::StatusCode::from_u16(cached.status).unwrap();
+    for (k, v) in cached.headers {
+        if let Ok(name) = axum::http::HeaderName::from_bytes(k.as_bytes())
+            && let Ok(value) = axum::http::HeaderValue::from_str(&v)
+        {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    res
+}
+
+/// キャッシュ済みのレスポンスを返します。SSE はイベントごとに分けてストリームとして返し直します
+fn replay_cached_response(mut cached: CachedResponse, replay_delay: Option<Duration>) -> Response {
+    if !cached.is_event_stream() {
+        return cached_response(cached);
+    }
+    let status = cached.status;
+    let headers = std::mem::take(&mut cached.headers);
+    let mut res = cached.into_sse_stream(replay_delay).into_response();
+    *res.status_mut() = axum::http::StatusCode::from_u16(status).unwrap();
+    for (k, v) in headers {
+        if let Ok(name) = axum::http::HeaderName::from_bytes(k.as_bytes())
+            && name != axum::http::header::CONTENT_TYPE
+            && name != axum::http::header::CONTENT_LENGTH
+            && let Ok(value) = axum::http::HeaderValue::from_str(&v)
+        {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    res
+}
+
+/// 上流に送るボディを作成します
+///
+/// ルートの変換・プロバイダーの形式への変換でボディが変更された場合のみ再シリアライズし、
+/// 変更がなければ元のバイト列をそのまま使います（書式を変えないため）。
+fn prepare_upstream_body(rule: &RouteRule, json_body: Option<serde_json::Value>, original: &Bytes) -> Bytes {
+    let Some(mut json) = json_body else {
+        return original.clone();
+    };
+    let mut changed = false;
+    if let Some(name) = &rule.transform {
+        let before = json.clone();
+        changed = transform::apply(name, &mut json) && json != before;
+    }
+    if let Some(translated) = rule.provider.translate_request(&json) {
+        debug!("Translated request for {} to the {:?} format", rule.path, rule.provider);
+        json = translated;
+        changed = true;
+    }
+    if changed {
+        return serde_json::to_vec(&json).map(Bytes::from).unwrap_or_else(|_| original.clone());
+    }
+    original.clone()
+}
+
+/// ルートの `allow_streaming` / `force_stream_off` を適用します。ボディを書き換えた場合は true
+fn enforce_streaming_policy(rule: &RouteRule, json: &mut serde_json::Value) -> Result<bool, OrchixError> {
+    if !requests_streaming(json) {
+        return Ok(false);
+    }
+    if rule.force_stream_off {
+        if let Some(body) = json.as_object_mut() {
+            body.insert("stream".to_string(), serde_json::Value::Bool(false));
+            // `stream_options` は `stream: true` のときのみ有効なため一緒に取り除く
+            body.remove("stream_options");
+        }
+        return Ok(true);
+    }
+    if !rule.allow_streaming {
+        return Err(OrchixError::new(
+            axum::http::StatusCode::BAD_REQUEST,
+            "streaming_not_supported",
+            format!("Route {} does not accept streaming requests; send \"stream\": false", rule.path),
+        ));
+    }
+    Ok(false)
+}
+
+/// 読み直した設定のうち、ルーティング・機能フラグ・ブロックリストを反映します
+///
+/// ルーティングやブロックリストの検証に失敗した場合は何も反映せず、現在の設定を使い続けます。
+/// ブロックリストは設定の内容で置き換わるため、管理 API で追加したものは失われます。
+fn apply_reloaded_config(state: &AppState, config: AppConfig) -> anyhow::Result<()> {
+    crate::blocklist::Blocklist::validate_all(&config.blocklist.fingerprints)?;
+    state.reload_routing(config.routing)?;
+    state.features.apply(config.features);
+    state.blocklist.replace(&config.blocklist.fingerprints)?;
+    Ok(())
+}
+
+/// SIGHUP を受け取るたびに `load` で設定を読み直して反映するタスクを起動します
+#[cfg(unix)]
+pub fn spawn_reload_on_sighup<F>(state: Arc<AppState>, load: F) -> std::io::Result<tokio::task::JoinHandle<()>>
+where
+    F: Fn() -> anyhow::Result<AppConfig> + Send + 'static,
+{
+    // シグナルの受信は起動前に登録しておく（登録前の SIGHUP はプロセスを終了させるため）
+    let hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let signals = futures::stream::unfold(hangup, |mut hangup| async move {
+        hangup.recv().await?;
+        info!("Received SIGHUP, reloading configuration");
+        Some(((), hangup))
+    });
+    Ok(tokio::spawn(reload_on_triggers(state, Box::pin(signals), load)))
+}
+
+/// トリガーごとに設定を読み直します
+///
+/// 続けて届いたトリガーは、最後のトリガーから `server.reload_debounce_ms` 経過した時点で
+/// 1回の読み直しにまとめます（設定ファイルの連続した書き換えなど）。
+async fn reload_on_triggers<S, F>(state: Arc<AppState>, mut triggers: S, load: F)
+where
+    S: futures::Stream<Item = ()> + Unpin,
+    F: Fn() -> anyhow::Result<AppConfig>,
+{
+    while triggers.next().await.is_some() {
+        while let Ok(Some(())) = tokio::time::timeout(state.reload_debounce, triggers.next()).await {}
+        // 管理 API からの読み直しが実行中なら、終わるのを待ってから読み直す
+        let _reloading = state.reload_lock.lock().await;
+        if let Err(e) = load().and_then(|config| apply_reloaded_config(&state, config)) {
+            warn!("Failed to reload configuration, keeping the previous rules: {}", e);
+        }
+    }
+}
+
+/// 設定ファイルを読み直し、ルーティングと機能フラグを反映します（読み直しの実行中は 409）
+async fn reload_handler(State(state): State<Arc<AppState>>) -> Response {
+    let Ok(_reloading) = state.reload_lock.try_lock() else {
+        warn!("Rejected configuration reload while another reload is in progress");
+        return OrchixError::new(
+            axum::http::StatusCode::CONFLICT,
+            "reload_in_progress",
+            "A configuration reload is already in progress",
+        )
+        .into_response();
+    };
+    match (state.config_loader)().and_then(|config| apply_reloaded_config(&state, config)) {
+        Ok(()) => Json(state.features.snapshot()).into_response(),
+        Err(e) => {
+            warn!("Failed to reload configuration: {}", e);
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to reload configuration: {}", e)).into_response()
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CacheInvalidateQuery {
+    /// このパスそのものか、その配下（`/` で続くパス）のリクエストのエントリのみを無効化する（未指定ならすべて）
+    path: Option<String>,
+}
+
+/// キャッシュを無効化し、無効化したエントリの数を返します
+async fn cache_invalidate_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Query(query): axum::extract::Query<CacheInvalidateQuery>,
+) -> Response {
+    match state.cache.invalidate(query.path.as_deref()).await {
+        Ok(invalidated) => {
+            info!("Invalidated {} cache entries (path prefix: {})", invalidated, query.path.as_deref().unwrap_or("*"));
+            Json(serde_json::json!({"invalidated": invalidated, "path": query.path})).into_response()
+        }
+        Err(e) => {
+            warn!("Failed to invalidate cache entries: {}", e);
+            OrchixError::new(
+                axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                "cache_unavailable",
+                "Failed to invalidate cache entries",
+            )
+            .into_response()
+        }
+    }
+}
+
+// トラフィックタップ用ハンドラ（SSE）
+async fn tap_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    info!("Tap subscriber connected");
+    let events = BroadcastStream::new(state.tap.subscribe()).filter_map(|event| {
+        // 購読側が遅れて取りこぼしたイベントはスキップ
+        let event = event.ok()?;
+        Some(Ok::<_, Infallible>(
+            axum::response::sse::Event::default()
+                .event("tap")
+                .json_data(event)
+                .unwrap_or_default(),
+        ))
+    });
+
+    Sse::new(state.shutdown.guard_stream(events)).keep_alive(axum::response::sse::KeepAlive::default())
+}
+
+// ストリーミングテスト用ハンドラ
+async fn stream_test_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+) -> impl IntoResponse {
+    let path = req.uri().path().to_string();
+    // テスト用なので固定の空ボディでハッシュ
+    let cache_key = CacheKey::for_request(&state.caching_config, req.method().as_str(), &path, req.uri().query(), &[]);
+
+    // キャッシュの確認（`no-cache` を求めたリクエストは参照も保存もしない）
+    let bypassed = request_bypasses_cache(req.headers());
+    if state.caching_enabled()
+        && !bypassed
+        && let Some(cached) = state.cache.get(&cache_key).await
+    {
+        info!("Cache hit (streaming) for path: {}", path);
+        let mut res = replay_cached_response(cached, state.caching_config.stream_replay_delay());
+        res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static("HIT"));
+        return res;
+    }
+
+    info!("Stream test requested");
+
+    let stream = stream::iter(vec![
+        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"Hello, "}}]}"#),
+        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"this "}}]}"#),
+        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"is "}}]}"#),
+        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"a "}}]}"#),
+        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"content":"stream. "}}]}"#),
+        // 途中からツール呼び出しをシミュレート
+        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"name":"get_weather"}}]}}]}"#),
+        Ok::<&str, Infallible>(r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"function":{"name":"rm_rf"}}]}}]}"#),
+        Ok::<&str, Infallible>("[DONE]"),
+    ])
+    .throttle(Duration::from_millis(500));
+
+    // StreamingAnalyzer でラップして検証を行う
+    let bytes_stream = futures::StreamExt::map(stream, |res| {
+        match res {
+            Ok(data) => {
+                let formatted = if data == "[DONE]" {
+                    "data: [DONE]\n\n".to_string()
+                } else {
+                    format!("data: {}\n\n", data)
+                };
+                Ok::<Bytes, axum::Error>(Bytes::from(formatted))
+            },
+            Err(_) => unreachable!(),
+        }
+    });
+
+    let routing = state.routing();
+    let route = routing.router.resolve_match(&path);
+    let rule = route.as_ref().map(|route| route.rule);
+    let target = route.as_ref().and_then(|route| preferred_target(&state, route));
+    let interceptor = route.as_ref().and_then(|route| routing.interceptor_for(route)).unwrap_or(&state.interceptor);
+    let aggregated_key = state.caching_config.stream_cache_mode.stores_aggregated().then(|| {
+        CacheKey::aggregated(&state.caching_config, req.method().as_str(), &path, req.uri().query(), &serde_json::Value::Null)
+    });
+    let source = StreamSource {
+        cache_key: (state.caching_enabled() && !bypassed).then_some(cache_key),
+        aggregated_key,
+        prompt_tokens: 0,
+        trace: None,
+    };
+    let mut res = stream_response(&state, rule, target.as_ref(), interceptor, req.headers(), Box::pin(bytes_stream), source);
+    if state.caching_enabled()
+        && let Some(cache_status) = cache_status(bypassed, true)
+    {
+        res.headers_mut().insert(CACHE_STATUS_HEADER, axum::http::HeaderValue::from_static(cache_status));
+    }
+    res
+}
+
+/// ストリーミングのレスポンスをキャッシュ・使用量の集計に結び付ける情報
+struct StreamSource {
+    /// 生の SSE を保存するキー（キャッシュが無効なら None）
+    cache_key: Option<CacheKey>,
+    /// 組み立てたレスポンスを保存するキー（`stream_cache_mode` が aggregated を含む場合）
+    aggregated_key: Option<CacheKey>,
+    prompt_tokens: u32,
+    /// リクエスト側のインターセプションのトレース（管理者が要求した場合のみ）
+    trace: Option<InterceptionTrace>,
+}
+
+/// 上流の SSE を `StreamingAnalyzer` で検証しながらクライアントに返します
+///
+/// 推定コストは `target` のモデル（キャプチャを代入した後の名前）の単価で求めます。
+fn stream_response<S>(
+    state: &AppState,
+    rule: Option<&RouteRule>,
+    target: Option<&UpstreamTarget>,
+    interceptor: &Interceptor,
+    client_headers: &axum::http::HeaderMap,
+    upstream: S,
+    source: StreamSource,
+) -> Response
+where
+    S: futures::Stream<Item = Result<Bytes, axum::Error>> + Unpin + Send + 'static,
+{
+    let options = rule.map(|r| r.streaming.clone()).unwrap_or_default();
+    let metadata = options.metadata_events.then(|| {
+        serde_json::json!({"route": rule.map(|r| r.path.as_str()), "request_id": request_id(client_headers)})
+    });
+    let response_format = rule.map(|r| r.response_format).unwrap_or_default();
+    let price = target.and_then(|t| state.cost_manager.price_for(&t.model));
+    let cache_info = source.cache_key.map(|key| (state.cache.clone(), key));
+    let analyzer = StreamingAnalyzer::new(upstream, Arc::new(interceptor.clone()), cache_info)
+        .with_options(options)
+        .with_response_format(response_format)
+        .with_interception(state.features.interception())
+        .with_usage_trailer(source.prompt_tokens, price)
+        .with_cache_ttl(rule.and_then(|r| r.cache_ttl()));
+    let analyzer = match metadata {
+        Some(metadata) => {
+            let mut leading = metadata.clone();
+            leading["type"] = "start".into();
+            let mut trailing = metadata;
+            trailing["type"] = "end".into();
+            analyzer.with_leading_event(leading).with_trailing_event(trailing)
+        }
+        None => analyzer,
+    };
+    let citation = rule
+        .and_then(|r| r.citations.as_ref())
+        .and_then(|citations| citations.sources(client_headers).map(|sources| (citations, sources)));
+    let analyzer = match citation {
+        Some((citations, sources)) => analyzer.with_sources_event(&citations.field, sources),
+        None => analyzer,
+    };
+    let analyzer = match source.trace {
+        Some(trace) => analyzer.with_interception_trace(trace),
+        None => analyzer,
+    };
+    let analyzer = match source.aggregated_key {
+        Some(key) => analyzer.with_aggregated_cache(key, state.caching_config.stream_cache_mode.stores_raw()),
+        None => analyzer,
+    };
+
+    Sse::new(state.shutdown.guard_stream(analyzer))
+        .keep_alive(axum::response::sse::KeepAlive::default())
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{Request as HttpRequest, StatusCode};
+    use tower::ServiceExt;
+
+    const BASE_CONFIG: &str = r#"
+        [server]
+        host = "127.0.0.1"
+        port = 0
+
+        [log]
+        level = "info"
+
+        [[routing]]
+        path = "/v1/chat"
+        target_model = "gpt-4"
+        target_url = "http://MOCK_UPSTREAM/v1/chat/completions"
+
+        [interception]
+        forbidden_tools = ["rm_rf"]
+
+        [security]
+        api_keys = []
+
+        [caching]
+        enabled = false
+        ttl_seconds = 60
+        max_capacity = 100
+
+        [cost]
+        enabled = false
+        hourly_rate_limit = 100
+        daily_budget_tokens = 100000
+        max_request_tokens = 4000
+    "#;
+
+    /// 基本設定に追加の TOML を連結して設定を作成する
+    ///
+    /// `MOCK_UPSTREAM` / `MOCK_PORT` はテスト用の上流サーバーのアドレス・ポートに置き換える。
+    fn test_config(extra: &str) -> AppConfig {
+        let addr = mock_upstream();
+        let config = format!("{}\n{}", BASE_CONFIG, extra)
+            .replace("MOCK_UPSTREAM", &addr.to_string())
+            .replace("MOCK_PORT", &addr.port().to_string());
+        toml::from_str(&config).unwrap()
+    }
+
+    /// テスト用の上流サーバー（受け取ったリクエストを JSON で返す）のアドレス。プロセス内で共有する
+    fn mock_upstream() -> SocketAddr {
+        static ADDR: std::sync::OnceLock<SocketAddr> = std::sync::OnceLock::new();
+        *ADDR.get_or_init(|| crate::test_support::MockUpstream::new().echo().start_detached())
+    }
+
+    async fn json_body(res: Response) -> serde_json::Value {
+        serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    fn test_state(extra: &str) -> Arc<AppState> {
+        Arc::new(AppState::new(&test_config(extra)).unwrap())
+    }
+
+    async fn taken_port() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[tokio::test]
+    async fn test_bind_taken_port_reports_address() {
+        let (_guard, port) = taken_port().await;
+
+        let err = bind_listener("127.0.0.1", port, None).await.unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains(&format!("127.0.0.1:{}", port)), "{}", msg);
+        assert!(msg.contains("already in use"), "{}", msg);
+    }
+
+    #[tokio::test]
+    async fn test_bind_retries_next_port() {
+        let (_guard, port) = taken_port().await;
+        let retry = PortRetryConfig { mode: PortRetryMode::NextPort, attempts: 5, interval_ms: 0 };
+
+        let listener = bind_listener("127.0.0.1", port, Some(&retry)).await.unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_bind_waits_for_port_release() {
+        let (guard, port) = taken_port().await;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(guard);
+        });
+        let retry = PortRetryConfig { mode: PortRetryMode::Wait, attempts: 20, interval_ms: 50 };
+
+        let listener = bind_listener("127.0.0.1", port, Some(&retry)).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_proxied_request_appears_in_tap() {
+        let state = test_state("[tap]\nenabled = true");
+        let mut rx = state.tap.subscribe();
+        let app = build_app(state);
+
+        let res = app
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from(r#"{"model":"gpt-4"}"#)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.method, "POST");
+        assert_eq!(event.path, "/v1/chat");
+        assert_eq!(event.status, 200);
+        assert!(event.request_excerpt.contains("gpt-4"));
+    }
+
+    /// 実際のソケットで待ち受け、アドレスを返す
+    async fn serve(state: Arc<AppState>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, build_app(state)).await.unwrap() });
+        addr
+    }
+
+    async fn read_head(stream: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+        let mut buf = vec![0u8; 4096];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_accepts_body_after_interim_response() {
+        use tokio::io::AsyncWriteExt;
+        let addr = serve(test_state("")).await;
+        let body = r#"{"model":"gpt-4"}"#;
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "POST /v1/chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        assert!(read_head(&mut stream).await.starts_with("HTTP/1.1 100 Continue"));
+
+        stream.write_all(body.as_bytes()).await.unwrap();
+        let response = read_head(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains(r#""body":"{\"model\":\"gpt-4\"}""#), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_forwarded_to_opted_in_route() {
+        use tokio::io::AsyncWriteExt;
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let state = test_state(&format!(r#"
+            [[routing]]
+            path = "/expect"
+            target_model = "gpt-4"
+            target_url = "{}"
+            forward_expect_continue = true
+
+            [[routing]]
+            path = "/plain"
+            target_model = "gpt-4"
+            target_url = "{}"
+        "#, upstream.url("/expect"), upstream.url("/plain")));
+        let addr = serve(state).await;
+        let body = r#"{"model":"gpt-4"}"#;
+
+        for path in ["/expect", "/plain"] {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let head = format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+                path,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            assert!(read_head(&mut stream).await.starts_with("HTTP/1.1 100 Continue"));
+            stream.write_all(body.as_bytes()).await.unwrap();
+            let response = read_head(&mut stream).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        }
+
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].headers[axum::http::header::EXPECT], "100-continue");
+        assert_eq!(requests[0].body, body.as_bytes());
+        assert!(!requests[1].headers.contains_key(axum::http::header::EXPECT));
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_rejects_oversized_body_without_continue() {
+        use tokio::io::AsyncWriteExt;
+        let addr = serve(test_state("")).await;
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "POST /v1/chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+            MAX_BODY_BYTES + 1
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let response = read_head(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    }
+
+    async fn error_code(res: Response) -> String {
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        body["error"]["code"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_oversized_streamed_body_is_413() {
+        // Content-Length なし（チャンク転送）で上限を超えるボディ
+        let chunks = futures::stream::iter((0..2).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![b'a'; MAX_BODY_BYTES]))));
+        let res = build_app(test_state(""))
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from_stream(chunks)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_code(res).await, "body_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_aborted_body_is_400() {
+        let chunks = futures::stream::iter([
+            Ok(Bytes::from_static(b"{\"model\":")),
+            Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "client went away")),
+        ]);
+        let res = build_app(test_state(""))
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from_stream(chunks)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res).await, "body_read_failed");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_expectation_is_rejected() {
+        let res = build_app(test_state(""))
+            .oneshot(HttpRequest::post("/v1/chat").header("expect", "something-else").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::EXPECTATION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_headers_track_remaining_requests() {
+        let mut config = test_config("");
+        config.cost.enabled = true;
+        config.cost.hourly_rate_limit = 2;
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let send = || app.clone().oneshot(HttpRequest::post("/v1/chat").body(Body::from("{}")).unwrap());
+        let header = |res: &Response, name: &str| res.headers()[name].to_str().unwrap().parse::<u64>().unwrap();
+
+        for remaining in [1, 0] {
+            let res = send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(header(&res, RATE_LIMIT_LIMIT_HEADER), 2);
+            assert_eq!(header(&res, RATE_LIMIT_REMAINING_HEADER), remaining);
+            assert!((3599..=3600).contains(&header(&res, RATE_LIMIT_RESET_HEADER)));
+        }
+        let res = send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&res, RATE_LIMIT_LIMIT_HEADER), 2);
+        assert_eq!(header(&res, RATE_LIMIT_REMAINING_HEADER), 0);
+        assert!(header(&res, RATE_LIMIT_RESET_HEADER) <= 3600);
+
+        // コスト制御が無効ならヘッダーを付けない（認証しない場合はすべてのリクエストを1つのクライアントとして数える）
+        let res = build_app(test_state("")).oneshot(HttpRequest::post("/v1/chat").body(Body::from("{}")).unwrap()).await.unwrap();
+        assert!(res.headers().get(RATE_LIMIT_LIMIT_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_counted_per_api_key() {
+        let mut config = test_config("");
+        config.cost.enabled = true;
+        config.cost.hourly_rate_limit = 3;
+        config.security.api_keys = vec!["tenant-a".into(), "tenant-b".into()];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let send = |key: &'static str| {
+            app.clone().oneshot(HttpRequest::post("/v1/chat").header("x-api-key", key).body(Body::from("{}")).unwrap())
+        };
+        let remaining = |res: Response| res.headers()[RATE_LIMIT_REMAINING_HEADER].to_str().unwrap().to_string();
+
+        assert_eq!(remaining(send("tenant-a").await.unwrap()), "2");
+        assert_eq!(remaining(send("tenant-a").await.unwrap()), "1");
+        // 別のキーは独立して数える
+        assert_eq!(remaining(send("tenant-b").await.unwrap()), "2");
+        assert_eq!(remaining(send("tenant-a").await.unwrap()), "0");
+        assert_eq!(send("tenant-a").await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = send("tenant-b").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(remaining(res), "1");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_capped_per_key() {
+        let mut config = test_config("");
+        config.security.api_keys = vec!["tenant-a".into(), "tenant-b".into()];
+        config.security.max_concurrent_requests.insert("tenant-a".to_string(), 1);
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let stream = |key: &str| {
+            HttpRequest::get("/v1/stream_test").header("x-api-key", key).body(Body::empty()).unwrap()
+        };
+
+        // ストリーミング中のレスポンスは枠を保持し続ける
+        let open = app.clone().oneshot(stream("tenant-a")).await.unwrap();
+        assert_eq!(open.status(), StatusCode::OK);
+        let rejected = app.clone().oneshot(stream("tenant-a")).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+        let other = app.clone().oneshot(stream("tenant-b")).await.unwrap();
+        assert_eq!(other.status(), StatusCode::OK);
+
+        drop(open);
+        let reopened = app.oneshot(stream("tenant-a")).await.unwrap();
+        assert_eq!(reopened.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_usage_headers_on_completed_request() {
+        let mut config = test_config("");
+        config.cost.prices.insert(
+            "gpt-4".to_string(),
+            crate::cost_control::ModelPrice { prompt_per_1k: 1.0, completion_per_1k: 2.0 },
+        );
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+
+        // 40文字 → 10 トークン（上流の応答は usage を含まないため推定）
+        let body = r#"{"model":"gpt-4","messages":["abcdefg"]}"#;
+        assert_eq!(body.chars().count(), 40);
+        let res = app
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+
+        let header = |name: &str| res.headers()[name].to_str().unwrap().to_string();
+        let completion: u32 = header(COMPLETION_TOKENS_HEADER).parse().unwrap();
+        assert_eq!(header(PROMPT_TOKENS_HEADER), "10");
+        let expected = (10.0 * 1.0 + completion as f64 * 2.0) / 1000.0;
+        assert_eq!(header(ESTIMATED_COST_HEADER), format!("{:.6}", expected));
+    }
+
+    #[tokio::test]
+    async fn test_tap_requires_admin_key() {
+        let mut config = test_config("[tap]\nenabled = true");
+        config.security.admin_keys = vec!["admin".to_string()];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let res = app
+            .oneshot(HttpRequest::get("/admin/tap").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    /// キャッシュ範囲を指定して1回リクエストし、レスポンスがキャッシュされたかを返す
+    async fn cached_with_band(min: usize, max: Option<usize>) -> bool {
+        let mut config = test_config("");
+        config.caching.enabled = true;
+        config.caching.min_cache_bytes = min;
+        config.caching.max_cache_bytes = max;
+        let state = Arc::new(AppState::new(&config).unwrap());
+
+        let body = r#"{"model":"gpt-4"}"#;
+        build_app(state.clone())
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        state.cache.get(&CacheKey::new("/v1/chat", body.as_bytes())).await.is_some()
+    }
+
+    #[tokio::test]
+    async fn test_cache_admission_by_response_size() {
+        assert!(!cached_with_band(1000, None).await, "under-minimum response must not be cached");
+        assert!(!cached_with_band(0, Some(10)).await, "over-maximum response must not be cached");
+        assert!(cached_with_band(10, Some(1000)).await, "in-band response must be cached");
+    }
+
+    #[tokio::test]
+    async fn test_regex_route_substitutes_captures() {
+        let app = build_app(test_state(r#"
+            [[routing]]
+            path = "/models/(?P<model>[^/]+)/completions"
+            match_type = "regex"
+            target_model = "{model}"
+            target_url = "http://MOCK_UPSTREAM/{model}/v1/completions"
+        "#));
+        let res = app
+            .oneshot(HttpRequest::post("/models/llama-3/completions").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(json_body(res).await["path"], "/llama-3/v1/completions");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_host_allowlist_applies_to_captured_hosts() {
+        let mut config = test_config(r#"
+            [[routing]]
+            path = "/hosts/(?P<host>[^/]+)"
+            match_type = "regex"
+            target_model = "gpt-4"
+            target_url = "http://{host}:MOCK_PORT/v1"
+        "#);
+        config.server.allowed_upstream_hosts = vec!["127.0.0.1".to_string()];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+
+        let post = |path: &str| HttpRequest::post(path).body(Body::from("{}")).unwrap();
+        let res = app.clone().oneshot(post("/hosts/127.0.0.1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = app.oneshot(post("/hosts/169.254.169.254")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(error_code(res).await, "upstream_host_not_allowed");
+    }
+
+    #[test]
+    fn test_disallowed_route_host_fails_startup() {
+        let mut config = test_config("");
+        config.server.allowed_upstream_hosts = vec!["api.openai.com".to_string()];
+        assert!(AppState::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_model_aliases_share_cache_entry() {
+        let mut config = test_config(r#"
+            [model_aliases]
+            fast = "gpt-4o"
+            cheap = "gpt-4o"
+        "#);
+        config.caching.enabled = true;
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+        let post = |model: &str| {
+            HttpRequest::post("/v1/chat").body(Body::from(format!(r#"{{"model":"{}"}}"#, model))).unwrap()
+        };
+
+        app.clone().oneshot(post("fast")).await.unwrap();
+        let key = CacheKey::for_request(&config.caching, "POST", "/v1/chat", None, br#"{"model":"gpt-4o"}"#);
+        assert!(state.cache.get(&key).await.is_some(), "entry is keyed by the upstream model");
+
+        state.cache.set(key, CachedResponse {
+            status: 200,
+            headers: Default::default(),
+            body: Bytes::from_static(b"shared entry"),
+        }).await;
+        let res = app.oneshot(post("cheap")).await.unwrap();
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), "shared entry");
+    }
+
+    #[tokio::test]
+    async fn test_registered_transform_runs_on_matched_route() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        crate::register_transform("test_count_calls", |body| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            body["transformed"] = serde_json::Value::Bool(true);
+        });
+
+        let app = build_app(test_state(r#"
+            [[routing]]
+            path = "/v1/transformed"
+            target_model = "gpt-4"
+            target_url = "http://MOCK_UPSTREAM"
+            transform = "test_count_calls"
+        "#));
+
+        for path in ["/v1/chat", "/v1/transformed"] {
+            app.clone()
+                .oneshot(HttpRequest::post(path).body(Body::from(r#"{"model":"gpt-4"}"#)).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_prepare_upstream_body_applies_transform() {
+        crate::register_transform("test_add_field", |body| body["added"] = serde_json::json!(1));
+        let mut rule = test_config("").routing.remove(0);
+        let original = Bytes::from_static(br#"{"model":"gpt-4"}"#);
+
+        let untouched = prepare_upstream_body(&rule, serde_json::from_slice(&original).ok(), &original);
+        assert_eq!(untouched, original);
+
+        rule.transform = Some("test_add_field".to_string());
+        let transformed = prepare_upstream_body(&rule, serde_json::from_slice(&original).ok(), &original);
+        let json: serde_json::Value = serde_json::from_slice(&transformed).unwrap();
+        assert_eq!(json["added"], 1);
+    }
+
+    #[test]
+    fn test_unmodified_body_keeps_original_bytes() {
+        crate::register_transform("test_noop", |_| {});
+        let mut rule = test_config("").routing.remove(0);
+        rule.transform = Some("test_noop".to_string());
+        // 再シリアライズすると空白が変わる書式
+        let original = Bytes::from_static(b"{ \"model\" : \"gpt-4\" }");
+
+        let body = prepare_upstream_body(&rule, serde_json::from_slice(&original).ok(), &original);
+        assert_eq!(body, original);
+    }
+
+    #[test]
+    fn test_upstream_content_length_matches_modified_body() {
+        crate::register_transform("test_grow", |body| body["padding"] = serde_json::json!("x".repeat(32)));
+        let mut rule = test_config("").routing.remove(0);
+        rule.transform = Some("test_grow".to_string());
+        let original = Bytes::from_static(br#"{"model":"gpt-4"}"#);
+        let mut client_headers = axum::http::HeaderMap::new();
+        client_headers.insert(axum::http::header::CONTENT_LENGTH, original.len().into());
+        client_headers.insert(axum::http::header::EXPECT, axum::http::HeaderValue::from_static("100-continue"));
+
+        let body = prepare_upstream_body(&rule, serde_json::from_slice(&original).ok(), &original);
+        let request = UpstreamRequest::new(&client_headers, body);
+        assert!(request.body.len() > original.len());
+        assert_eq!(request.headers[axum::http::header::CONTENT_LENGTH], request.body.len().to_string());
+        assert!(!request.headers.contains_key(axum::http::header::EXPECT));
+    }
+
+    #[test]
+    fn test_host_override_sets_upstream_host() {
+        let mut client_headers = axum::http::HeaderMap::new();
+        client_headers.insert(axum::http::header::HOST, axum::http::HeaderValue::from_static("orchix.local:3000"));
+        let rule = test_config(r#"
+            [[routing]]
+            path = "/internal"
+            target_model = "gpt-4"
+            target_url = "http://10.0.0.5:8080"
+            host_override = "inference.internal.example"
+        "#).routing.remove(1);
+
+        let request = UpstreamRequest::new(&client_headers, Bytes::new())
+            .with_host_override(rule.host_override.as_deref());
+        assert_eq!(request.headers[axum::http::header::HOST], "inference.internal.example");
+
+        // 上書きがなければクライアントの Host は転送しない
+        let request = UpstreamRequest::new(&client_headers, Bytes::new()).with_host_override(None);
+        assert!(!request.headers.contains_key(axum::http::header::HOST));
+    }
+
+    #[tokio::test]
+    async fn test_host_override_sets_tls_sni() {
+        // ClientHello の SNI だけを記録して接続を閉じる TLS の上流
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sni_tx, sni_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut acceptor = rustls::server::Acceptor::default();
+            let mut buf = [0u8; 4096];
+            let accepted = loop {
+                let n = tokio::io::AsyncReadExt::read(&mut socket, &mut buf).await.unwrap();
+                acceptor.read_tls(&mut &buf[..n]).unwrap();
+                if let Some(accepted) = acceptor.accept().map_err(|(e, _)| e).unwrap() {
+                    break accepted;
+                }
+            };
+            let _ = sni_tx.send(accepted.client_hello().server_name().map(str::to_string));
+        });
+
+        let state = test_state(&format!(
+            "[[routing]]\npath = \"/internal\"\ntarget_model = \"gpt-4\"\ntarget_url = \"https://127.0.0.1:{}/v1\"\n\
+             host_override = \"inference.internal.example\"",
+            port,
+        ));
+        let _ = build_app(state).oneshot(HttpRequest::post("/internal").body(Body::from("{}")).unwrap()).await.unwrap();
+
+        // 接続先は target_url の IP のまま、SNI は上書きした名前になる
+        assert_eq!(sni_rx.await.unwrap().as_deref(), Some("inference.internal.example"));
+    }
+
+    #[test]
+    fn test_client_credentials_not_forwarded_upstream() {
+        let mut client_headers = axum::http::HeaderMap::new();
+        client_headers.insert(axum::http::header::AUTHORIZATION, axum::http::HeaderValue::from_static("Bearer orchix-key"));
+        client_headers.insert(crate::auth::API_KEY_HEADER, axum::http::HeaderValue::from_static("orchix-key"));
+        let defaults = std::collections::HashMap::from([("authorization".to_string(), "Bearer provider-key".to_string())]);
+
+        let request = UpstreamRequest::new(&client_headers, Bytes::new());
+        assert!(!request.headers.contains_key(axum::http::header::AUTHORIZATION));
+        assert!(!request.headers.contains_key(crate::auth::API_KEY_HEADER));
+        // ルートに設定した上流の認証情報は付与する
+        let request = request.with_default_headers(&defaults);
+        assert_eq!(request.headers[axum::http::header::AUTHORIZATION], "Bearer provider-key");
+    }
+
+    #[tokio::test]
+    async fn test_retries_resend_the_buffered_body() {
+        let retry = RetryConfig { max_attempts: 3, ..Default::default() };
+        let mut client_headers = axum::http::HeaderMap::new();
+        client_headers.insert(axum::http::header::CONTENT_LENGTH, 17.into());
+        let request = UpstreamRequest::new(&client_headers, Bytes::from_static(br#"{"model":"gpt-4"}"#));
+
+        let sent = std::sync::Mutex::new(Vec::new());
+        let response = send_with_retries(&retry, &request, |attempt| {
+            sent.lock().unwrap().push(attempt.body.clone());
+            let status = if sent.lock().unwrap().len() < 3 { 503 } else { 200 };
+            async move { CachedResponse { status, headers: Default::default(), body: Bytes::new() } }
+        })
+        .await;
+        assert_eq!(response.status, 200);
+        let sent = sent.into_inner().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|body| body == &request.body));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_body_not_retried_unless_buffered() {
+        let retry = RetryConfig { max_attempts: 3, buffer_streamed_bodies: false, ..Default::default() };
+        // Content-Length のない（チャンク転送の）ボディ
+        let request = UpstreamRequest::new(&axum::http::HeaderMap::new(), Bytes::from_static(b"{}"));
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let response = send_with_retries(&retry, &request, |_| {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { CachedResponse { status: 503, headers: Default::default(), body: Bytes::new() } }
+        })
+        .await;
+        assert_eq!(response.status, 503);
+        assert_eq!(attempts.into_inner(), 1);
+
+        let buffered = RetryConfig { buffer_streamed_bodies: true, ..retry };
+        assert!(request.replayable(&buffered));
+    }
+
+    #[test]
+    fn test_default_request_headers_fill_missing_only() {
+        let rule = test_config(r#"
+            [[routing]]
+            path = "/anthropic"
+            target_model = "claude-3-5-sonnet"
+            target_url = "https://api.anthropic.com/v1/messages"
+            [routing.default_request_headers]
+            anthropic-beta = "prompt-caching-2024-07-31"
+            anthropic-version = "2023-06-01"
+        "#).routing.remove(1);
+
+        let mut client_headers = axum::http::HeaderMap::new();
+        client_headers.insert("anthropic-beta", axum::http::HeaderValue::from_static("client-beta"));
+        let request = UpstreamRequest::new(&client_headers, Bytes::new())
+            .with_default_headers(&rule.default_request_headers);
+
+        // クライアントが送ったものはそのまま、足りないものだけ補う
+        assert_eq!(request.headers["anthropic-beta"], "client-beta");
+        assert_eq!(request.headers["anthropic-version"], "2023-06-01");
+
+        let request = UpstreamRequest::new(&axum::http::HeaderMap::new(), Bytes::new())
+            .with_default_headers(&rule.default_request_headers);
+        assert_eq!(request.headers["anthropic-beta"], "prompt-caching-2024-07-31");
+    }
+
+    #[tokio::test]
+    async fn test_configured_metadata_headers_only() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let upstream = &upstream;
+        let send = |metadata: UpstreamMetadataConfig| {
+            let app = build_app(proxy_state(upstream, |config| {
+                config.security.api_keys = vec!["secret-key".into()];
+                config.upstream_metadata = metadata;
+            }));
+            let request = HttpRequest::post("/proxy")
+                .header(crate::auth::API_KEY_HEADER, "secret-key")
+                .header("x-request-id", "req-123")
+                .header(UPSTREAM_ROUTE_HEADER, "spoofed")
+                .header(HOPS_HEADER, "2")
+                .body(Body::from("{}"))
+                .unwrap();
+            async move {
+                assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::OK);
+                upstream.requests().pop().unwrap().headers
+            }
+        };
+
+        let headers = send(UpstreamMetadataConfig { route: true, key_id: true, request_id: false, hops: false }).await;
+        assert_eq!(headers[UPSTREAM_ROUTE_HEADER], "/proxy");
+        let key_id = headers[UPSTREAM_KEY_ID_HEADER].to_str().unwrap();
+        assert_eq!(key_id.len(), 16);
+        assert!(!key_id.contains("secret"));
+        assert!(!headers.contains_key(UPSTREAM_REQUEST_ID_HEADER));
+        assert!(!headers.contains_key(HOPS_HEADER));
+
+        // デフォルトではどれも付与せず、クライアントが送ったものも転送しない
+        let headers = send(UpstreamMetadataConfig::default()).await;
+        assert!(!headers.contains_key(UPSTREAM_ROUTE_HEADER));
+        assert!(!headers.contains_key(UPSTREAM_KEY_ID_HEADER));
+
+        let headers = send(UpstreamMetadataConfig { request_id: true, ..Default::default() }).await;
+        assert_eq!(headers[UPSTREAM_REQUEST_ID_HEADER], "req-123");
+
+        // 転送先が Orchix の場合は経由回数を1つ増やして送る
+        let headers = send(UpstreamMetadataConfig { hops: true, ..Default::default() }).await;
+        assert_eq!(headers[HOPS_HEADER], "3");
+    }
+
+    #[test]
+    fn test_reasoning_hidden_per_route() {
+        let upstream = || CachedResponse {
+            status: 200,
+            headers: Default::default(),
+            body: Bytes::from(r#"{"choices":[{"message":{"content":"42","reasoning_content":"secret chain"}}]}"#),
+        };
+        let mut rule = test_config("").routing.remove(0);
+
+        // 既定では変更しない
+        assert_eq!(postprocess_response(&rule, upstream()).body, upstream().body);
+
+        // 取り除いた推論過程は log_reasoning の場合のみサーバー側のログに残る
+        #[derive(Clone, Default)]
+        struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let postprocess_logged = |rule: &RouteRule| {
+            let logs = Logs::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+            let response = tracing::subscriber::with_default(subscriber, || postprocess_response(rule, upstream()));
+            let logged = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            (serde_json::from_slice::<serde_json::Value>(&response.body).unwrap(), logged)
+        };
+
+        rule.hide_reasoning = true;
+        let (body, logged) = postprocess_logged(&rule);
+        assert!(body["choices"][0]["message"].get("reasoning_content").is_none());
+        assert!(!logged.contains("secret chain"));
+
+        rule.log_reasoning = true;
+        let (body, logged) = postprocess_logged(&rule);
+        assert_eq!(body["choices"][0]["message"]["content"], "42");
+        assert!(body["choices"][0]["message"].get("reasoning_content").is_none());
+        assert!(logged.contains("Withheld reasoning for route /v1/chat: secret chain"), "{}", logged);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_identical_requests_are_coalesced() {
+        // 上流の応答を遅らせ、後続が待機する時間を作る
+        let upstream = crate::test_support::MockUpstream::new()
+            .respond_with(200, r#"{"ok":true}"#)
+            .delay(Duration::from_millis(200))
+            .start()
+            .await;
+        let state = proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.coalesce_requests = true;
+        });
+        let app = build_app(state.clone());
+
+        let requests = (0..4).map(|_| {
+            let app = app.clone();
+            tokio::spawn(async move {
+                app.oneshot(HttpRequest::post("/proxy").body(Body::from(r#"{"model":"gpt-4"}"#)).unwrap())
+                    .await
+                    .unwrap()
+            })
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        let coalesced = responses
+            .into_iter()
+            .map(|r| r.unwrap())
+            .filter(|r| r.headers().get(COALESCED_HEADER).is_some_and(|v| v == "true"))
+            .count();
+        assert_eq!(coalesced, 3);
+        assert_eq!(state.single_flight.coalesced_count(), 3);
+        assert_eq!(upstream.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_too_many_tool_definitions_rejected() {
+        let mut config = test_config("");
+        config.interception.max_tool_definitions = Some(1);
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+
+        let body = r#"{"tools":[{"function":{"name":"a"}},{"function":{"name":"b"}}]}"#;
+        let res = app
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_over_deep_json_rejected_before_interception() {
+        let app = build_app(test_state(""));
+        // serde_json の再帰上限を超え、パースに失敗して検査を素通りしていた深さ
+        let body = format!(r#"{{"tool_calls":{}{}}}"#, "[".repeat(200), "]".repeat(200));
+        let res = app
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["error"]["code"], "json_too_complex");
+    }
+
+    #[tokio::test]
+    async fn test_preflight_reports_remaining_budget_without_forwarding() {
+        let mut config = test_config("");
+        config.cost.enabled = true;
+        config.cost.daily_budget_tokens = 1000;
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+
+        let preflight = |tokens: &'static str| {
+            HttpRequest::head("/v1/chat")
+                .header(ESTIMATED_TOKENS_HEADER, tokens)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = app.clone().oneshot(preflight("10")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-orchix-budget-remaining"], "1000");
+        assert_eq!(res.headers()["x-orchix-would-throttle"], "false");
+
+        // 実際のリクエストで使用量が記録されると残り予算に反映される
+        state.cost_manager.track_usage(crate::auth::ANONYMOUS_CLIENT, 400).await;
+        let res = app.clone().oneshot(preflight("10")).await.unwrap();
+        assert_eq!(res.headers()["x-orchix-budget-remaining"], "600");
+
+        let res = app.oneshot(preflight("100000")).await.unwrap();
+        assert_eq!(res.headers()["x-orchix-would-throttle"], "true");
+        assert_eq!(res.headers()["x-orchix-throttle-reason"], "max_request_tokens");
+    }
+
+    #[tokio::test]
+    async fn test_fault_injection_returns_synthetic_503_on_matched_route() {
+        let app = build_app(test_state(r#"
+            [fault_injection]
+            enabled = true
+            routes = ["/v1/chat"]
+            error_probability = 1.0
+        "#));
+
+        let res = app.clone()
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = app
+            .oneshot(HttpRequest::post("/v1/images").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response_serves_later_non_stream_request() {
+        let mut config = test_config("");
+        config.caching.enabled = true;
+        config.caching.stream_cache_mode = crate::config::StreamCacheMode::Aggregated;
+        let state = Arc::new(AppState::new(&config).unwrap());
+
+        // ストリーミングのリクエストでキャッシュを作る
+        let streamed = serde_json::json!({"model": "gpt-4", "stream": true, "messages": [{"role": "user", "content": "hi"}]});
+        let raw_key = CacheKey::new("/v1/chat", streamed.to_string().as_bytes());
+        let aggregated_key = CacheKey::aggregated(&state.caching_config, "POST", "/v1/chat", None, &streamed);
+        let upstream = futures::stream::iter([
+            "data: {\"id\":\"c1\",\"model\":\"gpt-4\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"Hello\"}}]}\n\n",
+            "data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\" world\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: [DONE]\n\n",
+        ].map(|chunk| Ok::<_, axum::Error>(Bytes::from_static(chunk.as_bytes()))));
+        let analyzer = StreamingAnalyzer::new(upstream, Arc::new(state.interceptor.clone()), Some((state.cache.clone(), raw_key.clone())))
+            .with_aggregated_cache(aggregated_key.clone(), false);
+        let rendered = Sse::new(analyzer).into_response().into_body();
+        axum::body::to_bytes(rendered, usize::MAX).await.unwrap();
+        for _ in 0..100 {
+            if state.cache.get(&aggregated_key).await.is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(state.cache.get(&raw_key).await.is_none(), "raw stream must not be cached in aggregated mode");
+
+        // 同じ入力の非ストリーミングリクエストは組み立て済みの JSON を受け取る
+        let buffered = r#"{"messages":[{"content":"hi","role":"user"}],"model":"gpt-4"}"#;
+        let res = build_app(state)
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from(buffered)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello world");
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_version_and_uptime() {
+        let res = build_app(test_state(""))
+            .oneshot(HttpRequest::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert!(body["uptime_seconds"].is_u64());
+        assert_eq!(body["build"]["name"], "orchix");
+    }
+
+    #[tokio::test]
+    async fn test_readiness_fails_during_shutdown_while_liveness_stays_up() {
+        let state = test_state("");
+        let app = build_app(state.clone());
+        let get = |path: &'static str| app.clone().oneshot(HttpRequest::get(path).body(Body::empty()).unwrap());
+        assert_eq!(get("/health").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get("/health/ready").await.unwrap().status(), StatusCode::OK);
+
+        state.shutdown.begin();
+        let res = get("/health").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(json_body(res).await["status"], "ok");
+        let res = get("/health/ready").await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json_body(res).await["status"], "draining");
+    }
+
+    #[tokio::test]
+    async fn test_hashed_api_keys_authenticate_presented_keys() {
+        let mut config = test_config("");
+        config.security.key_format = crate::auth::KeyFormat::Sha256;
+        config.security.api_keys = vec![crate::auth::hash_api_key("tenant-key").into()];
+        config.security.admin_keys = vec![crate::auth::hash_api_key("admin-key")];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let call = |path: &'static str, key: &str| {
+            let request = HttpRequest::post(path).header("authorization", format!("Bearer {}", key)).body(Body::from("{}")).unwrap();
+            app.clone().oneshot(request)
+        };
+
+        assert_eq!(call("/v1/chat", "tenant-key").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call("/v1/chat", &config.security.api_keys[0].key).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call("/v1/chat", "other-key").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call("/admin/reload", "tenant-key").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        // 大文字で書いたハッシュ値でも同時実行数の上限が効く
+        config.security.max_concurrent_requests.insert(crate::auth::hash_api_key("tenant-key").to_uppercase(), 3);
+        let state = AppState::new(&config).unwrap();
+        assert_eq!(state.concurrency.available(&crate::auth::hash_api_key("tenant-key")), Some(3));
+
+        // ハッシュ値でない設定は起動時に拒否する
+        config.security.api_keys = vec!["tenant-key".into()];
+        assert!(AppState::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_fair_queue_weights_follow_key_format() {
+        let hashed = crate::auth::hash_api_key("gold-key");
+        let config = |key_format: crate::auth::KeyFormat, key: &str| {
+            let mut config = test_config(&format!(
+                "[[routing]]\npath = \"/queued\"\ntarget_model = \"gpt-4\"\ntarget_url = \"http://localhost/v1\"\n\
+                 [routing.fair_queue]\nmax_concurrent = 1\nweights = {{ \"{}\" = 3 }}",
+                key,
+            ));
+            config.security.key_format = key_format;
+            config
+        };
+        let gold = crate::auth::KeyId::of("gold-key").0;
+        let weight = |state: AppState| state.routing().queues.iter().flatten().next().unwrap().weight(&gold);
+
+        // 認証したリクエストの KeyId で引ける（ハッシュ値は大文字で書いてもよい）
+        assert_eq!(weight(AppState::new(&config(crate::auth::KeyFormat::Sha256, &hashed.to_uppercase())).unwrap()), 3);
+        assert_eq!(weight(AppState::new(&config(crate::auth::KeyFormat::Plain, "gold-key")).unwrap()), 3);
+
+        // ハッシュ値でない重みのキーは起動時に拒否する
+        assert!(AppState::new(&config(crate::auth::KeyFormat::Sha256, "gold-key")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scoped_api_keys_limited_to_allowed_routes() {
+        let mut config = test_config(r#"
+            [[routing]]
+            path = "/v1/embeddings"
+            target_model = "text-embedding-3-small"
+            target_url = "http://MOCK_UPSTREAM/v1/embeddings"
+        "#);
+        config.security.api_keys = vec![
+            "full-key".into(),
+            crate::auth::ApiKeyEntry { key: "embed-key".to_string(), allowed_paths: Some(vec!["/v1/embeddings".to_string()]) },
+        ];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let call = |path: &'static str, key: &str| {
+            let request = HttpRequest::post(path).header("x-api-key", key).body(Body::from("{}")).unwrap();
+            app.clone().oneshot(request)
+        };
+
+        assert_eq!(call("/v1/embeddings", "embed-key").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call("/v1/chat", "embed-key").await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("/v1/chat", "full-key").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call("/v1/embeddings", "full-key").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_scoped_api_keys_checked_against_model_matched_route() {
+        let mut config = test_config(r#"
+            [[routing]]
+            path = "/v1"
+            match_model = "gpt-4o"
+            target_model = "gpt-4o"
+            target_url = "http://MOCK_UPSTREAM/v1/chat/completions"
+        "#);
+        config.security.api_keys =
+            vec![crate::auth::ApiKeyEntry { key: "basic-key".to_string(), allowed_paths: Some(vec!["/v1/chat".to_string()]) }];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let call = |model: &str| {
+            let body = serde_json::json!({ "model": model }).to_string();
+            app.clone().oneshot(HttpRequest::post("/v1/chat").header("x-api-key", "basic-key").body(Body::from(body)).unwrap())
+        };
+
+        // パスだけで照合すると /v1/chat だが、model で /v1 のルートに振り分けられるリクエストは拒否する
+        assert_eq!(call("gpt-3.5").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call("gpt-4o").await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_health_plain_mode_and_auth_exemption() {
+        let health = || HttpRequest::get("/health").body(Body::empty()).unwrap();
+        let mut config = test_config("[health]\nformat = \"plain\"");
+        config.security.api_keys = vec!["key".into()];
+
+        // 既定では API キーが設定されていても認証不要
+        let res = build_app(Arc::new(AppState::new(&config).unwrap())).oneshot(health()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), "OK");
+
+        config.health.require_auth = true;
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        assert_eq!(app.clone().oneshot(health()).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        let authed = HttpRequest::get("/health").header("x-api-key", "key").body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(authed).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_key_service_authenticates_unlisted_keys() {
+        let kms = crate::test_support::MockUpstream::new().respond_with(200, r#"{"valid":true}"#).start().await;
+        let mut config = test_config("");
+        config.security.key_service = Some(toml::from_str(&format!("url = \"{}\"", kms.url("/validate"))).unwrap());
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let chat = |key: Option<&str>| {
+            let mut req = HttpRequest::post("/v1/chat");
+            if let Some(key) = key {
+                req = req.header("x-api-key", key);
+            }
+            app.clone().oneshot(req.body(Body::from("{}")).unwrap())
+        };
+
+        assert_eq!(chat(Some("managed-key")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(chat(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(kms.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_reports_labeled_requests() {
+        let app = build_app(test_state(
+            r#"
+            [[routing]]
+            path = "/scrape/chat"
+            target_model = "gpt-4"
+            target_url = "http://MOCK_UPSTREAM/v1/chat/completions"
+
+            [metrics]
+            enabled = true
+            api_key = "scrape-key"
+            "#,
+        ));
+        let proxied = app.clone()
+            .oneshot(HttpRequest::post("/scrape/chat").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(proxied.status(), StatusCode::OK);
+
+        let scrape = |key: &str| app.clone().oneshot(HttpRequest::get("/metrics").header("x-api-key", key).body(Body::empty()).unwrap());
+        assert_eq!(scrape("wrong").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        let res = scrape("scrape-key").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let text = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(text.contains(r#"orchix_requests_total{route="/scrape/chat",status="200"} 1"#), "{}", text);
+        assert!(text.contains(r#"orchix_request_duration_seconds_bucket{route="/scrape/chat",le="#), "{}", text);
+
+        // 無効な場合はエンドポイントが無い
+        let disabled = build_app(test_state(""))
+            .oneshot(HttpRequest::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(disabled.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_report_fair_queue_depth() {
+        let state = test_state(
+            r#"
+            [[routing]]
+            path = "/queued"
+            target_model = "gpt-4"
+            target_url = "http://MOCK_UPSTREAM/v1/chat/completions"
+            [routing.fair_queue]
+            max_concurrent = 1
+
+            [metrics]
+            enabled = true
+            "#,
+        );
+        let app = build_app(state.clone());
+        let queue = state.routing().queues.iter().flatten().next().unwrap().clone();
+        let held = queue.acquire("other").await;
+        let waiting = tokio::spawn(app.clone().oneshot(HttpRequest::post("/queued").body(Body::from("{}")).unwrap()));
+        while queue.queued() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let res = app.clone().oneshot(HttpRequest::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        let text = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(text.contains("orchix_queued_requests 1\n"), "{}", text);
+        assert!(text.contains("orchix_in_flight_requests 2\n"), "{}", text);
+
+        drop(held);
+        assert_eq!(waiting.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(queue.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sampled_requests_captured_to_disk() {
+        let dir = std::env::temp_dir().join(format!("orchix-capture-e2e-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let app = build_app(test_state(&format!(
+            "[capture]\nenabled = true\nsample_rate = 1.0\ndirectory = {:?}",
+            dir.to_str().unwrap()
+        )));
+        let res = app
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from(r#"{"password":"p","prompt":"hi"}"#)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+
+        let path = dir.join("captures.jsonl");
+        let line = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(text) = std::fs::read_to_string(&path)
+                    && let Some(line) = text.lines().next()
+                {
+                    return line.to_string();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let captured: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(captured["route"], "/v1/chat");
+        assert_eq!(captured["request"]["password"], "[REDACTED]");
+        assert_eq!(captured["status"], 200);
+        // 書き出しはバックグラウンドで行われ、クライアントには同じボディが返る
+        assert_eq!(captured["response"], serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_requests_shed_when_in_flight_limit_reached() {
+        let app = build_app(test_state("[admission]\nenabled = true\nmax_in_flight = 2"));
+        let stream = || HttpRequest::get("/v1/stream_test").body(Body::empty()).unwrap();
+
+        // 受け付け済みのストリーミングは完了まで処理中として数える
+        let first = app.clone().oneshot(stream()).await.unwrap();
+        let second = app.clone().oneshot(stream()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+
+        let shed = app.clone()
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let health = app.clone().oneshot(HttpRequest::get("/health").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+
+        drop(first);
+        let admitted = app
+            .oneshot(HttpRequest::post("/v1/chat").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(admitted.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_connections_limited_per_client_ip() {
+        use tokio::io::AsyncWriteExt;
+        let state = test_state("[admission]\nenabled = true\nmax_connections_per_ip = 2");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = into_make_service(build_app(state.clone()), state.clone());
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        let open = || async {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /health HTTP/1.1\r\nHost: orchix\r\n\r\n").await.unwrap();
+            let head = read_head(&mut stream).await;
+            (stream, head)
+        };
+
+        // リクエストを送り終えた後も、接続が開いている間は数える
+        let (first, head) = open().await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        let (_second, head) = open().await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        let (_rejected, head) = open().await;
+        assert!(head.starts_with("HTTP/1.1 503"), "{}", head);
+        assert!(head.to_ascii_lowercase().contains("connection: close"), "{}", head);
+
+        drop(first);
+        for _ in 0..100 {
+            if state.admission.connections_from(addr.ip()) < 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (_third, head) = open().await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_connections_via_trusted_proxy_limited_per_forwarded_client() {
+        let mut config = test_config("[admission]\nenabled = true\nmax_connections_per_ip = 2");
+        config.security.trusted_proxies = vec!["192.0.2.1".parse().unwrap()];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let stream_from = |peer: [u8; 4], forwarded: Option<&'static str>| {
+            let mut request = HttpRequest::get("/v1/stream_test").extension(axum::extract::ConnectInfo(SocketAddr::from((peer, 40000))));
+            if let Some(forwarded) = forwarded {
+                request = request.header("x-forwarded-for", forwarded);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // 信頼するプロキシ経由では X-Forwarded-For のクライアントごとに処理中のリクエスト（ストリーミングを含む）を数える
+        let first = stream_from([192, 0, 2, 1], Some("10.0.0.1")).await.unwrap();
+        let _second = stream_from([192, 0, 2, 1], Some("10.0.0.1")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(stream_from([192, 0, 2, 1], Some("10.0.0.1")).await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(stream_from([192, 0, 2, 1], Some("10.0.0.1, 10.0.0.3")).await.unwrap().status(), StatusCode::OK);
+        // 直接の接続は受け付け時に数えるため、リクエストごとには数えない（信頼しない接続元の X-Forwarded-For も無視する）
+        assert_eq!(stream_from([10, 0, 0, 4], Some("10.0.0.1")).await.unwrap().status(), StatusCode::OK);
+
+        drop(first);
+        assert_eq!(stream_from([192, 0, 2, 1], Some("10.0.0.1")).await.unwrap().status(), StatusCode::OK);
+    }
+
+    fn features_off() -> crate::features::FeaturesConfig {
+        crate::features::FeaturesConfig { caching: false, interception: false, fault_injection: false, shadow_traffic: false }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_tool_returns_openai_error_envelope() {
+        let res = build_app(test_state(""))
+            .oneshot(
+                HttpRequest::post("/v1/chat")
+                    .body(Body::from(r#"{"tool_calls":[{"function":{"name":"rm_rf"}}]}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let error = body["error"].as_object().unwrap();
+        assert_eq!(error.len(), 3);
+        assert_eq!(error["type"], "orchix_policy_violation");
+        assert_eq!(error["code"], "tool_blocked");
+        assert!(error["message"].as_str().unwrap().contains("rm_rf"));
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_violating_schema_rejected() {
+        let mut config = test_config("");
+        config.interception.tool_schemas.insert(
+            "write_file".to_string(),
+            serde_json::json!({"type": "object", "required": ["path"], "properties": {"path": {"type": "string", "pattern": "^sandbox/"}}}),
+        );
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let call = |path: &str| {
+            let arguments = serde_json::json!({ "path": path }).to_string();
+            let body = serde_json::json!({"tool_calls": [{"function": {"name": "write_file", "arguments": arguments}}]});
+            HttpRequest::post("/v1/chat").body(Body::from(body.to_string())).unwrap()
+        };
+
+        let res = app.clone().oneshot(call("/etc/passwd")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(res).await, "tool_blocked");
+        assert_ne!(app.oneshot(call("sandbox/a.txt")).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_feature_flags_gate_behaviors() {
+        let mut config = test_config(r#"
+            [fault_injection]
+            enabled = true
+            error_probability = 1.0
+        "#);
+        config.caching.enabled = true;
+        config.interception.forbidden_tools = vec!["rm_rf".to_string()];
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+        let forbidden = r#"{"tool_calls":[{"function":{"name":"rm_rf"}}]}"#;
+        let send = |body: &'static str| {
+            app.clone().oneshot(HttpRequest::post("/v1/chat").body(Body::from(body)).unwrap())
+        };
+
+        // 有効時は障害注入が働く
+        assert_eq!(send("{}").await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        state.features.apply(features_off());
+        assert_eq!(send("{}").await.unwrap().status(), StatusCode::OK, "fault injection must be gated");
+        assert_eq!(send(forbidden).await.unwrap().status(), StatusCode::OK, "interception must be gated");
+        assert!(state.cache.get(&CacheKey::new("/v1/chat", b"{}")).await.is_none(), "caching must be gated");
+
+        state.features.apply(crate::features::FeaturesConfig { fault_injection: false, ..Default::default() });
+        assert_eq!(send(forbidden).await.unwrap().status(), StatusCode::FORBIDDEN);
+        send("{}").await.unwrap();
+        assert!(state.cache.get(&CacheKey::new("/v1/chat", b"{}")).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_shadow_traffic_mirrors_request_until_flag_is_off() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let shadow = crate::test_support::MockUpstream::new().respond_with(500, "{}").start().await;
+        let state = proxy_state(&upstream, |config| config.routing.last_mut().unwrap().shadow_url = Some(shadow.url("/shadow")));
+        let app = build_app(state.clone());
+        let send = || app.clone().oneshot(HttpRequest::post("/proxy/chat?v=1").body(Body::from(r#"{"a":1}"#)).unwrap());
+
+        // 写しの失敗はクライアントへの応答に影響しない
+        assert_eq!(send().await.unwrap().status(), StatusCode::OK);
+        while shadow.hits() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let mirrored = &shadow.requests()[0];
+        assert_eq!(mirrored.path, "/shadow/chat?v=1");
+        assert_eq!(mirrored.body, Bytes::from_static(br#"{"a":1}"#));
+
+        state.features.apply(crate::features::FeaturesConfig { shadow_traffic: false, ..Default::default() });
+        assert_eq!(send().await.unwrap().status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(shadow.hits(), 1, "shadow traffic must be gated");
+        assert_eq!(upstream.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_rejected_only_on_require_body_routes() {
+        let app = build_app(test_state(
+            "[[routing]]\npath = \"/strict\"\ntarget_model = \"gpt-4\"\ntarget_url = \"http://MOCK_UPSTREAM/v1/chat/completions\"\nrequire_body = true",
+        ));
+        let post = |path: &'static str, body: &'static str| app.clone().oneshot(HttpRequest::post(path).body(Body::from(body)).unwrap());
+
+        let res = post("/strict", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res).await, "missing_body");
+        let res = post("/strict", "not json").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res).await, "invalid_json");
+        assert_eq!(post("/strict", r#"{"model":"gpt-4"}"#).await.unwrap().status(), StatusCode::OK);
+
+        // 既定のルートは空のボディでもそのまま転送する
+        assert_eq!(post("/v1/chat", "").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_require_body_follows_model_matched_route() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            let mut by_model = config.routing.last().unwrap().clone();
+            by_model.match_model = Some("gpt-4o".to_string());
+            by_model.require_body = true;
+            config.routing.push(by_model);
+        }));
+        let post = |body: &'static str| app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap());
+
+        // JSON でないボディは `model` で照合できないため、path だけで選ばれたルート（require_body なし）に転送する
+        assert_eq!(post("").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(post(r#"{"model":"gpt-4o"}"#).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(upstream.hits(), 2);
+
+        let app = build_app(proxy_state(&upstream, |config| {
+            let mut by_model = config.routing.last().unwrap().clone();
+            config.routing.last_mut().unwrap().require_body = true;
+            by_model.match_model = Some("gpt-4o".to_string());
+            config.routing.push(by_model);
+        }));
+        let post = |body: &'static str| app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap());
+        let res = post("").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res).await, "missing_body");
+        assert_eq!(post(r#"{"model":"gpt-4o"}"#).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_route_interception_overrides_global_policy() {
+        let app = build_app(test_state(
+            r#"
+            [[routing]]
+            path = "/internal/agent"
+            target_model = "gpt-4"
+            target_url = "http://MOCK_UPSTREAM/v1/chat/completions"
+            [routing.interception]
+            forbidden_tools = ["send_email"]
+            "#,
+        ));
+        let call = |path: &'static str, tool: &str| {
+            let body = serde_json::json!({"tool_calls": [{"function": {"name": tool, "arguments": "{}"}}]}).to_string();
+            app.clone().oneshot(HttpRequest::post(path).body(Body::from(body)).unwrap())
+        };
+
+        // 全体のポリシー（rm_rf を禁止）は上書きしたルートには適用されない
+        assert_eq!(call("/v1/chat", "rm_rf").await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("/internal/agent", "rm_rf").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call("/internal/agent", "send_email").await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("/v1/chat", "send_email").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_admin_reload_restores_configured_flags() {
+        let mut config = test_config("");
+        config.security.admin_keys = vec!["admin".to_string()];
+        let reloaded = config.clone();
+        let state = Arc::new(AppState::new(&config).unwrap().with_config_loader(move || Ok(reloaded.clone())));
+        state.features.apply(features_off());
+
+        // 読み直す設定ではすべて有効になっている
+        let res = build_app(state.clone())
+            .oneshot(HttpRequest::post("/admin/reload").header("x-api-key", "admin").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(state.features.snapshot(), crate::features::FeaturesConfig::default());
+    }
+
+    #[tokio::test]
+    async fn test_reload_triggers_debounced_and_serialized() {
+        let mut config = test_config("");
+        config.server.reload_debounce_ms = 50;
+        config.security.admin_keys = vec!["admin".to_string()];
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let loads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (trigger, triggers) = tokio::sync::mpsc::unbounded_channel();
+        let counter = loads.clone();
+        let task = tokio::spawn(reload_on_triggers(
+            state.clone(),
+            tokio_stream::wrappers::UnboundedReceiverStream::new(triggers),
+            move || {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(test_config(""))
+            },
+        ));
+
+        // 間隔の短い5回のトリガーは1回の読み直しにまとまる
+        for _ in 0..5 {
+            trigger.send(()).unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(loads.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // 読み直しの実行中は管理 API の読み直しを 409 で拒否し、トリガーによる読み直しは終わるまで待つ
+        let reloading = state.reload_lock.lock().await;
+        let app = build_app(state.clone());
+        let admin_reload = || HttpRequest::post("/admin/reload").header("x-api-key", "admin").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(admin_reload()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(error_code(res).await, "reload_in_progress");
+        trigger.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(loads.load(std::sync::atomic::Ordering::SeqCst), 1, "waits for the in-progress reload");
+        drop(reloading);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(loads.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(app.oneshot(admin_reload()).await.unwrap().status(), StatusCode::OK);
+
+        drop(trigger);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_routing_reload_keeps_previous_rules() {
+        let state = test_state("[[routing]]\npath = \"/hot\"\ntarget_model = \"gpt-4\"\ntarget_url = \"http://MOCK_UPSTREAM/old\"");
+        let invalid = test_config("[[routing]]\npath = \"/hot/(unclosed\"\nmatch_type = \"regex\"\ntarget_model = \"gpt-4\"\ntarget_url = \"http://MOCK_UPSTREAM/new\"");
+        let rules = state.routing().router.rules.len();
+
+        assert!(state.reload_routing(invalid.routing).is_err());
+        let routing = state.routing();
+        assert_eq!(routing.router.rules.len(), rules);
+        assert_eq!(routing.router.resolve("/hot").unwrap().targets[0].url, format!("http://{}/old", mock_upstream()));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_route_interception_rejected_on_reload() {
+        let state = test_state("");
+        let route = |interception: &str| {
+            test_config(&format!(
+                "[[routing]]\npath = \"/agent\"\ntarget_model = \"gpt-4\"\ntarget_url = \"http://MOCK_UPSTREAM/v1\"\n[routing.interception]\n{}",
+                interception
+            ))
+        };
+        let rules = state.routing().router.rules.len();
+
+        // 不正なスキーマ
+        let invalid_schema = route("forbidden_tools = []\n[routing.interception.tool_schemas.write_file]\ntype = 12");
+        let err = state.reload_routing(invalid_schema.routing.clone()).unwrap_err();
+        assert!(err.to_string().contains("/agent"), "{}", err);
+        assert!(AppState::new(&invalid_schema).is_err());
+        // fail_mode = "error" のまま空のポリシー
+        let empty_policy = route("forbidden_tools = []\nfail_mode = \"error\"");
+        let err = state.reload_routing(empty_policy.routing).unwrap_err();
+        assert!(err.to_string().contains("policy is empty"), "{}", err);
+
+        assert_eq!(state.routing().router.rules.len(), rules);
+        assert!(state.reload_routing(route("forbidden_tools = [\"send_email\"]").routing).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_blocklisted_fingerprints_rejected() {
+        let abusive = r#"{"prompt":"spam"}"#;
+        let fingerprint = crate::blocklist::RequestFingerprint::compute(abusive.as_bytes(), None, None).body;
+        let mut config = test_config(&format!("[blocklist]\nfingerprints = [\"{}\"]", fingerprint));
+        config.security.admin_keys = vec!["admin".to_string()];
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+        let send = |body: &'static str, ip: [u8; 4]| {
+            let request = HttpRequest::post("/v1/chat")
+                .extension(axum::extract::ConnectInfo(SocketAddr::from((ip, 40000))))
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let res = send(abusive, [10, 0, 0, 1]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(res).await, "request_blocked");
+        assert_eq!(send(r#"{"prompt":"hello"}"#, [10, 0, 0, 1]).await.unwrap().status(), StatusCode::OK);
+
+        // 管理 API で接続元の IP を追加する
+        let add = HttpRequest::post("/admin/blocklist")
+            .header("x-api-key", "admin")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"fingerprint":"ip:192.0.2.9"}"#))
+            .unwrap();
+        assert_eq!(app.clone().oneshot(add).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send(r#"{"prompt":"hello"}"#, [192, 0, 2, 9]).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        // 再読み込みで設定の内容に置き換わる
+        apply_reloaded_config(&state, test_config("")).unwrap();
+        assert_eq!(send(abusive, [192, 0, 2, 9]).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sighup_reloads_routing_without_dropping_requests() {
+        let old = crate::test_support::MockUpstream::new()
+            .respond_with(200, "old")
+            .delay(Duration::from_millis(300))
+            .start()
+            .await;
+        let new = crate::test_support::MockUpstream::new().respond_with(200, "new").start().await;
+        let route = |upstream: &crate::test_support::MockServer| {
+            format!("[[routing]]\npath = \"/hot\"\ntarget_model = \"gpt-4\"\ntarget_url = \"{}\"", upstream.url("/v1"))
+        };
+        // 読み直すたびに返す設定（config.toml を書き換える代わり）
+        let config = Arc::new(std::sync::Mutex::new(route(&old)));
+        let state = Arc::new(AppState::new(&test_config(&config.lock().unwrap())).unwrap());
+        let source = config.clone();
+        spawn_reload_on_sighup(state.clone(), move || Ok(test_config(&source.lock().unwrap()))).unwrap();
+
+        let app = build_app(state.clone());
+        let send = || app.clone().oneshot(HttpRequest::post("/hot").body(Body::from("{}")).unwrap());
+        let body = |res: Response| async { axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap() };
+        let in_flight = tokio::spawn(send());
+        while old.hits() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        *config.lock().unwrap() = route(&new);
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        while state.routing().router.resolve("/hot").unwrap().targets[0].url != new.url("/v1") {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        assert_eq!(body(send().await.unwrap()).await, "new");
+        // 差し替え前に受け付けたリクエストは元のルールのまま完了する
+        let res = in_flight.await.unwrap().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "old");
+        assert_eq!((old.hits(), new.hits()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_cross_region_fallback_when_local_circuit_open() {
+        let local = crate::test_support::MockUpstream::new().respond_with(200, "local").start().await;
+        let remote = crate::test_support::MockUpstream::new().respond_with(200, "remote").start().await;
+        let mut config = test_config(&format!(
+            "[circuit_breaker]\nenabled = true\nfailure_threshold = 1\n\
+             [[routing]]\npath = \"/regional\"\ntarget_model = \"gpt-4\"\ntarget_url = [\n\
+             {{ id = \"remote\", url = \"{}\", region = \"us-east\" }},\n\
+             {{ id = \"local\", url = \"{}\", region = \"eu-west\" }},\n]",
+            remote.url("/v1"),
+            local.url("/v1"),
+        ));
+        config.server.region = Some("eu-west".to_string());
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+        let send = || async {
+            let res = app.clone().oneshot(HttpRequest::post("/regional").body(Body::from("{}")).unwrap()).await.unwrap();
+            axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()
+        };
+
+        assert_eq!(send().await, "local");
+        state.circuit_breaker.record_failure(&local.url("/v1"));
+        assert_eq!(send().await, "remote");
+
+        let ready = app.clone().oneshot(HttpRequest::get("/health/ready").body(Body::empty()).unwrap()).await.unwrap();
+        let report = json_body(ready).await;
+        assert_eq!(report["region_selection"], serde_json::json!({"region": "eu-west", "local": 1, "cross_region": 1}));
+    }
+
+    #[tokio::test]
+    async fn test_cross_region_fallback_when_local_saturated() {
+        let local = crate::test_support::MockUpstream::new()
+            .respond_with(200, "local")
+            .delay(Duration::from_millis(300))
+            .start()
+            .await;
+        let remote = crate::test_support::MockUpstream::new().respond_with(200, "remote").start().await;
+        let mut config = test_config(&format!(
+            "[[routing]]\npath = \"/regional\"\ntarget_model = \"gpt-4\"\ntarget_url = [\n\
+             {{ id = \"local\", url = \"{}\", region = \"eu-west\", max_in_flight = 1 }},\n\
+             {{ id = \"remote\", url = \"{}\", region = \"us-east\" }},\n]",
+            local.url("/v1"),
+            remote.url("/v1"),
+        ));
+        config.server.region = Some("eu-west".to_string());
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+        let send = || {
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(HttpRequest::post("/regional").body(Body::from("{}")).unwrap()).await.unwrap();
+                axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()
+            }
+        };
+
+        // 同じリージョンの転送先が上限まで処理中の間は、他のリージョンへ送る
+        let first = tokio::spawn(send());
+        while !state.upstream_load.is_saturated("local", Some(1)) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(send().await, "remote");
+        assert_eq!(first.await.unwrap(), "local");
+        assert!(!state.upstream_load.is_saturated("local", Some(1)));
+        assert_eq!(send().await, "local");
+    }
+
+    #[tokio::test]
+    async fn test_drained_upstream_receives_no_new_traffic() {
+        let mut config = test_config(r#"
+            [[routing]]
+            path = "/v1/pool"
+            target_model = "gpt-4"
+            target_url = [
+                { id = "primary", url = "http://MOCK_UPSTREAM/primary" },
+                { id = "secondary", url = "http://MOCK_UPSTREAM/secondary" },
+            ]
+        "#);
+        config.security.admin_keys = vec!["admin".to_string()];
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+        let send = |path: &str| {
+            app.clone().oneshot(HttpRequest::post(path).header("x-api-key", "admin").body(Body::from("{}")).unwrap())
+        };
+        let body = |res: Response| async { axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap() };
+
+        assert_eq!(json_body(send("/v1/pool").await.unwrap()).await["path"], "/primary");
+        assert_eq!(send("/admin/upstreams/primary/drain").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(json_body(send("/v1/pool").await.unwrap()).await["path"], "/secondary");
+
+        // ドレインは障害扱いではなく、readiness に表示されるだけ
+        assert!(!state.circuit_breaker.is_open(&format!("http://{}/primary", mock_upstream())));
+        let ready = app.clone().oneshot(HttpRequest::get("/health/ready").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(ready.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body(ready).await).unwrap();
+        assert_eq!(report["draining_upstreams"], serde_json::json!(["primary"]));
+
+        assert_eq!(send("/admin/upstreams/primary/undrain").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(json_body(send("/v1/pool").await.unwrap()).await["path"], "/primary");
+        assert_eq!(send("/admin/upstreams/unknown/drain").await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_serves_degraded_cache() {
+        // 時計を止めると上流との通信待ちの間に接続タイムアウトまで進んでしまうので、短い TTL を実時間で待つ
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let state = proxy_state(&upstream, |config| {
+            config.circuit_breaker = toml::from_str("enabled = true\nfailure_threshold = 1\nopen_seconds = 3600").unwrap();
+            config.caching.enabled = true;
+            config.caching.degraded_ttl_seconds = 300;
+            config.routing.last_mut().unwrap().cache_ttl_seconds = Some(1);
+        });
+        let app = build_app(state.clone());
+        let request = || HttpRequest::post("/proxy").body(Body::from("{}")).unwrap();
+
+        assert_eq!(app.clone().oneshot(request()).await.unwrap().status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        // 上流が失敗し続けて回路が開いた状態
+        state.circuit_breaker.record_failure(&upstream.url("/base"));
+        let res = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "STALE-DEGRADED");
+
+        // キャッシュがなければ上流を呼ばずに 503
+        let res = app
+            .oneshot(HttpRequest::post("/proxy").body(Body::from(r#"{"other":1}"#)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(upstream.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_redis_cache_shared_across_instances() {
+        let redis = crate::test_support::MockRedis::start().await;
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let instance = || {
+            let state = proxy_state(&upstream, |config| {
+                config.caching.enabled = true;
+                config.caching.backend = crate::config::CacheBackend::Redis;
+                config.caching.redis = Some(toml::from_str(&format!("url = \"{}\"", redis.url())).unwrap());
+            });
+            build_app(state)
+        };
+        let send = |app: Router| app.oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap());
+
+        let first = instance();
+        assert_eq!(send(first.clone()).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send(first).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(upstream.hits(), 1);
+        assert!(redis.keys().iter().all(|key| key.starts_with("orchix:cache:")));
+        assert_eq!(redis.keys().len(), 1);
+
+        // 別のインスタンス（再起動後）も同じエントリを使う
+        let res = send(instance()).await.unwrap();
+        assert_eq!(json_body(res).await, serde_json::json!({"ok": true}));
+        assert_eq!(upstream.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_redis_entry_with_invalid_status_is_a_miss() {
+        let redis = crate::test_support::MockRedis::start().await;
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.backend = crate::config::CacheBackend::Redis;
+            config.caching.redis = Some(toml::from_str(&format!("url = \"{}\"", redis.url())).unwrap());
+        }));
+        let send = || app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap());
+        send().await.unwrap();
+
+        let key = redis.keys().remove(0);
+        let mut entry: serde_json::Value = serde_json::from_slice(&redis.get(&key).unwrap()).unwrap();
+        entry["response"]["status"] = serde_json::json!(1000);
+        redis.insert(&key, entry.to_string());
+
+        let res = send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "MISS");
+        assert_eq!(upstream.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_probability_serves_fraction_of_hits() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.routing.last_mut().unwrap().cache_probability = Some(0.25);
+        }));
+
+        // 初回はミス。以降のヒットのうち約 25% だけキャッシュから返し、残りは取得し直す
+        let requests = 400;
+        for _ in 0..requests {
+            let res = app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let served_from_cache = (requests - upstream.hits()) as f64 / (requests - 1) as f64;
+        assert!((0.15..0.35).contains(&served_from_cache), "served {} of hits from cache", served_from_cache);
+    }
+
+    #[tokio::test]
+    async fn test_cache_invalidation_endpoint_purges_by_path_prefix() {
+        let mut config = test_config(
+            r#"
+            [[routing]]
+            path = "/v1/embeddings"
+            target_model = "text-embedding-3-small"
+            target_url = "http://MOCK_UPSTREAM/v1/embeddings"
+            "#,
+        );
+        config.caching.enabled = true;
+        config.security.admin_keys = vec!["admin".to_string()];
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        for path in ["/v1/chat", "/v1/chat", "/v1/embeddings"] {
+            let body = if path == "/v1/chat" { r#"{"n":1}"# } else { "{}" };
+            app.clone().oneshot(HttpRequest::post(path).body(Body::from(body)).unwrap()).await.unwrap();
+        }
+        app.clone().oneshot(HttpRequest::post("/v1/chat").body(Body::from(r#"{"n":2}"#)).unwrap()).await.unwrap();
+        let purge = |uri: &'static str, key: &'static str| {
+            app.clone().oneshot(HttpRequest::delete(uri).header("x-api-key", key).body(Body::empty()).unwrap())
+        };
+
+        assert_ne!(purge("/v1/cache", "wrong").await.unwrap().status(), StatusCode::OK);
+        let res = purge("/v1/cache?path=/v1/chat", "admin").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(json_body(res).await, serde_json::json!({"invalidated": 2, "path": "/v1/chat"}));
+        assert_eq!(json_body(purge("/v1/cache?path=/v1/chat", "admin").await.unwrap()).await["invalidated"], 0);
+        assert_eq!(json_body(purge("/v1/cache", "admin").await.unwrap()).await["invalidated"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_redis_cache_invalidated_by_path() {
+        let redis = crate::test_support::MockRedis::start().await;
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let state = proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.backend = crate::config::CacheBackend::Redis;
+            config.caching.redis = Some(toml::from_str(&format!("url = \"{}\"", redis.url())).unwrap());
+        });
+        build_app(state.clone()).oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap()).await.unwrap();
+        assert_eq!(redis.keys().len(), 1);
+
+        assert_eq!(state.cache.invalidate(Some("/v1/chat")).await.unwrap(), 0);
+        assert_eq!(state.cache.invalidate(Some("/pro")).await.unwrap(), 0);
+        assert_eq!(state.cache.invalidate(Some("/proxy")).await.unwrap(), 1);
+        assert!(redis.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_redis_degrades_to_cache_miss() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.backend = crate::config::CacheBackend::Redis;
+            config.caching.redis = Some(toml::from_str(&format!("url = \"redis://{}\"", unused)).unwrap());
+        }));
+
+        for _ in 0..2 {
+            let res = app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        assert_eq!(upstream.hits(), 2);
+
+        // 接続設定が無い場合は起動時に拒否する
+        let mut config = test_config("");
+        config.caching.backend = crate::config::CacheBackend::Redis;
+        assert!(AppState::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_route_cache_ttl_overrides_global_ttl() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let mut config = test_config(&format!(
+            "[[routing]]\npath = \"/short\"\ntarget_model = \"gpt-4\"\ntarget_url = \"{url}\"\ncache_ttl_seconds = 1\n\
+             [[routing]]\npath = \"/long\"\ntarget_model = \"text-embedding-3-small\"\ntarget_url = \"{url}\"\ncache_ttl_seconds = 86400",
+            url = upstream.url("/base"),
+        ));
+        config.caching.enabled = true;
+        config.caching.ttl_seconds = 1;
+        let app = build_app(Arc::new(AppState::new(&config).unwrap()));
+        let send = |path: &'static str| app.clone().oneshot(HttpRequest::post(path).body(Body::from("{}")).unwrap());
+
+        for path in ["/short", "/long", "/short", "/long"] {
+            send(path).await.unwrap();
+        }
+        assert_eq!(upstream.hits(), 2);
+
+        // 全体の ttl_seconds より長いルートのエントリも、ルートの TTL まで残る
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        send("/short").await.unwrap();
+        assert_eq!(upstream.hits(), 3, "short-TTL route must expire");
+        send("/long").await.unwrap();
+        assert_eq!(upstream.hits(), 3, "long-TTL route must outlive the global ttl_seconds");
+    }
+
+    #[tokio::test]
+    async fn test_hot_entries_refreshed_ahead_after_expiry() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let state = proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.refresh_ahead_min_hits = Some(2);
+            config.routing.last_mut().unwrap().cache_ttl_seconds = Some(1);
+        });
+        spawn_refresh_ahead(&state).unwrap();
+        let app = build_app(state.clone());
+        let send = |body: &'static str| app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap());
+
+        // hot は2回ヒットし、cold は保存しただけ
+        for body in [r#"{"q":"hot"}"#, r#"{"q":"hot"}"#, r#"{"q":"hot"}"#, r#"{"q":"cold"}"#] {
+            send(body).await.unwrap();
+        }
+        assert_eq!(upstream.hits(), 2);
+
+        // 実時間の TTL で期限切れにして追い出す
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        state.cache.run_pending_tasks().await;
+        let hot = CacheKey::new("/proxy", br#"{"q":"hot"}"#);
+        for _ in 0..100 {
+            if state.cache.get(&hot).await.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(upstream.hits(), 3);
+        let refreshed = &upstream.requests()[2];
+        assert_eq!(refreshed.body, r#"{"q":"hot"}"#);
+
+        // 再取得したエントリは次のリクエストにそのまま返す
+        let res = send(r#"{"q":"hot"}"#).await.unwrap();
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "HIT");
+        assert_eq!(upstream.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stale_refresh_strips_orchix_headers() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let state = proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.stale_while_revalidate_seconds = 60;
+            let route = config.routing.last_mut().unwrap();
+            route.cache_ttl_seconds = Some(1);
+            route.citations = Some(Default::default());
+        });
+        let app = build_app(state.clone());
+        let request = || {
+            HttpRequest::post("/proxy")
+                .header("x-orchix-sources", "[]")
+                .header(TRACE_INTERCEPTION_HEADER, "true")
+                .header(NO_CACHE_HEADER, "false")
+                .body(Body::from("{}"))
+                .unwrap()
+        };
+
+        app.clone().oneshot(request()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let res = app.oneshot(request()).await.unwrap();
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "STALE");
+        for _ in 0..100 {
+            if upstream.hits() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // 通常の転送と同じく、バックグラウンドの再取得にも Orchix 宛てのヘッダーは付けない
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 2);
+        for recorded in &requests {
+            for name in ["x-orchix-sources", TRACE_INTERCEPTION_HEADER, NO_CACHE_HEADER] {
+                assert!(!recorded.headers.contains_key(name), "{} was forwarded upstream", name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stale_entry_is_served_then_refreshed() {
+        // 時計を止めると上流との通信待ちの間に接続タイムアウトまで進んでしまうので、短い TTL を実時間で待つ
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let state = proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.stale_while_revalidate_seconds = 60;
+            config.routing.last_mut().unwrap().cache_ttl_seconds = Some(1);
+        });
+        let app = build_app(state.clone());
+        let body = r#"{"model":"gpt-4"}"#;
+        let key = CacheKey::for_request(&state.caching_config, "POST", "/proxy", None, body.as_bytes());
+        let request = || HttpRequest::post("/proxy").body(Body::from(body)).unwrap();
+
+        let res = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "MISS");
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(state.cache.lookup(&key).await.map(|(_, f)| f), Some(Freshness::Stale));
+
+        let res = app.oneshot(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "STALE");
+
+        // バックグラウンドの再取得が終わるとエントリは新鮮になる
+        for _ in 0..100 {
+            if state.cache.lookup(&key).await.map(|(_, f)| f) == Some(Freshness::Fresh) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(state.cache.lookup(&key).await.map(|(_, f)| f), Some(Freshness::Fresh));
+        assert_eq!(upstream.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_stale_hits_refresh_once() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .respond_with(200, r#"{"ok":true}"#)
+            .delay(Duration::from_millis(200))
+            .start()
+            .await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.stale_while_revalidate_seconds = 60;
+            // 保存した直後から古いエントリとして扱う
+            config.routing.last_mut().unwrap().cache_ttl_seconds = Some(0);
+        }));
+        let request = || HttpRequest::post("/proxy").body(Body::from("{}")).unwrap();
+        assert_eq!(app.clone().oneshot(request()).await.unwrap().headers()[CACHE_STATUS_HEADER], "MISS");
+
+        let stale = futures::future::join_all((0..5).map(|_| app.clone().oneshot(request()))).await;
+        for res in stale {
+            assert_eq!(res.unwrap().headers()[CACHE_STATUS_HEADER], "STALE");
+        }
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(upstream.hits(), 2, "stale hits on the same key share one background refresh");
+    }
+
+    /// `upstream` に転送する `/proxy` ルートを追加した状態
+    fn proxy_state(upstream: &crate::test_support::MockServer, configure: impl FnOnce(&mut AppConfig)) -> Arc<AppState> {
+        let mut config = test_config(&format!(
+            "[[routing]]\npath = \"/proxy\"\ntarget_model = \"gpt-4\"\ntarget_url = \"{}\"",
+            upstream.url("/base"),
+        ));
+        configure(&mut config);
+        Arc::new(AppState::new(&config).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_vary_headers_separate_cache_entries_per_tenant() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.vary_headers = vec!["x-tenant-id".to_string()];
+        }));
+        let send = |tenant: &'static str| {
+            app.clone().oneshot(HttpRequest::post("/proxy").header("x-tenant-id", tenant).body(Body::from("{}")).unwrap())
+        };
+
+        for tenant in ["a", "b", "a"] {
+            assert_eq!(send(tenant).await.unwrap().status(), StatusCode::OK);
+        }
+        // 別のテナントのエントリは共有せず、同じテナントの2回目だけがキャッシュから返る
+        assert_eq!(upstream.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_request_headers_bypass_cache() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| config.caching.enabled = true));
+        let send = |header: Option<(&'static str, &'static str)>| {
+            let mut request = HttpRequest::post("/proxy");
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            app.clone().oneshot(request.body(Body::from("{}")).unwrap())
+        };
+
+        assert_eq!(send(None).await.unwrap().headers()[CACHE_STATUS_HEADER], "MISS");
+        assert_eq!(send(None).await.unwrap().headers()[CACHE_STATUS_HEADER], "HIT");
+        assert_eq!(upstream.hits(), 1);
+
+        for header in [("cache-control", "max-age=0, no-cache"), (NO_CACHE_HEADER, "true")] {
+            let res = send(Some(header)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[CACHE_STATUS_HEADER], "BYPASS", "{:?}", header);
+        }
+        assert_eq!(upstream.hits(), 3, "bypassed requests must reach the upstream");
+        assert!(!upstream.requests()[2].headers.contains_key(NO_CACHE_HEADER));
+        assert_eq!(send(Some((NO_CACHE_HEADER, "false"))).await.unwrap().headers()[CACHE_STATUS_HEADER], "HIT");
+    }
+
+    #[tokio::test]
+    async fn test_route_cache_enabled_overrides_global_setting() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let state = proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            let mut uncached = config.routing.last().unwrap().clone();
+            uncached.path = "/uncached".to_string();
+            uncached.cache_enabled = Some(false);
+            config.routing.push(uncached);
+        });
+        let app = build_app(state);
+        let send = |path: &'static str| app.clone().oneshot(HttpRequest::post(path).body(Body::from("{}")).unwrap());
+
+        assert_eq!(send("/proxy").await.unwrap().headers()[CACHE_STATUS_HEADER], "MISS");
+        assert_eq!(send("/proxy").await.unwrap().headers()[CACHE_STATUS_HEADER], "HIT");
+        assert_eq!(upstream.hits(), 1);
+        for _ in 0..2 {
+            let res = send("/uncached").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(!res.headers().contains_key(CACHE_STATUS_HEADER));
+        }
+        assert_eq!(upstream.hits(), 3, "a route with cache_enabled = false must always reach the upstream");
+
+        // 全体で無効でも、cache_enabled = true のルートはキャッシュする
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.routing.last_mut().unwrap().cache_enabled = Some(true);
+        }));
+        app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap()).await.unwrap();
+        let res = app.oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap()).await.unwrap();
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "HIT");
+        assert_eq!(upstream.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_status_follows_model_matched_route() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            let mut by_model = config.routing.last().unwrap().clone();
+            by_model.match_model = Some("gpt-4o".to_string());
+            by_model.cache_enabled = Some(false);
+            config.routing.push(by_model);
+        }));
+        let send = |model: &str| {
+            let body = serde_json::json!({"model": model}).to_string();
+            app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap())
+        };
+
+        // path だけで照合したルートではなく、`model` で選ばれたルートの設定に従う
+        for _ in 0..2 {
+            assert!(!send("gpt-4o").await.unwrap().headers().contains_key(CACHE_STATUS_HEADER));
+        }
+        assert_eq!(upstream.hits(), 2);
+        assert_eq!(send("gpt-4").await.unwrap().headers()[CACHE_STATUS_HEADER], "MISS");
+        assert_eq!(send("gpt-4").await.unwrap().headers()[CACHE_STATUS_HEADER], "HIT");
+    }
+
+    #[tokio::test]
+    async fn test_uncacheable_upstream_responses_not_stored() {
+        let send = |app: axum::Router| app.oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap());
+
+        let failing = crate::test_support::MockUpstream::new().respond_with(500, r#"{"error":"boom"}"#).start().await;
+        let app = build_app(proxy_state(&failing, |config| config.caching.enabled = true));
+        for _ in 0..2 {
+            let res = send(app.clone()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            // 保存の対象にならないレスポンスには MISS を付けない
+            assert!(!res.headers().contains_key(CACHE_STATUS_HEADER));
+        }
+        assert_eq!(failing.hits(), 2, "an upstream 500 must not be cached");
+
+        for (name, value) in [("set-cookie", "session=abc"), ("cache-control", "private, no-store")] {
+            let upstream = crate::test_support::MockUpstream::new()
+                .respond_with(200, r#"{"ok":true}"#)
+                .header(name, value)
+                .start()
+                .await;
+            let app = build_app(proxy_state(&upstream, |config| config.caching.enabled = true));
+            send(app.clone()).await.unwrap();
+            assert!(!send(app).await.unwrap().headers().contains_key(CACHE_STATUS_HEADER), "{}: {}", name, value);
+            assert_eq!(upstream.hits(), 2);
+        }
+
+        // cacheable_statuses を指定した場合はそのステータスのみ保存する
+        let not_found = crate::test_support::MockUpstream::new().respond_with(404, r#"{"error":"missing"}"#).start().await;
+        let app = build_app(proxy_state(&not_found, |config| {
+            config.caching.enabled = true;
+            config.caching.cacheable_statuses = Some(vec![200, 404]);
+        }));
+        send(app.clone()).await.unwrap();
+        assert_eq!(send(app).await.unwrap().headers()[CACHE_STATUS_HEADER], "HIT");
+        assert_eq!(not_found.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_test_handler_reports_cache_status() {
+        let mut config = test_config("");
+        config.caching.enabled = true;
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let app = build_app(state.clone());
+        let stream = |no_cache: bool| {
+            let mut request = HttpRequest::get("/v1/stream_test");
+            if no_cache {
+                request = request.header("cache-control", "no-cache");
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(stream(false).await.unwrap().headers()[CACHE_STATUS_HEADER], "MISS");
+
+        let key = CacheKey::for_request(&state.caching_config, "GET", "/v1/stream_test", None, &[]);
+        let headers = std::collections::HashMap::from([("content-type".to_string(), "text/event-stream".to_string())]);
+        state.cache.set(key, CachedResponse { status: 200, headers, body: Bytes::from_static(b"data: [DONE]\n\n") }).await;
+        let res = stream(false).await.unwrap();
+        assert_eq!(res.headers()[CACHE_STATUS_HEADER], "HIT");
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), "data: [DONE]\n\n");
+        assert_eq!(stream(true).await.unwrap().headers()[CACHE_STATUS_HEADER], "BYPASS");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_requests_bypass_cache() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.cache_max_request_bytes = Some(32);
+        }));
+        let send = |body: String| app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap());
+
+        let small = r#"{"model":"gpt-4"}"#.to_string();
+        for expected in ["MISS", "HIT"] {
+            let res = send(small.clone()).await.unwrap();
+            assert_eq!(res.headers()[CACHE_STATUS_HEADER], expected);
+        }
+        assert_eq!(upstream.hits(), 1, "small requests must be served from the cache");
+
+        let large = format!(r#"{{"model":"gpt-4","prompt":"{}"}}"#, "x".repeat(64));
+        for _ in 0..2 {
+            let res = send(large.clone()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[CACHE_STATUS_HEADER], "BYPASS");
+        }
+        assert_eq!(upstream.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_oauth_token_injected_and_reused() {
+        let token_endpoint = crate::test_support::MockUpstream::new()
+            .respond_with(200, r#"{"access_token":"tok-1","expires_in":3600}"#)
+            .start()
+            .await;
+        let upstream = crate::test_support::MockUpstream::new().start().await;
+        let oauth: crate::upstream_auth::UpstreamOAuthConfig = toml::from_str(&format!(
+            "token_url = \"{}\"\nclient_id = \"orchix\"\nclient_secret = \"s3cret\"",
+            token_endpoint.url("/oauth/token"),
+        ))
+        .unwrap();
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.routing.last_mut().unwrap().upstream_oauth = Some(oauth);
+        }));
+
+        for _ in 0..2 {
+            let res = app.clone()
+                .oneshot(HttpRequest::post("/proxy").header("x-api-key", "client-key").body(Body::from("{}")).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        assert_eq!(token_endpoint.hits(), 1);
+        for request in upstream.requests() {
+            assert_eq!(request.headers["authorization"], "Bearer tok-1");
+            assert!(!request.headers.contains_key("x-api-key"));
+        }
+
+        // トークンを取得できない場合は上流に送らない
+        let failing = crate::test_support::MockUpstream::new().respond_with(500, "down").start().await;
+        let oauth = toml::from_str(&format!(
+            "token_url = \"{}\"\nclient_id = \"orchix\"\nclient_secret = \"s3cret\"",
+            failing.url("/oauth/token"),
+        ))
+        .unwrap();
+        let res = build_app(proxy_state(&upstream, |config| {
+            config.routing.last_mut().unwrap().upstream_oauth = Some(oauth);
+        }))
+        .oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap())
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(error_code(res).await, "upstream_auth_failed");
+        assert_eq!(upstream.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shadow_copy_omits_upstream_oauth_token() {
+        let token_endpoint = crate::test_support::MockUpstream::new()
+            .respond_with(200, r#"{"access_token":"tok-1","expires_in":3600}"#)
+            .start()
+            .await;
+        let upstream = crate::test_support::MockUpstream::new().start().await;
+        let shadow = crate::test_support::MockUpstream::new().start().await;
+        let oauth: crate::upstream_auth::UpstreamOAuthConfig = toml::from_str(&format!(
+            "token_url = \"{}\"\nclient_id = \"orchix\"\nclient_secret = \"s3cret\"",
+            token_endpoint.url("/oauth/token"),
+        ))
+        .unwrap();
+        let app = build_app(proxy_state(&upstream, |config| {
+            let route = config.routing.last_mut().unwrap();
+            route.upstream_oauth = Some(oauth);
+            route.shadow_url = Some(shadow.url("/shadow"));
+        }));
+
+        let res = app
+            .oneshot(HttpRequest::post("/proxy").header("x-api-key", "client-key").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(upstream.requests()[0].headers["authorization"], "Bearer tok-1");
+        while shadow.hits() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let mirrored = &shadow.requests()[0];
+        assert!(!mirrored.headers.contains_key("authorization"), "the shadow must not receive upstream credentials");
+        assert!(!mirrored.headers.contains_key("x-api-key"));
+    }
+
+    #[tokio::test]
+    async fn test_request_forwarded_to_joined_upstream_url() {
+        let upstream = crate::test_support::MockUpstream::new().start().await;
+        let state = proxy_state(&upstream, |config| config.upstream_metadata.route = true);
+
+        let res = build_app(state)
+            .oneshot(
+                HttpRequest::put("/proxy/items?limit=1")
+                    .header("x-custom", "kept")
+                    .header("connection", "x-custom-hop")
+                    .header("x-custom-hop", "dropped")
+                    .body(Body::from("hello"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let recorded = upstream.requests();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].method, axum::http::Method::PUT);
+        assert_eq!(recorded[0].path, "/base/items?limit=1");
+        assert_eq!(recorded[0].body, "hello");
+        assert_eq!(recorded[0].headers["x-custom"], "kept");
+        assert!(!recorded[0].headers.contains_key("x-custom-hop"));
+        assert_eq!(recorded[0].headers[UPSTREAM_ROUTE_HEADER], "/proxy");
+        // 経由回数は有効にしない限り送らない
+        assert!(!recorded[0].headers.contains_key(HOPS_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_loop_rejected_after_max_hops() {
+        // 自分自身の待ち受けアドレスへ転送するルートで、ループを再現する
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = test_config(&format!(
+            "[[routing]]\npath = \"/proxy\"\ntarget_model = \"gpt-4\"\ntarget_url = \"http://{}/proxy\"",
+            addr,
+        ));
+        config.server.max_hops = 3;
+        config.upstream_metadata.hops = true;
+        assert_eq!(
+            crate::routing::self_referential_targets(&config.routing, "127.0.0.1", addr.port()),
+            vec![("/proxy".to_string(), format!("http://{}/proxy", addr))],
+        );
+        let state = Arc::new(AppState::new(&config).unwrap());
+        let server = build_app(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, server).await.unwrap() });
+
+        let res = build_app(state)
+            .oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::LOOP_DETECTED);
+        assert_eq!(error_code(res).await, "loop_detected");
+    }
+
+    /// 1リクエストだけ受け付け、受信したリクエストの生のヘッダー部分を返す上流
+    async fn raw_upstream() -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/base", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}").await.unwrap();
+            head
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn test_header_case_applied_to_upstream_request() {
+        for (header_case, expected, unexpected) in [
+            ("title_case", "X-Custom-Header: kept", "x-custom-header:"),
+            ("lowercase", "x-custom-header: kept", "X-Custom-Header:"),
+        ] {
+            let (url, upstream) = raw_upstream().await;
+            let config = test_config(&format!(
+                "[[routing]]\npath = \"/proxy\"\ntarget_model = \"gpt-4\"\ntarget_url = \"{}\"\nheader_case = \"{}\"",
+                url, header_case,
+            ));
+            let state = Arc::new(AppState::new(&config).unwrap());
+            let res = build_app(state)
+                .oneshot(HttpRequest::post("/proxy").header("x-custom-header", "kept").body(Body::from("{}")).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let head = upstream.await.unwrap();
+            assert!(head.contains(expected), "{}: {}", header_case, head);
+            assert!(!head.contains(unexpected), "{}: {}", header_case, head);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_status_and_headers_preserved() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .respond_with(418, r#"{"error":"teapot"}"#)
+            .header("x-upstream", "mock")
+            .header("x-hop", "1")
+            .header("connection", "x-hop")
+            .start()
+            .await;
+        let state = proxy_state(&upstream, |config| config.caching.enabled = true);
+
+        let res = build_app(state.clone())
+            .oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(res.headers()["x-upstream"], "mock");
+        // `Connection` で指定された接続ごとのヘッダーは返さない
+        assert!(res.headers().get("x-hop").is_none());
+        assert!(res.headers().get(axum::http::header::CONNECTION).is_none());
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), r#"{"error":"teapot"}"#);
+        let key = CacheKey::for_request(&state.caching_config, "POST", "/proxy", None, b"{}");
+        assert!(state.cache.get(&key).await.is_none(), "upstream errors must not be cached");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_redirects_not_followed() {
+        let elsewhere = crate::test_support::MockUpstream::new().start().await;
+        let upstream = crate::test_support::MockUpstream::new()
+            .respond_with(302, "")
+            .header("location", &elsewhere.url("/v1/chat/completions"))
+            .start()
+            .await;
+        let res = build_app(proxy_state(&upstream, |_| {}))
+            .oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()["location"], elsewhere.url("/v1/chat/completions"));
+        assert_eq!(elsewhere.hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_upstream_hits_read_timeout() {
+        let upstream = crate::test_support::MockUpstream::new().delay(Duration::from_secs(5)).start().await;
+        let state = proxy_state(&upstream, |config| config.server.upstream_read_timeout_secs = 1);
+        let started = Instant::now();
+        let res = build_app(state)
+            .oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_upstream_returns_502() {
+        let app = build_app(test_state(r#"
+            [[routing]]
+            path = "/down"
+            target_model = "gpt-4"
+            target_url = "http://127.0.0.1:9"
+        "#));
+        let res = app
+            .oneshot(HttpRequest::post("/down").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(error_code(res).await, "upstream_unreachable");
+    }
+
+    #[tokio::test]
+    async fn test_failure_response_returned_when_upstreams_fail() {
+        let failing = crate::test_support::MockUpstream::new().respond_with(500, "internal error").start().await;
+        let failure = r#"
+            [routing.failure_response]
+            status = 503
+            body = '{"id":"unavailable","object":"chat.completion","choices":[{"index":0,"message":{"role":"assistant","content":"Service temporarily unavailable"},"finish_reason":"stop"}]}'
+        "#;
+        let app = build_app(test_state(&format!(
+            "[[routing]]\npath = \"/down\"\ntarget_model = \"gpt-4\"\ntarget_url = \"http://127.0.0.1:9\"\n{failure}\n\
+             [[routing]]\npath = \"/erroring\"\ntarget_model = \"gpt-4\"\ntarget_url = \"{}\"\n{failure}",
+            failing.url("/v1"),
+        )));
+
+        for path in ["/down", "/erroring"] {
+            let res = app
+                .clone()
+                .oneshot(HttpRequest::post(path).body(Body::from("{}")).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE, "{}", path);
+            assert_eq!(res.headers()["content-type"], "application/json");
+            let body = json_body(res).await;
+            assert_eq!(body["choices"][0]["message"]["content"], "Service temporarily unavailable");
+        }
+        assert_eq!(failing.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_model_in_body_selects_route() {
+        let openai = crate::test_support::MockUpstream::new().respond_with(200, "openai").start().await;
+        let anthropic = crate::test_support::MockUpstream::new().respond_with(200, "anthropic").start().await;
+        let app = build_app(test_state(&format!(
+            "[[routing]]\npath = \"/llm/completions\"\nmatch_model = \"claude-3\"\ntarget_model = \"claude-3\"\ntarget_url = \"{}\"\n\
+             [[routing]]\npath = \"/llm/completions\"\ntarget_model = \"gpt-4\"\ntarget_url = \"{}\"",
+            anthropic.url("/v1"),
+            openai.url("/v1"),
+        )));
+
+        for (body, expected) in [
+            (r#"{"model":"claude-3"}"#, "anthropic"),
+            (r#"{"model":"gpt-4"}"#, "openai"),
+            (r#"{"messages":[]}"#, "openai"),
+        ] {
+            let res = app
+                .clone()
+                .oneshot(HttpRequest::post("/llm/completions").body(Body::from(body)).unwrap())
+                .await
+                .unwrap();
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(bytes, expected, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_rejected_on_non_streaming_route() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, "{}").start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.routing.last_mut().unwrap().allow_streaming = false;
+        }));
+        let send = |body: &'static str| app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap());
+
+        let res = send(r#"{"stream":true}"#).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res).await, "streaming_not_supported");
+        assert_eq!(upstream.hits(), 0);
+
+        assert_eq!(send(r#"{"stream":false}"#).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(upstream.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_rewritten_off_when_forced() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, "{}").start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            let rule = config.routing.last_mut().unwrap();
+            rule.allow_streaming = false;
+            rule.force_stream_off = true;
+        }));
+
+        let res = app
+            .oneshot(
+                HttpRequest::post("/proxy")
+                    .body(Body::from(r#"{"model":"gpt-4","stream":true,"stream_options":{"include_usage":true}}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let forwarded: serde_json::Value = serde_json::from_slice(&upstream.requests()[0].body).unwrap();
+        assert_eq!(forwarded, serde_json::json!({"model": "gpt-4", "stream": false}));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_upstream_response_is_analyzed() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .stream_events([r#"{"choices":[{"index":0,"delta":{"content":"streamed"}}]}"#, "[DONE]"])
+            .start()
+            .await;
+        let res = build_app(proxy_state(&upstream, |_| {}))
+            .oneshot(HttpRequest::post("/proxy").body(Body::from(r#"{"stream":true}"#)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        let body = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(body.contains("streamed"), "{}", body);
+        assert!(body.contains("data: [DONE]"), "{}", body);
+        assert!(body.contains(crate::streaming::USAGE_EVENT), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_cost_uses_substituted_model() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .stream_events([r#"{"choices":[{"index":0,"delta":{"content":"streamed"}}]}"#, "[DONE]"])
+            .start()
+            .await;
+        let mut config = test_config(&format!(
+            "[[routing]]\npath = \"/models/(?P<model>[^/]+)\"\nmatch_type = \"regex\"\ntarget_model = \"{{model}}\"\ntarget_url = \"{}\"",
+            upstream.url("/base"),
+        ));
+        config.cost.prices.insert(
+            "llama-3".to_string(),
+            crate::cost_control::ModelPrice { prompt_per_1k: 1.0, completion_per_1k: 2.0 },
+        );
+        let res = build_app(Arc::new(AppState::new(&config).unwrap()))
+            .oneshot(HttpRequest::post("/models/llama-3").body(Body::from(r#"{"stream":true}"#)).unwrap())
+            .await
+            .unwrap();
+        let body = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+
+        // 単価はテンプレート（`{model}`）ではなく代入後のモデル名で引く
+        let trailer = body.split(&format!("event: {}\n", crate::streaming::USAGE_EVENT)).nth(1).expect("usage event");
+        let data: serde_json::Value =
+            serde_json::from_str(trailer.lines().next().unwrap().trim_start_matches("data: ")).unwrap();
+        assert!(data["estimated_cost"].as_f64().is_some(), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_tap_does_not_buffer_streamed_responses() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .stream_events([r#"{"choices":[{"index":0,"delta":{"content":"tapped"}}]}"#, "[DONE]"])
+            .event_interval(Duration::from_millis(500))
+            .start()
+            .await;
+        let state = proxy_state(&upstream, |config| config.tap.enabled = true);
+        let mut rx = state.tap.subscribe();
+        let request = HttpRequest::post("/proxy").body(Body::from(r#"{"stream":true}"#)).unwrap();
+
+        // ボディを読み終える前にレスポンスが返る
+        let res = tokio::time::timeout(Duration::from_millis(400), build_app(state).oneshot(request))
+            .await
+            .expect("streamed response is not buffered by the tap")
+            .unwrap();
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.status, 200);
+        assert!(event.response_excerpt.is_empty());
+        let body = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(body.contains("tapped"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_upstream_headers_are_all_forwarded() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .respond_with(200, r#"{"ok":true}"#)
+            .append_header("set-cookie", "a=1")
+            .append_header("set-cookie", "b=2")
+            .append_header("link", "</one>; rel=preload")
+            .append_header("link", "</two>; rel=preload")
+            .start()
+            .await;
+        let app = build_app(proxy_state(&upstream, |_| {}));
+        let res = app.oneshot(HttpRequest::post("/proxy").body(Body::from("{}")).unwrap()).await.unwrap();
+
+        let values = |name: &str| -> Vec<String> {
+            res.headers().get_all(name).iter().map(|v| v.to_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(values("set-cookie"), ["a=1", "b=2"]);
+        assert_eq!(values("link"), ["</one>; rel=preload", "</two>; rel=preload"]);
+        assert_eq!(values("content-type"), ["application/json"]);
+    }
+
+    #[tokio::test]
+    async fn test_cached_stream_replayed_as_discrete_events() {
+        let events = [
+            r#"{"choices":[{"index":0,"delta":{"content":"Hello"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":" world"}}]}"#,
+            "[DONE]",
+        ];
+        let upstream = crate::test_support::MockUpstream::new().stream_events(events).start().await;
+        let state = proxy_state(&upstream, |config| config.caching.enabled = true);
+        let app = build_app(state.clone());
+        let body = r#"{"stream":true}"#;
+        // ボディをフレーム単位で読み、1つの塊ではなくイベントごとに届くことを確認する
+        let send = || async {
+            let res = app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap()).await.unwrap();
+            let content_type = res.headers()["content-type"].clone();
+            let frames: Vec<Bytes> = futures::StreamExt::collect::<Vec<_>>(res.into_body().into_data_stream())
+                .await
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+            (content_type, frames)
+        };
+
+        send().await;
+        let key = CacheKey::for_request(&state.caching_config, "POST", "/proxy", None, body.as_bytes());
+        for _ in 0..100 {
+            if state.cache.get(&key).await.is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        let (content_type, replayed) = send().await;
+        assert_eq!(upstream.hits(), 1);
+        assert_eq!(content_type, "text/event-stream");
+        let replayed: Vec<String> = replayed.iter().map(|frame| String::from_utf8(frame.to_vec()).unwrap()).collect();
+        assert_eq!(replayed, events.map(|data| format!("data: {}\n\n", data)));
+    }
+
+    #[tokio::test]
+    async fn test_cached_anthropic_stream_replays_translated_events() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .stream_events([
+                r#"{"type":"message_start","message":{"id":"msg_1","model":"claude-3"}}"#,
+                r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"hello"}}"#,
+                r#"{"type":"message_stop"}"#,
+            ])
+            .start()
+            .await;
+        let state = proxy_state(&upstream, |config| config.caching.enabled = true);
+        let app = build_app(state.clone());
+        let body = r#"{"stream":true}"#;
+        let send = || async {
+            let res = app.clone().oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap()).await.unwrap();
+            let cache = res.headers().get(CACHE_STATUS_HEADER).cloned();
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            (cache, String::from_utf8(bytes.to_vec()).unwrap())
+        };
+
+        let (_, first) = send().await;
+        let key = CacheKey::for_request(&state.caching_config, "POST", "/proxy", None, body.as_bytes());
+        for _ in 0..100 {
+            if state.cache.get(&key).await.is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        // キャッシュからの再生も初回と同じ OpenAI 形式のイベントになる
+        let (cache, replayed) = send().await;
+        assert_eq!(upstream.hits(), 1);
+        assert_eq!(cache.as_ref().map(|value| value.to_str().unwrap()), Some("HIT"));
+        // 使用量のトレーラーはその応答限りのものなので保存しない
+        assert!(first.starts_with(&replayed), "{}", first);
+        assert!(replayed.contains(r#""content":"hello""#), "{}", replayed);
+        assert!(replayed.contains("data: [DONE]"), "{}", replayed);
+        assert!(!replayed.contains("content_block_delta"), "{}", replayed);
+    }
+
+    #[tokio::test]
+    async fn test_interception_trace_returned_to_admins_only() {
+        let upstream = crate::test_support::MockUpstream::new().respond_with(200, r#"{"ok":true}"#).start().await;
+        let app = build_app(proxy_state(&upstream, |config| config.security.admin_keys = vec!["admin".to_string()]));
+        let send = |key: &'static str, tool: &str| {
+            let body = serde_json::json!({"tool_calls": [{"function": {"name": tool, "arguments": "{}"}}]}).to_string();
+            app.clone().oneshot(
+                HttpRequest::post("/proxy")
+                    .header("x-api-key", key)
+                    .header(TRACE_INTERCEPTION_HEADER, "1")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let trace_of = |res: &Response| -> serde_json::Value {
+            serde_json::from_str(res.headers()[INTERCEPTION_TRACE_HEADER].to_str().unwrap()).unwrap()
+        };
+
+        let res = send("admin", "rm_rf").await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let trace = trace_of(&res);
+        assert_eq!(trace["blocked_by"], "tool_calls");
+        let stages: Vec<_> = trace["stages"].as_array().unwrap().iter().map(|s| (s["stage"].clone(), s["verdict"].clone())).collect();
+        assert_eq!(stages, [
+            ("json_limits".into(), "pass".into()),
+            ("tool_definitions".into(), "pass".into()),
+            ("tool_calls".into(), "block".into()),
+        ]);
+
+        let res = send("admin", "search").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(trace_of(&res)["blocked_by"], serde_json::Value::Null);
+        // トレースの要求は上流に転送しない
+        assert!(!upstream.requests()[0].headers.contains_key(TRACE_INTERCEPTION_HEADER));
+
+        let res = send("user", "rm_rf").await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(!res.headers().contains_key(INTERCEPTION_TRACE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_stream_not_cached() {
+        // `[DONE]` の前に上流が接続を閉じる
+        let upstream = crate::test_support::MockUpstream::new()
+            .stream_events([r#"{"choices":[{"index":0,"delta":{"content":"partial"}}]}"#])
+            .start()
+            .await;
+        // 保存の有無を確かめられるよう Redis に保存する
+        let redis = crate::test_support::MockRedis::start().await;
+        let state = proxy_state(&upstream, |config| {
+            config.caching.enabled = true;
+            config.caching.backend = crate::config::CacheBackend::Redis;
+            config.caching.redis = Some(toml::from_str(&format!("url = \"{}\"", redis.url())).unwrap());
+        });
+        let send = || async {
+            let res = build_app(state.clone())
+                .oneshot(HttpRequest::post("/proxy").body(Body::from(r#"{"stream":true}"#)).unwrap())
+                .await
+                .unwrap();
+            let status = res.headers().get(CACHE_STATUS_HEADER).cloned();
+            axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            status
+        };
+
+        assert_eq!(send().await.unwrap(), "MISS");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(redis.keys().is_empty(), "{:?}", redis.keys());
+        assert_eq!(send().await.unwrap(), "MISS");
+        assert_eq!(upstream.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_interception_trace_sent_as_trailing_stream_event() {
+        let upstream = crate::test_support::MockUpstream::new()
+            .stream_events([r#"{"choices":[{"index":0,"delta":{"content":"streamed"}}]}"#, "[DONE]"])
+            .start()
+            .await;
+        let app = build_app(proxy_state(&upstream, |config| config.security.admin_keys = vec!["admin".to_string()]));
+        let res = app
+            .oneshot(
+                HttpRequest::post("/proxy")
+                    .header("x-api-key", "admin")
+                    .header(TRACE_INTERCEPTION_HEADER, "1")
+                    .body(Body::from(r#"{"stream":true}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(!res.headers().contains_key(INTERCEPTION_TRACE_HEADER));
+        let body = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        let trace = body
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("event: orchix\ndata: "))
+            .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap())
+            .find(|data| data["type"] == "interception_trace")
+            .expect(&body);
+        let last = trace["stages"].as_array().unwrap().last().unwrap().clone();
+        assert_eq!(last, serde_json::json!({"stage": "stream_content", "verdict": "pass"}));
+        assert!(body.find("interception_trace").unwrap() < body.find("data: [DONE]").unwrap(), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_route_citations_attached_to_buffered_and_streamed_responses() {
+        let sources = r#"[{"title":"Handbook","url":"https://example.com/handbook"}]"#;
+        let request = |body: &'static str| {
+            HttpRequest::post("/proxy").header("x-orchix-sources", sources).body(Body::from(body)).unwrap()
+        };
+        let with_citations = |config: &mut AppConfig| {
+            config.routing.last_mut().unwrap().citations = Some(Default::default());
+        };
+
+        let buffered = crate::test_support::MockUpstream::new()
+            .respond_with(200, r#"{"id":"chatcmpl-1","choices":[{"message":{"content":"answer"}}]}"#)
+            .start()
+            .await;
+        let res = build_app(proxy_state(&buffered, with_citations)).oneshot(request("{}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = json_body(res).await;
+        assert_eq!(body["choices"][0]["message"]["content"], "answer");
+        assert_eq!(body["sources"][0]["url"], "https://example.com/handbook");
+        assert!(!buffered.requests()[0].headers.contains_key("x-orchix-sources"), "sources are not forwarded upstream");
+
+        let streamed = crate::test_support::MockUpstream::new()
+            .stream_events([r#"{"choices":[{"index":0,"delta":{"content":"answer"}}]}"#, "[DONE]"])
+            .start()
+            .await;
+        let res = build_app(proxy_state(&streamed, with_citations)).oneshot(request(r#"{"stream":true}"#)).await.unwrap();
+        let body = String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        let events: Vec<&str> = body.split("\n\n").filter(|e| !e.is_empty()).collect();
+        let done = events.iter().position(|e| *e == "data: [DONE]").unwrap();
+        let trailing = events[..done].iter().find(|e| e.contains(r#""type":"sources""#)).expect(&body);
+        assert!(trailing.starts_with("event: orchix\n"), "{}", body);
+        assert!(trailing.contains("https://example.com/handbook"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_openai_request_translated_for_anthropic_route() {
+        let upstream = crate::test_support::MockUpstream::new().start().await;
+        let app = build_app(proxy_state(&upstream, |config| {
+            config.routing.last_mut().unwrap().provider = crate::request_format::Provider::Anthropic;
+        }));
+        let body = r#"{"model":"claude-3","messages":[{"role":"system","content":"Be brief."},{"role":"user","content":"hi"}]}"#;
+        let res = app.oneshot(HttpRequest::post("/proxy").body(Body::from(body)).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let forwarded: serde_json::Value = serde_json::from_slice(&upstream.requests()[0].body).unwrap();
+        assert_eq!(forwarded["system"], "Be brief.");
+        assert_eq!(forwarded["messages"], serde_json::json!([{"role": "user", "content": [{"type": "text", "text": "hi"}]}]));
+        assert_eq!(forwarded["max_tokens"], crate::request_format::DEFAULT_ANTHROPIC_MAX_TOKENS);
+    }
+
+    /// 受け取ったイベントをメモリに溜める送信先
+    #[derive(Default)]
+    struct MemorySink(std::sync::Mutex<Vec<RequestEvent>>);
+
+    impl EventSink for MemorySink {
+        fn publish(&self, event: RequestEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_event_published_per_request() {
+        let sink = Arc::new(MemorySink::default());
+        let mut state = AppState::new(&test_config("")).unwrap();
+        state.event_sink = sink.clone();
+        let app = build_app(Arc::new(state));
+        let post = |body: &'static str| app.clone().oneshot(HttpRequest::post("/v1/chat").body(Body::from(body)).unwrap());
+
+        post(r#"{"model":"gpt-4"}"#).await.unwrap();
+        post(r#"{"tool_calls":[{"function":{"name":"rm_rf"}}]}"#).await.unwrap();
+
+        let events = sink.0.lock().unwrap().clone();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].route.as_deref(), Some("/v1/chat"));
+        assert_eq!(events[0].status, 200);
+        assert!(events[0].prompt_tokens.is_some() && events[0].completion_tokens.is_some());
+        assert!(!events[0].blocked);
+        assert_eq!(events[1].status, 403);
+        assert!(events[1].blocked);
+    }
 }
@@ -0,0 +1,142 @@
+use axum::http::HeaderMap;
+use serde::Deserialize;
+use schemars::JsonSchema;
+use serde_json::Value;
+use tracing::warn;
+
+/// レスポンス中の推論過程（chain-of-thought）を表すフィールド
+const REASONING_FIELDS: &[&str] = &["reasoning_content", "reasoning", "thinking"];
+
+/// 非ストリーミングのレスポンスから推論過程を取り除き、取り除いた内容を返します
+///
+/// OpenAI 互換の `choices[].message` のフィールドと、Anthropic 形式の
+/// `content[]` 内の `thinking` / `redacted_thinking` ブロックが対象です。
+pub fn strip_reasoning(response: &mut Value) -> Vec<String> {
+    let mut removed = Vec::new();
+
+    if let Some(choices) = response.get_mut("choices").and_then(|v| v.as_array_mut()) {
+        for message in choices.iter_mut().filter_map(|c| c.get_mut("message").and_then(|m| m.as_object_mut())) {
+            for field in REASONING_FIELDS {
+                if let Some(value) = message.remove(*field) {
+                    removed.push(as_text(value));
+                }
+            }
+        }
+    }
+
+    if let Some(blocks) = response.get_mut("content").and_then(|v| v.as_array_mut()) {
+        blocks.retain(|block| {
+            let kind = block.get("type").and_then(|t| t.as_str());
+            if !matches!(kind, Some("thinking") | Some("redacted_thinking")) {
+                return true;
+            }
+            let text = block.get("thinking").or_else(|| block.get("data")).cloned().unwrap_or_default();
+            removed.push(as_text(text));
+            false
+        });
+    }
+
+    removed
+}
+
+fn as_text(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+/// 検索結果などの出典をレスポンスに付与する設定（ルートごと）
+///
+/// 出典はリクエストヘッダーに JSON の配列で渡します（前段の検索処理が設定する想定）。
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(default)]
+pub struct CitationConfig {
+    /// 出典を受け取るリクエストヘッダー（上流へは転送しない）
+    pub header: String,
+    /// レスポンスに追加するフィールド名
+    pub field: String,
+}
+
+impl Default for CitationConfig {
+    fn default() -> Self {
+        Self { header: "x-orchix-sources".to_string(), field: "sources".to_string() }
+    }
+}
+
+impl CitationConfig {
+    /// リクエストヘッダーから出典の配列を取り出します（無い・配列でない場合は None）
+    pub fn sources(&self, headers: &HeaderMap) -> Option<Value> {
+        let raw = headers.get(self.header.as_str())?;
+        match serde_json::from_slice::<Value>(raw.as_bytes()) {
+            Ok(sources @ Value::Array(_)) => Some(sources),
+            _ => {
+                warn!("Ignoring {} header that is not a JSON array", self.header);
+                None
+            }
+        }
+    }
+}
+
+/// 非ストリーミングのレスポンス（JSON オブジェクト）に出典のフィールドを追加します。追加した場合は true
+pub fn attach_sources(response: &mut Value, field: &str, sources: &Value) -> bool {
+    match response.as_object_mut() {
+        Some(object) => {
+            object.insert(field.to_string(), sources.clone());
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_openai_reasoning_removed_and_returned() {
+        let mut response = json!({
+            "choices": [{"message": {"role": "assistant", "content": "42", "reasoning_content": "6 * 7 = 42"}}]
+        });
+        let removed = strip_reasoning(&mut response);
+        assert_eq!(removed, vec!["6 * 7 = 42"]);
+        assert_eq!(response, json!({"choices": [{"message": {"role": "assistant", "content": "42"}}]}));
+    }
+
+    #[test]
+    fn test_anthropic_thinking_blocks_removed() {
+        let mut response = json!({
+            "content": [
+                {"type": "thinking", "thinking": "Let me think..."},
+                {"type": "text", "text": "Answer"}
+            ]
+        });
+        assert_eq!(strip_reasoning(&mut response), vec!["Let me think..."]);
+        assert_eq!(response["content"], json!([{"type": "text", "text": "Answer"}]));
+    }
+
+    #[test]
+    fn test_response_without_reasoning_is_untouched() {
+        let mut response = json!({"choices": [{"message": {"content": "hi"}}]});
+        let before = response.clone();
+        assert!(strip_reasoning(&mut response).is_empty());
+        assert_eq!(response, before);
+    }
+
+    #[test]
+    fn test_sources_read_from_header_and_attached() {
+        let config = CitationConfig::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-orchix-sources", r#"[{"title":"Doc","url":"https://example.com/doc"}]"#.parse().unwrap());
+        let sources = config.sources(&headers).unwrap();
+
+        let mut response = json!({"id": "chatcmpl-1", "choices": []});
+        assert!(attach_sources(&mut response, &config.field, &sources));
+        assert_eq!(response["sources"][0]["url"], "https://example.com/doc");
+        assert!(!attach_sources(&mut json!("text"), &config.field, &sources));
+
+        headers.insert("x-orchix-sources", "not json".parse().unwrap());
+        assert_eq!(config.sources(&headers), None);
+    }
+}
@@ -0,0 +1,266 @@
+use serde::Deserialize;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+/// リクエストの `max_tokens` が無い場合に Anthropic へ送る値（Anthropic では必須）
+pub const DEFAULT_ANTHROPIC_MAX_TOKENS: u64 = 4096;
+
+/// ルートの転送先のプロバイダー（OpenAI 形式のリクエストをこの形式に変換して送る）
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    /// OpenAI 互換（変換しない）
+    #[default]
+    Openai,
+    /// Anthropic Messages API
+    Anthropic,
+}
+
+impl Provider {
+    /// OpenAI 形式のチャットのリクエストを転送先の形式に変換します
+    ///
+    /// 変換不要な場合（OpenAI 互換のルート、既に転送先の形式のリクエスト）は None を返します。
+    pub fn translate_request(self, body: &Value) -> Option<Value> {
+        match self {
+            Self::Openai => None,
+            Self::Anthropic => is_openai_chat(body).then(|| openai_to_anthropic(body)),
+        }
+    }
+}
+
+/// OpenAI のチャット形式のリクエストか（Anthropic 固有の書き方を含むものは対象外）
+fn is_openai_chat(body: &Value) -> bool {
+    let Some(messages) = body.get("messages").and_then(|m| m.as_array()) else {
+        return false;
+    };
+    let anthropic_tools = body
+        .get("tools")
+        .and_then(|t| t.as_array())
+        .is_some_and(|tools| tools.iter().any(|tool| tool.get("input_schema").is_some()));
+    let anthropic_blocks = messages
+        .iter()
+        .filter_map(|m| m.get("content").and_then(|c| c.as_array()))
+        .flatten()
+        .any(|block| matches!(block.get("type").and_then(|t| t.as_str()), Some("tool_use" | "tool_result" | "image")));
+    body.get("system").is_none() && !anthropic_tools && !anthropic_blocks
+}
+
+fn openai_to_anthropic(body: &Value) -> Value {
+    let mut out = Map::new();
+    if let Some(model) = body.get("model") {
+        out.insert("model".to_string(), model.clone());
+    }
+
+    // system / developer のメッセージはトップレベルの system にまとめる
+    let mut system = Vec::new();
+    let mut messages: Vec<Value> = Vec::new();
+    for message in body.get("messages").and_then(|m| m.as_array()).into_iter().flatten() {
+        let role = message.get("role").and_then(|r| r.as_str()).unwrap_or("user");
+        let (role, blocks) = match role {
+            "system" | "developer" => {
+                system.push(text_of(message.get("content")));
+                continue;
+            }
+            "assistant" => ("assistant", assistant_blocks(message)),
+            "tool" => ("user", vec![json!({
+                "type": "tool_result",
+                "tool_use_id": message.get("tool_call_id").cloned().unwrap_or_default(),
+                "content": text_of(message.get("content")),
+            })]),
+            _ => ("user", content_blocks(message.get("content"))),
+        };
+        // Anthropic は同じ役割のメッセージの連続を受け付けないため、1つにまとめる
+        match messages.last_mut() {
+            Some(last) if last["role"] == role => {
+                if let Some(content) = last["content"].as_array_mut() {
+                    content.extend(blocks);
+                }
+            }
+            _ => messages.push(json!({"role": role, "content": blocks})),
+        }
+    }
+    if !system.is_empty() {
+        out.insert("system".to_string(), Value::String(system.join("\n\n")));
+    }
+    out.insert("messages".to_string(), Value::Array(messages));
+
+    let max_tokens = body.get("max_completion_tokens").or_else(|| body.get("max_tokens")).cloned();
+    out.insert("max_tokens".to_string(), max_tokens.unwrap_or(json!(DEFAULT_ANTHROPIC_MAX_TOKENS)));
+    for field in ["temperature", "top_p", "stream"] {
+        if let Some(value) = body.get(field) {
+            out.insert(field.to_string(), value.clone());
+        }
+    }
+    match body.get("stop") {
+        Some(Value::String(stop)) => {
+            out.insert("stop_sequences".to_string(), json!([stop]));
+        }
+        Some(stop @ Value::Array(_)) => {
+            out.insert("stop_sequences".to_string(), stop.clone());
+        }
+        _ => {}
+    }
+    if let Some(tools) = body.get("tools").and_then(|t| t.as_array()) {
+        let tools: Vec<Value> = tools.iter().filter_map(|tool| tool.get("function")).map(anthropic_tool).collect();
+        out.insert("tools".to_string(), Value::Array(tools));
+    }
+    if let Some(choice) = body.get("tool_choice").and_then(anthropic_tool_choice) {
+        out.insert("tool_choice".to_string(), choice);
+    }
+    if let Some(user) = body.get("user") {
+        out.insert("metadata".to_string(), json!({"user_id": user}));
+    }
+    Value::Object(out)
+}
+
+/// 文字列またはテキストのパーツの配列から本文を取り出します
+fn text_of(content: Option<&Value>) -> String {
+    match content {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+            .collect::<Vec<_>>()
+            .join(""),
+        _ => String::new(),
+    }
+}
+
+fn content_blocks(content: Option<&Value>) -> Vec<Value> {
+    match content {
+        Some(Value::String(text)) => vec![json!({"type": "text", "text": text})],
+        Some(Value::Array(parts)) => parts.iter().filter_map(content_block).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn content_block(part: &Value) -> Option<Value> {
+    match part.get("type").and_then(|t| t.as_str())? {
+        "text" => Some(json!({"type": "text", "text": part.get("text").cloned().unwrap_or_default()})),
+        "image_url" => {
+            let url = part.pointer("/image_url/url").and_then(|u| u.as_str())?;
+            // data URL は base64 のソースに、それ以外は URL のソースにする
+            let source = match url.strip_prefix("data:").and_then(|rest| rest.split_once(";base64,")) {
+                Some((media_type, data)) => json!({"type": "base64", "media_type": media_type, "data": data}),
+                None => json!({"type": "url", "url": url}),
+            };
+            Some(json!({"type": "image", "source": source}))
+        }
+        _ => None,
+    }
+}
+
+fn assistant_blocks(message: &Value) -> Vec<Value> {
+    let mut blocks = content_blocks(message.get("content"));
+    for call in message.get("tool_calls").and_then(|c| c.as_array()).into_iter().flatten() {
+        let arguments = call.pointer("/function/arguments").and_then(|a| a.as_str()).unwrap_or("{}");
+        blocks.push(json!({
+            "type": "tool_use",
+            "id": call.get("id").cloned().unwrap_or_default(),
+            "name": call.pointer("/function/name").cloned().unwrap_or_default(),
+            "input": serde_json::from_str::<Value>(arguments).unwrap_or_else(|_| json!({})),
+        }));
+    }
+    blocks
+}
+
+fn anthropic_tool(function: &Value) -> Value {
+    let mut tool = json!({
+        "name": function.get("name").cloned().unwrap_or_default(),
+        "input_schema": function.get("parameters").cloned().unwrap_or_else(|| json!({"type": "object"})),
+    });
+    if let Some(description) = function.get("description") {
+        tool["description"] = description.clone();
+    }
+    tool
+}
+
+fn anthropic_tool_choice(choice: &Value) -> Option<Value> {
+    match choice {
+        Value::String(mode) => match mode.as_str() {
+            "auto" => Some(json!({"type": "auto"})),
+            "required" => Some(json!({"type": "any"})),
+            "none" => Some(json!({"type": "none"})),
+            _ => None,
+        },
+        _ => choice.pointer("/function/name").map(|name| json!({"type": "tool", "name": name})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openai_chat_translated_to_anthropic_messages() {
+        let openai = json!({
+            "model": "claude-3-5-sonnet",
+            "messages": [
+                {"role": "system", "content": "You are terse."},
+                {"role": "user", "content": "What's the weather in Tokyo?"},
+                {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Tokyo\"}"}}
+                ]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+                {"role": "user", "content": [{"type": "text", "text": "And tomorrow?"}]}
+            ],
+            "max_tokens": 256,
+            "temperature": 0.2,
+            "stop": "END",
+            "n": 1,
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather", "description": "Look up weather", "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }}],
+            "tool_choice": "required"
+        });
+
+        let anthropic = Provider::Anthropic.translate_request(&openai).unwrap();
+        assert_eq!(anthropic, json!({
+            "model": "claude-3-5-sonnet",
+            "system": "You are terse.",
+            "messages": [
+                {"role": "user", "content": [{"type": "text", "text": "What's the weather in Tokyo?"}]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "call_1", "name": "get_weather", "input": {"city": "Tokyo"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "call_1", "content": "Sunny"},
+                    {"type": "text", "text": "And tomorrow?"}
+                ]}
+            ],
+            "max_tokens": 256,
+            "temperature": 0.2,
+            "stop_sequences": ["END"],
+            "tools": [{
+                "name": "get_weather", "description": "Look up weather",
+                "input_schema": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }],
+            "tool_choice": {"type": "any"}
+        }));
+    }
+
+    #[test]
+    fn test_missing_max_tokens_and_images() {
+        let openai = json!({
+            "model": "claude-3-haiku",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.jpg"}}
+            ]}]
+        });
+        let anthropic = Provider::Anthropic.translate_request(&openai).unwrap();
+        assert_eq!(anthropic["max_tokens"], DEFAULT_ANTHROPIC_MAX_TOKENS);
+        assert_eq!(anthropic["messages"][0]["content"], json!([
+            {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="}},
+            {"type": "image", "source": {"type": "url", "url": "https://example.com/cat.jpg"}}
+        ]));
+    }
+
+    #[test]
+    fn test_anthropic_requests_and_openai_routes_are_untouched() {
+        let anthropic = json!({"model": "claude-3", "system": "Be brief.", "max_tokens": 10, "messages": [{"role": "user", "content": "hi"}]});
+        assert_eq!(Provider::Anthropic.translate_request(&anthropic), None);
+        assert_eq!(Provider::Anthropic.translate_request(&json!({"prompt": "hi"})), None);
+        assert_eq!(Provider::Openai.translate_request(&json!({"messages": []})), None);
+    }
+}
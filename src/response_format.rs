@@ -0,0 +1,252 @@
+use serde::Deserialize;
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+/// 上流レスポンスの形式（クライアントには常に OpenAI 互換の形式で返す）
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// ストリームごとに最初のイベントから形式を判定する
+    #[default]
+    Auto,
+    /// OpenAI 互換（変換しない）
+    Openai,
+    /// Anthropic Messages API のストリーミングイベント
+    Anthropic,
+    /// OpenAI Responses API のストリーミングイベント
+    Responses,
+}
+
+impl ResponseFormat {
+    /// ストリーミングのチャンク変換器を返します（変換不要なら None）
+    pub fn chunk_translator(self) -> Option<ChunkTranslator> {
+        match self {
+            Self::Openai => None,
+            Self::Auto => Some(ChunkTranslator::default()),
+            format => Some(ChunkTranslator::new(format)),
+        }
+    }
+
+    /// イベントの内容から形式を判定します（判定できなければ None）
+    pub fn detect(event: &Value) -> Option<Self> {
+        if event.get("choices").is_some() {
+            return Some(Self::Openai);
+        }
+        match event.get("type").and_then(|t| t.as_str())? {
+            t if t.starts_with("response.") => Some(Self::Responses),
+            "message_start" | "message_delta" | "message_stop" | "content_block_start" | "content_block_delta"
+            | "content_block_stop" | "ping" => Some(Self::Anthropic),
+            _ => None,
+        }
+    }
+}
+
+/// Anthropic / Responses API のストリーミングイベントを `chat.completion.chunk` に変換する
+///
+/// 開始イベントで受け取った id / model を以降のチャンクにも付与します。
+/// 形式が `Auto` の場合は最初に判定できたイベントの形式をストリームの終わりまで使います。
+#[derive(Debug, Default)]
+pub struct ChunkTranslator {
+    format: Option<ResponseFormat>,
+    id: Value,
+    model: Value,
+    // Anthropic の `message_start` で報告された入力トークン数
+    input_tokens: Option<Value>,
+    // Responses API の関数呼び出しの output_index（位置を tool_calls の index とする）
+    function_outputs: Vec<u64>,
+}
+
+impl ChunkTranslator {
+    pub fn new(format: ResponseFormat) -> Self {
+        Self {
+            format: Some(format),
+            ..Self::default()
+        }
+    }
+
+    /// 判定済み（または指定された）形式
+    pub fn format(&self) -> Option<ResponseFormat> {
+        self.format
+    }
+
+    /// SSE の `data` を変換します。1つのイベントが複数のチャンクになる場合もあります
+    ///
+    /// クライアントに送らないイベントは空の Vec を返します。
+    pub fn translate(&mut self, data: &str) -> Vec<String> {
+        if self.format == Some(ResponseFormat::Openai) {
+            return vec![data.to_string()];
+        }
+        let Ok(event) = serde_json::from_str::<Value>(data) else {
+            return vec![data.to_string()];
+        };
+        if self.format.is_none() {
+            // 判定できるまではそのまま通す
+            self.format = ResponseFormat::detect(&event);
+        }
+
+        match self.format {
+            Some(ResponseFormat::Anthropic) => self.translate_anthropic(&event).into_iter().collect(),
+            Some(ResponseFormat::Responses) => self.translate_responses(&event),
+            _ => vec![data.to_string()],
+        }
+    }
+
+    fn translate_anthropic(&mut self, event: &Value) -> Option<String> {
+        let (delta, finish_reason, usage) = match event.get("type").and_then(|t| t.as_str()) {
+            Some("message_start") => {
+                let message = event.get("message").cloned().unwrap_or_default();
+                self.id = message.get("id").cloned().unwrap_or_default();
+                self.model = message.get("model").cloned().unwrap_or_default();
+                self.input_tokens = message.pointer("/usage/input_tokens").cloned();
+                (json!({"role": "assistant", "content": ""}), Value::Null, None)
+            }
+            Some("content_block_start") => {
+                let block = event.get("content_block")?;
+                if block.get("type").and_then(|t| t.as_str()) != Some("tool_use") {
+                    return None;
+                }
+                let call = json!({
+                    "index": event.get("index").cloned().unwrap_or(json!(0)),
+                    "id": block.get("id").cloned().unwrap_or_default(),
+                    "type": "function",
+                    "function": {"name": block.get("name").cloned().unwrap_or_default(), "arguments": ""},
+                });
+                (json!({"tool_calls": [call]}), Value::Null, None)
+            }
+            Some("content_block_delta") => {
+                let delta = event.get("delta")?;
+                match delta.get("type").and_then(|t| t.as_str()) {
+                    Some("text_delta") => (json!({"content": delta.get("text").cloned().unwrap_or_default()}), Value::Null, None),
+                    Some("input_json_delta") => {
+                        let call = json!({
+                            "index": event.get("index").cloned().unwrap_or(json!(0)),
+                            "function": {"arguments": delta.get("partial_json").cloned().unwrap_or_default()},
+                        });
+                        (json!({"tool_calls": [call]}), Value::Null, None)
+                    }
+                    _ => return None,
+                }
+            }
+            Some("message_delta") => {
+                let reason = match event.pointer("/delta/stop_reason").and_then(|r| r.as_str()) {
+                    Some("end_turn") | Some("stop_sequence") => json!("stop"),
+                    Some("max_tokens") => json!("length"),
+                    Some("tool_use") => json!("tool_calls"),
+                    Some(other) => json!(other),
+                    None => Value::Null,
+                };
+                let usage = event.pointer("/usage/output_tokens").map(|tokens| {
+                    json!({"prompt_tokens": self.input_tokens.clone().unwrap_or(json!(0)), "completion_tokens": tokens})
+                });
+                (json!({}), reason, usage)
+            }
+            Some("message_stop") => return Some("[DONE]".to_string()),
+            // ping / content_block_stop などは OpenAI 形式に対応するものがない
+            _ => return None,
+        };
+
+        Some(self.chunk(delta, finish_reason, usage))
+    }
+
+    fn translate_responses(&mut self, event: &Value) -> Vec<String> {
+        let (delta, finish_reason, usage) = match event.get("type").and_then(|t| t.as_str()) {
+            Some("response.created") => {
+                let response = event.get("response").cloned().unwrap_or_default();
+                self.id = response.get("id").cloned().unwrap_or_default();
+                self.model = response.get("model").cloned().unwrap_or_default();
+                (json!({"role": "assistant", "content": ""}), Value::Null, None)
+            }
+            Some("response.output_text.delta") => {
+                (json!({"content": event.get("delta").cloned().unwrap_or_default()}), Value::Null, None)
+            }
+            Some("response.output_item.added") => {
+                let Some(item) = event.get("item").filter(|i| i.get("type").and_then(|t| t.as_str()) == Some("function_call")) else {
+                    return Vec::new();
+                };
+                self.function_outputs.push(event.get("output_index").and_then(|i| i.as_u64()).unwrap_or(0));
+                let call = json!({
+                    "index": self.function_outputs.len() - 1,
+                    "id": item.get("call_id").cloned().unwrap_or_default(),
+                    "type": "function",
+                    "function": {"name": item.get("name").cloned().unwrap_or_default(), "arguments": ""},
+                });
+                (json!({"tool_calls": [call]}), Value::Null, None)
+            }
+            Some("response.function_call_arguments.delta") => {
+                let output_index = event.get("output_index").and_then(|i| i.as_u64()).unwrap_or(0);
+                let Some(index) = self.function_outputs.iter().position(|&i| i == output_index) else {
+                    return Vec::new();
+                };
+                let call = json!({
+                    "index": index,
+                    "function": {"arguments": event.get("delta").cloned().unwrap_or_default()},
+                });
+                (json!({"tool_calls": [call]}), Value::Null, None)
+            }
+            Some("response.completed") | Some("response.incomplete") => {
+                let response = event.get("response").cloned().unwrap_or_default();
+                let reason = if !self.function_outputs.is_empty() {
+                    json!("tool_calls")
+                } else if response.get("status").and_then(|s| s.as_str()) == Some("incomplete") {
+                    json!("length")
+                } else {
+                    json!("stop")
+                };
+                let usage = response.get("usage").map(|usage| {
+                    json!({
+                        "prompt_tokens": usage.get("input_tokens").cloned().unwrap_or(json!(0)),
+                        "completion_tokens": usage.get("output_tokens").cloned().unwrap_or(json!(0)),
+                    })
+                });
+                // Responses API には [DONE] がないため、終了チャンクの後に補う
+                return vec![self.chunk(json!({}), reason, usage), "[DONE]".to_string()];
+            }
+            // in_progress / content_part.added / *.done などは OpenAI 形式に対応するものがない
+            _ => return Vec::new(),
+        };
+
+        vec![self.chunk(delta, finish_reason, usage)]
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Value, usage: Option<Value>) -> String {
+        let mut chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        });
+        if let Some(usage) = usage {
+            chunk["usage"] = usage;
+        }
+        chunk.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openai_is_passthrough() {
+        assert!(ResponseFormat::Openai.chunk_translator().is_none());
+    }
+
+    #[test]
+    fn test_unparseable_data_is_passed_through() {
+        let mut translator = ChunkTranslator::new(ResponseFormat::Anthropic);
+        assert_eq!(translator.translate("not json"), vec!["not json"]);
+    }
+
+    #[test]
+    fn test_format_is_detected_from_first_recognizable_event() {
+        let mut translator = ChunkTranslator::default();
+        assert_eq!(translator.translate("[DONE]"), vec!["[DONE]"]);
+        assert_eq!(translator.format(), None);
+
+        translator.translate(r#"{"type":"message_start","message":{"id":"msg_1","model":"claude-3"}}"#);
+        assert_eq!(translator.format(), Some(ResponseFormat::Anthropic));
+        // 判定後は他の形式に見えるイベントでも切り替えない
+        assert!(translator.translate(r#"{"type":"response.created"}"#).is_empty());
+        assert_eq!(translator.format(), Some(ResponseFormat::Anthropic));
+    }
+}
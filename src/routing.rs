@@ -1,25 +1,1090 @@
 use serde::Deserialize;
+use schemars::JsonSchema;
+use serde_json::Value;
+use regex::Regex;
+use std::collections::HashMap;
 use tracing::info;
+use crate::upstreams::UpstreamDrains;
 
-#[derive(Debug, Deserialize, Clone)]
+/// `target_url` に代入するキャプチャでエンコードする文字（RFC 3986 の非予約文字以外）
+const CAPTURE_ENCODE_SET: &percent_encoding::AsciiSet =
+    &percent_encoding::NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 pub struct RouteRule {
     pub path: String,
+    /// `path` の解釈方法（デフォルトは prefix）
+    #[serde(default)]
+    pub match_type: MatchType,
     pub target_model: String,
-    pub target_url: String,
+    /// リクエストボディの `model` がこの値の場合のみマッチする（`path` も一致する必要がある）
+    ///
+    /// 設定したルートは `model` が一致したときだけ選ばれ、パスのみの照合では対象外になります。
+    /// `model_aliases` による書き換え後のモデル名と比較します。
+    #[serde(default)]
+    pub match_model: Option<String>,
+    /// 転送先。URL の文字列、または `{ id, url }` の配列で複数指定できます
+    #[serde(rename = "target_url", deserialize_with = "deserialize_targets")]
+    #[schemars(with = "Targets")]
+    pub targets: Vec<TargetEndpoint>,
+    /// ストリーミング判定の上書き（デフォルトは auto）
+    #[serde(default)]
+    pub stream_detection: StreamDetection,
+    /// `register_transform` で登録したボディ変換の名前
+    #[serde(default)]
+    pub transform: Option<String>,
+    /// ストリーミング解析のオプション
+    #[serde(default)]
+    pub streaming: crate::streaming::StreamOptions,
+    /// 上流レスポンスの形式（OpenAI 互換の形式に変換して返す）
+    #[serde(default)]
+    pub response_format: crate::response_format::ResponseFormat,
+    /// 転送先のプロバイダー。OpenAI 形式のチャットのリクエストをその形式に変換して送る（`transform` の後に適用）
+    #[serde(default)]
+    pub provider: crate::request_format::Provider,
+    /// 上流へ送る `Host` ヘッダーを上書きする名前（`host[:port]`。不正な値は読み込み時に拒否する）
+    ///
+    /// IP アドレスの `target_url` に接続しつつ、共有の Ingress などが期待する
+    /// ホスト名で振り分けさせる場合に使います。TLS の SNI と証明書の検証もこの名前で行います。
+    #[serde(default)]
+    pub host_override: Option<String>,
+    /// リクエストの写しを送る転送先（レスポンスは破棄し、クライアントへの応答には影響しない）
+    ///
+    /// `target_url` と同じく、ルートに続くパスとクエリを連結します。`[features]` の `shadow_traffic` で停止できます。
+    #[serde(default)]
+    pub shadow_url: Option<String>,
+    /// クライアントが送らなかった場合のみ上流へ付与するヘッダー（`anthropic-beta` など）
+    ///
+    /// クライアントが同名のヘッダーを送っていればそちらを優先します。クライアントの値を上書きするヘッダーの設定は無く、
+    /// 上書きできるのは `host_override` による `Host` のみです。
+    #[serde(default)]
+    pub default_request_headers: HashMap<String, String>,
+    /// クライアントの `Expect: 100-continue` を上流へ転送する（デフォルトは外して転送する）
+    ///
+    /// ボディは Orchix が読み取り済みのため、上流の 100 Continue は中継せず、待たずにボディを送ります。
+    /// 期待を受け取って処理を変える上流（大きなボディを先に検証するものなど）向けです。
+    #[serde(default)]
+    pub forward_expect_continue: bool,
+    /// HTTP/1 で上流へ送るヘッダー名の大文字・小文字（デフォルトはライブラリの動作どおり小文字）
+    #[serde(default)]
+    pub header_case: HeaderCase,
+    /// 上流がすべて失敗した場合にエラーの代わりに返す固定のレスポンス
+    #[serde(default)]
+    pub failure_response: Option<FailureResponse>,
+    /// 非ストリーミングのレスポンスから推論過程（`reasoning_content` など）を取り除く
+    #[serde(default)]
+    pub hide_reasoning: bool,
+    /// 取り除いた推論過程をサーバー側のログに記録する
+    #[serde(default)]
+    pub log_reasoning: bool,
+    /// 上流への同時実行数を制限し、API キー間で公平に割り当てる
+    #[serde(default)]
+    pub fair_queue: Option<crate::concurrency::FairQueueConfig>,
+    /// true の場合、ボディが空または JSON でないリクエストを 400 で拒否する（デフォルトはそのまま転送）
+    #[serde(default)]
+    pub require_body: bool,
+    /// false の場合、`stream: true` のリクエストを 400 で拒否する（キャッシュ前提のルートなど）
+    #[serde(default = "default_allow_streaming")]
+    pub allow_streaming: bool,
+    /// `stream: true` のリクエストを `stream: false` に書き換えて転送する（`allow_streaming` より優先）
+    #[serde(default)]
+    pub force_stream_off: bool,
+    /// このルートで使うツールのポリシー（未設定なら全体の `[interception]`）
+    ///
+    /// 全体の設定とは合成せず、丸ごと置き換えます。ルートを決める前に行うボディの
+    /// `json_limits` の確認には、常に全体の設定を使います。
+    #[serde(default)]
+    pub interception: Option<crate::interception::InterceptionConfig>,
+    /// 上流へ OAuth（クライアントクレデンシャル）で取得したトークンを `Authorization: Bearer` で送る
+    #[serde(default)]
+    pub upstream_oauth: Option<crate::upstream_auth::UpstreamOAuthConfig>,
+    /// リクエストヘッダーで渡された出典をレスポンスに付与する（ストリーミングは末尾のイベントで送る）
+    #[serde(default)]
+    pub citations: Option<crate::postprocess::CitationConfig>,
+    /// このルートのレスポンスをキャッシュする秒数（未設定なら `ttl_seconds` / `streaming_ttl_seconds`）
+    #[serde(default, alias = "cache_ttl_secs")]
+    pub cache_ttl_seconds: Option<u64>,
+    /// このルートでキャッシュを使うか（未設定なら `[caching]` の `enabled`。機能フラグで無効な場合は使わない）
+    #[serde(default)]
+    pub cache_enabled: Option<bool>,
+    /// キャッシュにヒットした場合に、キャッシュから返す割合 (0.0 - 1.0、未設定なら常に返す)
+    ///
+    /// 返さなかった場合は上流から取得し直してエントリを更新します（生成結果に変化を持たせるルート向け）。
+    #[serde(default)]
+    pub cache_probability: Option<f64>,
+}
+
+/// ルートの転送先の1つ
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+pub struct TargetEndpoint {
+    pub url: String,
+    /// 管理 API（`/admin/upstreams/{id}/drain`）で指定する名前（省略時は URL）
+    #[serde(default)]
+    pub id: Option<String>,
+    /// 重み付きの振り分けでの比率（省略時は 1、0 なら設定に残したまま振り分けない）
+    ///
+    /// ルートのどの転送先にも指定が無い場合は、重みを使わず設定順の優先度で選びます。
+    #[serde(default)]
+    pub weight: Option<u32>,
+    /// 転送先のリージョン（`server.region` と同じなら優先して選ぶ）
+    #[serde(default)]
+    pub region: Option<String>,
+    /// この転送先へ同時に送るリクエストの上限（達している間は飽和として他の転送先を選ぶ。省略時は無制限）
+    #[serde(default)]
+    pub max_in_flight: Option<usize>,
+}
+
+/// 上流の障害時に返す合成レスポンス（OpenAI 形式のエラー応答などをそのまま書く）
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+pub struct FailureResponse {
+    #[serde(default = "default_failure_status")]
+    pub status: u16,
+    pub body: String,
+    #[serde(default = "default_failure_content_type")]
+    pub content_type: String,
+}
+
+fn default_allow_streaming() -> bool {
+    true
+}
+
+fn default_failure_status() -> u16 {
+    503
+}
+
+fn default_failure_content_type() -> String {
+    "application/json".to_string()
+}
+
+impl TargetEndpoint {
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or(&self.url)
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+}
+
+/// `target_url` の書き方（URL の文字列、または転送先の配列）
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Targets {
+    Single(String),
+    Multiple(#[schemars(length(min = 1))] Vec<TargetEndpoint>),
+}
+
+fn deserialize_targets<'de, D>(deserializer: D) -> Result<Vec<TargetEndpoint>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Targets::deserialize(deserializer)? {
+        Targets::Single(url) => Ok(vec![TargetEndpoint { url, id: None, weight: None, region: None, max_in_flight: None }]),
+        Targets::Multiple(targets) if targets.is_empty() => Err(serde::de::Error::custom("target_url must not be empty")),
+        Targets::Multiple(targets) => Ok(targets),
+    }
+}
+
+impl RouteRule {
+    /// ルートで指定されたキャッシュの TTL
+    pub fn cache_ttl(&self) -> Option<std::time::Duration> {
+        self.cache_ttl_seconds.map(std::time::Duration::from_secs)
+    }
+
+    /// 全体の設定 `global` に対して、このルートでキャッシュを使うか（`cache_enabled` を優先する）
+    pub fn caches(&self, global: bool) -> bool {
+        self.cache_enabled.unwrap_or(global)
+    }
+
+    /// 0〜1の乱数 `roll` に対して、キャッシュのヒットをそのまま返すか（`cache_probability`）
+    pub fn serves_cache_hit(&self, roll: f64) -> bool {
+        self.cache_probability.is_none_or(|probability| roll < probability)
+    }
+
+    /// 新しいリクエストの転送先を選びます
+    ///
+    /// いずれかの転送先に `weight` があれば、ドレイン中でない転送先から重みに比例してランダムに選びます。
+    /// 重みの指定が無ければ、設定順で最初の、ドレイン中でない転送先を返します。
+    /// 選べる転送先が無ければ None。
+    pub fn pick_target(&self, drains: &UpstreamDrains) -> Option<&TargetEndpoint> {
+        self.pick_preferred(drains, None, |_| true)
+    }
+
+    /// `region` と同じリージョンの正常な（`healthy` を満たす）転送先を優先して選びます
+    ///
+    /// 同じリージョンに正常な転送先が無ければ他のリージョンの正常な転送先を選びます。
+    /// `healthy` には回路が開いている・飽和している転送先を除く判定を渡してください。
+    /// 正常な転送先が1つも無い場合は、同じリージョンを優先してドレイン中でない転送先を返します
+    /// （障害時の扱いは呼び出し側に任せる）。
+    pub fn pick_preferred(
+        &self,
+        drains: &UpstreamDrains,
+        region: Option<&str>,
+        healthy: impl Fn(&TargetEndpoint) -> bool,
+    ) -> Option<&TargetEndpoint> {
+        let roll = rand::random::<f64>();
+        let local = |target: &TargetEndpoint| region.is_none_or(|region| target.region.as_deref() == Some(region));
+        self.pick_with_roll(drains, roll, |t| local(t) && healthy(t))
+            .or_else(|| self.pick_with_roll(drains, roll, &healthy))
+            .or_else(|| self.pick_with_roll(drains, roll, local))
+            .or_else(|| self.pick_with_roll(drains, roll, |_| true))
+    }
+
+    /// ドレイン中でなく `eligible` を満たす転送先から、0〜1の乱数 `roll` を使って選びます
+    fn pick_with_roll(&self, drains: &UpstreamDrains, roll: f64, eligible: impl Fn(&TargetEndpoint) -> bool) -> Option<&TargetEndpoint> {
+        let mut available = self.targets.iter().filter(|target| !drains.is_draining(target.id()) && eligible(target));
+        if self.targets.iter().all(|target| target.weight.is_none()) {
+            return available.next();
+        }
+
+        let available: Vec<_> = available.filter(|target| target.weight() > 0).collect();
+        let total: u64 = available.iter().map(|target| u64::from(target.weight())).sum();
+        let mut point = (roll * total as f64) as u64;
+        for target in &available {
+            let weight = u64::from(target.weight());
+            if point < weight {
+                return Some(target);
+            }
+            point -= weight;
+        }
+        // roll が 1.0 に丸められた場合
+        available.last().copied()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchType {
+    /// `path` を前方一致で比較する
+    #[default]
+    Prefix,
+    /// `path` を正規表現としてパスの先頭から照合する
+    ///
+    /// 名前付きグループ（`(?P<model>[^/]+)`）は `target_url` / `target_model` の
+    /// `{model}` のようなプレースホルダーに代入されます。
+    Regex,
+}
+
+/// 上流レスポンスをストリーミングとして扱うかの判定方法
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamDetection {
+    /// レスポンスの Content-Type を優先し、無ければリクエストの `stream` フラグで判定
+    #[default]
+    Auto,
+    /// 常にストリーミングとして解析する
+    ForceStream,
+    /// 常に通常のレスポンスとしてバッファリングする
+    ForceBuffer,
+}
+
+impl StreamDetection {
+    pub fn is_streaming(&self, request_stream: bool, content_type: Option<&str>) -> bool {
+        match self {
+            StreamDetection::ForceStream => true,
+            StreamDetection::ForceBuffer => false,
+            StreamDetection::Auto => match content_type {
+                Some(ct) => ct.trim_start().to_ascii_lowercase().starts_with("text/event-stream"),
+                None => request_stream,
+            },
+        }
+    }
+}
+
+/// 上流へ送るヘッダー名の書き方（HTTP/2 では常に小文字）
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderCase {
+    /// `content-type` のように小文字で送る
+    #[default]
+    Lowercase,
+    /// `Content-Type` のように単語の先頭を大文字にして送る（大文字・小文字を区別する上流向け）
+    TitleCase,
+}
+
+/// リクエストボディが `"stream": true` を指定しているか
+pub fn requests_streaming(body: &Value) -> bool {
+    body.get("stream").and_then(|v| v.as_bool()).unwrap_or(false)
 }
 
 pub struct Router {
     pub rules: Vec<RouteRule>,
+    // rules と同じ順序のコンパイル済み正規表現（prefix のルールは None）
+    patterns: Vec<Option<Regex>>,
+}
+
+/// ルートの照合結果
+#[derive(Debug)]
+pub struct RouteMatch<'a> {
+    pub rule: &'a RouteRule,
+    /// 正規表現の名前付きグループで取得した値
+    pub captures: HashMap<String, String>,
+    /// どのように照合されたか（ルーティングの診断用）
+    pub provenance: RouteProvenance,
+    /// 照合した部分より後ろのパス（転送先の URL に連結する）
+    pub remainder: String,
+}
+
+/// ルート照合の経緯
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteProvenance {
+    /// マッチしたルールの評価順（設定ファイル内の順序。小さいほど優先）
+    pub priority: usize,
+    pub match_type: MatchType,
+    /// 評価して選ばれなかったルールの `path`（一致しなかったもの・一致が短かったものを設定順に）
+    pub skipped: Vec<String>,
+}
+
+/// プレースホルダーを展開した転送先
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamTarget {
+    /// 転送先の ID（`TargetEndpoint::id`）
+    pub id: String,
+    pub url: String,
+    pub model: String,
+    pub region: Option<String>,
+    /// `TargetEndpoint::max_in_flight`
+    pub max_in_flight: Option<usize>,
+}
+
+impl RouteMatch<'_> {
+    /// ログ出力用に照合の経緯をまとめます
+    pub fn describe(&self) -> String {
+        let mut captures: Vec<_> = self.captures.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        captures.sort_unstable();
+        format!(
+            "rule #{} '{}' matched as {:?} (captures: [{}], skipped: {:?})",
+            self.provenance.priority,
+            self.rule.path,
+            self.provenance.match_type,
+            captures.join(", "),
+            self.provenance.skipped,
+        )
+    }
+
+    /// 転送先を選び、`target_url` / `target_model` の `{name}` をキャプチャした値で置き換えます
+    ///
+    /// すべての転送先がドレイン中なら None を返します。
+    pub fn target(&self, drains: &UpstreamDrains) -> Option<UpstreamTarget> {
+        self.preferred_target(drains, None, |_, _| true)
+    }
+
+    /// `RouteRule::pick_target` と同様に、同じリージョンの正常な転送先を優先して選びます
+    ///
+    /// `healthy` には転送先と展開後の URL が渡されます。
+    pub fn preferred_target(
+        &self,
+        drains: &UpstreamDrains,
+        region: Option<&str>,
+        healthy: impl Fn(&TargetEndpoint, &str) -> bool,
+    ) -> Option<UpstreamTarget> {
+        let endpoint = self.rule.pick_preferred(drains, region, |endpoint| healthy(endpoint, &self.substitute_url(&endpoint.url)))?;
+        Some(UpstreamTarget {
+            id: endpoint.id().to_string(),
+            url: self.substitute_url(&endpoint.url),
+            model: self.model(),
+            region: endpoint.region.clone(),
+            max_in_flight: endpoint.max_in_flight,
+        })
+    }
+
+    /// `target_model` の `{name}` をキャプチャした値で置き換えた転送先のモデル
+    pub fn model(&self) -> String {
+        self.substitute(&self.rule.target_model)
+    }
+
+    /// 転送先の URL に残りのパスとクエリ文字列を連結します
+    ///
+    /// 残りのパスは必ず `/` 始まりで連結するため、`@host` などで転送先のホストが変わることはありません。
+    pub fn upstream_url(&self, target: &UpstreamTarget, query: Option<&str>) -> String {
+        self.join_url(&target.url, query)
+    }
+
+    /// ルールの `shadow_url` にパスの残りとクエリを連結した URL
+    pub fn shadow_url(&self, query: Option<&str>) -> Option<String> {
+        self.rule.shadow_url.as_deref().map(|base| self.join_url(base, query))
+    }
+
+    fn join_url(&self, base: &str, query: Option<&str>) -> String {
+        let mut url = base.to_string();
+        let remainder = self.remainder.trim_start_matches('/');
+        if !remainder.is_empty() {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str(remainder);
+        }
+        if let Some(query) = query.filter(|q| !q.is_empty()) {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(query);
+        }
+        url
+    }
+
+    fn substitute(&self, template: &str) -> String {
+        self.captures.iter().fold(template.to_string(), |acc, (name, value)| {
+            acc.replace(&format!("{{{}}}", name), value)
+        })
+    }
+
+    /// `target_url` の `{name}` を、パーセントエンコードしたキャプチャの値で置き換えます
+    ///
+    /// キャプチャに `/` や `?`、`#` が含まれても、URL のパスやクエリの構造を変えないようにします。
+    fn substitute_url(&self, template: &str) -> String {
+        self.captures.iter().fold(template.to_string(), |acc, (name, value)| {
+            let encoded = percent_encoding::utf8_percent_encode(value, CAPTURE_ENCODE_SET).to_string();
+            acc.replace(&format!("{{{}}}", name), &encoded)
+        })
+    }
 }
 
 impl Router {
-    pub fn new(rules: Vec<RouteRule>) -> Self {
-        Self { rules }
+    /// ルールを読み込みます。正規表現のコンパイルに失敗した場合はエラーを返します
+    pub fn try_new(rules: Vec<RouteRule>) -> anyhow::Result<Self> {
+        let patterns = rules
+            .iter()
+            .map(|rule| match rule.match_type {
+                MatchType::Prefix => Ok(None),
+                MatchType::Regex => Regex::new(&format!("^(?:{})", rule.path))
+                    .map(Some)
+                    .map_err(|e| anyhow::anyhow!("Invalid regex in route '{}': {}", rule.path, e)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for rule in &rules {
+            if let Some(failure) = &rule.failure_response
+                && !(100..=599).contains(&failure.status)
+            {
+                anyhow::bail!("Invalid failure_response status {} in route '{}'", failure.status, rule.path);
+            }
+            if let Some(probability) = rule.cache_probability
+                && !(0.0..=1.0).contains(&probability)
+            {
+                anyhow::bail!("Invalid cache_probability {} in route '{}' (must be between 0.0 and 1.0)", probability, rule.path);
+            }
+            // 0 では枠が空かず、リクエストが待ち続けるため拒否する
+            if rule.fair_queue.as_ref().is_some_and(|queue| queue.max_concurrent == 0) {
+                anyhow::bail!("Invalid fair_queue.max_concurrent 0 in route '{}' (must be at least 1)", rule.path);
+            }
+            // `Host` ヘッダーにそのまま入れるため、`host[:port]` の形だけを受け付ける
+            if let Some(host) = &rule.host_override
+                && (host.contains('@') || host.parse::<axum::http::uri::Authority>().is_err())
+            {
+                anyhow::bail!("Invalid host_override '{}' in route '{}' (must be host[:port])", host, rule.path);
+            }
+            // 未登録の名前のままでは、リクエストのたびに変換を飛ばして転送してしまうため拒否する
+            if let Some(name) = &rule.transform
+                && crate::transform::lookup(name).is_none()
+            {
+                anyhow::bail!("Unknown transform '{}' in route '{}' (register it with register_transform)", name, rule.path);
+            }
+        }
+        Ok(Self { rules, patterns })
     }
 
+    /// パスに一致するルートを返します
+    ///
+    /// 複数のルートが一致する場合は、一致した部分が最も長いもの（前方一致なら最長の `path`）を選び、
+    /// 長さが同じなら設定順で先のものを優先します。
     pub fn resolve(&self, path: &str) -> Option<&RouteRule> {
+        self.resolve_match(path).map(|m| m.rule)
+    }
+
+    /// リクエストボディの `model` も使ってルートを照合します
+    ///
+    /// 優先順位は次のとおりです。
+    /// 1. `match_model` がボディの `model` と等しく、`path` も一致するルート（一致が最長のもの）
+    /// 2. `match_model` を持たず、`path` が一致するルート（`resolve` と同じ）
+    ///
+    /// ボディに文字列の `model` が無い場合は 2 のみで照合します。
+    pub fn resolve_with_body(&self, path: &str, body: &Value) -> Option<&RouteRule> {
+        self.resolve_match_with_body(path, body).map(|m| m.rule)
+    }
+
+    /// `resolve_with_body` と同じ優先順位で、キャプチャを含めてルートを照合します
+    pub fn resolve_match_with_body(&self, path: &str, body: &Value) -> Option<RouteMatch<'_>> {
+        if let Some(model) = body.get("model").and_then(Value::as_str) {
+            info!("Resolving route for path: {} (model: {})", path, model);
+            if let Some(found) = self.find_match(path, |rule| rule.match_model.as_deref() == Some(model)) {
+                return Some(found);
+            }
+        }
+        self.resolve_match(path)
+    }
+
+    /// キャプチャを含めてルートを照合します（`match_model` を持つルートは対象外）
+    pub fn resolve_match(&self, path: &str) -> Option<RouteMatch<'_>> {
         info!("Resolving route for path: {}", path);
-        // シンプルな前方一致でのマッチング
-        self.rules.iter().find(|rule| path.starts_with(&rule.path))
+        self.find_match(path, |rule| rule.match_model.is_none())
+    }
+
+    /// `eligible` なルートのうち、パスの一致が最も長いもの（同じ長さなら設定順で先のもの）を返します
+    fn find_match(&self, path: &str, eligible: impl Fn(&RouteRule) -> bool) -> Option<RouteMatch<'_>> {
+        let mut best: Option<(usize, HashMap<String, String>, usize)> = None;
+        for (priority, (rule, pattern)) in self.rules.iter().zip(&self.patterns).enumerate() {
+            if !eligible(rule) {
+                continue;
+            }
+            let captures = match pattern {
+                // シンプルな前方一致でのマッチング
+                None => path.starts_with(&rule.path).then(|| (HashMap::new(), rule.path.len())),
+                Some(regex) => regex.captures(path).map(|caps| {
+                    let captures = regex
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|name| Some((name.to_string(), caps.name(name)?.as_str().to_string())))
+                        .collect();
+                    (captures, caps.get(0).map_or(0, |m| m.end()))
+                }),
+            };
+            if let Some((captures, matched)) = captures
+                && best.as_ref().is_none_or(|(_, _, longest)| matched > *longest)
+            {
+                best = Some((priority, captures, matched));
+            }
+        }
+        let (priority, captures, matched) = best?;
+        let rule = &self.rules[priority];
+        let skipped = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| index != priority)
+            .map(|(_, rule)| rule.path.clone())
+            .collect();
+        let provenance = RouteProvenance { priority, match_type: rule.match_type, skipped };
+        let remainder = path[matched..].to_string();
+        Some(RouteMatch { rule, captures, provenance, remainder })
+    }
+}
+
+/// クライアント向けのモデル名（別名）を上流のモデル名に対応付ける
+#[derive(Debug, Clone, Default)]
+pub struct ModelAliases {
+    aliases: HashMap<String, String>,
+}
+
+impl ModelAliases {
+    pub fn new(aliases: HashMap<String, String>) -> Self {
+        Self { aliases }
+    }
+
+    /// ボディの `model` が別名なら上流のモデル名に書き換えます。書き換えた場合は true
+    pub fn normalize(&self, body: &mut Value) -> bool {
+        let Some(model) = body.get_mut("model") else {
+            return false;
+        };
+        match model.as_str().and_then(|name| self.aliases.get(name)) {
+            Some(resolved) => {
+                *model = Value::String(resolved.clone());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// 転送先として許可する上流ホストの一覧（SSRF 対策）
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamHostAllowlist {
+    hosts: Vec<String>,
+}
+
+impl UpstreamHostAllowlist {
+    pub fn new(hosts: &[String]) -> Self {
+        Self {
+            hosts: hosts.iter().map(|h| h.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// URL の転送を許可するか判定します。許可しない場合は理由を返します
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        if self.hosts.is_empty() {
+            return Ok(());
+        }
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_ascii_lowercase))
+            .ok_or_else(|| format!("Upstream URL '{}' has no valid host", url))?;
+        self.check_host(&host)
+    }
+
+    fn check_host(&self, host: &str) -> Result<(), String> {
+        let host = host.to_ascii_lowercase();
+        let allowed = self.hosts.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+            None => *pattern == host,
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!("Upstream host '{}' is not in allowed_upstream_hosts", host))
+        }
+    }
+
+    /// 起動時に各ルートの転送先を検証します
+    ///
+    /// ホスト部分にキャプチャのプレースホルダーを含む URL は、リクエスト時に検証します。
+    pub fn validate_routes(&self, rules: &[RouteRule]) -> anyhow::Result<()> {
+        if self.hosts.is_empty() {
+            return Ok(());
+        }
+        for rule in rules {
+            rule.targets
+                .iter()
+                .try_for_each(|target| {
+                    let authority = target.url.split("://").nth(1).and_then(|rest| rest.split('/').next());
+                    if authority.is_some_and(|a| a.contains('{')) {
+                        Ok(())
+                    } else {
+                        self.check(&target.url)
+                    }
+                })
+                .and_then(|_| rule.host_override.as_deref().map_or(Ok(()), |host| self.check_host(host)))
+                .and_then(|_| rule.shadow_url.as_deref().map_or(Ok(()), |url| self.check(url)))
+                .map_err(|e| anyhow::anyhow!("Route '{}': {}", rule.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// 待ち受けアドレス（`server.host` / `server.port`）自身を指す転送先を返します（ルートのパスと URL）
+///
+/// DNS の名前解決は行わず、同じホスト名か、ループバック・全アドレス（`0.0.0.0` など）で
+/// 待ち受けている場合のループバック宛てを自分自身とみなします。
+pub fn self_referential_targets(rules: &[RouteRule], host: &str, port: u16) -> Vec<(String, String)> {
+    fn is_loopback(host: &str) -> bool {
+        host.eq_ignore_ascii_case("localhost")
+            || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+    let listen = host.trim_matches(|c| c == '[' || c == ']');
+    let listen_local = is_loopback(listen) || listen.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_unspecified());
+    let points_here = |url: &str| {
+        let Ok(parsed) = url::Url::parse(url) else {
+            return false;
+        };
+        let Some(target) = parsed.host_str().map(|h| h.trim_matches(|c| c == '[' || c == ']')) else {
+            return false;
+        };
+        parsed.port_or_known_default() == Some(port)
+            && (target.eq_ignore_ascii_case(listen) || (listen_local && is_loopback(target)))
+    };
+    rules
+        .iter()
+        .flat_map(|rule| rule.targets.iter().map(move |target| (rule, target)))
+        .filter(|(_, target)| points_here(&target.url))
+        .map(|(rule, target)| (rule.path.clone(), target.url.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_auto_detection() {
+        let auto = StreamDetection::Auto;
+        assert!(auto.is_streaming(false, Some("text/event-stream; charset=utf-8")));
+        assert!(!auto.is_streaming(true, Some("application/json")));
+        assert!(auto.is_streaming(true, None));
+        assert!(!auto.is_streaming(false, None));
+    }
+
+    #[test]
+    fn test_force_overrides_mismatched_upstream() {
+        // stream:false を要求したのに上流が event-stream を返すケース
+        assert!(!StreamDetection::ForceBuffer.is_streaming(false, Some("text/event-stream")));
+        // stream:true を要求したのに上流が JSON の Content-Type を返すケース
+        assert!(StreamDetection::ForceStream.is_streaming(true, Some("application/json")));
+    }
+
+    #[test]
+    fn test_requests_streaming() {
+        assert!(requests_streaming(&json!({"stream": true})));
+        assert!(!requests_streaming(&json!({"stream": "yes"})));
+        assert!(!requests_streaming(&json!({})));
+    }
+
+    #[test]
+    fn test_stream_detection_config() {
+        let rule: RouteRule = toml::from_str(r#"
+            path = "/v1/chat"
+            target_model = "gpt-4"
+            target_url = "http://localhost"
+            stream_detection = "force_buffer"
+        "#).unwrap();
+        assert_eq!(rule.stream_detection, StreamDetection::ForceBuffer);
+    }
+
+    fn rule(path: &str, match_type: MatchType, target_url: &str, target_model: &str) -> RouteRule {
+        toml::from_str(&format!(
+            "path = '{}'\nmatch_type = '{}'\ntarget_url = '{}'\ntarget_model = '{}'",
+            path,
+            if match_type == MatchType::Regex { "regex" } else { "prefix" },
+            target_url,
+            target_model,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_route_cache_overrides() {
+        let mut route = rule("/v1/embeddings", MatchType::Prefix, "http://backend", "text-embedding-3");
+        assert!(route.caches(true) && !route.caches(false));
+        route.cache_enabled = Some(false);
+        assert!(!route.caches(true));
+        route.cache_enabled = Some(true);
+        assert!(route.caches(false));
+
+        let aliased: RouteRule = toml::from_str("path = '/v1/embeddings'\ntarget_model = 'm'\ntarget_url = 'http://backend'\ncache_ttl_secs = 3600").unwrap();
+        assert_eq!(aliased.cache_ttl(), Some(std::time::Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_cache_probability_decision_and_validation() {
+        let mut sampled = rule("/v1/creative", MatchType::Prefix, "http://backend", "gpt-4");
+        assert!(sampled.serves_cache_hit(0.99), "hits are always served without cache_probability");
+        sampled.cache_probability = Some(0.3);
+        assert!(sampled.serves_cache_hit(0.29));
+        assert!(!sampled.serves_cache_hit(0.3));
+
+        sampled.cache_probability = Some(1.5);
+        let Err(error) = Router::try_new(vec![sampled]) else { panic!("cache_probability above 1.0 must be rejected") };
+        let error = error.to_string();
+        assert!(error.contains("cache_probability"), "{}", error);
+    }
+
+    #[test]
+    fn test_fair_queue_without_slots_rejected() {
+        let mut queued = rule("/v1/chat", MatchType::Prefix, "http://backend", "gpt-4");
+        queued.fair_queue = Some(toml::from_str("max_concurrent = 0").unwrap());
+        let Err(error) = Router::try_new(vec![queued.clone()]) else { panic!("max_concurrent = 0 must be rejected") };
+        assert!(error.to_string().contains("fair_queue.max_concurrent"), "{}", error);
+
+        queued.fair_queue = Some(toml::from_str("max_concurrent = 1").unwrap());
+        assert!(Router::try_new(vec![queued]).is_ok());
+    }
+
+    #[test]
+    fn test_regex_captures_substituted_into_target() {
+        let router = Router::try_new(vec![rule(
+            "/models/(?P<model>[^/]+)/completions",
+            MatchType::Regex,
+            "http://backend/{model}/v1/completions",
+            "{model}",
+        )])
+        .unwrap();
+
+        let m = router.resolve_match("/models/llama-3/completions").unwrap();
+        assert_eq!(m.captures["model"], "llama-3");
+        assert_eq!(m.target(&UpstreamDrains::default()), Some(UpstreamTarget {
+            id: "http://backend/{model}/v1/completions".to_string(),
+            url: "http://backend/llama-3/v1/completions".to_string(),
+            model: "llama-3".to_string(),
+            region: None,
+            max_in_flight: None,
+        }));
+
+        assert!(router.resolve_match("/other/models/llama-3/completions").is_none());
+    }
+
+    #[test]
+    fn test_captures_percent_encoded_in_target_url() {
+        let router = Router::try_new(vec![rule(
+            "/models/(?P<model>.+)/completions",
+            MatchType::Regex,
+            "http://backend/{model}/v1/completions",
+            "{model}",
+        )])
+        .unwrap();
+
+        let m = router.resolve_match("/models/org/llama 3?x=1#frag/completions").unwrap();
+        let target = m.target(&UpstreamDrains::default()).unwrap();
+        assert_eq!(target.url, "http://backend/org%2Fllama%203%3Fx%3D1%23frag/v1/completions");
+        // モデル名は JSON のボディに入れるため、そのまま代入する
+        assert_eq!(target.model, "org/llama 3?x=1#frag");
+    }
+
+    #[test]
+    fn test_regex_alternation_route() {
+        let router = Router::try_new(vec![
+            rule("/v1/models/(gpt-4|gpt-3.5)/.*", MatchType::Regex, "http://openai", "gpt-4"),
+            rule("/v1/models", MatchType::Prefix, "http://fallback", "llama-3"),
+        ])
+        .unwrap();
+
+        assert_eq!(router.resolve("/v1/models/gpt-3.5/completions").unwrap().match_type, MatchType::Regex);
+        assert_eq!(router.resolve("/v1/models/claude/completions").unwrap().path, "/v1/models");
+        // パターンは先頭に固定される
+        assert_eq!(router.resolve("/proxy/v1/models/gpt-4/completions").map(|r| r.path.as_str()), None);
+    }
+
+    fn model_rule(model: &str, target_url: &str) -> RouteRule {
+        RouteRule {
+            match_model: Some(model.to_string()),
+            ..rule("/v1/chat/completions", MatchType::Prefix, target_url, model)
+        }
+    }
+
+    #[test]
+    fn test_model_in_body_takes_precedence_over_path() {
+        let router = Router::try_new(vec![
+            rule("/v1/chat/completions", MatchType::Prefix, "http://default", "gpt-4"),
+            model_rule("claude-3", "http://anthropic"),
+            model_rule("gpt-4", "http://openai"),
+        ])
+        .unwrap();
+        let target = |body: Value| router.resolve_with_body("/v1/chat/completions", &body).unwrap().targets[0].url.clone();
+
+        assert_eq!(target(json!({"model": "claude-3"})), "http://anthropic");
+        assert_eq!(target(json!({"model": "gpt-4"})), "http://openai");
+        // 一致する match_model が無ければパスで照合する
+        assert_eq!(target(json!({"model": "llama-3"})), "http://default");
+        // パスが一致しなければ model が一致しても選ばない
+        assert!(router.resolve_with_body("/v1/embeddings", &json!({"model": "claude-3"})).is_none());
+    }
+
+    #[test]
+    fn test_body_without_model_falls_back_to_path() {
+        let router = Router::try_new(vec![
+            model_rule("claude-3", "http://anthropic"),
+            rule("/v1/chat", MatchType::Prefix, "http://default", "gpt-4"),
+        ])
+        .unwrap();
+
+        for body in [json!({"messages": []}), json!({"model": 3}), json!([1, 2])] {
+            let m = router.resolve_match_with_body("/v1/chat/completions", &body).unwrap();
+            assert_eq!(m.rule.targets[0].url, "http://default");
+            assert_eq!(m.provenance.skipped, vec!["/v1/chat/completions".to_string()]);
+        }
+        // match_model のルートはパスのみの照合では選ばれない
+        assert!(Router::try_new(vec![model_rule("claude-3", "http://anthropic")]).unwrap().resolve("/v1/chat/completions").is_none());
+    }
+
+    #[test]
+    fn test_upstream_url_joins_remaining_path() {
+        let router = Router::try_new(vec![rule("/openai", MatchType::Prefix, "https://api.openai.com/v1", "gpt-4")]).unwrap();
+        let url = |path: &str, query: Option<&str>| {
+            let m = router.resolve_match(path).unwrap();
+            m.upstream_url(&m.target(&UpstreamDrains::default()).unwrap(), query)
+        };
+
+        assert_eq!(url("/openai", None), "https://api.openai.com/v1");
+        assert_eq!(url("/openai/chat/completions", Some("a=1")), "https://api.openai.com/v1/chat/completions?a=1");
+        // 区切りのない残りでもホストは変わらない
+        assert_eq!(url("/openai@evil.example", None), "https://api.openai.com/v1/@evil.example");
+    }
+
+    #[test]
+    fn test_prefix_match_has_no_captures() {
+        let router = Router::try_new(vec![rule("/v1/chat", MatchType::Prefix, "http://backend/{model}", "gpt-4")]).unwrap();
+        let m = router.resolve_match("/v1/chat/completions").unwrap();
+        assert!(m.captures.is_empty());
+        assert_eq!(m.target(&UpstreamDrains::default()).unwrap().url, "http://backend/{model}");
+    }
+
+    #[test]
+    fn test_provenance_records_evaluation() {
+        let router = Router::try_new(vec![
+            rule("/v1/images", MatchType::Prefix, "http://backend", "dalle-3"),
+            rule("/models/(?P<model>[^/]+)", MatchType::Regex, "http://backend/{model}", "{model}"),
+            rule("/models", MatchType::Prefix, "http://backend", "gpt-4"),
+        ])
+        .unwrap();
+
+        let m = router.resolve_match("/models/llama-3").unwrap();
+        assert_eq!(m.provenance, RouteProvenance {
+            priority: 1,
+            match_type: MatchType::Regex,
+            skipped: vec!["/v1/images".to_string(), "/models".to_string()],
+        });
+        assert_eq!(
+            m.describe(),
+            r#"rule #1 '/models/(?P<model>[^/]+)' matched as Regex (captures: [model=llama-3], skipped: ["/v1/images", "/models"])"#
+        );
+    }
+
+    #[test]
+    fn test_longest_prefix_wins_regardless_of_order() {
+        let router = Router::try_new(vec![
+            rule("/v1", MatchType::Prefix, "http://general", "gpt-4"),
+            rule("/v1/chat", MatchType::Prefix, "http://chat", "gpt-4"),
+            rule("/v1/chat/completions", MatchType::Prefix, "http://exact", "gpt-4"),
+        ])
+        .unwrap();
+
+        assert_eq!(router.resolve("/v1/chat/stream").unwrap().path, "/v1/chat");
+        assert_eq!(router.resolve("/v1/embeddings").unwrap().path, "/v1");
+        // 完全一致は最長の一致になる
+        let exact = router.resolve_match("/v1/chat/completions").unwrap();
+        assert_eq!(exact.rule.path, "/v1/chat/completions");
+        assert_eq!(exact.provenance.priority, 2);
+        assert_eq!(exact.remainder, "");
+    }
+
+    #[test]
+    fn test_equal_length_matches_keep_config_order() {
+        let router = Router::try_new(vec![
+            rule("/v1/chat", MatchType::Prefix, "http://first", "gpt-4"),
+            rule("/v1/chat", MatchType::Prefix, "http://second", "gpt-4"),
+        ])
+        .unwrap();
+        assert_eq!(router.resolve("/v1/chat/completions").unwrap().targets[0].url, "http://first");
+    }
+
+    #[test]
+    fn test_pick_target_skips_draining_targets() {
+        let rule: RouteRule = toml::from_str(r#"
+            path = "/v1/pool"
+            target_model = "gpt-4"
+            target_url = [{ id = "a", url = "http://a" }, { id = "b", url = "http://b" }]
+        "#).unwrap();
+        let drains = UpstreamDrains::default();
+        assert_eq!(rule.pick_target(&drains).unwrap().id(), "a");
+        drains.drain("a");
+        assert_eq!(rule.pick_target(&drains).unwrap().id(), "b");
+        drains.drain("b");
+        assert!(rule.pick_target(&drains).is_none());
+
+        assert!(toml::from_str::<RouteRule>("path = '/x'\ntarget_model = 'm'\ntarget_url = []").is_err());
+    }
+
+    #[test]
+    fn test_weighted_targets_share_traffic() {
+        let rule: RouteRule = toml::from_str(r#"
+            path = "/v1/pool"
+            target_model = "gpt-4"
+            target_url = [
+                { id = "a", url = "http://a", weight = 3 },
+                { id = "b", url = "http://b" },
+                { id = "off", url = "http://off", weight = 0 },
+            ]
+        "#).unwrap();
+        let drains = UpstreamDrains::default();
+        let pick = |roll: f64| rule.pick_with_roll(&drains, roll, |_| true).unwrap().id().to_string();
+        assert_eq!(pick(0.0), "a");
+        assert_eq!(pick(0.74), "a");
+        assert_eq!(pick(0.75), "b");
+        assert_eq!(pick(1.0), "b");
+
+        let n = 10_000;
+        let a = (0..n).filter(|_| rule.pick_target(&drains).unwrap().id() == "a").count();
+        let share = a as f64 / n as f64;
+        assert!((share - 0.75).abs() < 0.03, "share of a {}", share);
+
+        // 重み 0 の転送先は選ばない
+        drains.drain("a");
+        drains.drain("b");
+        assert!(rule.pick_target(&drains).is_none());
+    }
+
+    #[test]
+    fn test_same_region_targets_preferred() {
+        let rule: RouteRule = toml::from_str(r#"
+            path = "/v1/pool"
+            target_model = "gpt-4"
+            target_url = [
+                { id = "us-1", url = "http://us-1", region = "us-east" },
+                { id = "eu-1", url = "http://eu-1", region = "eu-west" },
+                { id = "eu-2", url = "http://eu-2", region = "eu-west" },
+            ]
+        "#).unwrap();
+        let drains = UpstreamDrains::default();
+        let pick = |healthy: &dyn Fn(&TargetEndpoint) -> bool| {
+            rule.pick_preferred(&drains, Some("eu-west"), healthy).unwrap().id().to_string()
+        };
+
+        assert_eq!(pick(&|_| true), "eu-1");
+        // 同じリージョン内で正常なものを選ぶ
+        assert_eq!(pick(&|t| t.id() != "eu-1"), "eu-2");
+        drains.drain("eu-2");
+        assert_eq!(pick(&|t| t.id() != "eu-1"), "us-1");
+        // どれも正常でなければドレイン中でないものを返す
+        assert_eq!(pick(&|_| false), "eu-1");
+        // リージョン未設定のインスタンスは設定順
+        assert_eq!(rule.pick_preferred(&drains, None, |_| true).unwrap().id(), "us-1");
+    }
+
+    #[test]
+    fn test_invalid_host_override_is_rejected() {
+        let mut routed = rule("/internal", MatchType::Prefix, "https://10.0.0.5:8443", "llama-3");
+        routed.host_override = Some("inference.internal.example:8443".to_string());
+        assert!(Router::try_new(vec![routed.clone()]).is_ok());
+
+        for host in ["bad host", "user@inference.internal.example", "inference.internal.example/v1", ""] {
+            routed.host_override = Some(host.to_string());
+            let Err(error) = Router::try_new(vec![routed.clone()]) else { panic!("host_override {:?} must be rejected", host) };
+            assert!(error.to_string().contains("host_override"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_unregistered_transform_is_rejected() {
+        let mut routed = rule("/v1/chat", MatchType::Prefix, "http://backend", "gpt-4");
+        routed.transform = Some("test_routing_unregistered".to_string());
+        let Err(error) = Router::try_new(vec![routed.clone()]) else { panic!("unregistered transform must be rejected") };
+        assert!(error.to_string().contains("test_routing_unregistered"), "{}", error);
+
+        crate::register_transform("test_routing_registered", |_| {});
+        routed.transform = Some("test_routing_registered".to_string());
+        assert!(Router::try_new(vec![routed]).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_regex_is_rejected() {
+        assert!(Router::try_new(vec![rule("/v1/(unclosed", MatchType::Regex, "http://backend", "gpt-4")]).is_err());
+    }
+
+    #[test]
+    fn test_model_alias_normalized() {
+        let aliases = ModelAliases::new(HashMap::from([("fast".to_string(), "gpt-4o".to_string())]));
+        let mut body = json!({"model": "fast", "messages": []});
+        assert!(aliases.normalize(&mut body));
+        assert_eq!(body["model"], "gpt-4o");
+        assert!(!aliases.normalize(&mut body));
+        assert!(!aliases.normalize(&mut json!({"messages": []})));
+    }
+
+    #[test]
+    fn test_upstream_host_allowlist() {
+        let allowlist = UpstreamHostAllowlist::new(&["api.openai.com".to_string(), "*.internal".to_string()]);
+        assert!(allowlist.check("https://api.openai.com/v1/chat/completions").is_ok());
+        assert!(allowlist.check("http://llama.inference.INTERNAL:8080/v1").is_ok());
+        assert!(allowlist.check("http://internal/v1").is_err());
+        assert!(allowlist.check("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(allowlist.check("not a url").is_err());
+        assert!(UpstreamHostAllowlist::default().check("http://anywhere").is_ok());
+    }
+
+    #[test]
+    fn test_routes_validated_at_startup() {
+        let allowlist = UpstreamHostAllowlist::new(&["backend".to_string()]);
+        assert!(allowlist.validate_routes(&[rule("/v1/chat", MatchType::Prefix, "http://backend/v1", "gpt-4")]).is_ok());
+        // ホスト部分のプレースホルダーはリクエスト時に検証する
+        assert!(allowlist
+            .validate_routes(&[rule("/(?P<host>[^/]+)", MatchType::Regex, "http://{host}/v1", "gpt-4")])
+            .is_ok());
+
+        let err = allowlist
+            .validate_routes(&[rule("/v1/evil", MatchType::Prefix, "http://evil.example.com/v1", "gpt-4")])
+            .unwrap_err();
+        assert!(err.to_string().contains("/v1/evil"));
+    }
+
+    #[test]
+    fn test_self_referential_targets_detected() {
+        let rules = vec![
+            rule("/v1/loop", MatchType::Prefix, "http://localhost:8080/v1", "gpt-4"),
+            rule("/v1/other-port", MatchType::Prefix, "http://127.0.0.1:9090/v1", "gpt-4"),
+            rule("/v1/remote", MatchType::Prefix, "http://api.example.com:8080/v1", "gpt-4"),
+        ];
+        assert_eq!(
+            self_referential_targets(&rules, "0.0.0.0", 8080),
+            vec![("/v1/loop".to_string(), "http://localhost:8080/v1".to_string())],
+        );
+        // 特定のアドレスで待ち受けている場合は、同じホスト名だけが自分自身
+        assert!(self_referential_targets(&rules, "10.0.0.5", 8080).is_empty());
+        let named = [rule("/v1/named", MatchType::Prefix, "http://orchix.internal/v1", "gpt-4")];
+        assert_eq!(self_referential_targets(&named, "orchix.internal", 80).len(), 1);
     }
 }
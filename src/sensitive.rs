@@ -0,0 +1,87 @@
+use axum::http::HeaderMap;
+use std::collections::{HashMap, HashSet};
+
+/// 既定でキャッシュ・ログの対象外とするヘッダー
+pub const DEFAULT_SENSITIVE_HEADERS: &[&str] = &[
+    "set-cookie",
+    "cookie",
+    "authorization",
+    "proxy-authorization",
+    "x-api-key",
+    "api-key",
+    "openai-organization",
+    "anthropic-api-key",
+];
+
+pub fn default_sensitive_headers() -> Vec<String> {
+    DEFAULT_SENSITIVE_HEADERS.iter().map(|h| h.to_string()).collect()
+}
+
+/// キャッシュに保存せず、ログにも出さないヘッダーの一覧（大文字小文字は区別しない）
+#[derive(Debug, Clone)]
+pub struct SensitiveHeaders(HashSet<String>);
+
+impl Default for SensitiveHeaders {
+    fn default() -> Self {
+        Self::new(&default_sensitive_headers())
+    }
+}
+
+impl SensitiveHeaders {
+    pub fn new(names: &[String]) -> Self {
+        Self(names.iter().map(|n| n.to_ascii_lowercase()).collect())
+    }
+
+    pub fn is_sensitive(&self, name: &str) -> bool {
+        self.0.contains(&name.to_ascii_lowercase())
+    }
+
+    /// キャッシュ保存用のヘッダーから該当するものを取り除きます
+    pub fn strip(&self, headers: &mut HashMap<String, String>) {
+        headers.retain(|name, _| !self.is_sensitive(name));
+    }
+
+    /// ログ出力用に、該当するヘッダーの値を伏せた一覧を返します
+    pub fn redacted(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.is_sensitive(name.as_str()) {
+                    "[REDACTED]".to_string()
+                } else {
+                    value.to_str().unwrap_or("<binary>").to_string()
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_strip_is_case_insensitive() {
+        let sensitive = SensitiveHeaders::default();
+        let mut headers = HashMap::from([
+            ("Set-Cookie".to_string(), "session=abc".to_string()),
+            ("content-type".to_string(), "application/json".to_string()),
+        ]);
+        sensitive.strip(&mut headers);
+        assert_eq!(headers.keys().collect::<Vec<_>>(), vec!["content-type"]);
+    }
+
+    #[test]
+    fn test_redacted_hides_values() {
+        let sensitive = SensitiveHeaders::new(&["x-provider-secret".to_string()]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-provider-secret", HeaderValue::from_static("s3cr3t"));
+        headers.insert("accept", HeaderValue::from_static("*/*"));
+
+        let redacted = sensitive.redacted(&headers);
+        assert!(redacted.contains(&("x-provider-secret".to_string(), "[REDACTED]".to_string())));
+        assert!(redacted.contains(&("accept".to_string(), "*/*".to_string())));
+    }
+}
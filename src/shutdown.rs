@@ -0,0 +1,191 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    middleware::Next,
+    extract::State,
+};
+use futures::{Stream, StreamExt};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use crate::networking::AppState;
+
+/// グレースフルシャットダウンの進行状況を管理します
+///
+/// シャットダウン開始後、通常のリクエストは `regular_timeout`、
+/// SSE / WebSocket のストリームは `streaming_timeout` まで完了を待ちます。
+pub struct Shutdown {
+    // シャットダウン開始時刻（未開始なら None）
+    started: watch::Sender<Option<Instant>>,
+    regular_timeout: Duration,
+    streaming_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(regular_timeout: Duration, streaming_timeout: Duration) -> Self {
+        let (started, _) = watch::channel(None);
+        Self {
+            started,
+            regular_timeout,
+            streaming_timeout,
+        }
+    }
+
+    /// シャットダウンを開始します（2回目以降の呼び出しは無視）
+    pub fn begin(&self) {
+        self.started.send_if_modified(|started| {
+            if started.is_some() {
+                return false;
+            }
+            info!(
+                "Shutdown started, draining requests ({:?}) and streams ({:?})",
+                self.regular_timeout, self.streaming_timeout
+            );
+            *started = Some(Instant::now());
+            true
+        });
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.started.borrow().is_some()
+    }
+
+    /// シャットダウン開始から `timeout` 経過すると完了する Future
+    pub fn deadline(&self, timeout: Duration) -> impl Future<Output = ()> + Send + use<> {
+        let mut rx = self.started.subscribe();
+        async move {
+            let started = loop {
+                let current = *rx.borrow_and_update();
+                if let Some(started) = current {
+                    break started;
+                }
+                if rx.changed().await.is_err() {
+                    // 送信側が破棄された場合、シャットダウンは開始されない
+                    std::future::pending::<()>().await;
+                }
+            };
+            tokio::time::sleep_until(started + timeout).await;
+        }
+    }
+
+    pub fn regular_deadline(&self) -> impl Future<Output = ()> + Send + use<> {
+        self.deadline(self.regular_timeout)
+    }
+
+    pub fn streaming_deadline(&self) -> impl Future<Output = ()> + Send + use<> {
+        self.deadline(self.streaming_timeout)
+    }
+
+    /// すべての猶予が尽きる時刻（プロセスを強制終了する目安）
+    pub fn final_deadline(&self) -> impl Future<Output = ()> + Send + use<> {
+        self.deadline(self.regular_timeout.max(self.streaming_timeout))
+    }
+
+    /// ストリーミング猶予を過ぎたらストリームを終了させるラッパー
+    pub fn guard_stream<S: Stream>(&self, stream: S) -> impl Stream<Item = S::Item> + use<S> {
+        stream.take_until(self.streaming_deadline())
+    }
+}
+
+/// 通常リクエストのドレイン猶予を過ぎたら 503 で打ち切るミドルウェア
+///
+/// ストリーミングレスポンスはヘッダー送信時点でここを通過し、
+/// 以降は `Shutdown::guard_stream` の猶予に従います。
+pub async fn shutdown_middleware(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    tokio::select! {
+        res = next.run(req) => res,
+        _ = state.shutdown.regular_deadline() => {
+            warn!("Request cut off by shutdown timeout");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+/// SIGTERM または Ctrl-C を待ちます
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_survives_regular_timeout_but_not_streaming_timeout() {
+        let shutdown = Shutdown::new(Duration::from_millis(100), Duration::from_millis(500));
+
+        // 10ms ごとに無限にイベントを流すストリーム
+        let ticks = stream::unfold(0u32, |n| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Some((n, n + 1))
+        });
+        let mut guarded = Box::pin(shutdown.guard_stream(ticks));
+        let regular = shutdown.regular_deadline();
+
+        let start = Instant::now();
+        shutdown.begin();
+        assert!(shutdown.is_draining());
+
+        let mut regular = Box::pin(regular);
+        let mut received_after_regular = 0;
+        let mut regular_passed = false;
+        loop {
+            tokio::select! {
+                item = guarded.next() => match item {
+                    Some(_) if regular_passed => received_after_regular += 1,
+                    Some(_) => {}
+                    None => break,
+                },
+                _ = &mut regular, if !regular_passed => regular_passed = true,
+            }
+        }
+
+        let elapsed = start.elapsed();
+        assert!(regular_passed);
+        assert!(received_after_regular > 0, "stream should keep flowing after the regular timeout");
+        assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_millis(520), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_waits_for_shutdown() {
+        let shutdown = Shutdown::new(Duration::from_millis(10), Duration::from_millis(10));
+        let deadline = shutdown.regular_deadline();
+        let timed_out = tokio::time::timeout(Duration::from_secs(60), deadline).await;
+        assert!(timed_out.is_err(), "deadline must not fire before shutdown begins");
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::broadcast;
+use crate::cache::{CacheKey, CachedResponse};
+
+/// 同一キーの同時リクエストを1回の処理にまとめる（シングルフライト）
+#[derive(Default)]
+pub struct SingleFlight {
+    inflight: Mutex<HashMap<CacheKey, broadcast::Sender<CachedResponse>>>,
+    coalesced: AtomicU64,
+}
+
+/// `SingleFlight::join` の結果
+pub enum Flight<'a> {
+    /// このリクエストが処理を担当する
+    Leader(FlightLeader<'a>),
+    /// 先行リクエストの結果を待つ
+    Follower(broadcast::Receiver<CachedResponse>),
+}
+
+/// 処理担当のハンドル。`complete` せずに破棄すると待機側は自前で処理します
+pub struct FlightLeader<'a> {
+    flights: &'a SingleFlight,
+    key: CacheKey,
+    sender: broadcast::Sender<CachedResponse>,
+}
+
+impl SingleFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn join(&self, key: &CacheKey) -> Flight<'_> {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(sender) = inflight.get(key) {
+            return Flight::Follower(sender.subscribe());
+        }
+
+        let (sender, _) = broadcast::channel(1);
+        inflight.insert(key.clone(), sender.clone());
+        Flight::Leader(FlightLeader {
+            flights: self,
+            key: key.clone(),
+            sender,
+        })
+    }
+
+    /// 先行リクエストの結果を待ちます。担当側が失敗した場合は None
+    pub async fn wait(&self, mut receiver: broadcast::Receiver<CachedResponse>) -> Option<CachedResponse> {
+        let response = receiver.recv().await.ok()?;
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+        Some(response)
+    }
+
+    /// まとめられたリクエストの累計数
+    pub fn coalesced_count(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+impl FlightLeader<'_> {
+    /// 結果を待機中のリクエストへ共有します
+    pub fn complete(self, response: CachedResponse) {
+        self.flights.inflight.lock().unwrap().remove(&self.key);
+        // 待機者がいない場合の送信エラーは無視
+        let _ = self.sender.send(response);
+    }
+}
+
+impl Drop for FlightLeader<'_> {
+    fn drop(&mut self) {
+        // complete 済みなら既に削除されている
+        let mut inflight = self.flights.inflight.lock().unwrap();
+        if inflight.get(&self.key).is_some_and(|s| s.same_channel(&self.sender)) {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn response(body: &'static str) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: HashMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_followers_share_leader_result() {
+        let flights = SingleFlight::new();
+        let key = CacheKey::new("/v1/chat", b"{}");
+
+        let Flight::Leader(leader) = flights.join(&key) else { panic!("first join must lead") };
+        let followers: Vec<_> = (0..3)
+            .map(|_| match flights.join(&key) {
+                Flight::Follower(rx) => rx,
+                Flight::Leader(_) => panic!("later joins must follow"),
+            })
+            .collect();
+
+        leader.complete(response("shared"));
+        for rx in followers {
+            assert_eq!(flights.wait(rx).await.unwrap().body, "shared");
+        }
+        assert_eq!(flights.coalesced_count(), 3);
+
+        // 完了後は新しいリクエストが再び担当になる
+        assert!(matches!(flights.join(&key), Flight::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_leader_releases_followers() {
+        let flights = SingleFlight::new();
+        let key = CacheKey::new("/v1/chat", b"{}");
+
+        let leader = flights.join(&key);
+        let Flight::Follower(rx) = flights.join(&key) else { panic!("second join must follow") };
+        drop(leader);
+
+        assert!(flights.wait(rx).await.is_none());
+        assert_eq!(flights.coalesced_count(), 0);
+        assert!(matches!(flights.join(&key), Flight::Leader(_)));
+    }
+}
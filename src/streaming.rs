@@ -1,12 +1,73 @@
 use futures::Stream;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use bytes::{Bytes, BytesMut};
 use tracing::warn;
 use serde_json::Value;
-use crate::interception::Interceptor;
+use schemars::JsonSchema;
+use crate::interception::{InterceptionTrace, Interceptor, TraceVerdict};
 use std::sync::Arc;
 use axum::response::sse::Event;
+use serde::Deserialize;
+use crate::cost_control::{self, ModelPrice, Usage};
+use crate::response_format::{ChunkTranslator, ResponseFormat};
+
+/// ストリーム終了時に送る使用量イベントの名前
+pub const USAGE_EVENT: &str = "orchix.usage";
+
+/// Orchix が先頭・末尾に差し込むメタデータイベントの名前（クライアントは無視してよい）
+pub const METADATA_EVENT: &str = "orchix";
+
+/// ルートごとのストリーミング解析オプション
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct StreamOptions {
+    /// 上流の SSE コメント行（`: ping` などのキープアライブ）を取り除き、
+    /// Orchix 側のキープアライブ間隔に統一する
+    pub strip_keepalive_comments: bool,
+    /// 1つのストリームで追跡する choice のインデックス数の上限（超えるとストリームを中断）
+    pub max_choice_indices: usize,
+    /// 1つのストリームで追跡するツール呼び出しのインデックス数の上限（全 choice の合計）
+    pub max_tool_call_indices: usize,
+    /// 先頭（ルート・リクエスト ID）と末尾（使用量・コスト）に `event: orchix` を差し込む
+    pub metadata_events: bool,
+    /// `[DONE]` の後に届いたデータの扱い（いずれの場合もクライアントには送らない）
+    pub trailing_data: TrailingDataMode,
+    /// クライアントへ送るイベントの毎秒の上限（未設定なら制限しない）
+    ///
+    /// 上限に達している間は上流を読まずに待つため、上流にはバックプレッシャーがかかります。
+    pub max_events_per_second: Option<u32>,
+    /// 本文（`delta.content`）だけの差分をこのミリ秒数の間まとめ、1つのイベントとして送る（未設定なら結合しない）
+    ///
+    /// ツール呼び出しや終了理由を含むチャンクはまとめず、その前に保留中の差分を送ります。
+    pub coalesce_window_ms: Option<u64>,
+}
+
+/// `[DONE]` 以降に上流が送ってきた余分なデータの扱い
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingDataMode {
+    /// 黙って捨てる
+    #[default]
+    Drop,
+    /// 捨てた上で警告ログを出す
+    Warn,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            strip_keepalive_comments: true,
+            max_choice_indices: 32,
+            max_tool_call_indices: 256,
+            metadata_events: false,
+            trailing_data: TrailingDataMode::Drop,
+            max_events_per_second: None,
+            coalesce_window_ms: None,
+        }
+    }
+}
 
 /// ストリーミングレスポンスを解析するためのラッパー
 pub struct StreamingAnalyzer<S> {
@@ -14,8 +75,292 @@ pub struct StreamingAnalyzer<S> {
     interceptor: Arc<Interceptor>,
     buffer: BytesMut,
     pending_events: std::collections::VecDeque<Result<Event, axum::Error>>,
-    full_response_buffer: BytesMut,
+    // クライアントに送った本文のイベント（変換・結合後の SSE）。キャッシュにはこれを保存する
+    emitted: BytesMut,
     cache_info: Option<(crate::cache::OrchixCache, crate::cache::CacheKey)>,
+    // ルートで指定されたキャッシュの TTL
+    cache_ttl: Option<std::time::Duration>,
+    options: StreamOptions,
+    usage: Option<UsageTracker>,
+    // 分割して届くツール呼び出しを (choice, tool) のインデックスごとに再構成する
+    tool_calls: std::collections::BTreeMap<(u64, u64), PendingToolCall>,
+    intercept: bool,
+    // 組み立て済みレスポンスのキャッシュ（キーが設定されている場合のみ集約する）
+    aggregate: Option<(crate::cache::CacheKey, ResponseAggregator)>,
+    cache_raw: bool,
+    translator: Option<ChunkTranslator>,
+    // これまでに現れた choice ごとのツール呼び出しのインデックス（上限の判定用）
+    seen_indices: std::collections::BTreeMap<u64, std::collections::BTreeSet<u64>>,
+    // ポリシー違反などのエラーを返した後は、上流を読まずに接続を閉じる
+    terminated: bool,
+    // `[DONE]` を受け取った後は上流を読まずにストリームを閉じる
+    done: bool,
+    // 空行が来るまで連結している現在のイベントの `data:` 行
+    event_data: Option<String>,
+    // 差し込むメタデータイベント（末尾は `[DONE]` の直前に送る）
+    leading_event: Option<Value>,
+    trailing_event: Option<Value>,
+    // 出典のイベント（末尾のメタデータイベントより前に送る）
+    sources_event: Option<Value>,
+    // リクエスト側のインターセプションの記録（ストリームの検査結果を加えて最後に送る）
+    interception_trace: Option<InterceptionTrace>,
+    pacer: Option<EventPacer>,
+    coalescer: Option<DeltaCoalescer>,
+}
+
+/// イベントの送信間隔を一定以上に保つ
+struct EventPacer {
+    interval: std::time::Duration,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+    // 直前の送信から `interval` が経過するまで待つ必要があるか
+    waiting: bool,
+}
+
+impl EventPacer {
+    fn new(max_events_per_second: u32) -> Self {
+        Self {
+            interval: std::time::Duration::from_secs(1) / max_events_per_second.max(1),
+            sleep: Box::pin(tokio::time::sleep(std::time::Duration::ZERO)),
+            waiting: false,
+        }
+    }
+
+    /// 次のイベントを送ってよければ Ready
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.waiting {
+            std::task::ready!(self.sleep.as_mut().poll(cx));
+            self.waiting = false;
+        }
+        Poll::Ready(())
+    }
+
+    fn sent(&mut self) {
+        let deadline = tokio::time::Instant::now() + self.interval;
+        self.sleep.as_mut().reset(deadline);
+        self.waiting = true;
+    }
+}
+
+/// 本文だけの小さな差分を時間枠の間まとめる
+struct DeltaCoalescer {
+    window: std::time::Duration,
+    // 保留中の差分が来た時点から `window` 後に送る
+    sleep: Pin<Box<tokio::time::Sleep>>,
+    // 後続の差分の `content` を連結した保留中のチャンク
+    held: Option<Value>,
+}
+
+impl DeltaCoalescer {
+    fn new(window_ms: u64) -> Self {
+        Self {
+            window: std::time::Duration::from_millis(window_ms),
+            sleep: Box::pin(tokio::time::sleep(std::time::Duration::ZERO)),
+            held: None,
+        }
+    }
+
+    /// 本文だけの差分を保留中のチャンクに連結します
+    ///
+    /// まとめられない差分、または保留中のものと choice が異なる場合は false を返します。
+    fn merge(&mut self, json: &Value) -> bool {
+        let Some((index, content)) = content_delta(json) else {
+            return false;
+        };
+        match &mut self.held {
+            None => {
+                self.held = Some(json.clone());
+                self.sleep.as_mut().reset(tokio::time::Instant::now() + self.window);
+                true
+            }
+            Some(held) if content_delta(held).is_some_and(|(held_index, _)| held_index == index) => {
+                if let Some(Value::String(text)) = held.pointer_mut("/choices/0/delta/content") {
+                    text.push_str(content);
+                }
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// 保留中の差分をイベントの `data` として取り出します
+    fn take(&mut self) -> Option<String> {
+        self.held.take().map(|json| json.to_string())
+    }
+
+    /// 時間枠が過ぎていれば保留中の差分を取り出します（過ぎていなければ起床を登録する）
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Option<String> {
+        if self.held.is_none() || self.sleep.as_mut().poll(cx).is_pending() {
+            return None;
+        }
+        self.take()
+    }
+}
+
+/// choice が1つで、差分が `content` の文字列だけのチャンクなら (choice のインデックス, 本文) を返します
+fn content_delta(json: &Value) -> Option<(u64, &str)> {
+    let object = json.as_object()?;
+    // 使用量を含むチャンクはそのまま送る
+    if object.get("usage").is_some_and(|usage| !usage.is_null()) {
+        return None;
+    }
+    let [choice] = object.get("choices")?.as_array()?.as_slice() else {
+        return None;
+    };
+    let choice = choice.as_object()?;
+    let settled = |key: &str| choice.get(key).is_none_or(Value::is_null);
+    if !settled("finish_reason") || !settled("logprobs") {
+        return None;
+    }
+    let delta = choice.get("delta")?.as_object()?;
+    if delta.len() != 1 {
+        return None;
+    }
+    let content = delta.get("content")?.as_str()?;
+    Some((choice.get("index").and_then(Value::as_u64).unwrap_or(0), content))
+}
+
+/// `chat.completion.chunk` のストリームを非ストリーミング形式の JSON に組み立てる
+#[derive(Default)]
+pub struct ResponseAggregator {
+    id: Option<Value>,
+    model: Option<Value>,
+    created: Option<Value>,
+    choices: std::collections::BTreeMap<u64, AggregatedChoice>,
+    usage: Option<Value>,
+}
+
+#[derive(Default)]
+struct AggregatedChoice {
+    role: Option<String>,
+    content: Option<String>,
+    tool_calls: std::collections::BTreeMap<u64, (Option<Value>, String, String)>,
+    finish_reason: Option<Value>,
+}
+
+impl ResponseAggregator {
+    pub fn push(&mut self, chunk: &Value) {
+        for (field, slot) in [("id", &mut self.id), ("model", &mut self.model), ("created", &mut self.created)] {
+            if slot.is_none() {
+                *slot = chunk.get(field).cloned();
+            }
+        }
+        if let Some(usage) = chunk.get("usage").filter(|u| !u.is_null()) {
+            self.usage = Some(usage.clone());
+        }
+
+        let Some(choices) = chunk.get("choices").and_then(|v| v.as_array()) else {
+            return;
+        };
+        for (position, choice) in choices.iter().enumerate() {
+            let index = choice.get("index").and_then(|v| v.as_u64()).unwrap_or(position as u64);
+            let aggregated = self.choices.entry(index).or_default();
+            if let Some(reason) = choice.get("finish_reason").filter(|r| !r.is_null()) {
+                aggregated.finish_reason = Some(reason.clone());
+            }
+            let Some(delta) = choice.get("delta") else {
+                continue;
+            };
+            if let Some(role) = delta.get("role").and_then(|v| v.as_str()) {
+                aggregated.role = Some(role.to_string());
+            }
+            if let Some(content) = delta.get("content").and_then(|v| v.as_str()) {
+                aggregated.content.get_or_insert_with(String::new).push_str(content);
+            }
+            for call in delta.get("tool_calls").and_then(|v| v.as_array()).into_iter().flatten() {
+                let call_index = call.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                let (id, name, arguments) = aggregated.tool_calls.entry(call_index).or_default();
+                if id.is_none() {
+                    *id = call.get("id").cloned();
+                }
+                if let Some(part) = call.pointer("/function/name").and_then(|v| v.as_str()) {
+                    name.push_str(part);
+                }
+                if let Some(part) = call.pointer("/function/arguments").and_then(|v| v.as_str()) {
+                    arguments.push_str(part);
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> Value {
+        let choices: Vec<Value> = self.choices.into_iter().map(|(index, choice)| {
+            let mut message = serde_json::json!({
+                "role": choice.role.unwrap_or_else(|| "assistant".to_string()),
+                "content": choice.content,
+            });
+            if !choice.tool_calls.is_empty() {
+                message["tool_calls"] = choice.tool_calls.into_values().map(|(id, name, arguments)| {
+                    serde_json::json!({
+                        "id": id,
+                        "type": "function",
+                        "function": {"name": name, "arguments": arguments},
+                    })
+                }).collect();
+            }
+            serde_json::json!({
+                "index": index,
+                "message": message,
+                "finish_reason": choice.finish_reason,
+            })
+        }).collect();
+
+        let mut response = serde_json::json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        });
+        if let Some(usage) = self.usage {
+            response["usage"] = usage;
+        }
+        response
+    }
+}
+
+#[derive(Default)]
+struct PendingToolCall {
+    name: String,
+    arguments: String,
+    checked: bool,
+}
+
+/// ストリーム中の使用量を集計する
+struct UsageTracker {
+    prompt_tokens: u32,
+    price: Option<ModelPrice>,
+    completion_text: String,
+    // 上流が `usage` を含むチャンクを送ってきた場合はその値を優先する
+    reported: Option<Usage>,
+}
+
+impl UsageTracker {
+    fn observe(&mut self, json: &Value) {
+        if let Some(usage) = Usage::from_json(json) {
+            self.reported = Some(usage);
+        }
+        if let Some(choices) = json.get("choices").and_then(|v| v.as_array()) {
+            for content in choices.iter().filter_map(|c| c.pointer("/delta/content").and_then(|v| v.as_str())) {
+                self.completion_text.push_str(content);
+            }
+        }
+    }
+
+    fn summary(&self) -> Value {
+        let usage = self.reported.unwrap_or(Usage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: cost_control::estimate_tokens(&self.completion_text),
+        });
+        serde_json::json!({
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "estimated_cost": self.price.map(|p| p.cost(&usage)),
+        })
+    }
+
+    fn into_event(self) -> Event {
+        Event::default().event(USAGE_EVENT).data(self.summary().to_string())
+    }
 }
 
 impl<S> StreamingAnalyzer<S> {
@@ -29,53 +374,373 @@ impl<S> StreamingAnalyzer<S> {
             interceptor,
             buffer: BytesMut::new(),
             pending_events: std::collections::VecDeque::new(),
-            full_response_buffer: BytesMut::new(),
+            emitted: BytesMut::new(),
             cache_info,
+            cache_ttl: None,
+            options: StreamOptions::default(),
+            usage: None,
+            tool_calls: std::collections::BTreeMap::new(),
+            intercept: true,
+            aggregate: None,
+            cache_raw: true,
+            // 形式はストリームごとに最初のイベントから判定する
+            translator: ResponseFormat::Auto.chunk_translator(),
+            seen_indices: std::collections::BTreeMap::new(),
+            terminated: false,
+            done: false,
+            event_data: None,
+            leading_event: None,
+            trailing_event: None,
+            sources_event: None,
+            interception_trace: None,
+            pacer: None,
+            coalescer: None,
+        }
+    }
+
+    /// キャッシュに保存するエントリの TTL を指定します（None なら全体の設定）
+    pub fn with_cache_ttl(mut self, ttl: Option<std::time::Duration>) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// 上流の形式に応じて、各イベントを OpenAI 互換のチャンクに変換してから解析・送信します
+    pub fn with_response_format(mut self, format: ResponseFormat) -> Self {
+        self.translator = format.chunk_translator();
+        self
+    }
+
+    /// 組み立て済みの JSON を `key` にキャッシュします
+    ///
+    /// `keep_raw` が false の場合、SSE のボディそのものはキャッシュしません。
+    /// キャッシュ情報（`cache_info`）が設定されている場合のみ有効です。
+    pub fn with_aggregated_cache(mut self, key: crate::cache::CacheKey, keep_raw: bool) -> Self {
+        self.aggregate = Some((key, ResponseAggregator::default()));
+        self.cache_raw = keep_raw;
+        self
+    }
+
+    /// ポリシー検査の有無を切り替えます（無効でも使用量の集計やキャッシュは行う）
+    pub fn with_interception(mut self, enabled: bool) -> Self {
+        self.intercept = enabled;
+        self
+    }
+
+    /// ストリーム終了時に使用量と推定コストを `orchix.usage` イベントとして送ります
+    ///
+    /// ストリーミングではヘッダー送信後に値が確定するため、ヘッダーの代わりに使います。
+    pub fn with_usage_trailer(mut self, prompt_tokens: u32, price: Option<ModelPrice>) -> Self {
+        self.usage = Some(UsageTracker {
+            prompt_tokens,
+            price,
+            completion_text: String::new(),
+            reported: None,
+        });
+        self
+    }
+
+    /// 最初のイベントとして `event: orchix` を送ります（上流のイベントより前）
+    pub fn with_leading_event(mut self, data: Value) -> Self {
+        self.leading_event = Some(data);
+        self
+    }
+
+    /// `[DONE]` の直前（無ければストリームの最後）に `event: orchix` を送ります
+    ///
+    /// `with_usage_trailer` が設定されていれば、`usage` に使用量と推定コストを含めます。
+    pub fn with_trailing_event(mut self, data: Value) -> Self {
+        self.trailing_event = Some(data);
+        self
+    }
+
+    /// `[DONE]` の直前に出典を `event: orchix`（`type` は `sources`）として送ります
+    pub fn with_sources_event(mut self, field: &str, sources: Value) -> Self {
+        self.sources_event = Some(serde_json::json!({"type": "sources", field: sources}));
+        self
+    }
+
+    /// ストリームの検査結果を加えたトレースを `event: orchix`（`type` は `interception_trace`）として送ります
+    ///
+    /// ポリシー違反で打ち切る場合も、エラーの直前に送ります。
+    pub fn with_interception_trace(mut self, trace: InterceptionTrace) -> Self {
+        self.interception_trace = Some(trace);
+        self
+    }
+
+    fn push_interception_trace(&mut self, verdict: TraceVerdict, detail: Option<String>) {
+        if let Some(mut trace) = self.interception_trace.take() {
+            trace.record("stream_content", verdict, detail);
+            let mut data = trace.to_json();
+            data["type"] = "interception_trace".into();
+            self.pending_events.push_back(Ok(Event::default().event(METADATA_EVENT).data(data.to_string())));
+        }
+    }
+
+    fn push_trailing_event(&mut self) {
+        let verdict = if self.intercept { TraceVerdict::Pass } else { TraceVerdict::Skipped };
+        self.push_interception_trace(verdict, None);
+        if let Some(data) = self.sources_event.take() {
+            self.pending_events.push_back(Ok(Event::default().event(METADATA_EVENT).data(data.to_string())));
         }
+        if let Some(mut data) = self.trailing_event.take() {
+            if let (Some(usage), Some(object)) = (&self.usage, data.as_object_mut()) {
+                object.insert("usage".to_string(), usage.summary());
+            }
+            self.pending_events.push_back(Ok(Event::default().event(METADATA_EVENT).data(data.to_string())));
+        }
+    }
+
+    pub fn with_options(mut self, options: StreamOptions) -> Self {
+        self.pacer = options.max_events_per_second.map(EventPacer::new);
+        self.coalescer = options.coalesce_window_ms.filter(|&ms| ms > 0).map(DeltaCoalescer::new);
+        self.options = options;
+        self
     }
 
+    /// これまでにクライアントへ送った本文のイベント（SSE の形式）
     pub fn get_full_response(&self) -> Bytes {
-        self.full_response_buffer.clone().freeze()
+        self.emitted.clone().freeze()
+    }
+
+    /// 本文の `data` をイベントにし、キャッシュする場合は送った内容として記録します
+    fn data_event(&mut self, data: String) -> Event {
+        if self.cache_raw && self.cache_info.is_some() {
+            for line in data.split('\n') {
+                self.emitted.extend_from_slice(b"data: ");
+                self.emitted.extend_from_slice(line.as_bytes());
+                self.emitted.extend_from_slice(b"\n");
+            }
+            self.emitted.extend_from_slice(b"\n");
+        }
+        Event::default().data(data)
     }
 
     /// 改行区切りで SSE 行を抽出して解析する
     fn process_buffer(&mut self) {
-        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+        while !self.done
+            && let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
             let line_bytes = self.buffer.split_to(pos + 1);
             let line = String::from_utf8_lossy(&line_bytes);
-            let line = line.trim();
+            // CRLF の区切りにも対応する
+            let line = line.trim_end_matches(['\n', '\r']);
 
-            if line.is_empty() {
+            // 空行でイベントが終わる
+            if line.trim().is_empty() {
+                if !self.dispatch_event() {
+                    return;
+                }
+                continue;
+            }
+
+            // SSE のコメント行（主にキープアライブ）
+            if let Some(comment) = line.strip_prefix(':') {
+                if !self.options.strip_keepalive_comments {
+                    self.flush_coalesced();
+                    self.pending_events.push_back(Ok(Event::default().comment(comment.trim_start())));
+                }
                 continue;
             }
 
-            if line.starts_with("data: ") {
-                let data = &line[6..];
-                
-                // 特定のデータを解析
-                if data != "[DONE]" {
-                    if let Ok(json) = serde_json::from_str::<Value>(data) {
-                        if let Err(msg) = self.content_interception(&json) {
-                            self.pending_events.push_back(Err(axum::Error::new(msg)));
-                            return;
-                        }
+            self.push_data_line(line);
+        }
+        if self.done {
+            self.discard_trailing_data();
+        }
+    }
+
+    /// 上流のストリームの終了時に、空行で閉じられていない最後のイベントを処理します
+    fn process_end_of_stream(&mut self) {
+        self.process_buffer();
+        if self.done {
+            return;
+        }
+        if !self.buffer.is_empty() {
+            let rest = self.buffer.split();
+            self.push_data_line(String::from_utf8_lossy(&rest).trim_end_matches('\r'));
+        }
+        self.dispatch_event();
+    }
+
+    /// `data:` 行を現在のイベントに追加します（複数行は改行でつなげて1つのデータにする）
+    fn push_data_line(&mut self, line: &str) {
+        let Some(data) = line.strip_prefix("data:") else {
+            return;
+        };
+        let data = data.strip_prefix(' ').unwrap_or(data);
+        match &mut self.event_data {
+            Some(pending) => {
+                pending.push('\n');
+                pending.push_str(data);
+            }
+            None => self.event_data = Some(data.to_string()),
+        }
+    }
+
+    /// 連結した `data:` を1つのイベントとして解析・送信します。ブロックした場合は false
+    fn dispatch_event(&mut self) -> bool {
+        let Some(data) = self.event_data.take() else {
+            return true;
+        };
+        // 上流の形式に応じて OpenAI 互換のチャンクに変換する
+        let translated = match &mut self.translator {
+            Some(translator) => translator.translate(&data),
+            None => vec![data],
+        };
+        translated.into_iter().all(|data| self.process_data(data))
+    }
+
+    /// `[DONE]` より後ろのバイト列を捨てます
+    fn discard_trailing_data(&mut self) {
+        let trailing = std::mem::take(&mut self.buffer);
+        if self.options.trailing_data == TrailingDataMode::Warn && !trailing.iter().all(u8::is_ascii_whitespace) {
+            warn!("Discarding {} bytes received after [DONE]", trailing.len());
+        }
+    }
+
+    /// OpenAI 互換の `data` を解析して送信キューに積みます。ブロックした場合は false
+    fn process_data(&mut self, data: String) -> bool {
+        // 変換後の1チャンクから複数の終端が出ても2つ目以降は送らない
+        if self.done {
+            return true;
+        }
+        // 特定のデータを解析
+        if data != "[DONE]"
+            && let Ok(json) = serde_json::from_str::<Value>(&data)
+        {
+            // 大量のインデックスを宣言して再構成用のバッファを膨らませるストリームを止める
+            if let Err(msg) = self.track_indices(&json) {
+                warn!("{}", msg);
+                self.flush_coalesced();
+                self.pending_events.push_back(Err(axum::Error::new(msg)));
+                return false;
+            }
+            if self.intercept
+                && let Err(msg) = self.content_interception(&json).and_then(|_| self.assemble_tool_calls(&json))
+            {
+                self.flush_coalesced();
+                self.push_interception_trace(TraceVerdict::Block, Some(msg.clone()));
+                self.pending_events.push_back(Err(axum::Error::new(msg)));
+                return false;
+            }
+            if let Some(usage) = &mut self.usage {
+                usage.observe(&json);
+            }
+            if let Some((_, aggregator)) = &mut self.aggregate {
+                aggregator.push(&json);
+            }
+            if self.coalesce(&json) {
+                return true;
+            }
+        }
+        self.flush_coalesced();
+
+        // 終端の前に Orchix のメタデータを送る
+        if data == "[DONE]" {
+            self.push_trailing_event();
+            self.done = true;
+        }
+
+        // Event として再構築して追加
+        let event = self.data_event(data);
+        self.pending_events.push_back(Ok(event));
+        true
+    }
+
+    /// 本文だけの差分を結合の保留に加えます（加えた場合は true で、イベントはまだ送らない）
+    fn coalesce(&mut self, json: &Value) -> bool {
+        let Some(coalescer) = &mut self.coalescer else {
+            return false;
+        };
+        if coalescer.merge(json) {
+            return true;
+        }
+        // 別の choice の差分は、保留中のものを送ってから新しく保留する
+        if let Some(data) = coalescer.take() {
+            let event = self.data_event(data);
+            self.pending_events.push_back(Ok(event));
+        }
+        self.coalescer.as_mut().is_some_and(|coalescer| coalescer.merge(json))
+    }
+
+    /// 保留中の差分を送信キューに積みます（後続のイベントより前に送るため）
+    fn flush_coalesced(&mut self) {
+        if let Some(data) = self.coalescer.as_mut().and_then(DeltaCoalescer::take) {
+            let event = self.data_event(data);
+            self.pending_events.push_back(Ok(event));
+        }
+    }
+
+    /// choice / ツール呼び出しのインデックス数が上限内かを確認します
+    fn track_indices(&mut self, json: &Value) -> Result<(), String> {
+        let Some(choices) = json.get("choices").and_then(|v| v.as_array()) else {
+            return Ok(());
+        };
+        for (position, choice) in choices.iter().enumerate() {
+            let choice_index = choice.get("index").and_then(|v| v.as_u64()).unwrap_or(position as u64);
+            if !self.seen_indices.contains_key(&choice_index) && self.seen_indices.len() >= self.options.max_choice_indices {
+                return Err(format!("Stream exceeded the limit of {} choice indices", self.options.max_choice_indices));
+            }
+            let tracked: usize = self.seen_indices.values().map(|tools| tools.len()).sum();
+            let tools = self.seen_indices.entry(choice_index).or_default();
+            let calls = choice.pointer("/delta/tool_calls").and_then(|v| v.as_array()).into_iter().flatten();
+            let mut added = 0;
+            for call in calls {
+                let index = call.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                if !tools.contains(&index) {
+                    if tracked + added >= self.options.max_tool_call_indices {
+                        return Err(format!(
+                            "Stream exceeded the limit of {} tool call indices",
+                            self.options.max_tool_call_indices
+                        ));
                     }
+                    tools.insert(index);
+                    added += 1;
                 }
+            }
+        }
+        Ok(())
+    }
 
-                // Event として再構築して追加
-                self.pending_events.push_back(Ok(axum::response::sse::Event::default().data(data)));
+    /// 分割されたツール呼び出しの引数を連結し、JSON として完成した時点で検証する
+    fn assemble_tool_calls(&mut self, json: &Value) -> Result<(), String> {
+        let Some(choices) = json.get("choices").and_then(|v| v.as_array()) else {
+            return Ok(());
+        };
+        for (position, choice) in choices.iter().enumerate() {
+            let choice_index = choice.get("index").and_then(|v| v.as_u64()).unwrap_or(position as u64);
+            let Some(calls) = choice.pointer("/delta/tool_calls").and_then(|v| v.as_array()) else {
+                continue;
+            };
+            for call in calls {
+                let index = call.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                let pending = self.tool_calls.entry((choice_index, index)).or_default();
+                if let Some(name) = call.pointer("/function/name").and_then(|v| v.as_str()) {
+                    pending.name.push_str(name);
+                }
+                if let Some(fragment) = call.pointer("/function/arguments").and_then(|v| v.as_str()) {
+                    pending.arguments.push_str(fragment);
+                }
+                if !pending.checked && serde_json::from_str::<Value>(&pending.arguments).is_ok() {
+                    pending.checked = true;
+                    let arguments = Value::String(pending.arguments.clone());
+                    self.interceptor.validate_argument_schema(&pending.name, &arguments)?;
+                    self.interceptor.validate_path_arguments(&pending.name, &arguments)?;
+                }
             }
         }
+        Ok(())
     }
 
     /// ストリーム内の JSON チャンクを解析し、ポリシー違反がないかチェックする
+    ///
+    /// Anthropic / Responses API のイベントは `translator` で OpenAI 互換のチャンクに変換してから渡される。
     fn content_interception(&self, json: &Value) -> Result<(), String> {
         // choices[0].delta.tool_calls などを想定
         if let Some(choices) = json.get("choices").and_then(|v| v.as_array()) {
             for choice in choices {
                 if let Some(delta) = choice.get("delta") {
                     // delta 内の tool_calls をチェック
-                    if let Err(msg) = self.interceptor.validate_tools(delta) {
+                    if let Err(msg) = self.interceptor.validate_tool_deltas(delta) {
                         warn!("Forbidden tool detected in stream: {}", msg);
                         return Err(msg);
                     }
@@ -93,54 +758,740 @@ where
     type Item = Result<Event, axum::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // エラーを返した後は上流を読まず、キャッシュもせずに終了する
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        // 送信レートの上限に達している間は、上流も読まずに待つ
+        if let Some(pacer) = &mut self.pacer {
+            std::task::ready!(pacer.poll_ready(cx));
+        }
+        let poll = self.as_mut().poll_events(cx);
+        match &poll {
+            Poll::Ready(Some(Err(_))) => self.terminated = true,
+            Poll::Ready(Some(Ok(_))) => {
+                if let Some(pacer) = &mut self.pacer {
+                    pacer.sent();
+                }
+            }
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl<S> StreamingAnalyzer<S>
+where
+    S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+{
+    fn poll_events(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Event, axum::Error>>> {
+        if let Some(data) = self.leading_event.take() {
+            return Poll::Ready(Some(Ok(Event::default().event(METADATA_EVENT).data(data.to_string()))));
+        }
+
         // 保留中のイベントがあればそれを優先的に返す
         if let Some(event) = self.pending_events.pop_front() {
             return Poll::Ready(Some(event));
         }
+        if self.done {
+            return self.finish();
+        }
+
+        // 行が完成するまで上流を読み続け、上流が Pending の場合だけ Pending を返す
+        loop {
+            let next = match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(next) => next,
+                // 上流を待っている間に結合の時間枠が過ぎたら、保留中の差分を送る
+                Poll::Pending => {
+                    return match self.coalescer.as_mut().and_then(|c| c.poll_expired(cx)) {
+                        Some(data) => Poll::Ready(Some(Ok(self.data_event(data)))),
+                        None => Poll::Pending,
+                    };
+                }
+            };
+            match next {
+                Some(Ok(bytes)) => {
+                    self.buffer.extend_from_slice(&bytes);
+                    self.process_buffer();
+
+                    // バッファを処理した後にイベントがあれば返す
+                    if let Some(event) = self.pending_events.pop_front() {
+                        return Poll::Ready(Some(event));
+                    }
+                    if let Some(data) = self.coalescer.as_mut().and_then(|c| c.poll_expired(cx)) {
+                        return Poll::Ready(Some(Ok(self.data_event(data))));
+                    }
+                }
+                Some(Err(e)) => {
+                    self.flush_coalesced();
+                    self.pending_events.push_back(Err(e));
+                    return Poll::Ready(self.pending_events.pop_front());
+                }
+                None => {
+                    // ストリーム終了時に残りのバッファを処理
+                    self.process_end_of_stream();
+                    return self.finish();
+                }
+            }
+        }
+    }
+
+    /// 終了処理（末尾のイベントとキャッシュ保存）を行い、残りのイベントを返します
+    fn finish(&mut self) -> Poll<Option<Result<Event, axum::Error>>> {
+        self.flush_coalesced();
+        self.push_trailing_event();
+        if let Some(usage) = self.usage.take() {
+            self.pending_events.push_back(Ok(usage.into_event()));
+        }
+
+        // キャッシュ情報があれば保存（終端まで届かなかったストリームは途中で切れた生成結果のため保存しない）
+        if let Some((cache, key)) = self.cache_info.take()
+            && self.done
+        {
+            let ttl = self.cache_ttl;
+            let raw = (self.cache_raw && cache.admits(self.emitted.len()))
+                .then(|| self.get_full_response());
+            let aggregated = self.aggregate.take().and_then(|(key, aggregator)| {
+                let body = Bytes::from(aggregator.finish().to_string());
+                cache.admits(body.len()).then_some((key, body))
+            });
+            tokio::spawn(async move {
+                if let Some(body) = raw
+                    && cache.should_store(&key).await
+                {
+                    cache.set_streaming(key, cached(body, "text/event-stream"), ttl).await;
+                }
+                if let Some((key, body)) = aggregated
+                    && cache.should_store(&key).await
+                {
+                    cache.set_with_ttl(key, cached(body, "application/json"), ttl).await;
+                }
+            });
+        }
+
+        Poll::Ready(self.pending_events.pop_front())
+    }
+}
+
+fn cached(body: Bytes, content_type: &str) -> crate::cache::CachedResponse {
+    let mut headers = std::collections::HashMap::new();
+    headers.insert("content-type".to_string(), content_type.to_string());
+    crate::cache::CachedResponse { status: 200, headers, body }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interception::InterceptionConfig;
+    use axum::response::{IntoResponse, sse::Sse};
+
+    fn interceptor() -> Arc<Interceptor> {
+        Arc::new(Interceptor::try_new(InterceptionConfig {
+            forbidden_tools: vec!["rm_rf".to_string()],
+            allowed_tools: None,
+            max_tool_definitions: None,
+            path_sandbox: Some(crate::interception::PathSandboxConfig {
+                tools: vec!["write_file".to_string()],
+                argument: "path".to_string(),
+                root: None,
+            }),
+            fail_mode: Default::default(),
+            json_limits: Default::default(),
+            tool_schemas: Default::default(),
+        }).unwrap())
+    }
+
+    fn chunks(parts: &[&'static str]) -> impl Stream<Item = Result<Bytes, axum::Error>> + Unpin {
+        futures::stream::iter(parts.iter().map(|p| Ok(Bytes::from_static(p.as_bytes()))).collect::<Vec<_>>())
+    }
+
+    /// 解析結果を SSE としてレンダリングした文字列を返す
+    async fn render<S>(analyzer: StreamingAnalyzer<S>) -> String
+    where
+        S: Stream<Item = Result<Bytes, axum::Error>> + Unpin + Send + 'static,
+    {
+        let body = Sse::new(analyzer).into_response().into_body();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    const KEEPALIVE_STREAM: &[&str] = &[
+        ": ping\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+        ": ping\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"world\"}}]}\n\n",
+        ":keepalive\n\n",
+        "data: [DONE]\n\n",
+    ];
+
+    #[tokio::test]
+    async fn test_keepalive_comments_are_stripped() {
+        let analyzer = StreamingAnalyzer::new(chunks(KEEPALIVE_STREAM), interceptor(), None);
+        let output = render(analyzer).await;
+
+        assert!(!output.contains("ping"));
+        assert!(!output.contains("keepalive"));
+        assert_eq!(output.matches("data: ").count(), 3, "data events must never be dropped: {}", output);
+    }
+
+    #[tokio::test]
+    async fn test_partial_lines_do_not_spin_the_executor() {
+        struct CountingWaker(std::sync::atomic::AtomicUsize);
+        impl futures::task::ArcWake for CountingWaker {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        let body: String = KEEPALIVE_STREAM.concat();
+        let bytes: Vec<Result<Bytes, axum::Error>> = body.bytes().map(|b| Ok(Bytes::copy_from_slice(&[b]))).collect();
+        let mut analyzer = StreamingAnalyzer::new(futures::stream::iter(bytes), interceptor(), None);
+        let counter = Arc::new(CountingWaker(Default::default()));
+        let waker = futures::task::waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut events = 0;
+        while let Poll::Ready(Some(event)) = Pin::new(&mut analyzer).poll_next(&mut cx) {
+            assert!(event.is_ok());
+            events += 1;
+        }
+        assert_eq!(events, 3);
+        assert_eq!(counter.0.load(std::sync::atomic::Ordering::SeqCst), 0, "partial lines must not self-wake");
+    }
+
+    #[tokio::test]
+    async fn test_crlf_separated_events() {
+        let stream = chunks(&[
+            "data: {\"choices\":[{\"delta\":{\"content\":\"1234\"}}]}\r",
+            "\n\r\ndata: {\"choices\":[{\"delta\":{\"content\":\"5678\"}}]}\r\n\r\n",
+            "data: [DONE]\r\n\r\n",
+        ]);
+        let analyzer = StreamingAnalyzer::new(stream, interceptor(), None).with_usage_trailer(1, None);
+        let output = render(analyzer).await;
+
+        assert!(!output.contains('\r'), "{}", output);
+        assert!(output.contains("data: {\"choices\":[{\"delta\":{\"content\":\"1234\"}}]}\n\n"));
+        assert!(output.contains("data: [DONE]\n\n"));
+        // 両方のチャンクの本文が JSON として解析されている
+        assert!(output.contains(r#""completion_tokens":2"#), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn test_multi_line_data_fields_form_one_event() {
+        let stream = chunks(&[
+            "data: {\"choices\":[{\"delta\":\n",
+            "data: {\"tool_calls\":[{\"index\":0,\"function\":{\"name\":\"rm_rf\",\"arguments\":\"{}\"}}]}}]}\n\n",
+            "data: [DONE]\n\n",
+        ]);
+        let output = render(StreamingAnalyzer::new(stream, interceptor(), None)).await;
+        assert!(!output.contains("[DONE]"), "the joined event must be parsed and blocked: {}", output);
+
+        let stream = chunks(&["data: {\"choices\":\r\n", "data: []}\r\n\r\n", "data: [DONE]\r\n\r\n"]);
+        let output = render(StreamingAnalyzer::new(stream, interceptor(), None)).await;
+        assert_eq!(output.matches("data: ").count(), 3, "{}", output);
+        assert!(output.contains("data: {\"choices\":\ndata: []}\n\n"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_comments_can_be_forwarded() {
+        let analyzer = StreamingAnalyzer::new(chunks(KEEPALIVE_STREAM), interceptor(), None)
+            .with_options(StreamOptions { strip_keepalive_comments: false, ..Default::default() });
+        let output = render(analyzer).await;
+
+        assert_eq!(output.matches(": ping").count(), 2, "{}", output);
+        assert_eq!(output.matches("data: ").count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_usage_trailer_reports_tokens_and_cost() {
+        let stream = chunks(&[
+            "data: {\"choices\":[{\"delta\":{\"content\":\"12345678\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        ]);
+        let price = ModelPrice { prompt_per_1k: 1.0, completion_per_1k: 2.0 };
+        let analyzer = StreamingAnalyzer::new(stream, interceptor(), None).with_usage_trailer(500, Some(price));
+        let output = render(analyzer).await;
+
+        let trailer = output.split("event: orchix.usage\n").nth(1).expect("trailing usage event");
+        let data: Value = serde_json::from_str(trailer.trim().strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(data["prompt_tokens"], 500);
+        assert_eq!(data["completion_tokens"], 2);
+        assert!((data["estimated_cost"].as_f64().unwrap() - 0.504).abs() < 1e-9);
+        assert!(output.find("[DONE]").unwrap() < output.find(USAGE_EVENT).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_usage_trailer_prefers_reported_usage() {
+        let stream = chunks(&[
+            "data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":34}}\n\n",
+            "data: [DONE]\n\n",
+        ]);
+        let analyzer = StreamingAnalyzer::new(stream, interceptor(), None).with_usage_trailer(1, None);
+        let output = render(analyzer).await;
+
+        assert!(output.contains(r#""prompt_tokens":12"#), "{}", output);
+        assert!(output.contains(r#""completion_tokens":34"#));
+        assert!(output.contains(r#""estimated_cost":null"#));
+    }
+
+    const FRAGMENTED_WRITE: &[&str] = &[
+        "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"name\":\"write_file\",\"arguments\":\"\"}}]}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"path\\\":\\\"../\"}}]}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"etc/passwd\\\"}\"}}]}}]}\n\n",
+        "data: [DONE]\n\n",
+    ];
+
+    #[tokio::test]
+    async fn test_reassembled_path_argument_is_checked() {
+        let mut analyzer = StreamingAnalyzer::new(chunks(FRAGMENTED_WRITE), interceptor(), None);
+        let mut saw_error = false;
+        while let Some(item) = futures::StreamExt::next(&mut analyzer).await {
+            if item.is_err() {
+                saw_error = true;
+                break;
+            }
+        }
+        assert!(saw_error, "traversal split across chunks must be blocked");
+    }
+
+    #[tokio::test]
+    async fn test_policy_violation_stops_reading_upstream() {
+        let pulled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let stream = futures::StreamExt::inspect(chunks(&[
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ok\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"name\":\"rm_rf\",\"arguments\":\"{}\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"leaked\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        ]), move |_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+        let mut analyzer = StreamingAnalyzer::new(stream, interceptor(), None);
+
+        let mut items = Vec::new();
+        while let Some(item) = futures::StreamExt::next(&mut analyzer).await {
+            items.push(item);
+        }
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(items[1].is_err());
+        // エラーの後は上流を読まず、以降のチャンクも届けない
+        assert_eq!(pulled.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(futures::StreamExt::next(&mut analyzer).await.is_none());
+        assert_eq!(pulled.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reassembled_safe_path_passes() {
+        let stream = chunks(&[
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"name\":\"write_file\",\"arguments\":\"{\\\"path\\\":\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"tmp/out.txt\\\"}\"}}]}}]}\n\n",
+            "data: [DONE]\n\n",
+        ]);
+        let output = render(StreamingAnalyzer::new(stream, interceptor(), None)).await;
+        assert!(output.contains("[DONE]"), "{}", output);
+    }
+
+    #[test]
+    fn test_aggregator_assembles_chunks() {
+        let mut aggregator = ResponseAggregator::default();
+        for chunk in [
+            serde_json::json!({"id": "c1", "model": "gpt-4", "created": 1, "choices": [{"index": 0, "delta": {"role": "assistant", "content": "Hello"}}]}),
+            serde_json::json!({"id": "c1", "choices": [{"index": 0, "delta": {"content": " world"}}]}),
+            serde_json::json!({"id": "c1", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "id": "t1", "function": {"name": "get_weather", "arguments": "{\"city\":"}}]}}]}),
+            serde_json::json!({"id": "c1", "choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "function": {"arguments": "\"Tokyo\"}"}}]}, "finish_reason": "tool_calls"}]}),
+        ] {
+            aggregator.push(&chunk);
+        }
+
+        let response = aggregator.finish();
+        assert_eq!(response["object"], "chat.completion");
+        assert_eq!(response["model"], "gpt-4");
+        let choice = &response["choices"][0];
+        assert_eq!(choice["message"]["content"], "Hello world");
+        assert_eq!(choice["message"]["tool_calls"][0]["function"]["arguments"], "{\"city\":\"Tokyo\"}");
+        assert_eq!(choice["finish_reason"], "tool_calls");
+    }
+
+    const ANTHROPIC_STREAM: &[&str] = &[
+        "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"model\":\"claude-3\",\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n\n",
+        "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+        "event: ping\ndata: {\"type\":\"ping\"}\n\n",
+        "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hello\"}}\n\n",
+        "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\" world\"}}\n\n",
+        "event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+        "event: message_delta\ndata: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\"usage\":{\"output_tokens\":2}}\n\n",
+        "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+    ];
+
+    /// Anthropic のツール呼び出し（`tool_use` ブロックと `input_json_delta`）のストリーム
+    fn anthropic_tool_stream(name: &str, arguments: &[&str]) -> Vec<String> {
+        let mut events = vec![
+            r#"{"type":"message_start","message":{"id":"msg_2","model":"claude-3"}}"#.to_string(),
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#.to_string(),
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Writing it."}}"#.to_string(),
+            r#"{"type":"content_block_stop","index":0}"#.to_string(),
+            serde_json::json!({"type": "content_block_start", "index": 1, "content_block": {"type": "tool_use", "id": "toolu_1", "name": name, "input": {}}}).to_string(),
+        ];
+        events.extend(arguments.iter().map(|partial| {
+            serde_json::json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": partial}}).to_string()
+        }));
+        events.push(r#"{"type":"content_block_stop","index":1}"#.to_string());
+        events.push(r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":9}}"#.to_string());
+        events.push(r#"{"type":"message_stop"}"#.to_string());
+        events.into_iter().map(|event| {
+            let name = serde_json::from_str::<Value>(&event).unwrap()["type"].as_str().unwrap().to_string();
+            format!("event: {}\ndata: {}\n\n", name, event)
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_tool_use_is_intercepted() {
+        let analyzer = |events: Vec<String>, format: ResponseFormat| {
+            let stream = futures::stream::iter(events.into_iter().map(|e| Ok::<_, axum::Error>(Bytes::from(e))));
+            render(StreamingAnalyzer::new(stream, interceptor(), None).with_response_format(format))
+        };
+
+        for format in [ResponseFormat::Auto, ResponseFormat::Anthropic] {
+            // 禁止されたツール
+            let output = analyzer(anthropic_tool_stream("rm_rf", &["{}"]), format).await;
+            assert!(!output.contains("[DONE]"), "forbidden tool must be blocked: {}", output);
+
+            // 分割して届いた引数を組み立ててからパスを検査する
+            let output = analyzer(anthropic_tool_stream("write_file", &["{\"path\":\"../", "etc/passwd\"}"]), format).await;
+            assert!(!output.contains("[DONE]"), "traversal must be blocked: {}", output);
+
+            let output = analyzer(anthropic_tool_stream("write_file", &["{\"path\":", "\"tmp/out.txt\"}"]), format).await;
+            assert!(output.contains("[DONE]"), "{}", output);
+            assert!(output.contains(r#""tool_calls":[{"function":{"arguments":"","name":"write_file"},"id":"toolu_1","index":1,"type":"function"}]"#), "{}", output);
+            assert!(output.contains(r#""finish_reason":"tool_calls""#));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_stream_translated_to_openai() {
+        let analyzer = StreamingAnalyzer::new(chunks(ANTHROPIC_STREAM), interceptor(), None)
+            .with_response_format(ResponseFormat::Anthropic);
+        let output = render(analyzer).await;
+
+        let events: Vec<&str> = output.lines().filter_map(|l| l.strip_prefix("data: ")).collect();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        let chunks: Vec<Value> = events[..events.len() - 1].iter().map(|e| serde_json::from_str(e).unwrap()).collect();
+        assert!(chunks.iter().all(|c| c["object"] == "chat.completion.chunk" && c["id"] == "msg_1" && c["model"] == "claude-3"));
+
+        let content: String = chunks.iter().filter_map(|c| c["choices"][0]["delta"]["content"].as_str()).collect();
+        assert_eq!(content, "Hello world");
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+        // `message_start` の入力トークン数を最後の usage に引き継ぐ
+        assert_eq!(chunks.last().unwrap()["usage"], serde_json::json!({"prompt_tokens": 25, "completion_tokens": 2}));
+        assert!(!output.contains("content_block"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn test_translated_tool_use_is_intercepted() {
+        let stream = chunks(&[
+            "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_2\",\"model\":\"claude-3\"}}\n\n",
+            "data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"tu_1\",\"name\":\"rm_rf\",\"input\":{}}}\n\n",
+        ]);
+        let mut analyzer = StreamingAnalyzer::new(stream, interceptor(), None)
+            .with_response_format(ResponseFormat::Anthropic);
+        let mut saw_error = false;
+        while let Some(item) = futures::StreamExt::next(&mut analyzer).await {
+            saw_error |= item.is_err();
+        }
+        assert!(saw_error);
+    }
+
+    const RESPONSES_STREAM: &[&str] = &[
+        "event: response.created\ndata: {\"type\":\"response.created\",\"response\":{\"id\":\"resp_1\",\"model\":\"gpt-4.1\"}}\n\n",
+        "event: response.output_text.delta\ndata: {\"type\":\"response.output_text.delta\",\"output_index\":0,\"delta\":\"Hello\"}\n\n",
+        "event: response.output_text.delta\ndata: {\"type\":\"response.output_text.delta\",\"output_index\":0,\"delta\":\" world\"}\n\n",
+        "event: response.output_text.done\ndata: {\"type\":\"response.output_text.done\",\"output_index\":0,\"text\":\"Hello world\"}\n\n",
+        "event: response.completed\ndata: {\"type\":\"response.completed\",\"response\":{\"status\":\"completed\",\"usage\":{\"input_tokens\":3,\"output_tokens\":2}}}\n\n",
+    ];
+
+    const OPENAI_STREAM: &[&str] = &[
+        "data: {\"id\":\"c1\",\"object\":\"chat.completion.chunk\",\"model\":\"gpt-4\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+        "data: {\"id\":\"c1\",\"object\":\"chat.completion.chunk\",\"model\":\"gpt-4\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\" world\"},\"finish_reason\":\"stop\"}]}\n\n",
+        "data: [DONE]\n\n",
+    ];
+
+    #[tokio::test]
+    async fn test_each_provider_format_is_detected_without_hint() {
+        for (stream, id) in [(OPENAI_STREAM, "c1"), (ANTHROPIC_STREAM, "msg_1"), (RESPONSES_STREAM, "resp_1")] {
+            let output = render(StreamingAnalyzer::new(chunks(stream), interceptor(), None)).await;
+
+            let events: Vec<&str> = output.lines().filter_map(|l| l.strip_prefix("data: ")).collect();
+            assert_eq!(events.last(), Some(&"[DONE]"), "{}", output);
+            let chunks: Vec<Value> = events[..events.len() - 1].iter().map(|e| serde_json::from_str(e).unwrap()).collect();
+            assert!(chunks.iter().all(|c| c["object"] == "chat.completion.chunk" && c["id"] == id), "{}", output);
+            let content: String = chunks.iter().filter_map(|c| c["choices"][0]["delta"]["content"].as_str()).collect();
+            assert_eq!(content, "Hello world");
+            assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detected_responses_function_call_is_intercepted() {
+        let stream = chunks(&[
+            "data: {\"type\":\"response.created\",\"response\":{\"id\":\"resp_2\",\"model\":\"gpt-4.1\"}}\n\n",
+            "data: {\"type\":\"response.output_item.added\",\"output_index\":1,\"item\":{\"type\":\"function_call\",\"call_id\":\"call_1\",\"name\":\"rm_rf\"}}\n\n",
+        ]);
+        let mut analyzer = StreamingAnalyzer::new(stream, interceptor(), None);
+        let mut saw_error = false;
+        while let Some(item) = futures::StreamExt::next(&mut analyzer).await {
+            saw_error |= item.is_err();
+        }
+        assert!(saw_error);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_stream_is_not_replayed_from_cache() {
+        let config: crate::config::CacheConfig =
+            toml::from_str("enabled = true\nttl_seconds = 600\nmax_capacity = 10").unwrap();
+        let cache = crate::cache::OrchixCache::new(&config);
+        let key = crate::cache::CacheKey::new("/v1/chat", b"{}");
+        let aggregated = crate::cache::CacheKey::new("/v1/chat#aggregated", b"{}");
+        let run = |parts: &'static [&'static str]| {
+            let analyzer = StreamingAnalyzer::new(chunks(parts), interceptor(), Some((cache.clone(), key.clone())))
+                .with_aggregated_cache(aggregated.clone(), true);
+            render(analyzer)
+        };
+
+        // 上流が `[DONE]` の前に切れたストリームは保存しない
+        run(&OPENAI_STREAM[..2]).await;
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        assert!(cache.get(&key).await.is_none(), "partial stream must not be replayed");
+        assert!(cache.lookup_degraded(&key).await.is_none());
+        assert!(cache.get(&aggregated).await.is_none());
+
+        run(OPENAI_STREAM).await;
+        for _ in 0..100 {
+            if cache.get(&key).await.is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(cache.get(&key).await.is_some());
+        assert!(cache.get(&aggregated).await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_streamed_cache_entry_expires_per_streaming_ttl() {
+        let config: crate::config::CacheConfig = toml::from_str(
+            "enabled = true\nttl_seconds = 600\nmax_capacity = 10\nstreaming_ttl_seconds = 30",
+        )
+        .unwrap();
+        let cache = crate::cache::OrchixCache::new(&config);
+        let key = crate::cache::CacheKey::new("/v1/chat", b"{}");
+
+        let analyzer = StreamingAnalyzer::new(chunks(OPENAI_STREAM), interceptor(), Some((cache.clone(), key.clone())));
+        render(analyzer).await;
+        for _ in 0..100 {
+            if cache.get(&key).await.is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(cache.get(&key).await.is_some());
+
+        tokio::time::advance(std::time::Duration::from_secs(31)).await;
+        assert!(cache.get(&key).await.is_none(), "streamed entry must expire after streaming_ttl_seconds");
+    }
+
+    #[tokio::test]
+    async fn test_stream_declaring_too_many_indices_is_terminated() {
+        let choices: Vec<String> = (0..5)
+            .map(|i| format!("data: {{\"choices\":[{{\"index\":{},\"delta\":{{\"content\":\"x\"}}}}]}}\n\n", i))
+            .collect();
+        let tools: String = (0..5).map(|i| format!("{{\"index\":{},\"function\":{{\"arguments\":\"\"}}}}", i)).collect::<Vec<_>>().join(",");
+        let tool_chunk = format!("data: {{\"choices\":[{{\"index\":0,\"delta\":{{\"tool_calls\":[{}]}}}}]}}\n\n", tools);
+        let options = StreamOptions { max_choice_indices: 4, max_tool_call_indices: 4, ..Default::default() };
+
+        for parts in [choices, vec![tool_chunk]] {
+            let stream = futures::stream::iter(parts.into_iter().map(|p| Ok::<_, axum::Error>(Bytes::from(p))));
+            let mut analyzer = StreamingAnalyzer::new(stream, interceptor(), None).with_options(options.clone());
+            let mut items = Vec::new();
+            while let Some(item) = futures::StreamExt::next(&mut analyzer).await {
+                items.push(item);
+            }
+            let error = items.last().unwrap().as_ref().map(|_| ()).unwrap_err().to_string();
+            assert!(error.contains("indices"), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metadata_events_wrap_provider_events() {
+        let analyzer = StreamingAnalyzer::new(chunks(OPENAI_STREAM), interceptor(), None)
+            .with_usage_trailer(3, None)
+            .with_leading_event(serde_json::json!({"type": "start", "route": "/v1/chat", "request_id": "req-1"}))
+            .with_trailing_event(serde_json::json!({"type": "end", "request_id": "req-1"}));
+        let output = render(analyzer).await;
+
+        let events: Vec<&str> = output.split("\n\n").filter(|e| !e.is_empty()).collect();
+        assert!(events[0].starts_with("event: orchix\n"), "{}", output);
+        assert!(events[0].contains(r#""route":"/v1/chat""#));
+        assert!(events[1].contains("chat.completion.chunk"), "provider events follow the leading event");
+
+        let done = events.iter().position(|e| *e == "data: [DONE]").unwrap();
+        let trailing = events[done - 1];
+        assert!(trailing.starts_with("event: orchix\n"), "{}", output);
+        let data: Value = serde_json::from_str(trailing.lines().nth(1).unwrap().strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(data["type"], "end");
+        assert_eq!(data["usage"]["prompt_tokens"], 3);
+        assert_eq!(events.iter().filter(|e| e.starts_with("event: orchix\n")).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_interception_trace_sent_before_policy_violation() {
+        let stream = chunks(&[
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"name\":\"rm_rf\",\"arguments\":\"{}\"}}]}}]}\n\n",
+        ]);
+        let mut trace = InterceptionTrace::default();
+        trace.record("tool_calls", TraceVerdict::Pass, None);
+        let mut analyzer = StreamingAnalyzer::new(stream, interceptor(), None).with_interception_trace(trace);
+        let mut items = Vec::new();
+        while let Some(item) = futures::StreamExt::next(&mut analyzer).await {
+            items.push(item);
+        }
+
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(items[1].is_err());
+        let event = format!("{:?}", items[0].as_ref().unwrap());
+        assert!(event.contains("interception_trace") && event.contains("stream_content"), "{}", event);
+    }
+
+    #[tokio::test]
+    async fn test_trailing_event_sent_without_done() {
+        let stream = chunks(&["data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"hi\"}}]}\n\n"]);
+        let output = render(StreamingAnalyzer::new(stream, interceptor(), None).with_trailing_event(serde_json::json!({"type": "end"}))).await;
+        assert!(output.trim_end().ends_with(r#"data: {"type":"end"}"#), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn test_data_after_done_is_suppressed() {
+        let upstream = chunks(&[
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"hi\"}}]}\n\n",
+            "data: [DONE]\n\ndata: {\"stray\":1}\n\n",
+            "garbage\ndata: [DONE]\n\n",
+        ]);
+        let stream = futures::StreamExt::chain(upstream, futures::stream::pending());
+        let analyzer = StreamingAnalyzer::new(stream, interceptor(), None).with_options(StreamOptions {
+            trailing_data: TrailingDataMode::Warn,
+            ..Default::default()
+        });
+
+        // 上流が接続を閉じなくても `[DONE]` の時点で終わる
+        let output = tokio::time::timeout(std::time::Duration::from_secs(1), render(analyzer)).await.unwrap();
+        assert!(!output.contains("stray"), "{}", output);
+        assert_eq!(output.matches("[DONE]").count(), 1, "{}", output);
+        assert!(output.trim_end().ends_with("data: [DONE]"), "{}", output);
+    }
+
+    /// `data:` イベントの JSON を順に取り出す
+    async fn data_events<S>(analyzer: StreamingAnalyzer<S>) -> Vec<Value>
+    where
+        S: Stream<Item = Result<Bytes, axum::Error>> + Unpin + Send + 'static,
+    {
+        render(analyzer)
+            .await
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str(data).ok())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_tiny_content_deltas_are_coalesced() {
+        let mut parts: Vec<String> = "Hello, world"
+            .chars()
+            .map(|c| format!("data: {{\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{}\"}}}}]}}\n\n", c))
+            .collect();
+        // ツール呼び出しの境界はまとめずに保つ
+        parts.insert(5, "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"name\":\"read_file\",\"arguments\":\"{}\"}}]}}]}\n\n".to_string());
+        parts.push("data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n".to_string());
+        parts.push("data: [DONE]\n\n".to_string());
+        let upstream = || futures::stream::iter(parts.clone().into_iter().map(|p| Ok::<_, axum::Error>(Bytes::from(p))));
+
+        let plain = data_events(StreamingAnalyzer::new(upstream(), interceptor(), None)).await;
+        let coalesced = data_events(StreamingAnalyzer::new(upstream(), interceptor(), None).with_options(StreamOptions {
+            coalesce_window_ms: Some(50),
+            ..Default::default()
+        }))
+        .await;
+
+        assert_eq!(plain.len(), 14);
+        assert_eq!(coalesced.len(), 4, "{:?}", coalesced);
+        assert_eq!(coalesced[0]["choices"][0]["delta"]["content"], "Hello");
+        assert!(coalesced[1]["choices"][0]["delta"]["tool_calls"].is_array());
+        assert_eq!(coalesced[2]["choices"][0]["delta"]["content"], ", world");
+        assert_eq!(coalesced[3]["choices"][0]["finish_reason"], "stop");
+        let text = |events: &[Value]| -> String {
+            events.iter().filter_map(|e| e["choices"][0]["delta"]["content"].as_str()).collect()
+        };
+        assert_eq!(text(&coalesced), text(&plain));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesced_delta_sent_when_window_elapses() {
+        // 2つ目の差分は時間枠の後に届くため、別のイベントになる
+        let upstream = futures::stream::unfold(0, |step| async move {
+            let part = match step {
+                0 => "data: {\"choices\":[{\"delta\":{\"content\":\"a\"}}]}\n\n",
+                1 => "data: {\"choices\":[{\"delta\":{\"content\":\"b\"}}]}\n\n",
+                2 => {
+                    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                    "data: {\"choices\":[{\"delta\":{\"content\":\"c\"}}]}\n\n"
+                }
+                _ => return None,
+            };
+            Some((Ok::<_, axum::Error>(Bytes::from_static(part.as_bytes())), step + 1))
+        });
+        let mut analyzer = StreamingAnalyzer::new(Box::pin(futures::StreamExt::fuse(upstream)), interceptor(), None).with_options(StreamOptions {
+            coalesce_window_ms: Some(50),
+            ..Default::default()
+        });
+
+        let started = tokio::time::Instant::now();
+        assert!(futures::StreamExt::next(&mut analyzer).await.unwrap().is_ok());
+        // 上流の3つ目を待たずに、時間枠が過ぎた時点で "ab" を送る
+        assert!(started.elapsed() < std::time::Duration::from_millis(200));
+        let rest: Vec<_> = futures::StreamExt::collect::<Vec<_>>(analyzer).await;
+        assert_eq!(rest.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_event_rate_is_capped() {
+        let read = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = read.clone();
+        let parts: Vec<String> = (0..20)
+            .map(|i| format!("data: {{\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{}\"}}}}]}}\n\n", i))
+            .collect();
+        let upstream = futures::StreamExt::map(futures::stream::iter(parts), move |part| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok::<_, axum::Error>(Bytes::from(part))
+        });
+        let mut analyzer = StreamingAnalyzer::new(upstream, interceptor(), None).with_options(StreamOptions {
+            max_events_per_second: Some(10),
+            ..Default::default()
+        });
+
+        let started = tokio::time::Instant::now();
+        let mut sent_at = Vec::new();
+        while let Some(event) = futures::StreamExt::next(&mut analyzer).await {
+            assert!(event.is_ok());
+            sent_at.push(started.elapsed());
+            // 待っている間に上流を先読みしない
+            assert!(read.load(std::sync::atomic::Ordering::SeqCst) <= sent_at.len(), "read ahead of emitted events");
+        }
 
-        match Pin::new(&mut self.inner).poll_next(cx) {
-            Poll::Ready(Some(Ok(bytes))) => {
-                self.buffer.extend_from_slice(&bytes);
-                self.full_response_buffer.extend_from_slice(&bytes);
-                self.process_buffer();
-                
-                // バッファを処理した後にイベントがあれば返す
-                if let Some(event) = self.pending_events.pop_front() {
-                    Poll::Ready(Some(event))
-                } else {
-                    // まだ行が完成していなければ次のチャンクを待つ
-                    // Note: 本来はここで再度 poll したいが、再帰を避けるため
-                    // Context を使って再度 wake する必要がある
-                    cx.waker().wake_by_ref();
-                    Poll::Pending
-                }
-            }
-            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
-            Poll::Ready(None) => {
-                // ストリーム終了時に残りのバッファを処理
-                self.process_buffer();
-                
-                // キャッシュ情報があれば保存
-                if let Some((cache, key)) = self.cache_info.take() {
-                    let body = self.full_response_buffer.clone().freeze();
-                    tokio::spawn(async move {
-                        let mut headers = std::collections::HashMap::new();
-                        headers.insert("content-type".to_string(), "text/event-stream".to_string());
-                        cache.set(key, crate::cache::CachedResponse {
-                            status: 200,
-                            headers,
-                            body,
-                        }).await;
-                    });
-                }
-
-                if let Some(event) = self.pending_events.pop_front() {
-                    Poll::Ready(Some(event))
-                } else {
-                    Poll::Ready(None)
-                }
-            }
-            Poll::Pending => Poll::Pending,
+        assert!(sent_at.len() >= 20);
+        for window in sent_at.windows(11) {
+            assert!(window[10] - window[0] >= std::time::Duration::from_secs(1), "more than 10 events within a second");
         }
     }
 }
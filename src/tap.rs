@@ -0,0 +1,173 @@
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// リダクション対象とする JSON キー（小文字で比較）
+const REDACTED_KEYS: &[&str] = &["api_key", "apikey", "authorization", "password", "secret", "token"];
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct TapConfig {
+    pub enabled: bool,
+    /// フィードに流すリクエストの割合 (0.0 - 1.0)
+    pub sample_rate: f64,
+    /// 1秒あたりに配信するイベント数の上限
+    pub max_events_per_second: u32,
+    /// ボディ抜粋の最大バイト数
+    pub excerpt_bytes: usize,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_rate: 1.0,
+            max_events_per_second: 20,
+            excerpt_bytes: 256,
+        }
+    }
+}
+
+/// タップで配信される1リクエスト分のイベント
+#[derive(Debug, Clone, Serialize)]
+pub struct TapEvent {
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: u64,
+    pub request_excerpt: String,
+    pub response_excerpt: String,
+}
+
+/// プロキシを通過するトラフィックをライブ配信するためのタップ
+pub struct Tap {
+    config: TapConfig,
+    sender: broadcast::Sender<TapEvent>,
+    // (秒単位のタイムスタンプ, その秒に配信したイベント数)
+    window: Mutex<(u64, u32)>,
+}
+
+impl Tap {
+    pub fn new(config: TapConfig) -> Self {
+        let (sender, _) = broadcast::channel(256);
+        Self {
+            config,
+            sender,
+            window: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TapEvent> {
+        self.sender.subscribe()
+    }
+
+    /// 購読者が存在し、サンプリングとレート上限を通過した場合に true を返す
+    ///
+    /// ボディ抜粋の作成コストを避けるため、イベント生成前に呼び出します。
+    pub fn should_sample(&self) -> bool {
+        if !self.config.enabled || self.sender.receiver_count() == 0 {
+            return false;
+        }
+        if self.config.sample_rate < 1.0 && rand::random::<f64>() >= self.config.sample_rate {
+            return false;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut window = self.window.lock().unwrap();
+        if window.0 != now {
+            *window = (now, 0);
+        }
+        if window.1 >= self.config.max_events_per_second {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+
+    pub fn publish(&self, event: TapEvent) {
+        // 購読者がいなくなった場合の送信エラーは無視
+        let _ = self.sender.send(event);
+    }
+
+    /// ボディの抜粋を作成します（機密キーをマスクし、上限バイト数で切り詰め）
+    pub fn excerpt(&self, body: &[u8]) -> String {
+        let text = match serde_json::from_slice::<Value>(body) {
+            Ok(mut json) => {
+                redact(&mut json);
+                json.to_string()
+            }
+            Err(_) => String::from_utf8_lossy(body).into_owned(),
+        };
+        truncate(text, self.config.excerpt_bytes)
+    }
+}
+
+pub(crate) fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if REDACTED_KEYS.contains(&k.to_lowercase().as_str()) {
+                    *v = Value::String("[REDACTED]".to_string());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn truncate(mut text: String, max_bytes: usize) -> String {
+    if text.len() > max_bytes {
+        let mut end = max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push('…');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap(max_events_per_second: u32) -> Tap {
+        Tap::new(TapConfig {
+            enabled: true,
+            sample_rate: 1.0,
+            max_events_per_second,
+            excerpt_bytes: 32,
+        })
+    }
+
+    #[test]
+    fn test_sampling_requires_subscriber_and_caps_rate() {
+        let tap = tap(2);
+        assert!(!tap.should_sample());
+
+        let _rx = tap.subscribe();
+        assert!(tap.should_sample());
+        assert!(tap.should_sample());
+        assert!(!tap.should_sample());
+    }
+
+    #[test]
+    fn test_excerpt_redacts_and_truncates() {
+        let tap = tap(1);
+        let excerpt = tap.excerpt(br#"{"api_key":"sk-123","model":"gpt-4"}"#);
+        assert!(!excerpt.contains("sk-123"));
+        assert!(excerpt.contains("[REDACTED]"));
+
+        let long = tap.excerpt("あ".repeat(100).as_bytes());
+        assert!(long.len() <= 32 + '…'.len_utf8());
+    }
+}
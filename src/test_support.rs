@@ -0,0 +1,483 @@
+//! テスト用のモック上流サーバー
+//!
+//! 転送・リトライ・サーキットブレーカー・ストリーミングのテストで、
+//! 実際のソケットで待ち受ける上流を組み立てるために使います。
+//!
+//! ```ignore
+//! let upstream = MockUpstream::new()
+//!     .fail_n_times(2)
+//!     .respond_with(200, r#"{"ok":true}"#)
+//!     .start()
+//!     .await;
+//! let url = upstream.url("/v1/chat/completions");
+//! ```
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, Method, StatusCode},
+    response::Response,
+    Router,
+};
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// モック上流が受け取ったリクエスト
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Clone)]
+enum Reply {
+    Canned { status: StatusCode, headers: Vec<(String, String)>, body: Bytes },
+    Events(Vec<String>),
+    Echo,
+}
+
+struct Shared {
+    reply: Reply,
+    delay: Option<Duration>,
+    event_interval: Option<Duration>,
+    failures_left: Mutex<usize>,
+    failure_status: StatusCode,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+/// モック上流のビルダー
+pub struct MockUpstream {
+    reply: Reply,
+    delay: Option<Duration>,
+    event_interval: Option<Duration>,
+    fail_n_times: usize,
+    failure_status: StatusCode,
+}
+
+impl Default for MockUpstream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockUpstream {
+    /// 既定では空の JSON オブジェクトを 200 で返します
+    pub fn new() -> Self {
+        Self {
+            reply: Reply::Canned {
+                status: StatusCode::OK,
+                headers: vec![("content-type".to_string(), "application/json".to_string())],
+                body: Bytes::from_static(b"{}"),
+            },
+            delay: None,
+            event_interval: None,
+            fail_n_times: 0,
+            failure_status: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// 固定のレスポンスを返します（content-type は application/json）
+    pub fn respond_with(mut self, status: u16, body: impl Into<Bytes>) -> Self {
+        self.reply = Reply::Canned {
+            status: StatusCode::from_u16(status).expect("valid status code"),
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: body.into(),
+        };
+        self
+    }
+
+    /// 固定レスポンスにヘッダーを追加します
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        if let Reply::Canned { headers, .. } = &mut self.reply {
+            headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            headers.push((name.to_string(), value.to_string()));
+        }
+        self
+    }
+
+    /// 固定レスポンスに、同じ名前のヘッダーを置き換えずに追加します
+    pub fn append_header(mut self, name: &str, value: &str) -> Self {
+        if let Reply::Canned { headers, .. } = &mut self.reply {
+            headers.push((name.to_string(), value.to_string()));
+        }
+        self
+    }
+
+    /// 受け取ったリクエストの method / path / body を JSON で返します
+    pub fn echo(mut self) -> Self {
+        self.reply = Reply::Echo;
+        self
+    }
+
+    /// レスポンスを返す前に待機します
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// `data:` の各イベントを SSE として返します（`[DONE]` は自動では付けない）
+    pub fn stream_events<I, S>(mut self, events: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.reply = Reply::Events(events.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// SSE のイベント間に待機を入れます
+    pub fn event_interval(mut self, interval: Duration) -> Self {
+        self.event_interval = Some(interval);
+        self
+    }
+
+    /// 最初の `n` 回のリクエストを失敗させます（既定は 500）
+    pub fn fail_n_times(mut self, n: usize) -> Self {
+        self.fail_n_times = n;
+        self
+    }
+
+    /// 失敗時のステータスを変更します
+    pub fn failure_status(mut self, status: u16) -> Self {
+        self.failure_status = StatusCode::from_u16(status).expect("valid status code");
+        self
+    }
+
+    /// ローカルのランダムなポートで待ち受けを開始します
+    pub async fn start(self) -> MockServer {
+        let (shared, app) = self.into_app();
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind mock upstream");
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app).await.ok();
+        });
+        MockServer { addr, shared, task }
+    }
+
+    /// 専用のスレッドとランタイムで待ち受けを開始し、アドレスを返します（プロセスの終了まで動かす）
+    ///
+    /// 時間を止めたテストや、ランタイムをまたいで共有する上流に使います。
+    pub fn start_detached(self) -> SocketAddr {
+        let (_, app) = self.into_app();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind mock upstream");
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, app).await.ok();
+            });
+        });
+        addr
+    }
+
+    fn into_app(self) -> (Arc<Shared>, Router) {
+        let shared = Arc::new(Shared {
+            reply: self.reply,
+            delay: self.delay,
+            event_interval: self.event_interval,
+            failures_left: Mutex::new(self.fail_n_times),
+            failure_status: self.failure_status,
+            requests: Mutex::new(Vec::new()),
+        });
+        let app = Router::new().fallback(handle).with_state(shared.clone());
+        (shared, app)
+    }
+}
+
+async fn handle(State(shared): State<Arc<Shared>>, req: Request) -> Response {
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    let recorded = RecordedRequest {
+        method: parts.method,
+        path: parts.uri.path_and_query().map(|p| p.to_string()).unwrap_or_default(),
+        headers: parts.headers,
+        body,
+    };
+    shared.requests.lock().unwrap().push(recorded.clone());
+
+    if let Some(delay) = shared.delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let fail = {
+        let mut left = shared.failures_left.lock().unwrap();
+        let fail = *left > 0;
+        *left = left.saturating_sub(1);
+        fail
+    };
+    if fail {
+        return Response::builder()
+            .status(shared.failure_status)
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"error":{"message":"mock upstream failure"}}"#))
+            .unwrap();
+    }
+
+    match shared.reply.clone() {
+        Reply::Canned { status, headers, body } => {
+            let mut builder = Response::builder().status(status);
+            for (name, value) in headers {
+                builder = builder.header(name, value);
+            }
+            builder.body(Body::from(body)).unwrap()
+        }
+        Reply::Events(events) => {
+            let interval = shared.event_interval;
+            let stream = futures::stream::unfold(events.into_iter(), move |mut events| async move {
+                let event = events.next()?;
+                if let Some(interval) = interval {
+                    tokio::time::sleep(interval).await;
+                }
+                Some((Ok::<_, std::io::Error>(Bytes::from(format!("data: {}\n\n", event))), events))
+            });
+            Response::builder()
+                .header("content-type", "text/event-stream")
+                .body(Body::from_stream(stream))
+                .unwrap()
+        }
+        Reply::Echo => {
+            let echoed = serde_json::json!({
+                "method": recorded.method.as_str(),
+                "path": recorded.path,
+                "body": String::from_utf8_lossy(&recorded.body),
+            });
+            Response::builder()
+                .header("content-type", "application/json")
+                .body(Body::from(echoed.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+/// 起動済みのモック上流。破棄すると停止します
+pub struct MockServer {
+    pub addr: SocketAddr,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// 受け取ったリクエストの一覧
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.shared.requests.lock().unwrap().clone()
+    }
+
+    pub fn hits(&self) -> usize {
+        self.shared.requests.lock().unwrap().len()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// 依存クレートなしで HTTP/1.1 のリクエストを送り、レスポンス全体を文字列で返します
+pub async fn raw_request(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method, path, addr, body.len(), body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// GET / SET / DEL / SCAN だけに応答するモックの Redis（それ以外のコマンドには `+OK` を返す）
+///
+/// SCAN は `MATCH <接頭辞>*` の形のみに対応し、1回で全件を返します。
+pub struct MockRedis {
+    pub addr: SocketAddr,
+    data: Arc<Mutex<std::collections::HashMap<Vec<u8>, Vec<u8>>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockRedis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data: Arc<Mutex<std::collections::HashMap<Vec<u8>, Vec<u8>>>> = Arc::default();
+        let store = data.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve_redis(socket, store.clone()));
+            }
+        });
+        Self { addr, data, task }
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://{}", self.addr)
+    }
+
+    /// 保存されているキーの一覧
+    pub fn keys(&self) -> Vec<String> {
+        self.data.lock().unwrap().keys().map(|key| String::from_utf8_lossy(key).to_string()).collect()
+    }
+
+    /// 保存されている値
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.data.lock().unwrap().get(key.as_bytes()).cloned()
+    }
+
+    /// 値を直接書き込みます（他のプロセスが書いた値や壊れた値の再現用）
+    pub fn insert(&self, key: &str, value: impl Into<Vec<u8>>) {
+        self.data.lock().unwrap().insert(key.as_bytes().to_vec(), value.into());
+    }
+}
+
+impl Drop for MockRedis {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve_redis(socket: tokio::net::TcpStream, data: Arc<Mutex<std::collections::HashMap<Vec<u8>, Vec<u8>>>>) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        // コマンドは `*<引数の数>` に続く `$<長さ>` + 値の配列
+        line.clear();
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let Some(count) = line.trim_end().strip_prefix('*').and_then(|n| n.parse::<usize>().ok()) else {
+            return;
+        };
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            let len: usize = line.trim_end().trim_start_matches('$').parse().unwrap();
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await.unwrap();
+            arg.truncate(len);
+            args.push(arg);
+        }
+        let reply = match args[0].to_ascii_uppercase().as_slice() {
+            b"GET" => match data.lock().unwrap().get(&args[1]) {
+                Some(value) => [format!("${}\r\n", value.len()).into_bytes(), value.clone(), b"\r\n".to_vec()].concat(),
+                None => b"$-1\r\n".to_vec(),
+            },
+            b"SET" => {
+                data.lock().unwrap().insert(args[1].clone(), args[2].clone());
+                b"+OK\r\n".to_vec()
+            }
+            b"DEL" => {
+                let mut data = data.lock().unwrap();
+                let removed = args[1..].iter().filter(|key| data.remove(*key).is_some()).count();
+                format!(":{}\r\n", removed).into_bytes()
+            }
+            b"SCAN" => {
+                let pattern = args.iter().position(|arg| arg.eq_ignore_ascii_case(b"MATCH")).map_or(&b"*"[..], |i| &args[i + 1]);
+                // `\` でエスケープした文字は、そのまま接頭辞として扱う
+                let mut prefix = Vec::new();
+                let mut escaped = false;
+                for &b in pattern.strip_suffix(b"*").unwrap_or(pattern) {
+                    if b == b'\\' && !escaped {
+                        escaped = true;
+                        continue;
+                    }
+                    escaped = false;
+                    prefix.push(b);
+                }
+                let keys: Vec<Vec<u8>> = data.lock().unwrap().keys().filter(|key| key.starts_with(&prefix)).cloned().collect();
+                let mut reply = format!("*2\r\n$1\r\n0\r\n*{}\r\n", keys.len()).into_bytes();
+                for key in keys {
+                    reply.extend(format!("${}\r\n", key.len()).into_bytes());
+                    reply.extend(key);
+                    reply.extend(b"\r\n");
+                }
+                reply
+            }
+            _ => b"+OK\r\n".to_vec(),
+        };
+        if writer.write_all(&reply).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_canned_response_and_recording() {
+        let upstream = MockUpstream::new()
+            .respond_with(201, r#"{"id":"x"}"#)
+            .header("x-mock", "yes")
+            .start()
+            .await;
+
+        let response = raw_request(upstream.addr, "POST", "/v1/chat?q=1", r#"{"model":"gpt-4"}"#).await;
+        assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+        assert!(response.contains("x-mock: yes"));
+        assert!(response.ends_with(r#"{"id":"x"}"#));
+
+        let recorded = upstream.requests();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].method, Method::POST);
+        assert_eq!(recorded[0].path, "/v1/chat?q=1");
+        assert_eq!(recorded[0].body, r#"{"model":"gpt-4"}"#);
+        assert_eq!(recorded[0].headers["content-length"], "17");
+        assert_eq!(upstream.url("/v1/chat"), format!("http://{}/v1/chat", upstream.addr));
+    }
+
+    #[tokio::test]
+    async fn test_fail_n_times_then_succeed() {
+        let upstream = MockUpstream::new().fail_n_times(2).failure_status(503).start().await;
+        for _ in 0..2 {
+            assert!(raw_request(upstream.addr, "GET", "/", "").await.starts_with("HTTP/1.1 503"));
+        }
+        assert!(raw_request(upstream.addr, "GET", "/", "").await.starts_with("HTTP/1.1 200"));
+        assert_eq!(upstream.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stream_events() {
+        let upstream = MockUpstream::new()
+            .stream_events([r#"{"choices":[]}"#, "[DONE]"])
+            .event_interval(Duration::from_millis(5))
+            .start()
+            .await;
+        let response = raw_request(upstream.addr, "POST", "/v1/chat", "{}").await;
+        assert!(response.contains("content-type: text/event-stream"));
+        assert!(response.contains("data: {\"choices\":[]}\n\n"));
+        assert!(response.contains("data: [DONE]\n\n"));
+    }
+
+    #[tokio::test]
+    async fn test_echo_returns_the_request() {
+        let upstream = MockUpstream::new().echo().start().await;
+        let response = raw_request(upstream.addr, "POST", "/v1/chat?q=1", "hello").await;
+        let body: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({"method": "POST", "path": "/v1/chat?q=1", "body": "hello"}));
+    }
+
+    #[tokio::test]
+    async fn test_delay() {
+        let upstream = MockUpstream::new().delay(Duration::from_millis(50)).start().await;
+        let started = std::time::Instant::now();
+        raw_request(upstream.addr, "GET", "/", "").await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
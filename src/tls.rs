@@ -0,0 +1,218 @@
+use serde::Deserialize;
+use schemars::JsonSchema;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{anyhow, bail};
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::SupportedProtocolVersion;
+
+/// リスナーで許可する TLS の最小バージョン
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// HTTPS リスナーの証明書とセキュリティポリシー（`[server.tls]`）
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+pub struct TlsConfig {
+    /// PEM 形式の証明書チェーン（サーバー証明書を先頭に書く）
+    pub cert_path: PathBuf,
+    /// PEM 形式の秘密鍵（PKCS#8 / PKCS#1 / SEC1）
+    pub key_path: PathBuf,
+    /// 最小バージョン（デフォルトは TLS 1.2）。1.0 / 1.1 は指定できません
+    #[serde(default)]
+    pub min_tls_version: TlsVersion,
+    /// 許可する暗号スイート名（例: "TLS13_AES_256_GCM_SHA384"）。未設定ならプロバイダのデフォルト
+    #[serde(default)]
+    pub cipher_suites: Option<Vec<String>>,
+}
+
+impl TlsConfig {
+    /// ネゴシエーションを許可するプロトコルバージョン
+    pub fn protocol_versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        static TLS12_AND_UP: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13, &rustls::version::TLS12];
+        static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+        match self.min_tls_version {
+            TlsVersion::Tls12 => TLS12_AND_UP,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+
+    /// 暗号スイートの許可リストを適用した CryptoProvider を作成します
+    pub fn crypto_provider(&self) -> anyhow::Result<CryptoProvider> {
+        let mut provider = ring::default_provider();
+        if let Some(allowed) = &self.cipher_suites {
+            for name in allowed {
+                if !provider.cipher_suites.iter().any(|s| suite_name(s) == *name) {
+                    bail!("Unknown TLS cipher suite in tls.cipher_suites: {}", name);
+                }
+            }
+            provider.cipher_suites.retain(|s| allowed.contains(&suite_name(s)));
+        }
+
+        // 許可リストと最小バージョンの組み合わせで使えるスイートが残るか確認
+        let versions = self.protocol_versions();
+        if !provider.cipher_suites.iter().any(|s| versions.contains(&s.version())) {
+            bail!("tls.cipher_suites leaves no cipher suite usable with TLS {:?} or later", self.min_tls_version);
+        }
+        Ok(provider)
+    }
+
+    /// ポリシーを適用した rustls の ServerConfig を作成します
+    pub fn server_config(
+        &self,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> anyhow::Result<rustls::ServerConfig> {
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(self.crypto_provider()?))
+            .with_protocol_versions(self.protocol_versions())
+            .map_err(|e| anyhow!("Invalid TLS policy: {}", e))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| anyhow!("Invalid TLS certificate or key: {}", e))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    /// 証明書と秘密鍵を読み込み、リスナー用の設定を作成します（起動時に呼び出す）
+    pub fn load_server_config(&self) -> anyhow::Result<rustls::ServerConfig> {
+        let certs = load_certs(&self.cert_path)?;
+        let key = PrivateKeyDer::from_pem_slice(&read_pem(&self.key_path, "private key")?)
+            .map_err(|e| anyhow!("Failed to parse TLS private key {}: {}", self.key_path.display(), e))?;
+        self.server_config(certs, key)
+    }
+}
+
+fn read_pem(path: &Path, kind: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow!("Failed to read TLS {} {}: {}", kind, path.display(), e))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(&read_pem(path, "certificate")?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Failed to parse TLS certificate {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn suite_name(suite: &rustls::SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConnection};
+
+    fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()).into();
+        (cert.cert.der().clone(), key)
+    }
+
+    /// メモリ上でハンドシェイクを行い、成功したかを返す
+    fn handshake(policy: &TlsConfig, client_versions: &[&'static SupportedProtocolVersion]) -> bool {
+        let (cert, key) = self_signed();
+        let server_config = policy.server_config(vec![cert.clone()], key).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(client_versions)
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        let mut client = ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+
+        for _ in 0..10 {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            if server.read_tls(&mut buf.as_slice()).is_ok() && server.process_new_packets().is_err() {
+                return false;
+            }
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            if client.read_tls(&mut buf.as_slice()).is_ok() && client.process_new_packets().is_err() {
+                return false;
+            }
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn test_default_minimum_is_tls12() {
+        let policy = TlsConfig::default();
+        assert!(handshake(&policy, &[&rustls::version::TLS12]));
+        assert!(handshake(&policy, &[&rustls::version::TLS13]));
+    }
+
+    #[test]
+    fn test_lower_version_client_is_refused() {
+        let policy = TlsConfig { min_tls_version: TlsVersion::Tls13, ..Default::default() };
+        assert!(!handshake(&policy, &[&rustls::version::TLS12]));
+        assert!(handshake(&policy, &[&rustls::version::TLS13]));
+    }
+
+    #[test]
+    fn test_legacy_versions_are_rejected_in_config() {
+        let paths = "cert_path = \"cert.pem\"\nkey_path = \"key.pem\"\n";
+        assert!(toml::from_str::<TlsConfig>(&format!("{}min_tls_version = \"1.1\"", paths)).is_err());
+        assert!(toml::from_str::<TlsConfig>(&format!("{}min_tls_version = \"1.3\"", paths)).is_ok());
+    }
+
+    #[test]
+    fn test_cipher_allow_list() {
+        let policy = TlsConfig {
+            min_tls_version: TlsVersion::Tls13,
+            cipher_suites: Some(vec!["TLS13_AES_256_GCM_SHA384".to_string()]),
+            ..Default::default()
+        };
+        let provider = policy.crypto_provider().unwrap();
+        assert_eq!(provider.cipher_suites.len(), 1);
+
+        let unknown = TlsConfig { cipher_suites: Some(vec!["NOT_A_SUITE".to_string()]), ..Default::default() };
+        assert!(unknown.crypto_provider().is_err());
+
+        // TLS 1.2 専用スイートのみを TLS 1.3 必須で指定すると使えるスイートがない
+        let unusable = TlsConfig {
+            min_tls_version: TlsVersion::Tls13,
+            cipher_suites: Some(vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()]),
+            ..Default::default()
+        };
+        assert!(unusable.crypto_provider().is_err());
+    }
+
+    #[test]
+    fn test_certificate_and_key_loaded_from_pem_files() {
+        let dir = std::env::temp_dir().join(format!("orchix-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        std::fs::write(dir.join("garbage.pem"), "not a pem file").unwrap();
+        let config = |cert: &str, key: &str| TlsConfig { cert_path: dir.join(cert), key_path: dir.join(key), ..Default::default() };
+
+        assert!(config("cert.pem", "key.pem").load_server_config().is_ok());
+
+        let missing = config("missing.pem", "key.pem").load_server_config().unwrap_err().to_string();
+        assert!(missing.contains("missing.pem"), "{}", missing);
+        let no_certs = config("garbage.pem", "key.pem").load_server_config().unwrap_err().to_string();
+        assert!(no_certs.contains("No certificates found"), "{}", no_certs);
+        let bad_key = config("cert.pem", "garbage.pem").load_server_config().unwrap_err().to_string();
+        assert!(bad_key.contains("private key"), "{}", bad_key);
+    }
+}
@@ -0,0 +1,65 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+use tracing::warn;
+
+/// 上流に送る JSON ボディを書き換える変換関数
+pub type Transform = Arc<dyn Fn(&mut Value) + Send + Sync>;
+
+static REGISTRY: LazyLock<RwLock<HashMap<String, Transform>>> = LazyLock::new(Default::default);
+
+/// 名前付きのボディ変換を登録します
+///
+/// 登録した変換は、ルート設定の `transform = "<name>"` から参照され、
+/// マッチしたリクエストを上流へ転送する直前に適用されます。
+/// 同じ名前で再登録した場合は上書きされます。
+/// ルートの読み込み（起動・再読み込み）では未登録の名前を拒否するため、設定を読み込む前に登録してください。
+pub fn register_transform<F>(name: impl Into<String>, transform: F)
+where
+    F: Fn(&mut Value) + Send + Sync + 'static,
+{
+    REGISTRY.write().unwrap().insert(name.into(), Arc::new(transform));
+}
+
+/// 登録済みの変換を取得します
+pub fn lookup(name: &str) -> Option<Transform> {
+    REGISTRY.read().unwrap().get(name).cloned()
+}
+
+/// 名前付きの変換をボディに適用します。適用した場合は true を返します
+pub fn apply(name: &str, body: &mut Value) -> bool {
+    match lookup(name) {
+        Some(transform) => {
+            transform(body);
+            true
+        }
+        None => {
+            warn!("Route references unregistered transform: {}", name);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_register_and_apply() {
+        register_transform("test_set_user", |body| {
+            body["user"] = json!("orchix");
+        });
+
+        let mut body = json!({"model": "gpt-4"});
+        assert!(apply("test_set_user", &mut body));
+        assert_eq!(body["user"], "orchix");
+    }
+
+    #[test]
+    fn test_unknown_transform_leaves_body_untouched() {
+        let mut body = json!({"model": "gpt-4"});
+        assert!(!apply("test_not_registered", &mut body));
+        assert_eq!(body, json!({"model": "gpt-4"}));
+    }
+}
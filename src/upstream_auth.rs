@@ -0,0 +1,264 @@
+use serde::Deserialize;
+use schemars::JsonSchema;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// 上流の認証に OAuth のクライアントクレデンシャルで取得したトークンを使う設定（ルートごと）
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash, JsonSchema)]
+pub struct UpstreamOAuthConfig {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// 有効期限のこの秒数前から新しいトークンを取得する（有効期間の半分を超える場合は半分にする）
+    #[serde(default = "default_refresh_before_secs")]
+    pub refresh_before_secs: u64,
+    /// トークンエンドポイントの応答待ちの上限
+    #[serde(default = "default_token_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+// 設定全体をログに出すため、`client_secret` は伏せて表示する
+impl std::fmt::Debug for UpstreamOAuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamOAuthConfig")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"[REDACTED]")
+            .field("scopes", &self.scopes)
+            .field("refresh_before_secs", &self.refresh_before_secs)
+            .field("timeout_ms", &self.timeout_ms)
+            .finish()
+    }
+}
+
+fn default_refresh_before_secs() -> u64 {
+    60
+}
+
+fn default_token_timeout_ms() -> u64 {
+    5000
+}
+
+/// トークンエンドポイントが `expires_in` を返さなかった場合の有効期間
+const DEFAULT_EXPIRES_IN_SECS: u64 = 3600;
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    access_token: String,
+    /// この時刻以降は取得し直す（有効期限の `refresh_before_secs` 前）
+    refresh_at: Instant,
+}
+
+impl CachedToken {
+    /// `requested_at` に要求して `expires_in` の有効期間を得たトークン
+    ///
+    /// 有効期間が `refresh_before` より短いトークンを毎回取得し直さないよう、早める幅は有効期間の半分までにします。
+    fn new(access_token: String, requested_at: Instant, expires_in: Duration, refresh_before: Duration) -> Self {
+        let margin = refresh_before.min(expires_in / 2);
+        Self { access_token, refresh_at: requested_at + expires_in - margin }
+    }
+
+    fn usable_at(&self, now: Instant) -> bool {
+        now < self.refresh_at
+    }
+}
+
+// 設定ごとのトークン。取得中はロックを保持し、同時に期限切れを検出したリクエストは完了を待つ
+type TokenSlot = Arc<tokio::sync::Mutex<Option<CachedToken>>>;
+
+/// 上流向けのアクセストークンを取得・キャッシュする
+///
+/// 同じ設定の取得は1回にまとめるため、期限切れの瞬間にトークンエンドポイントへ同時に問い合わせることはありません。
+pub struct UpstreamTokens {
+    client: reqwest::Client,
+    slots: Mutex<HashMap<UpstreamOAuthConfig, TokenSlot>>,
+}
+
+impl UpstreamTokens {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client, slots: Mutex::new(HashMap::new()) }
+    }
+
+    /// 使えるアクセストークンを返します（期限が近ければ取得し直す）
+    pub async fn bearer(&self, config: &UpstreamOAuthConfig) -> Result<String, String> {
+        let slot = self.slot(config);
+        let mut token = slot.lock().await;
+        if let Some(cached) = token.as_ref().filter(|t| t.usable_at(Instant::now())) {
+            return Ok(cached.access_token.clone());
+        }
+        let fetched = self.fetch(config).await?;
+        let access_token = fetched.access_token.clone();
+        *token = Some(fetched);
+        Ok(access_token)
+    }
+
+    /// 上流がトークンを拒否した場合に、次のリクエストで取得し直すよう破棄します
+    pub async fn invalidate(&self, config: &UpstreamOAuthConfig) {
+        *self.slot(config).lock().await = None;
+    }
+
+    fn slot(&self, config: &UpstreamOAuthConfig) -> TokenSlot {
+        self.slots.lock().unwrap().entry(config.clone()).or_default().clone()
+    }
+
+    async fn fetch(&self, config: &UpstreamOAuthConfig) -> Result<CachedToken, String> {
+        let form = {
+            let mut form = url::form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", "client_credentials")
+                .append_pair("client_id", &config.client_id)
+                .append_pair("client_secret", &config.client_secret);
+            if !config.scopes.is_empty() {
+                form.append_pair("scope", &config.scopes.join(" "));
+            }
+            form.finish()
+        };
+        let requested_at = Instant::now();
+        let response = self.client
+            .post(&config.token_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .body(form)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach token endpoint {}: {}", config.token_url, e))?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(|e| format!("Failed to read token response: {}", e))?;
+        if !status.is_success() {
+            warn!("Token endpoint {} returned {}", config.token_url, status);
+            return Err(format!("Token endpoint returned {}", status));
+        }
+        let token: TokenResponse = serde_json::from_slice(&body).map_err(|e| format!("Invalid token response: {}", e))?;
+        let expires_in = token.expires_in.unwrap_or(DEFAULT_EXPIRES_IN_SECS);
+        debug!("Fetched upstream access token from {} (expires in {}s)", config.token_url, expires_in);
+        // 要求時点から数えて、応答の遅延分だけ早めに期限切れとみなす
+        Ok(CachedToken::new(
+            token.access_token,
+            requested_at,
+            Duration::from_secs(expires_in),
+            Duration::from_secs(config.refresh_before_secs),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockUpstream;
+
+    fn config(token_url: String) -> UpstreamOAuthConfig {
+        UpstreamOAuthConfig {
+            token_url,
+            client_id: "orchix".to_string(),
+            client_secret: "s3cret".to_string(),
+            scopes: vec!["inference.read".to_string(), "inference.write".to_string()],
+            refresh_before_secs: 60,
+            timeout_ms: 2000,
+        }
+    }
+
+    #[test]
+    fn test_debug_redacts_client_secret() {
+        let debug = format!("{:?}", config("https://auth.example/token".to_string()));
+        assert!(!debug.contains("s3cret"), "{}", debug);
+        assert!(debug.contains("client_id: \"orchix\""), "{}", debug);
+    }
+
+    #[tokio::test]
+    async fn test_token_fetched_once_and_reused() {
+        let server = MockUpstream::new()
+            .respond_with(200, r#"{"access_token":"tok-1","token_type":"Bearer","expires_in":3600}"#)
+            .start()
+            .await;
+        let tokens = UpstreamTokens::new(reqwest::Client::new());
+        let config = config(server.url("/oauth/token"));
+
+        assert_eq!(tokens.bearer(&config).await.unwrap(), "tok-1");
+        assert_eq!(tokens.bearer(&config).await.unwrap(), "tok-1");
+        assert_eq!(server.hits(), 1);
+
+        let request = &server.requests()[0];
+        let form: HashMap<String, String> = url::form_urlencoded::parse(&request.body).into_owned().collect();
+        assert_eq!(form["grant_type"], "client_credentials");
+        assert_eq!(form["client_id"], "orchix");
+        assert_eq!(form["client_secret"], "s3cret");
+        assert_eq!(form["scope"], "inference.read inference.write");
+    }
+
+    #[test]
+    fn test_token_refreshed_before_expiry() {
+        let now = Instant::now();
+        let token = CachedToken::new("t".to_string(), now, Duration::from_secs(120), Duration::from_secs(60));
+        assert!(token.usable_at(now + Duration::from_secs(59)));
+        assert!(!token.usable_at(now + Duration::from_secs(60)));
+
+        // 有効期間が refresh_before_secs より短い場合は、有効期間の半分で取得し直す
+        let short = CachedToken::new("t".to_string(), now, Duration::from_secs(30), Duration::from_secs(60));
+        assert!(short.usable_at(now + Duration::from_secs(14)));
+        assert!(!short.usable_at(now + Duration::from_secs(15)));
+    }
+
+    #[tokio::test]
+    async fn test_short_lived_token_reused_until_half_its_lifetime() {
+        let server = MockUpstream::new()
+            .respond_with(200, r#"{"access_token":"short","expires_in":2}"#)
+            .start()
+            .await;
+        let tokens = UpstreamTokens::new(reqwest::Client::new());
+        let config = config(server.url("/oauth/token"));
+
+        // refresh_before_secs（60秒）より短い有効期間でも、リクエストごとには取得しない
+        for _ in 0..3 {
+            assert_eq!(tokens.bearer(&config).await.unwrap(), "short");
+        }
+        assert_eq!(server.hits(), 1);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        tokens.bearer(&config).await.unwrap();
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_one_fetch() {
+        let server = MockUpstream::new()
+            .respond_with(200, r#"{"access_token":"shared","expires_in":3600}"#)
+            .delay(Duration::from_millis(100))
+            .start()
+            .await;
+        let tokens = Arc::new(UpstreamTokens::new(reqwest::Client::new()));
+        let config = config(server.url("/oauth/token"));
+
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let (tokens, config) = (tokens.clone(), config.clone());
+                tokio::spawn(async move { tokens.bearer(&config).await })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap().unwrap(), "shared");
+        }
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_endpoint_error_not_cached() {
+        let server = MockUpstream::new().respond_with(500, "oops").start().await;
+        let tokens = UpstreamTokens::new(reqwest::Client::new());
+        let config = config(server.url("/oauth/token"));
+
+        assert!(tokens.bearer(&config).await.is_err());
+        assert!(tokens.bearer(&config).await.is_err());
+        assert_eq!(server.hits(), 2);
+    }
+}
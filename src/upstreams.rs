@@ -0,0 +1,160 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::info;
+use crate::error::OrchixError;
+use crate::networking::AppState;
+
+/// 管理 API でドレイン中にした転送先
+///
+/// ドレイン中の転送先には新しいリクエストを振り分けませんが、
+/// 処理中のリクエストはそのまま完了させ、障害としては扱いません。
+#[derive(Debug, Default)]
+pub struct UpstreamDrains {
+    draining: RwLock<BTreeSet<String>>,
+}
+
+impl UpstreamDrains {
+    pub fn drain(&self, id: &str) {
+        self.draining.write().unwrap().insert(id.to_string());
+    }
+
+    pub fn undrain(&self, id: &str) {
+        self.draining.write().unwrap().remove(id);
+    }
+
+    pub fn is_draining(&self, id: &str) -> bool {
+        self.draining.read().unwrap().contains(id)
+    }
+
+    /// ドレイン中の転送先の ID（昇順）
+    pub fn draining(&self) -> Vec<String> {
+        self.draining.read().unwrap().iter().cloned().collect()
+    }
+}
+
+/// 転送先ごとの処理中のリクエスト数（`max_in_flight` を設定した転送先のみ数える）
+///
+/// 上限に達した転送先は飽和として、リージョンの優先順位より先に振り分けの対象から外します。
+#[derive(Debug, Default)]
+pub struct UpstreamLoad {
+    in_flight: Mutex<HashMap<String, usize>>,
+}
+
+impl UpstreamLoad {
+    /// 転送先が `limit` に達しているか（上限が無ければ常に false）
+    pub fn is_saturated(&self, id: &str, limit: Option<usize>) -> bool {
+        limit.is_some_and(|limit| self.in_flight.lock().unwrap().get(id).copied().unwrap_or(0) >= limit)
+    }
+
+    /// 枠を1つ取ります（飽和していれば None）。枠は `UpstreamSlot` を破棄すると返ります
+    pub fn try_acquire(self: &Arc<Self>, id: &str, limit: Option<usize>) -> Option<UpstreamSlot> {
+        let Some(limit) = limit else {
+            return Some(UpstreamSlot(None));
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(id.to_string()).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(UpstreamSlot(Some((self.clone(), id.to_string()))))
+    }
+}
+
+/// `UpstreamLoad::try_acquire` で取った枠（上限の無い転送先では何もしない）
+pub struct UpstreamSlot(Option<(Arc<UpstreamLoad>, String)>);
+
+impl UpstreamSlot {
+    /// 数えていない（上限の無い転送先の）枠か
+    pub fn is_unlimited(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl Drop for UpstreamSlot {
+    fn drop(&mut self) {
+        if let Some((load, id)) = &self.0 {
+            let mut in_flight = load.in_flight.lock().unwrap();
+            if let Some(count) = in_flight.get_mut(id) {
+                *count -= 1;
+                if *count == 0 {
+                    in_flight.remove(id);
+                }
+            }
+        }
+    }
+}
+
+/// リージョンを考慮した転送先の選択結果の累計
+#[derive(Debug, Default)]
+pub struct RegionSelection {
+    local: AtomicU64,
+    cross_region: AtomicU64,
+}
+
+/// `RegionSelection` の現在値
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RegionSelectionSnapshot {
+    /// インスタンスと同じリージョンの転送先を選んだ回数
+    pub local: u64,
+    /// 同じリージョンに正常な転送先が無く、他のリージョンへ送った回数
+    pub cross_region: u64,
+}
+
+impl RegionSelection {
+    /// 選んだ転送先のリージョンを記録します（どちらかのリージョンが未設定なら記録しない）
+    pub fn record(&self, instance: Option<&str>, target: Option<&str>) {
+        let (Some(instance), Some(target)) = (instance, target) else {
+            return;
+        };
+        let (counter, locality) = if instance == target {
+            (&self.local, "local")
+        } else {
+            (&self.cross_region, "cross_region")
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        crate::metrics::record_region_selection(locality);
+    }
+
+    pub fn snapshot(&self) -> RegionSelectionSnapshot {
+        RegionSelectionSnapshot {
+            local: self.local.load(Ordering::Relaxed),
+            cross_region: self.cross_region.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// `POST /admin/upstreams/{id}/drain`
+pub async fn drain_handler(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    set_draining(&state, &id, true)
+}
+
+/// `POST /admin/upstreams/{id}/undrain`
+pub async fn undrain_handler(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    set_draining(&state, &id, false)
+}
+
+fn set_draining(state: &AppState, id: &str, draining: bool) -> Response {
+    let known = state.routing().router.rules.iter().flat_map(|rule| &rule.targets).any(|target| target.id() == id);
+    if !known {
+        return OrchixError::new(StatusCode::NOT_FOUND, "unknown_upstream", format!("No upstream with id '{}'", id))
+            .into_response();
+    }
+
+    if draining {
+        state.drains.drain(id);
+    } else {
+        state.drains.undrain(id);
+    }
+    info!("Upstream {} is now {}", id, if draining { "draining" } else { "active" });
+    Json(json!({"id": id, "draining": draining})).into_response()
+}